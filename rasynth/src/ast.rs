@@ -1,8 +1,46 @@
+/// where a name is in the source, byte offsets, end exclusive, the
+/// parser fills them in, anything built in code has the default
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+/// a token the grammar matched but cannot take, a number too large
+/// or a type that does not exist, span is of the token
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl TokenError {
+    pub fn new(start: usize, end: usize, message: String) -> Self {
+        TokenError {
+            span: Span::new(start, end),
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at {}", self.message, self.span.start)
+    }
+}
+
+/// the spans are of the operator and of the name
 #[derive(Debug, Clone)]
 pub enum Expr {
-    Operator(String, Vec<Expr>), // An operator with a list of arguments
-    NodeIdent(String),           // A wire in box
-    Num(Numeric),                // A numeric value
+    Operator(String, Vec<Expr>, Span), // An operator with a list of arguments
+    NodeIdent(String, Span),           // A wire in box
+    Num(Numeric),                      // A numeric value
 }
 
 #[derive(Debug, Clone)]
@@ -13,17 +51,24 @@ pub enum Numeric {
 
 #[derive(Debug, Clone)]
 pub enum LetDef {
-    Let(String, Expr),
+    Let(String, Expr, Span), // span of the name
 }
 
 #[derive(Debug, Clone)]
 pub enum BoxWire {
-    Boxw(String, Vec<Expr>),
+    Boxw(String, Vec<WireArg>, Span), // span of the box's name
+}
+
+/// an argument in a box wire, either positional or `:port expr`
+#[derive(Debug, Clone)]
+pub enum WireArg {
+    Positional(Expr),
+    Keyword(String, Expr, Span), // span of the port's name
 }
 
 #[derive(Debug, Clone)]
 pub enum BoxDef {
-    ModuleBox(String, Vec<Port>, Vec<Stmt>),
+    ModuleBox(String, Vec<Port>, Vec<Stmt>, Span), // span of the name
 }
 
 #[derive(Debug, Clone)]
@@ -34,11 +79,109 @@ pub enum Stmt {
 
 #[derive(Debug, Clone)]
 pub enum Port {
-    In(String, Type),
-    Out(String, Type),
+    In(String, Type, Option<Numeric>, Span), // optional default value
+    Out(String, Type, Span),
 }
 
-#[derive(Debug, Clone)]
+impl Port {
+    pub fn name(&self) -> &str {
+        match self {
+            Port::In(name, _, _, _) => name,
+            Port::Out(name, _, _) => name,
+        }
+    }
+}
+
+impl BoxDef {
+    pub fn name(&self) -> &String {
+        match self {
+            BoxDef::ModuleBox(name, _, _, _) => name,
+        }
+    }
+
+    /// in/out ports in declaration order
+    pub fn ports(&self) -> Vec<Port> {
+        let BoxDef::ModuleBox(_, ports, _, _) = self;
+        ports.clone()
+    }
+
+    /// match the args of a box wire against this box's ports
+    /// keyword args bind the named port, the trailing positional args
+    /// bind the remaining out ports and the leading ones fill the
+    /// remaining in ports in declaration order, unbound in ports fall
+    /// back to their default value, returns (port, expr) pairs in port
+    /// declaration order, unbound out ports are left out
+    pub fn resolve_wire(&self, args: &[WireArg]) -> Result<Vec<(Port, Expr)>, String> {
+        let box_name = self.name();
+        let ports = self.ports();
+        let mut bound: Vec<Option<Expr>> = vec![None; ports.len()];
+        for arg in args {
+            if let WireArg::Keyword(key, expr, _) = arg {
+                let idx = ports
+                    .iter()
+                    .position(|p| p.name() == key.as_str())
+                    .ok_or(format!("box {} has no port named {}", box_name, key))?;
+                if bound[idx].is_some() {
+                    return Err(format!("port {} of box {} bound twice", key, box_name));
+                }
+                bound[idx] = Some(expr.clone());
+            }
+        }
+        let positional = args
+            .iter()
+            .filter_map(|arg| match arg {
+                WireArg::Positional(expr) => Some(expr.clone()),
+                WireArg::Keyword(_, _, _) => None,
+            })
+            .collect::<Vec<_>>();
+        let free = |want_in: bool, bound: &Vec<Option<Expr>>| {
+            (0..ports.len())
+                .filter(|i| {
+                    bound[*i].is_none() && matches!(ports[*i], Port::In(_, _, _, _)) == want_in
+                })
+                .collect::<Vec<_>>()
+        };
+        let free_outs = free(false, &bound);
+        let n_outs = free_outs.len().min(positional.len());
+        let (in_args, out_args) = positional.split_at(positional.len() - n_outs);
+        let free_ins = free(true, &bound);
+        if in_args.len() > free_ins.len() {
+            return Err(format!("too many arguments for box {}", box_name));
+        }
+        for (idx, expr) in free_ins.into_iter().zip(in_args.iter()) {
+            bound[idx] = Some(expr.clone());
+        }
+        for (idx, expr) in free_outs.into_iter().zip(out_args.iter()) {
+            bound[idx] = Some(expr.clone());
+        }
+        let mut ret = Vec::new();
+        for (port, expr) in ports.iter().zip(bound) {
+            match (port, expr) {
+                (Port::Out(_, _, _), Some(expr @ Expr::NodeIdent(_, _)))
+                | (Port::In(_, _, _, _), Some(expr)) => ret.push((port.clone(), expr)),
+                (Port::Out(name, _, _), Some(_)) => {
+                    return Err(format!(
+                        "out port {} of box {} must bind a wire name",
+                        name, box_name
+                    ))
+                }
+                (Port::In(_, _, Some(default), _), None) => {
+                    ret.push((port.clone(), Expr::Num(default.clone())))
+                }
+                (Port::In(name, _, None, _), None) => {
+                    return Err(format!(
+                        "missing argument for port {} of box {}",
+                        name, box_name
+                    ))
+                }
+                (Port::Out(_, _, _), None) => {}
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     Int32,
     Float,
@@ -56,7 +199,104 @@ impl Type {
     }
 }
 
+/// (defmacro name (params...) body)
+/// body is an expression template, see macros.rs, the spans are of
+/// the name and of each param
+#[derive(Debug, Clone)]
+pub enum MacroDef {
+    Macro(String, Vec<String>, Expr, Span, Vec<Span>),
+}
+
+/// `(midi port source)` or `(midi port cc n)`, drives an in port of
+/// main from MIDI, see midi.rs for the sources
+#[derive(Debug, Clone)]
+pub enum MidiMap {
+    Map(String, String, Option<Numeric>),
+}
+
+/// `(voices n box)` or `(voices n box steal)`, plays box as n voices,
+/// see voices.rs
+#[derive(Debug, Clone)]
+pub enum VoicesDef {
+    Voices(Numeric, String, Option<String>),
+}
+
+/// `(controls (knob0 port min max [curve [step]]) ...)`, binds the
+/// board's controls to in ports of main, see controls.rs
+#[derive(Debug, Clone)]
+pub enum ControlsDef {
+    Controls(Vec<ControlMap>),
+}
+
+#[derive(Debug, Clone)]
+pub enum ControlMap {
+    Map(
+        String,
+        String,
+        Numeric,
+        Numeric,
+        Option<String>,
+        Option<Numeric>,
+    ),
+}
+
+#[derive(Debug, Clone)]
+pub enum TopItem {
+    BoxDef(BoxDef),
+    MacroDef(MacroDef),
+    MidiMap(MidiMap),
+    VoicesDef(VoicesDef),
+    ControlsDef(ControlsDef),
+}
+
 #[derive(Debug, Clone)]
 pub enum TopDef {
-    Boxes(Vec<BoxDef>),
+    Items(Vec<TopItem>),
+}
+
+impl TopDef {
+    pub fn boxes(&self) -> Vec<BoxDef> {
+        let TopDef::Items(items) = self;
+        items
+            .iter()
+            .filter_map(|item| match item {
+                TopItem::BoxDef(b) => Some(b.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn midi_maps(&self) -> Vec<MidiMap> {
+        let TopDef::Items(items) = self;
+        items
+            .iter()
+            .filter_map(|item| match item {
+                TopItem::MidiMap(m) => Some(m.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// the control maps of every controls form
+    pub fn controls(&self) -> Vec<ControlMap> {
+        let TopDef::Items(items) = self;
+        items
+            .iter()
+            .flat_map(|item| match item {
+                TopItem::ControlsDef(ControlsDef::Controls(maps)) => maps.clone(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    pub fn voices(&self) -> Vec<VoicesDef> {
+        let TopDef::Items(items) = self;
+        items
+            .iter()
+            .filter_map(|item| match item {
+                TopItem::VoicesDef(v) => Some(v.clone()),
+                _ => None,
+            })
+            .collect()
+    }
 }
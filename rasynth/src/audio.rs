@@ -0,0 +1,924 @@
+use crate::config;
+use crate::engine::Engine;
+use crate::graph::FlowGraph;
+use crate::params::Smoothing;
+use crate::preset::{self, Preset};
+use crate::reload::{self, Reloads};
+use crate::scope::Scope;
+use argparse::{ArgumentParser, Collect, Store, StoreOption, StoreTrue};
+use log::*;
+use std::error::Error;
+use std::fs::File;
+use std::io::{stderr, stdout, BufReader, BufWriter, Stdout, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_BLOCK_SIZE: usize = 256;
+
+/// run by the audio thread before each block, to change the engine's
+/// params from outside without locking
+pub type Control = Box<dyn FnMut(&mut Engine) + Send>;
+
+/// rasynth play FILE - render the main box's out ports to a sink
+pub fn command(args: Vec<String>) {
+    let mut file = String::new();
+    let board = config::board();
+    let mut backend = if cfg!(feature = "alsa") && !board.is_virtual {
+        "alsa".to_string()
+    } else {
+        "null".to_string()
+    };
+    let mut output: Option<String> = None;
+    let mut rate = board.audio.rate;
+    let mut channels: usize = 2;
+    let mut block_size = board.audio.block;
+    let mut seconds: Option<f64> = None;
+    let mut format = PcmFormat::S16Le;
+    let mut input: Option<String> = None;
+    let mut capture: Option<String> = None;
+    let mut input_map: Vec<String> = Vec::new();
+    let mut midi = false;
+    let mut connect: Vec<String> = Vec::new();
+    let mut osc: Option<String> = None;
+    let mut board_controls: Option<String> = None;
+    let mut preset_name: Option<String> = None;
+    let mut smooth: Vec<String> = Vec::new();
+    let mut watch = false;
+    let mut crossfade = reload::DEFAULT_CROSSFADE;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Play a raslisp patch");
+        ap.refer(&mut file).required().add_argument(
+            "file",
+            Store,
+            "Raslisp file or saved graph to play",
+        );
+        ap.refer(&mut backend).add_option(
+            &["-s", "--sink"],
+            Store,
+            "Audio backend: alsa, stdout, null or wav",
+        );
+        ap.refer(&mut output).add_option(
+            &["-o", "--output"],
+            StoreOption,
+            "ALSA device or WAV file path, the wav sink writes PATCH.wav without it",
+        );
+        ap.refer(&mut rate)
+            .add_option(&["-r", "--rate"], Store, "Sample rate in Hz");
+        ap.refer(&mut channels)
+            .add_option(&["-c", "--channels"], Store, "Number of channels");
+        ap.refer(&mut block_size)
+            .add_option(&["-b", "--block"], Store, "Frames per block");
+        ap.refer(&mut seconds).add_option(
+            &["-t", "--seconds"],
+            StoreOption,
+            "Stop after this long",
+        );
+        ap.refer(&mut format).add_option(
+            &["-f", "--format"],
+            Store,
+            "Sample format of the stdout backend: s16 or f32",
+        );
+        ap.refer(&mut input).add_option(
+            &["-i", "--input"],
+            StoreOption,
+            "WAV file feeding the in ports of --input-map",
+        );
+        ap.refer(&mut capture).add_option(
+            &["--capture"],
+            StoreOption,
+            "ALSA capture device feeding the in ports of --input-map",
+        );
+        ap.refer(&mut input_map).add_option(
+            &["--input-map"],
+            Collect,
+            "In port of main the next input channel feeds, once per channel",
+        );
+        ap.refer(&mut midi).add_option(
+            &["-m", "--midi"],
+            StoreTrue,
+            "Open an ALSA sequencer port for the (midi ...) maps",
+        );
+        ap.refer(&mut connect).add_option(
+            &["--connect"],
+            Collect,
+            "Sequencer port to take MIDI from, client:port or a name, implies --midi",
+        );
+        ap.refer(&mut osc).add_option(
+            &["--osc"],
+            StoreOption,
+            "UDP port, or address:port, to take OSC param changes on",
+        );
+        ap.refer(&mut board_controls).add_option(
+            &["--controls"],
+            StoreOption,
+            "Set params from the board's controls as the patch's controls \
+             form binds them, gpio or mock:SCRIPT",
+        );
+        ap.refer(&mut preset_name).add_option(
+            &["-p", "--preset"],
+            StoreOption,
+            "Start with the params of this preset of the patch",
+        );
+        ap.refer(&mut smooth).add_option(
+            &["--smooth"],
+            Collect,
+            "Glide of params set by OSC and MIDI controllers, [PARAM=]step, \
+             pole:SECONDS or ramp:SECONDS",
+        );
+        ap.refer(&mut watch).add_option(
+            &["-w", "--watch"],
+            StoreTrue,
+            "Reload the patch when the file changes",
+        );
+        ap.refer(&mut crossfade).add_option(
+            &["--crossfade"],
+            Store,
+            "Seconds the old and the reloaded patch play together",
+        );
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    if channels == 0 {
+        error!("--channels must be at least 1");
+        std::process::exit(2);
+    }
+    if input.is_some() && capture.is_some() {
+        error!("--input and --capture both feed the main box's in ports, give one of them");
+        std::process::exit(2);
+    }
+    let has_input = input.is_some() || capture.is_some();
+    if has_input && input_map.is_empty() {
+        error!("--input and --capture need --input-map to say which in ports they feed");
+        std::process::exit(2);
+    }
+    if !has_input && !input_map.is_empty() {
+        error!("--input-map without --input or --capture");
+        std::process::exit(2);
+    }
+    let target = match (output, backend.as_str()) {
+        (Some(output), _) => output,
+        (None, "wav") => Path::new(&file)
+            .with_extension("wav")
+            .to_string_lossy()
+            .into_owned(),
+        (None, _) => board.audio.device.clone(),
+    };
+    if backend == "stdout" {
+        // stdout carries the samples, keep the logs on stderr short
+        log::set_max_level(log::LevelFilter::Warn);
+    }
+    let graph = match FlowGraph::from_file(Path::new(&file)) {
+        Ok(graph) => graph,
+        Err(e) => {
+            error!("{}: {}", file, e);
+            std::process::exit(2);
+        }
+    };
+    let sink = match open_sink(&backend, &target, rate, channels, block_size, format) {
+        Ok(sink) => sink,
+        Err(e) => {
+            error!("Cannot open {} sink: {}", backend, e);
+            std::process::exit(2);
+        }
+    };
+    let mut engine = Engine::new(&graph, sink.sample_rate() as f32);
+    if let Some(name) = &preset_name {
+        let ports = preset::ports(&graph, &engine);
+        match Preset::load(Path::new(&file), name) {
+            Ok(preset) => {
+                for m in preset.check(&ports) {
+                    warn!("Preset {}: {}", name, m);
+                }
+                for (port, value) in preset.apply(&ports) {
+                    if let Err(e) = engine.set_param(&port, value) {
+                        warn!("Preset {}: {}", name, e);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Cannot load preset {}: {}", name, e);
+                std::process::exit(2);
+            }
+        }
+    }
+    if let Err(e) = engine.map_inputs(&input_map) {
+        error!("--input-map: {}", e);
+        std::process::exit(2);
+    }
+    for spec in smooth.iter() {
+        if let Err(e) = set_smoothing(&mut engine, spec) {
+            error!("--smooth {}: {}", spec, e);
+            std::process::exit(2);
+        }
+    }
+    let source = match (&input, &capture) {
+        (Some(path), _) => Some(open_source("wav", path, rate, 1, block_size)),
+        (None, Some(device)) => {
+            let channels = engine.inputs().len();
+            Some(open_source("alsa", device, rate, channels, block_size))
+        }
+        (None, None) => None,
+    };
+    let source = match source.transpose() {
+        Ok(source) => source,
+        Err(e) => {
+            error!("Cannot open audio input: {}", e);
+            std::process::exit(2);
+        }
+    };
+    info!(
+        "Playing {} on {}, outputs {:?}",
+        file,
+        backend,
+        engine.outputs()
+    );
+    if let Some(source) = &source {
+        if source.sample_rate() != sink.sample_rate() {
+            warn!(
+                "Input is {} Hz, playing at {} Hz without resampling",
+                source.sample_rate(),
+                sink.sample_rate()
+            );
+        }
+        if source.channels() > engine.inputs().len() {
+            warn!(
+                "Input has {} channels, {} are mapped, the rest are dropped",
+                source.channels(),
+                engine.inputs().len()
+            );
+        }
+        info!("Inputs {:?}", engine.inputs());
+    }
+    let mut controls: Vec<Control> = Vec::new();
+    if midi || !connect.is_empty() {
+        #[cfg(feature = "alsa")]
+        let control = crate::seq::open_control(&graph, &engine, &connect);
+        #[cfg(not(feature = "alsa"))]
+        let control: Result<Control, Box<dyn Error>> =
+            Err("MIDI input needs the alsa feature".into());
+        match control {
+            Ok(control) => controls.push(control),
+            Err(e) => {
+                error!("Cannot open MIDI input: {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+    if let Some(osc) = &osc {
+        let bind = match osc.contains(':') {
+            true => osc.clone(),
+            false => format!("0.0.0.0:{}", osc),
+        };
+        match crate::osc::open_control(&engine, &bind) {
+            Ok(control) => controls.push(control),
+            Err(e) => {
+                error!("Cannot open OSC on {}: {}", bind, e);
+                std::process::exit(2);
+            }
+        }
+    }
+    if let Some(spec) = &board_controls {
+        match crate::controls::open_control(&graph, &engine, spec) {
+            Ok(control) => controls.push(control),
+            Err(e) if e.is::<crate::config::BoardError>() => {
+                warn!("Playing without controls: {}", e)
+            }
+            Err(e) => {
+                error!("Cannot open controls {}: {}", spec, e);
+                std::process::exit(2);
+            }
+        }
+    }
+    let reloads = match watch {
+        true => match reload::watch(&file, engine.clone(), crossfade) {
+            Ok(reloads) => Some(reloads),
+            Err(e) => {
+                error!("Cannot watch {}: {}", file, e);
+                std::process::exit(2);
+            }
+        },
+        false => None,
+    };
+    let frames = seconds.map(|s| (s * sink.sample_rate() as f64) as u64);
+    let thread = AudioThread::spawn(engine, source, sink, controls, reloads, block_size, frames);
+    match thread.join() {
+        Ok(frames) => info!("Played {} frames", frames),
+        Err(e) => error!("Audio thread failed: {}", e),
+    }
+}
+
+/// PARAM=SMOOTHING sets one param, SMOOTHING alone all of them
+fn set_smoothing(engine: &mut Engine, spec: &str) -> Result<(), String> {
+    let (names, smoothing) = match spec.split_once('=') {
+        Some((name, smoothing)) => (vec![name.to_string()], smoothing),
+        None => (engine.params(), spec),
+    };
+    let smoothing = smoothing.parse::<Smoothing>()?;
+    for name in names {
+        engine.set_smoothing(&name, smoothing)?;
+    }
+    Ok(())
+}
+
+/// somewhere interleaved f32 frames can be written to, write blocks
+/// until the sink has taken the whole block, samples are in [-1, 1]
+pub trait AudioSink: Send {
+    fn sample_rate(&self) -> u32;
+    fn channels(&self) -> usize;
+    fn write(&mut self, interleaved: &[f32]) -> Result<(), Box<dyn Error>>;
+    /// flush whatever is buffered, called once when the audio thread stops
+    fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// somewhere interleaved f32 frames come from, read fills as many
+/// whole frames as it can and returns how many, 0 at the end
+pub trait AudioSource: Send {
+    fn sample_rate(&self) -> u32;
+    fn channels(&self) -> usize;
+    fn read(&mut self, interleaved: &mut [f32]) -> Result<usize, Box<dyn Error>>;
+}
+
+/// sample encoding of the raw stdout backend
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PcmFormat {
+    S16Le,
+    F32Le,
+}
+
+impl std::str::FromStr for PcmFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "s16" | "s16le" => Ok(PcmFormat::S16Le),
+            "f32" | "f32le" => Ok(PcmFormat::F32Le),
+            _ => Err(format!("unknown sample format {}", s)),
+        }
+    }
+}
+
+fn to_i16(x: f32) -> i16 {
+    (x.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+/// playback through an ALSA PCM device, e.g. the Pi's DAC
+#[cfg(feature = "alsa")]
+pub struct AlsaSink {
+    pcm: alsa::pcm::PCM,
+    rate: u32,
+    channels: usize,
+    buf: Vec<i16>,
+}
+
+#[cfg(feature = "alsa")]
+impl AlsaSink {
+    pub fn open(
+        device: &str,
+        rate: u32,
+        channels: usize,
+        block_size: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        use alsa::pcm::{Access, Format, HwParams, PCM};
+        use alsa::{Direction, ValueOr};
+        let pcm = PCM::new(device, Direction::Playback, false)?;
+        let rate = {
+            let hwp = HwParams::any(&pcm)?;
+            hwp.set_channels(channels as u32)?;
+            hwp.set_rate(rate, ValueOr::Nearest)?;
+            hwp.set_format(Format::s16())?;
+            hwp.set_access(Access::RWInterleaved)?;
+            hwp.set_period_size_near(block_size as alsa::pcm::Frames, ValueOr::Nearest)?;
+            hwp.set_buffer_size_near(4 * block_size as alsa::pcm::Frames)?;
+            pcm.hw_params(&hwp)?;
+            hwp.get_rate()?
+        };
+        info!("ALSA device {} opened at {} Hz", device, rate);
+        Ok(AlsaSink {
+            pcm,
+            rate,
+            channels,
+            buf: Vec::new(),
+        })
+    }
+}
+
+#[cfg(feature = "alsa")]
+impl AudioSink for AlsaSink {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+    fn channels(&self) -> usize {
+        self.channels
+    }
+    fn write(&mut self, interleaved: &[f32]) -> Result<(), Box<dyn Error>> {
+        self.buf.clear();
+        self.buf.extend(interleaved.iter().map(|x| to_i16(*x)));
+        let io = self.pcm.io_i16()?;
+        let mut done = 0;
+        while done < self.buf.len() {
+            match io.writei(&self.buf[done..]) {
+                Ok(frames) => done += frames * self.channels,
+                Err(e) => {
+                    warn!("ALSA underrun: {}", e);
+                    self.pcm.try_recover(e, true)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pcm.drain()?;
+        Ok(())
+    }
+}
+
+/// recording from an ALSA PCM device, e.g. the Pi's ADC
+#[cfg(feature = "alsa")]
+pub struct AlsaSource {
+    pcm: alsa::pcm::PCM,
+    rate: u32,
+    channels: usize,
+    buf: Vec<i16>,
+}
+
+#[cfg(feature = "alsa")]
+impl AlsaSource {
+    pub fn open(
+        device: &str,
+        rate: u32,
+        channels: usize,
+        block_size: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        use alsa::pcm::{Access, Format, HwParams, PCM};
+        use alsa::{Direction, ValueOr};
+        let pcm = PCM::new(device, Direction::Capture, false)?;
+        let rate = {
+            let hwp = HwParams::any(&pcm)?;
+            hwp.set_channels(channels as u32)?;
+            hwp.set_rate(rate, ValueOr::Nearest)?;
+            hwp.set_format(Format::s16())?;
+            hwp.set_access(Access::RWInterleaved)?;
+            hwp.set_period_size_near(block_size as alsa::pcm::Frames, ValueOr::Nearest)?;
+            hwp.set_buffer_size_near(4 * block_size as alsa::pcm::Frames)?;
+            pcm.hw_params(&hwp)?;
+            hwp.get_rate()?
+        };
+        pcm.start()?;
+        info!("ALSA capture device {} opened at {} Hz", device, rate);
+        Ok(AlsaSource {
+            pcm,
+            rate,
+            channels,
+            buf: Vec::new(),
+        })
+    }
+}
+
+#[cfg(feature = "alsa")]
+impl AudioSource for AlsaSource {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+    fn channels(&self) -> usize {
+        self.channels
+    }
+    fn read(&mut self, interleaved: &mut [f32]) -> Result<usize, Box<dyn Error>> {
+        self.buf.resize(interleaved.len(), 0);
+        let io = self.pcm.io_i16()?;
+        let mut done = 0;
+        while done < self.buf.len() {
+            match io.readi(&mut self.buf[done..]) {
+                Ok(frames) => done += frames * self.channels,
+                Err(e) => {
+                    warn!("ALSA overrun: {}", e);
+                    self.pcm.try_recover(e, true)?;
+                }
+            }
+        }
+        for (x, v) in interleaved.iter_mut().zip(self.buf.iter()) {
+            *x = *v as f32 / i16::MAX as f32;
+        }
+        Ok(interleaved.len() / self.channels)
+    }
+}
+
+/// raw interleaved pcm on stdout, for piping into aplay or sox
+pub struct StdoutSink {
+    out: Stdout,
+    format: PcmFormat,
+    rate: u32,
+    channels: usize,
+    bytes: Vec<u8>,
+}
+
+impl StdoutSink {
+    pub fn new(format: PcmFormat, rate: u32, channels: usize) -> Self {
+        StdoutSink {
+            out: std::io::stdout(),
+            format,
+            rate,
+            channels,
+            bytes: Vec::new(),
+        }
+    }
+}
+
+impl AudioSink for StdoutSink {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+    fn channels(&self) -> usize {
+        self.channels
+    }
+    fn write(&mut self, interleaved: &[f32]) -> Result<(), Box<dyn Error>> {
+        self.bytes.clear();
+        for x in interleaved {
+            match self.format {
+                PcmFormat::S16Le => self.bytes.extend(to_i16(*x).to_le_bytes()),
+                PcmFormat::F32Le => self.bytes.extend(x.to_le_bytes()),
+            }
+        }
+        self.out.lock().write_all(&self.bytes)?;
+        Ok(())
+    }
+    fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// drops everything, paced in real time if asked to, for running
+/// patches headless
+pub struct NullSink {
+    rate: u32,
+    channels: usize,
+    realtime: bool,
+    started: Instant,
+    frames: u64,
+}
+
+impl NullSink {
+    pub fn new(rate: u32, channels: usize, realtime: bool) -> Self {
+        NullSink {
+            rate,
+            channels,
+            realtime,
+            started: Instant::now(),
+            frames: 0,
+        }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+    fn channels(&self) -> usize {
+        self.channels
+    }
+    fn write(&mut self, interleaved: &[f32]) -> Result<(), Box<dyn Error>> {
+        self.frames += (interleaved.len() / self.channels) as u64;
+        if self.realtime {
+            let due = Duration::from_secs_f64(self.frames as f64 / self.rate as f64);
+            if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// writes a 16 bit WAV file
+pub struct WavSink {
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+    rate: u32,
+    channels: usize,
+}
+
+impl WavSink {
+    pub fn create(path: &str, rate: u32, channels: usize) -> Result<Self, Box<dyn Error>> {
+        let spec = hound::WavSpec {
+            channels: channels as u16,
+            sample_rate: rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        Ok(WavSink {
+            writer: Some(hound::WavWriter::create(path, spec)?),
+            rate,
+            channels,
+        })
+    }
+}
+
+impl AudioSink for WavSink {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+    fn channels(&self) -> usize {
+        self.channels
+    }
+    fn write(&mut self, interleaved: &[f32]) -> Result<(), Box<dyn Error>> {
+        let writer = self.writer.as_mut().ok_or("wav file already closed")?;
+        for x in interleaved {
+            writer.write_sample(to_i16(*x))?;
+        }
+        Ok(())
+    }
+    fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(writer) = self.writer.take() {
+            writer.finalize()?;
+        }
+        Ok(())
+    }
+}
+
+/// reads a WAV file of any bit depth, integer or float
+pub struct WavSource {
+    reader: hound::WavReader<BufReader<File>>,
+    rate: u32,
+    channels: usize,
+}
+
+impl WavSource {
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        info!(
+            "WAV input {}: {} Hz, {} channels, {} frames",
+            path,
+            spec.sample_rate,
+            spec.channels,
+            reader.duration()
+        );
+        Ok(WavSource {
+            reader,
+            rate: spec.sample_rate,
+            channels: spec.channels as usize,
+        })
+    }
+}
+
+impl AudioSource for WavSource {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+    fn channels(&self) -> usize {
+        self.channels
+    }
+    fn read(&mut self, interleaved: &mut [f32]) -> Result<usize, Box<dyn Error>> {
+        let spec = self.reader.spec();
+        let mut n = 0;
+        match spec.sample_format {
+            hound::SampleFormat::Float => {
+                for (x, v) in interleaved.iter_mut().zip(self.reader.samples::<f32>()) {
+                    *x = v?;
+                    n += 1;
+                }
+            }
+            hound::SampleFormat::Int => {
+                let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                for (x, v) in interleaved.iter_mut().zip(self.reader.samples::<i32>()) {
+                    *x = v? as f32 / scale;
+                    n += 1;
+                }
+            }
+        }
+        Ok(n / self.channels)
+    }
+}
+
+/// open an input by backend name: alsa or wav, target is the ALSA
+/// device or the WAV path, a WAV file brings its own rate and channels
+#[cfg_attr(not(feature = "alsa"), allow(unused_variables))]
+pub fn open_source(
+    backend: &str,
+    target: &str,
+    rate: u32,
+    channels: usize,
+    block_size: usize,
+) -> Result<Box<dyn AudioSource>, Box<dyn Error>> {
+    match backend {
+        #[cfg(feature = "alsa")]
+        "alsa" => Ok(Box::new(AlsaSource::open(
+            target, rate, channels, block_size,
+        )?)),
+        "wav" => Ok(Box::new(WavSource::open(target)?)),
+        _ => Err(format!("unknown or disabled audio input {}", backend).into()),
+    }
+}
+
+/// open a sink by backend name: alsa, stdout, null or wav, target is
+/// the ALSA device or the WAV path
+#[cfg_attr(not(feature = "alsa"), allow(unused_variables))]
+pub fn open_sink(
+    backend: &str,
+    target: &str,
+    rate: u32,
+    channels: usize,
+    block_size: usize,
+    format: PcmFormat,
+) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
+    match backend {
+        #[cfg(feature = "alsa")]
+        "alsa" => Ok(Box::new(AlsaSink::open(
+            target, rate, channels, block_size,
+        )?)),
+        "stdout" => Ok(Box::new(StdoutSink::new(format, rate, channels))),
+        "null" => Ok(Box::new(NullSink::new(rate, channels, true))),
+        "wav" => Ok(Box::new(WavSink::create(target, rate, channels)?)),
+        _ => Err(format!("unknown or disabled audio backend {}", backend).into()),
+    }
+}
+
+/// the thread that pulls blocks from an Engine and writes them to a
+/// sink, feeding it blocks from a source if there is one, running the
+/// controls before each block and swapping in reloaded patches
+pub struct AudioThread {
+    stop: Arc<AtomicBool>,
+    meters: Arc<Meters>,
+    scope: Arc<Scope>,
+    handle: JoinHandle<Result<u64, String>>,
+}
+
+/// what the audio thread measures each block, for the UI to read
+/// without a lock
+pub struct Meters {
+    load: AtomicU32,       // f32 bits, render time over the block's length
+    peaks: Vec<AtomicU32>, // f32 bits, largest |sample| per channel
+}
+
+impl Meters {
+    pub fn new(channels: usize) -> Self {
+        Meters {
+            load: AtomicU32::new(0),
+            peaks: (0..channels).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    /// share of the block's length the last block took to render
+    pub fn load(&self) -> f32 {
+        f32::from_bits(self.load.load(Ordering::Relaxed))
+    }
+
+    /// the peak of each channel since the last call
+    pub fn take_peaks(&self) -> Vec<f32> {
+        self.peaks
+            .iter()
+            .map(|p| f32::from_bits(p.swap(0, Ordering::Relaxed)))
+            .collect()
+    }
+
+    fn record(&self, block: &[f32], load: f32) {
+        self.load.store(load.to_bits(), Ordering::Relaxed);
+        let channels = self.peaks.len().max(1);
+        for frame in block.chunks(channels) {
+            for (x, peak) in frame.iter().zip(self.peaks.iter()) {
+                // the bits of non-negative floats order like the floats
+                peak.fetch_max(x.abs().to_bits(), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl AudioThread {
+    /// start rendering, stops after frames frames if given, otherwise
+    /// when the source runs out, the engine's sample rate is set to
+    /// the sink's
+    pub fn spawn(
+        mut engine: Engine,
+        mut source: Option<Box<dyn AudioSource>>,
+        mut sink: Box<dyn AudioSink>,
+        mut controls: Vec<Control>,
+        mut reloads: Option<Reloads>,
+        block_size: usize,
+        frames: Option<u64>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let meters = Arc::new(Meters::new(sink.channels()));
+        let meter = meters.clone();
+        let scope = Arc::new(Scope::new());
+        let tap = scope.clone();
+        engine.sample_rate = sink.sample_rate() as f32;
+        let handle = thread::Builder::new()
+            .name("audio".to_string())
+            .spawn(move || {
+                let channels = sink.channels();
+                let mut block = vec![0.0; block_size * channels];
+                let in_channels = source.as_ref().map(|s| s.channels()).unwrap_or(0);
+                let mut input = vec![0.0; block_size * in_channels];
+                // the engine a reload replaced and the frames left of its fade
+                let mut fading: Option<(Engine, usize)> = None;
+                let mut faded = vec![0.0; block_size * channels];
+                let crossfade = reloads
+                    .as_ref()
+                    .map(|r| (r.crossfade * engine.sample_rate) as usize)
+                    .unwrap_or(0);
+                let mut done: u64 = 0;
+                while !stop_flag.load(Ordering::Relaxed) {
+                    let n = match frames {
+                        Some(total) if done >= total => break,
+                        Some(total) => (total - done).min(block_size as u64) as usize,
+                        None => block_size,
+                    };
+                    let started = Instant::now();
+                    if let Some(reloads) = reloads.as_mut() {
+                        // the reload thread adopted the state of the
+                        // playing engine already, swapping is a move
+                        if let Ok(mut new) = reloads.engines.pop() {
+                            new.sample_rate = engine.sample_rate;
+                            debug!("Swapped in reload");
+                            let old = std::mem::replace(&mut engine, new);
+                            // a fade still going is cut short
+                            if let Some((older, _)) = fading.replace((old, crossfade)) {
+                                reloads.retire(older);
+                            }
+                        }
+                        reloads.serve(&engine);
+                    }
+                    for control in controls.iter_mut() {
+                        control(&mut engine);
+                    }
+                    let mut n = n;
+                    let got = match source.as_mut() {
+                        Some(src) => {
+                            let input = &mut input[..n * in_channels];
+                            let got = src.read(input).map_err(|e| e.to_string())?;
+                            if frames.is_none() {
+                                // without a length, play to the end of the input
+                                if got == 0 {
+                                    break;
+                                }
+                                n = got;
+                            }
+                            got
+                        }
+                        None => 0,
+                    };
+                    let input = &input[..got * in_channels];
+                    let block = &mut block[..n * channels];
+                    engine.process_input(input, in_channels, block, channels);
+                    if let Some((old, left)) = fading.as_mut() {
+                        let faded = &mut faded[..n * channels];
+                        old.process_input(input, in_channels, faded, channels);
+                        for (frame, old_frame) in
+                            block.chunks_mut(channels).zip(faded.chunks(channels))
+                        {
+                            let g = *left as f32 / crossfade.max(1) as f32;
+                            for (x, y) in frame.iter_mut().zip(old_frame) {
+                                *x = *x * (1.0 - g) + y * g;
+                            }
+                            *left = left.saturating_sub(1);
+                        }
+                    }
+                    if let (Some((_, 0)), Some(reloads)) = (&fading, reloads.as_mut()) {
+                        let (old, _) = fading.take().unwrap();
+                        reloads.retire(old);
+                    }
+                    let took = started.elapsed().as_secs_f32();
+                    meter.record(block, took * engine.sample_rate / n.max(1) as f32);
+                    tap.record(block, channels);
+                    tap.record_probe(&engine.values);
+                    sink.write(block).map_err(|e| e.to_string())?;
+                    done += n as u64;
+                }
+                sink.close().map_err(|e| e.to_string())?;
+                debug!("Audio thread rendered {} frames", done);
+                Ok(done)
+            })
+            .expect("failed to spawn audio thread");
+        AudioThread {
+            stop,
+            meters,
+            scope,
+            handle,
+        }
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    pub fn meters(&self) -> Arc<Meters> {
+        self.meters.clone()
+    }
+
+    /// the latest samples of one output channel
+    pub fn scope(&self) -> Arc<Scope> {
+        self.scope.clone()
+    }
+
+    /// wait for the thread, returns the number of frames rendered
+    pub fn join(self) -> Result<u64, String> {
+        self.handle
+            .join()
+            .unwrap_or(Err("audio thread panicked".to_string()))
+    }
+}
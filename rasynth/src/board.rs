@@ -1,32 +1,27 @@
 use log::*;
 use std::error::Error;
+use std::fmt::Debug;
 use std::thread;
 use std::time::Duration;
 
-use rppal::gpio::Gpio;
+use rppal::gpio::{Gpio, InputPin, OutputPin};
 use rppal::i2c::I2c;
 use rppal::system::DeviceInfo;
 
+use crate::config::{self, BoardError, Controller, I2cDevice};
+use crate::controls::{Ads1115, ControlReader, KnobReader, Levels};
+use crate::display::{self, Display};
 use display_interface_spi::SPIInterface;
-use embedded_graphics::{
-    mono_font::{ascii::FONT_10X20, MonoTextStyle},
-    pixelcolor::Rgb565,
-    prelude::*,
-    text::Text,
-};
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*, primitives::Rectangle};
 use embedded_hal_bus::spi::ExclusiveDevice;
-use mipidsi::{models::ST7789, options::ColorInversion, Builder};
+use mipidsi::{
+    models::ST7789,
+    options::{ColorInversion, Orientation, Rotation},
+    Builder,
+};
 
 use rppal::hal::Delay;
 use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
-use std::process::ExitCode;
-
-const LCD_DC: u8 = 23; // PIN 16 for LCD's DC(Data/Command Selection)
-const LCD_BACKLIGH: u8 = 24; // PIN 18 for LCD's Backlight Control
-
-// Display
-const W: i32 = 240;
-const H: i32 = 240;
 
 pub fn test_gpio() -> Result<(), Box<dyn Error>> {
     println!("Blinking an LED on a {}.", DeviceInfo::new()?.model());
@@ -54,47 +49,273 @@ impl embedded_hal::digital::ErrorType for NoCs {
     type Error = core::convert::Infallible;
 }
 
-/// test GPIO SPI LCD panel
-pub fn test_display() {
-    info!("Testing GPIO SPI LCD panel");
+/// the ST7789 panel with its backlight, drawing goes straight to the
+/// panel so there is nothing to flush
+pub struct Lcd<D> {
+    panel: D,
+    backlight: Option<OutputPin>,
+}
+
+impl<D: DrawTarget<Color = Rgb565>> Dimensions for Lcd<D> {
+    fn bounding_box(&self) -> Rectangle {
+        self.panel.bounding_box()
+    }
+}
+
+impl<D: DrawTarget<Color = Rgb565>> DrawTarget for Lcd<D> {
+    type Color = Rgb565;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        self.panel.draw_iter(pixels)
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        self.panel.fill_contiguous(area, colors)
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        self.panel.fill_solid(area, color)
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.panel.clear(color)
+    }
+}
+
+impl<D: DrawTarget<Color = Rgb565, Error: Debug>> Display for Lcd<D> {
+    fn set_backlight(&mut self, on: bool) {
+        match (on, self.backlight.as_mut()) {
+            (true, Some(pin)) => pin.set_high(),
+            (false, Some(pin)) => pin.set_low(),
+            (_, None) => {}
+        }
+    }
+}
+
+/// fails with Absent on a virtual board
+fn fitted(part: &'static str) -> Result<(), BoardError> {
+    match config::board().is_virtual {
+        true => Err(BoardError::Absent(part)),
+        false => Ok(()),
+    }
+}
+
+fn gpio() -> Result<Gpio, BoardError> {
+    Gpio::new().map_err(|e| BoardError::Gpio(None, e.to_string()))
+}
+
+fn output(gpio: &Gpio, pin: u8) -> Result<OutputPin, BoardError> {
+    gpio.get(pin)
+        .map(|p| p.into_output())
+        .map_err(|e| BoardError::Gpio(Some(pin), e.to_string()))
+}
+
+/// a switch's pin, pulled away from the level that closes it
+fn input(gpio: &Gpio, pin: u8) -> Result<InputPin, BoardError> {
+    let p = gpio
+        .get(pin)
+        .map_err(|e| BoardError::Gpio(Some(pin), e.to_string()))?;
+    Ok(match config::board().controls.active_low {
+        true => p.into_input_pullup(),
+        false => p.into_input_pulldown(),
+    })
+}
+
+/// open raspi's /dev/spidev* the LCD is on
+fn spi() -> Result<Spi, BoardError> {
+    let s = &config::board().spi;
+    let bus = match s.bus {
+        0 => Bus::Spi0,
+        1 => Bus::Spi1,
+        2 => Bus::Spi2,
+        3 => Bus::Spi3,
+        4 => Bus::Spi4,
+        5 => Bus::Spi5,
+        _ => Bus::Spi6,
+    };
+    let cs = match s.cs {
+        0 => SlaveSelect::Ss0,
+        1 => SlaveSelect::Ss1,
+        _ => SlaveSelect::Ss2,
+    };
+    let mode = match s.mode {
+        0 => Mode::Mode0,
+        1 => Mode::Mode1,
+        2 => Mode::Mode2,
+        _ => Mode::Mode3,
+    };
+    Spi::new(bus, cs, s.clock_hz, mode).map_err(|e| BoardError::Spi(s.bus, s.cs, e.to_string()))
+}
+
+/// an ADC that answered a conversion
+fn adc(dev: &I2cDevice) -> Result<Ads1115<I2c>, BoardError> {
+    let i2c = I2c::with_bus(dev.bus).map_err(|e| BoardError::I2c(dev.bus, None, e.to_string()))?;
+    let mut adc = Ads1115::new(i2c, dev.address as u8);
+    adc.read(0)
+        .map_err(|e| BoardError::I2c(dev.bus, Some(dev.address), e.to_string()))?;
+    Ok(adc)
+}
 
+/// open the board's LCD with the backlight off
+pub fn open_lcd() -> Result<Lcd<impl DrawTarget<Color = Rgb565, Error: Debug>>, BoardError> {
+    let board = config::board();
+    let d = &board.display;
+    fitted("LCD")?;
+    if d.controller == Controller::None {
+        return Err(BoardError::Absent("LCD"));
+    }
     // SPI_CLK -> LCD.SCL
     // SPI_MOSI -> LCD.SDA
-    let gpio = Gpio::new().unwrap();
-    let dc = gpio.get(LCD_DC).unwrap().into_output();
-    let mut backlight = gpio.get(LCD_BACKLIGH).unwrap().into_output();
-
-    // open raspi's /dev/spidev*
-    let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss1, 60_000_000_u32, Mode::Mode0).unwrap();
-    let spi_device = ExclusiveDevice::new_no_delay(spi, NoCs).unwrap();
+    let gpio = gpio()?;
+    let dc = output(&gpio, d.dc)?;
+    let backlight = match d.backlight {
+        Some(pin) => {
+            let mut pin = output(&gpio, pin)?;
+            pin.set_low();
+            Some(pin)
+        }
+        None => None,
+    };
+    let spi_device = ExclusiveDevice::new_no_delay(spi()?, NoCs)
+        .map_err(|e| BoardError::Display(format!("{:?}", e)))?;
     let di = SPIInterface::new(spi_device, dc);
     let mut delay = Delay::new();
-    let mut display = Builder::new(ST7789, di)
-        .display_size(W as u16, H as u16)
-        .invert_colors(ColorInversion::Inverted)
+    let rotation = match d.rotation {
+        90 => Rotation::Deg90,
+        180 => Rotation::Deg180,
+        270 => Rotation::Deg270,
+        _ => Rotation::Deg0,
+    };
+    let inversion = match d.invert {
+        true => ColorInversion::Inverted,
+        false => ColorInversion::Normal,
+    };
+    let panel = Builder::new(ST7789, di)
+        .display_size(d.width as u16, d.height as u16)
+        .display_offset(d.offset_x, d.offset_y)
+        .orientation(Orientation::new().rotate(rotation))
+        .invert_colors(inversion)
         .init(&mut delay)
-        .unwrap();
+        .map_err(|e| BoardError::Display(format!("{:?}", e)))?;
+    Ok(Lcd { panel, backlight })
+}
+
+/// the encoders and buttons on GPIO and the knobs on the ADCs on I2C,
+/// without the ADCs that do not answer
+pub struct BoardControls {
+    encoders: Vec<(InputPin, InputPin)>,
+    buttons: Vec<InputPin>,
+    active_low: bool,
+    knobs: KnobReader,
+}
+
+impl ControlReader for BoardControls {
+    fn read(&mut self, levels: &mut Levels) -> Result<bool, Box<dyn Error>> {
+        let closed = |pin: &InputPin| pin.is_low() == self.active_low;
+        levels.encoders.clear();
+        levels
+            .encoders
+            .extend(self.encoders.iter().map(|(a, b)| (closed(a), closed(b))));
+        levels.buttons.clear();
+        levels.buttons.extend(self.buttons.iter().map(closed));
+        // knobs are numbered across the ADCs in the order of the config
+        self.knobs.read(&mut levels.knobs);
+        Ok(true)
+    }
+}
 
-    // Text
-    let char_w = 10;
-    let char_h = 20;
-    let text_style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
-    let text = "Hello World ^_^;";
-    let mut text_x = W;
-    let mut text_y = H / 2;
+/// the board's encoders and buttons, the ADCs that are missing are
+/// left out with a warning
+pub fn open_controls() -> Result<BoardControls, BoardError> {
+    let board = config::board();
+    fitted("controls")?;
+    let pins = &board.controls;
+    let gpio = gpio()?;
+    let encoders = pins
+        .encoders
+        .iter()
+        .map(|[a, b]| Ok((input(&gpio, *a)?, input(&gpio, *b)?)))
+        .collect::<Result<Vec<_>, BoardError>>()?;
+    let buttons = pins
+        .buttons
+        .iter()
+        .map(|pin| input(&gpio, *pin))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut adcs = Vec::new();
+    for dev in board.i2c.iter() {
+        match adc(dev) {
+            Ok(adc) => adcs.push((adc, dev.channels)),
+            Err(e) => warn!("No {}, its knobs are off: {}", dev.device, e),
+        }
+    }
+    Ok(BoardControls {
+        encoders,
+        buttons,
+        active_low: pins.active_low,
+        knobs: KnobReader::spawn(adcs),
+    })
+}
 
-    // Alternating color
-    let colors = [Rgb565::RED, Rgb565::GREEN, Rgb565::BLUE];
+/// for rasynth doctor: the Pi's model once GPIO opens
+pub fn probe_gpio() -> Result<String, BoardError> {
+    fitted("GPIO")?;
+    gpio()?;
+    let model = DeviceInfo::new().map_err(|e| BoardError::Gpio(None, e.to_string()))?;
+    Ok(model.model().to_string())
+}
 
-    // Clear the display initially
-    display.clear(colors[0]).unwrap();
+/// for rasynth doctor: the LCD's SPI device opens
+pub fn probe_spi() -> Result<String, BoardError> {
+    fitted("SPI")?;
+    let s = &config::board().spi;
+    let spi = spi()?;
+    let hz = spi
+        .clock_speed()
+        .map_err(|e| BoardError::Spi(s.bus, s.cs, e.to_string()))?;
+    Ok(format!(
+        "/dev/spidev{}.{} at {} Hz, mode {}",
+        s.bus, s.cs, hz, s.mode
+    ))
+}
 
-    // Turn on backlight
-    backlight.set_high();
+/// for rasynth doctor: a switch's pin can be had, and whether it is closed
+pub fn probe_pin(pin: u8) -> Result<String, BoardError> {
+    fitted("controls")?;
+    let pin = input(&gpio()?, pin)?;
+    let closed = pin.is_low() == config::board().controls.active_low;
+    Ok(match closed {
+        true => format!("GPIO {} closed", pin.pin()),
+        false => format!("GPIO {} open", pin.pin()),
+    })
+}
+
+/// for rasynth doctor: an ADC answers, with where its first knob is
+pub fn probe_adc(dev: &I2cDevice) -> Result<String, BoardError> {
+    fitted("I2C")?;
+    let mut adc = adc(dev)?;
+    let knob = adc
+        .read(0)
+        .map_err(|e| BoardError::I2c(dev.bus, Some(dev.address), e.to_string()))?;
+    Ok(format!(
+        "{} at {:#x} on /dev/i2c-{}, {} knobs, the first at {:.2}",
+        dev.device, dev.address, dev.bus, dev.channels, knob
+    ))
+}
+
+/// test GPIO SPI LCD panel
+pub fn test_display() -> Result<(), Box<dyn Error>> {
+    info!("Testing GPIO SPI LCD panel");
+    let mut lcd = open_lcd()?;
+    lcd.set_backlight(true);
 
-    let start = std::time::Instant::now();
     let mut last = std::time::Instant::now();
-    let mut led_flags = 0b000;
     let mut counter = 0;
     loop {
         let elapsed = last.elapsed().as_secs_f64();
@@ -107,20 +328,14 @@ pub fn test_display() {
         if counter == 256 {
             break;
         }
-
-        // Fill the display with alternating colors every 8 frames
-        display.clear(colors[(counter / 8) % colors.len()]).unwrap();
-
-        // Draw text
-        let right = Text::new(text, Point::new(text_x, text_y), text_style)
-            .draw(&mut display)
-            .unwrap();
-        text_x = if right.x <= 0 { W } else { text_x - char_w };
+        display::test_pattern(&mut lcd, counter).map_err(|e| format!("{:?}", e))?;
+        lcd.flush()?;
     }
 
     // Turn off backlight and clear the display
-    backlight.set_low();
-    display.clear(Rgb565::BLACK).unwrap();
+    lcd.set_backlight(false);
+    lcd.clear(Rgb565::BLACK).map_err(|e| format!("{:?}", e))?;
 
     info!("Finished testing GPIO SPI LCD panel");
+    Ok(())
 }
@@ -0,0 +1,306 @@
+use crate::display;
+use log::*;
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// where the board's config is looked for when --board and
+/// RASYNTH_BOARD are not given
+pub const DEFAULT_PATH: &str = "/etc/rasynth/board.toml";
+
+static BOARD: OnceLock<BoardConfig> = OnceLock::new();
+
+/// what rasynth runs on: the LCD, its SPI bus, the controls' pins, the
+/// I2C devices and the audio device, the defaults are the original hat,
+/// a virtual board has no hardware and draws into a framebuffer
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BoardConfig {
+    pub name: String,
+    #[serde(rename = "virtual")]
+    pub is_virtual: bool,
+    pub display: DisplayConfig,
+    pub spi: SpiConfig,
+    pub controls: ControlPins,
+    pub i2c: Vec<I2cDevice>,
+    pub audio: AudioConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Controller {
+    St7789,
+    None, // headless
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    pub controller: Controller,
+    pub width: u32,
+    pub height: u32,
+    pub rotation: u32, // degrees clockwise, a multiple of 90
+    pub offset_x: u16, // of the panel in the controller's memory
+    pub offset_y: u16,
+    pub invert: bool,
+    pub dc: u8, // BCM numbers
+    pub backlight: Option<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpiConfig {
+    pub bus: u8,
+    pub cs: u8,
+    pub clock_hz: u32,
+    pub mode: u8,
+}
+
+/// the encoders' A and B pins and the buttons' pins, switches close to
+/// ground with active_low, to 3.3 V otherwise
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlPins {
+    pub encoders: Vec<[u8; 2]>,
+    pub buttons: Vec<u8>,
+    pub active_low: bool,
+}
+
+/// an ADC on I2C whose inputs are knobs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct I2cDevice {
+    pub device: String, // ads1115
+    pub bus: u8,
+    pub address: u16,
+    pub channels: u8,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub device: String, // ALSA
+    pub rate: u32,
+    pub block: usize,
+}
+
+impl Default for BoardConfig {
+    fn default() -> Self {
+        BoardConfig {
+            name: "rasynth".to_string(),
+            is_virtual: false,
+            display: DisplayConfig::default(),
+            spi: SpiConfig::default(),
+            controls: ControlPins::default(),
+            i2c: vec![I2cDevice::default()],
+            audio: AudioConfig::default(),
+        }
+    }
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            controller: Controller::St7789,
+            width: display::WIDTH,
+            height: display::HEIGHT,
+            rotation: 0,
+            offset_x: 0,
+            offset_y: 0,
+            invert: true,
+            dc: 23,
+            backlight: Some(24),
+        }
+    }
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        SpiConfig {
+            bus: 0,
+            cs: 1,
+            clock_hz: 60_000_000,
+            mode: 0,
+        }
+    }
+}
+
+impl Default for ControlPins {
+    fn default() -> Self {
+        ControlPins {
+            encoders: vec![[5, 6], [20, 21]],
+            buttons: vec![13, 19, 26, 16],
+            active_low: true,
+        }
+    }
+}
+
+impl Default for I2cDevice {
+    fn default() -> Self {
+        I2cDevice {
+            device: "ads1115".to_string(),
+            bus: 1,
+            address: crate::controls::ADS1115_ADDRESS as u16,
+            channels: 4,
+        }
+    }
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            device: "default".to_string(),
+            rate: crate::engine::DEFAULT_SAMPLE_RATE as u32,
+            block: crate::audio::DEFAULT_BLOCK_SIZE,
+        }
+    }
+}
+
+impl BoardConfig {
+    /// no display, controls or I2C, audio on the null sink unless asked
+    pub fn virtual_board() -> Self {
+        BoardConfig {
+            name: "virtual".to_string(),
+            is_virtual: true,
+            display: DisplayConfig {
+                controller: Controller::None,
+                ..Default::default()
+            },
+            controls: ControlPins {
+                encoders: Vec::new(),
+                buttons: Vec::new(),
+                active_low: true,
+            },
+            i2c: Vec::new(),
+            ..Default::default()
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)?;
+        let config: BoardConfig = toml::from_str(&text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let d = &self.display;
+        if d.width == 0 || d.height == 0 {
+            return Err("display width and height must be above 0".to_string());
+        }
+        if ![0, 90, 180, 270].contains(&d.rotation) {
+            return Err(format!(
+                "display rotation {} is not 0, 90, 180 or 270",
+                d.rotation
+            ));
+        }
+        if self.spi.mode > 3 {
+            return Err(format!("SPI mode {} is not 0-3", self.spi.mode));
+        }
+        if self.spi.bus > 6 || self.spi.cs > 2 {
+            return Err(format!(
+                "no SPI{} chip select {}, buses are 0-6 and chip selects 0-2",
+                self.spi.bus, self.spi.cs
+            ));
+        }
+        let mut pins = vec![d.dc];
+        pins.extend(d.backlight);
+        pins.extend(self.controls.encoders.iter().flatten());
+        pins.extend(self.controls.buttons.iter());
+        for (i, pin) in pins.iter().enumerate() {
+            if *pin > 27 {
+                return Err(format!("GPIO {} is not on the header, use 0-27", pin));
+            }
+            if pins[..i].contains(pin) {
+                return Err(format!("GPIO {} is used twice", pin));
+            }
+        }
+        for dev in self.i2c.iter() {
+            if dev.device != "ads1115" {
+                return Err(format!("unknown I2C device {}, use ads1115", dev.device));
+            }
+            if dev.address > 0x7f {
+                return Err(format!("I2C address {:#x} is not 7 bits", dev.address));
+            }
+            if dev.channels > 4 {
+                return Err(format!("the ADS1115 has 4 inputs, not {}", dev.channels));
+            }
+        }
+        Ok(())
+    }
+
+    /// the display's size once rotated
+    pub fn size(&self) -> (u32, u32) {
+        let d = &self.display;
+        match d.rotation {
+            90 | 270 => (d.height, d.width),
+            _ => (d.width, d.height),
+        }
+    }
+}
+
+/// why a part of the board could not be opened
+#[derive(Debug)]
+pub enum BoardError {
+    Absent(&'static str),         // the board has none of it
+    Gpio(Option<u8>, String),     // the pin if it was one pin
+    Spi(u8, u8, String),          // bus and chip select
+    I2c(u8, Option<u16>, String), // bus and the device's address
+    Display(String),              // the controller did not answer
+    Audio(String, String),        // the ALSA device
+}
+
+impl fmt::Display for BoardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BoardError::Absent(part) => write!(f, "the {} board has no {}", board().name, part),
+            BoardError::Gpio(None, e) => write!(f, "GPIO: {}", e),
+            BoardError::Gpio(Some(pin), e) => write!(f, "GPIO {}: {}", pin, e),
+            BoardError::Spi(bus, cs, e) => write!(f, "/dev/spidev{}.{}: {}", bus, cs, e),
+            BoardError::I2c(bus, None, e) => write!(f, "/dev/i2c-{}: {}", bus, e),
+            BoardError::I2c(bus, Some(address), e) => {
+                write!(f, "{:#x} on /dev/i2c-{}: {}", address, bus, e)
+            }
+            BoardError::Display(e) => write!(f, "LCD init failed: {}", e),
+            BoardError::Audio(device, e) => write!(f, "ALSA {}: {}", device, e),
+        }
+    }
+}
+
+impl Error for BoardError {}
+
+impl BoardError {
+    /// the part is missing from the config rather than broken
+    pub fn is_absent(&self) -> bool {
+        matches!(self, BoardError::Absent(_))
+    }
+}
+
+/// load the board for the rest of the run: path, or virtual, or what
+/// RASYNTH_BOARD names, or DEFAULT_PATH when it exists, or the defaults
+pub fn init(path: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let path = path
+        .map(str::to_string)
+        .or_else(|| std::env::var("RASYNTH_BOARD").ok())
+        .or_else(|| {
+            Path::new(DEFAULT_PATH)
+                .exists()
+                .then(|| DEFAULT_PATH.to_string())
+        });
+    let config = match path.as_deref() {
+        Some("virtual") => BoardConfig::virtual_board(),
+        Some(path) => BoardConfig::load(Path::new(path)).map_err(|e| format!("{}: {}", path, e))?,
+        None => BoardConfig::default(),
+    };
+    info!("Board: {}", config.name);
+    BOARD
+        .set(config)
+        .map_err(|_| "the board was already loaded".into())
+}
+
+/// the board loaded by init, the defaults before that
+pub fn board() -> &'static BoardConfig {
+    BOARD.get_or_init(BoardConfig::default)
+}
@@ -0,0 +1,978 @@
+use crate::ast::{ControlMap, Numeric, TopDef};
+use crate::audio::Control;
+use crate::board;
+use crate::engine::Engine;
+use crate::graph::FlowGraph;
+use crate::input::{Input, InputEvent};
+use crate::params::ParamStore;
+use argparse::{ArgumentParser, StoreOption};
+use embedded_hal::i2c::I2c;
+use log::*;
+use std::error::Error;
+use std::fmt;
+use std::io::{stderr, stdout};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// how often the control thread reads the pins and the ADC
+pub const POLL: Duration = Duration::from_millis(1);
+/// readings a button must hold before it counts as changed
+pub const DEBOUNCE_POLLS: u32 = 5;
+/// how far a knob must move before it is reported again
+pub const KNOB_DEADBAND: f32 = 1.0 / 256.0;
+/// how far along its range a detent moves a bound port without a step
+pub const ENCODER_TRAVEL: f32 = 1.0 / 64.0;
+
+/// a change of one of the board's controls
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlEvent {
+    Encoder(usize, i32), // detents, clockwise positive
+    Button(usize, bool), // pressed
+    Knob(usize, f32),    // in [0, 1]
+}
+
+impl ControlEvent {
+    /// what the control is called in patches, knob0, encoder1, button2
+    pub fn name(&self) -> String {
+        match self {
+            ControlEvent::Encoder(k, _) => format!("encoder{}", k),
+            ControlEvent::Button(k, _) => format!("button{}", k),
+            ControlEvent::Knob(k, _) => format!("knob{}", k),
+        }
+    }
+
+    /// what the UI makes of it: encoder 0 turns, button 0 selects,
+    /// button 1 goes back, buttons 2 and 3 change pages
+    pub fn to_input(&self) -> Option<InputEvent> {
+        match self {
+            ControlEvent::Encoder(0, n) => Some(InputEvent::Turn(*n)),
+            ControlEvent::Button(0, true) => Some(InputEvent::Select),
+            ControlEvent::Button(1, true) => Some(InputEvent::Back),
+            ControlEvent::Button(2, true) => Some(InputEvent::Left),
+            ControlEvent::Button(3, true) => Some(InputEvent::Right),
+            _ => None,
+        }
+    }
+}
+
+/// how a bound control's travel maps onto its port's range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    Linear,
+    Exponential, // equal travel multiplies, for frequencies and times
+}
+
+impl FromStr for Curve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lin" | "linear" => Ok(Curve::Linear),
+            "exp" | "exponential" => Ok(Curve::Exponential),
+            _ => Err(format!("unknown curve {}, use lin or exp", s)),
+        }
+    }
+}
+
+/// a control driving an in port of main, knobs set where along the
+/// range it is, encoders move it a step or ENCODER_TRAVEL a detent,
+/// buttons hold it at max while pressed and min otherwise
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub control: String,
+    pub port: String, // main/x
+    pub min: f32,
+    pub max: f32,
+    pub curve: Curve,
+    pub step: f32, // 0 for none, the value stays a multiple of it from min
+    travel: f32,   // [0, 1]
+}
+
+fn num(n: &Numeric) -> f32 {
+    match n {
+        Numeric::Int32(i) => *i as f32,
+        Numeric::Float(f) => *f,
+    }
+}
+
+/// knob0, encoder1, button2 and so on
+pub fn is_control(name: &str) -> bool {
+    ["knob", "encoder", "button"].iter().any(|kind| {
+        name.strip_prefix(kind)
+            .map(|k| !k.is_empty() && k.chars().all(|c| c.is_ascii_digit()))
+            .unwrap_or(false)
+    })
+}
+
+impl Binding {
+    pub fn from_map(m: &ControlMap) -> Result<Self, String> {
+        let ControlMap::Map(control, port, min, max, curve, step) = m;
+        if !is_control(control) {
+            return Err(format!(
+                "unknown control {}, use knobN, encoderN or buttonN",
+                control
+            ));
+        }
+        let (min, max) = (num(min), num(max));
+        let curve = match curve {
+            Some(curve) => curve.parse::<Curve>()?,
+            None => Curve::Linear,
+        };
+        if min == max {
+            return Err(format!("{} has an empty range", control));
+        }
+        if curve == Curve::Exponential && min * max <= 0.0 {
+            return Err(format!(
+                "{} has an exponential curve through zero, its range must be all above or all below",
+                control
+            ));
+        }
+        let step = step.as_ref().map(num).unwrap_or(0.0);
+        Ok(Binding {
+            control: control.clone(),
+            port: format!("main/{}", port),
+            min,
+            max,
+            curve,
+            step,
+            travel: 0.0,
+        })
+    }
+
+    /// the port's value where the control is
+    pub fn value(&self) -> f32 {
+        let t = self.travel;
+        let v = match self.curve {
+            Curve::Linear => self.min + t * (self.max - self.min),
+            Curve::Exponential => self.min * (self.max / self.min).powf(t),
+        };
+        self.snap(v)
+    }
+
+    fn snap(&self, v: f32) -> f32 {
+        let v = match self.step > 0.0 {
+            true => self.min + ((v - self.min) / self.step).round() * self.step,
+            false => v,
+        };
+        v.clamp(self.min.min(self.max), self.min.max(self.max))
+    }
+
+    /// put the control where the port's value is
+    pub fn set_value(&mut self, v: f32) {
+        let t = match self.curve {
+            Curve::Linear => (v - self.min) / (self.max - self.min),
+            Curve::Exponential => (v / self.min).ln() / (self.max / self.min).ln(),
+        };
+        self.travel = if t.is_finite() {
+            t.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+    }
+
+    /// move with an event of its control, the port's new value
+    pub fn apply(&mut self, event: &ControlEvent) -> f32 {
+        match event {
+            ControlEvent::Knob(_, v) => self.travel = *v,
+            ControlEvent::Button(_, pressed) => self.travel = *pressed as u8 as f32,
+            ControlEvent::Encoder(_, n) if self.step > 0.0 => {
+                let v = self.value() + *n as f32 * self.step * (self.max - self.min).signum();
+                self.set_value(self.snap(v));
+            }
+            ControlEvent::Encoder(_, n) => {
+                self.travel = (self.travel + *n as f32 * ENCODER_TRAVEL).clamp(0.0, 1.0);
+            }
+        }
+        self.value()
+    }
+}
+
+/// the bindings of a patch's controls forms, starting where the
+/// engine's ports are
+#[derive(Debug, Clone, Default)]
+pub struct Bindings {
+    bindings: Vec<Binding>,
+}
+
+impl Bindings {
+    pub fn new(top: &TopDef, engine: &Engine) -> Result<Self, String> {
+        let mut bindings = top
+            .controls()
+            .iter()
+            .map(Binding::from_map)
+            .collect::<Result<Vec<_>, _>>()?;
+        for b in bindings.iter_mut() {
+            if let Some(v) = engine.value(&b.port) {
+                b.set_value(v);
+            }
+        }
+        Ok(Bindings { bindings })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    /// the port the event moves and its new value, None for a control
+    /// the patch leaves alone
+    pub fn apply(&mut self, event: &ControlEvent) -> Option<(String, f32)> {
+        let control = event.name();
+        let b = self.bindings.iter_mut().find(|b| b.control == control)?;
+        Some((b.port.clone(), b.apply(event)))
+    }
+}
+
+/// one reading of every control, encoders as their A and B levels,
+/// buttons as pressed, knobs in [0, 1]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Levels {
+    pub encoders: Vec<(bool, bool)>,
+    pub buttons: Vec<bool>,
+    pub knobs: Vec<f32>,
+}
+
+/// where the levels come from, the board's pins or a script
+pub trait ControlReader {
+    /// fill in the current levels, false once there are no more
+    fn read(&mut self, levels: &mut Levels) -> Result<bool, Box<dyn Error>>;
+}
+
+/// a rotary encoder's two phases into detents, four transitions a
+/// detent, impossible transitions (a missed reading) count for nothing
+#[derive(Debug, Clone, Default)]
+pub struct Quadrature {
+    state: u8,
+    quarters: i32,
+}
+
+impl Quadrature {
+    /// the detents passed since the last update
+    pub fn update(&mut self, a: bool, b: bool) -> i32 {
+        const STEP: [i32; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+        let state = (a as u8) << 1 | b as u8;
+        self.quarters += STEP[(self.state << 2 | state) as usize];
+        self.state = state;
+        let detents = self.quarters / 4;
+        self.quarters -= detents * 4;
+        detents
+    }
+}
+
+/// a button that changes only after DEBOUNCE_POLLS equal readings
+#[derive(Debug, Clone, Default)]
+pub struct Debounce {
+    stable: bool,
+    count: u32,
+}
+
+impl Debounce {
+    /// the new level when it changed
+    pub fn update(&mut self, level: bool) -> Option<bool> {
+        match level == self.stable {
+            true => self.count = 0,
+            false => self.count += 1,
+        }
+        if self.count < DEBOUNCE_POLLS {
+            return None;
+        }
+        self.stable = level;
+        self.count = 0;
+        Some(level)
+    }
+}
+
+/// a potentiometer, reported when it moves past the deadband
+#[derive(Debug, Clone, Default)]
+pub struct Knob {
+    value: Option<f32>,
+}
+
+impl Knob {
+    pub fn update(&mut self, value: f32) -> Option<f32> {
+        let value = value.clamp(0.0, 1.0);
+        match self.value {
+            Some(old) if (value - old).abs() < KNOB_DEADBAND => None,
+            _ => {
+                self.value = Some(value);
+                Some(value)
+            }
+        }
+    }
+}
+
+/// a reader with the decoders that turn its levels into events
+pub struct Controls<R> {
+    reader: R,
+    levels: Levels,
+    encoders: Vec<Quadrature>,
+    buttons: Vec<Debounce>,
+    knobs: Vec<Knob>,
+}
+
+impl<R: ControlReader> Controls<R> {
+    pub fn new(reader: R) -> Self {
+        Controls {
+            reader,
+            levels: Levels::default(),
+            encoders: Vec::new(),
+            buttons: Vec::new(),
+            knobs: Vec::new(),
+        }
+    }
+
+    /// read once and add what changed to events, false once the
+    /// reader has no more
+    pub fn poll(&mut self, events: &mut Vec<ControlEvent>) -> Result<bool, Box<dyn Error>> {
+        if !self.reader.read(&mut self.levels)? {
+            return Ok(false);
+        }
+        let levels = &self.levels;
+        self.encoders
+            .resize(levels.encoders.len(), Quadrature::default());
+        self.buttons
+            .resize(levels.buttons.len(), Debounce::default());
+        self.knobs.resize(levels.knobs.len(), Knob::default());
+        for (k, (q, (a, b))) in self.encoders.iter_mut().zip(&levels.encoders).enumerate() {
+            match q.update(*a, *b) {
+                0 => {}
+                n => events.push(ControlEvent::Encoder(k, n)),
+            }
+        }
+        for (k, (d, level)) in self.buttons.iter_mut().zip(&levels.buttons).enumerate() {
+            if let Some(pressed) = d.update(*level) {
+                events.push(ControlEvent::Button(k, pressed));
+            }
+        }
+        for (k, (knob, value)) in self.knobs.iter_mut().zip(&levels.knobs).enumerate() {
+            if let Some(value) = knob.update(*value) {
+                events.push(ControlEvent::Knob(k, value));
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// an ADS1115 read one single ended channel at a time
+pub struct Ads1115<I> {
+    i2c: I,
+    address: u8,
+}
+
+/// why a conversion gave no value
+#[derive(Debug)]
+pub enum AdcError<E> {
+    I2c(E),
+    Timeout, // the conversion never finished
+}
+
+impl<E: fmt::Display> fmt::Display for AdcError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdcError::I2c(e) => write!(f, "{}", e),
+            AdcError::Timeout => write!(f, "ADS1115 conversion did not finish"),
+        }
+    }
+}
+
+/// the ADS1115's address with ADDR to ground
+pub const ADS1115_ADDRESS: u8 = 0x48;
+/// what the pots are wired across, full scale of the ADC's ±4.096 V
+/// range is a little more
+pub const POT_VOLTS: f32 = 3.3;
+/// one conversion at 860 SPS with room for a slow internal clock
+const CONVERSION: Duration = Duration::from_micros(1300);
+/// reads of the ready bit after that before giving up
+const READY_POLLS: u32 = 3;
+
+impl<I: I2c> Ads1115<I> {
+    pub fn new(i2c: I, address: u8) -> Self {
+        Ads1115 { i2c, address }
+    }
+
+    /// one conversion of AINn against ground, as a fraction of POT_VOLTS
+    pub fn read(&mut self, channel: u8) -> Result<f32, AdcError<I::Error>> {
+        // start a single shot at ±4.096 V and 860 SPS, comparator off
+        let config: u16 =
+            1 << 15 | (0b100 | channel as u16 & 3) << 12 | 0b001 << 9 | 1 << 8 | 0b111 << 5 | 0b11;
+        let [hi, lo] = config.to_be_bytes();
+        self.i2c
+            .write(self.address, &[0x01, hi, lo])
+            .map_err(AdcError::I2c)?;
+        thread::sleep(CONVERSION);
+        let mut buf = [0u8; 2];
+        for _ in 0..READY_POLLS {
+            self.i2c
+                .write_read(self.address, &[0x01], &mut buf)
+                .map_err(AdcError::I2c)?;
+            if buf[0] & 0x80 != 0 {
+                self.i2c
+                    .write_read(self.address, &[0x00], &mut buf)
+                    .map_err(AdcError::I2c)?;
+                let raw = i16::from_be_bytes(buf).max(0) as f32;
+                return Ok(raw / i16::MAX as f32 * 4.096 / POT_VOLTS);
+            }
+        }
+        Err(AdcError::Timeout)
+    }
+}
+
+/// the knobs on some ADCs, read over and over on a thread of their own
+/// since a conversion takes longer than a poll, so the pins are still
+/// read every POLL
+pub struct KnobReader {
+    values: Arc<Mutex<Vec<f32>>>,
+}
+
+impl KnobReader {
+    /// knobs are numbered across the ADCs in order, with their channel
+    /// counts, an ADC that stops answering is dropped with its knobs,
+    /// the thread ends when the reader is dropped
+    pub fn spawn<I>(adcs: Vec<(Ads1115<I>, u8)>) -> Self
+    where
+        I: I2c + Send + 'static,
+        I::Error: fmt::Display,
+    {
+        let values = Arc::new(Mutex::new(Vec::new()));
+        let shared = Arc::downgrade(&values);
+        thread::Builder::new()
+            .name("knobs".to_string())
+            .spawn(move || {
+                let mut adcs = adcs;
+                let mut knobs = Vec::new();
+                while !adcs.is_empty() {
+                    knobs.clear();
+                    adcs.retain_mut(|(adc, channels)| {
+                        match (0..*channels)
+                            .map(|c| adc.read(c))
+                            .collect::<Result<Vec<_>, _>>()
+                        {
+                            Ok(read) => {
+                                knobs.extend(read);
+                                true
+                            }
+                            Err(e) => {
+                                warn!("An ADC stopped answering, its knobs are off: {}", e);
+                                false
+                            }
+                        }
+                    });
+                    match shared.upgrade() {
+                        Some(values) => values.lock().unwrap().clone_from(&knobs),
+                        None => break,
+                    }
+                }
+                debug!("Knobs closed");
+            })
+            .expect("failed to spawn knobs thread");
+        KnobReader { values }
+    }
+
+    /// the last reading of every knob, none before the first
+    pub fn read(&self, knobs: &mut Vec<f32>) {
+        knobs.clone_from(&self.values.lock().unwrap());
+    }
+}
+
+/// levels from a script, for running the controls without a board:
+/// e0+2 turns encoder 0 two detents clockwise, e1-1 one back, b2
+/// presses and releases button 2 with some bounce, k0=0.5 sets knob 0,
+/// . waits a debounce
+pub struct MockControls {
+    frames: Vec<Levels>,
+    next: usize,
+}
+
+impl MockControls {
+    pub fn new(script: &str) -> Result<Self, String> {
+        let mut levels = Levels::default();
+        let mut frames = Vec::new();
+        let hold = |levels: &Levels, frames: &mut Vec<Levels>, n: u32| {
+            for _ in 0..n {
+                frames.push(levels.clone());
+            }
+        };
+        let index = |s: &str, token: &str| {
+            s.parse::<usize>()
+                .map_err(|_| format!("bad control in {:?}", token))
+        };
+        for token in script.split_whitespace() {
+            let (kind, rest) = token.split_at(1);
+            match kind {
+                "e" => {
+                    let at = rest
+                        .find(['+', '-'])
+                        .ok_or(format!("{:?} needs + or -", token))?;
+                    let k = index(&rest[..at], token)?;
+                    let n = rest[at..]
+                        .parse::<i32>()
+                        .map_err(|_| format!("bad detents in {:?}", token))?;
+                    if levels.encoders.len() <= k {
+                        levels.encoders.resize(k + 1, (false, false));
+                    }
+                    // clockwise is A leading B, both from rest
+                    const CW: [(bool, bool); 4] =
+                        [(true, false), (true, true), (false, true), (false, false)];
+                    const CCW: [(bool, bool); 4] =
+                        [(false, true), (true, true), (true, false), (false, false)];
+                    let phases = if n > 0 { CW } else { CCW };
+                    for _ in 0..n.abs() {
+                        for phase in phases {
+                            levels.encoders[k] = phase;
+                            hold(&levels, &mut frames, 1);
+                        }
+                    }
+                }
+                "b" => {
+                    let k = index(rest, token)?;
+                    if levels.buttons.len() <= k {
+                        levels.buttons.resize(k + 1, false);
+                    }
+                    for pressed in [true, false] {
+                        // contacts chatter before they settle
+                        for bounce in [pressed, !pressed, pressed, !pressed] {
+                            levels.buttons[k] = bounce;
+                            hold(&levels, &mut frames, 1);
+                        }
+                        levels.buttons[k] = pressed;
+                        hold(&levels, &mut frames, DEBOUNCE_POLLS);
+                    }
+                }
+                "k" => {
+                    let (k, v) = rest
+                        .split_once('=')
+                        .ok_or(format!("{:?} needs =value", token))?;
+                    let k = index(k, token)?;
+                    let v = v
+                        .parse::<f32>()
+                        .map_err(|_| format!("bad value in {:?}", token))?;
+                    if levels.knobs.len() <= k {
+                        levels.knobs.resize(k + 1, 0.0);
+                    }
+                    levels.knobs[k] = v;
+                    hold(&levels, &mut frames, 1);
+                }
+                "." => hold(&levels, &mut frames, DEBOUNCE_POLLS),
+                _ => return Err(format!("unknown control {:?}", token)),
+            }
+        }
+        Ok(MockControls { frames, next: 0 })
+    }
+}
+
+impl ControlReader for MockControls {
+    fn read(&mut self, levels: &mut Levels) -> Result<bool, Box<dyn Error>> {
+        match self.frames.get(self.next) {
+            Some(frame) => {
+                levels.clone_from(frame);
+                self.next += 1;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// poll a reader every POLL on a thread of its own, the events come
+/// out of the receiver until the reader ends or fails
+pub fn spawn<R: ControlReader + Send + 'static>(reader: R) -> Receiver<ControlEvent> {
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("controls".to_string())
+        .spawn(move || {
+            let mut controls = Controls::new(reader);
+            let mut events = Vec::new();
+            loop {
+                match controls.poll(&mut events) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        error!("Reading controls failed: {}", e);
+                        break;
+                    }
+                }
+                for event in events.drain(..) {
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+                thread::sleep(POLL);
+            }
+            debug!("Controls closed");
+        })
+        .expect("failed to spawn controls thread");
+    rx
+}
+
+/// the board's controls driving the UI, what a patch binds goes to
+/// the UI too, which sets the ports with it
+pub struct ControlInput {
+    rx: Receiver<ControlEvent>,
+    open: bool,
+}
+
+impl ControlInput {
+    pub fn new(rx: Receiver<ControlEvent>) -> Self {
+        ControlInput { rx, open: true }
+    }
+}
+
+impl Input for ControlInput {
+    fn poll(&mut self) -> Option<InputEvent> {
+        match self.rx.try_recv() {
+            Ok(event) => Some(InputEvent::Control(event)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.open = false;
+                None
+            }
+        }
+    }
+
+    fn open(&self) -> bool {
+        self.open
+    }
+}
+
+/// the controls named by --controls: gpio for the board's, or
+/// mock:SCRIPT
+pub fn open(spec: &str) -> Result<Receiver<ControlEvent>, Box<dyn Error>> {
+    match spec.split_once(':') {
+        Some(("mock", script)) => Ok(spawn(MockControls::new(script)?)),
+        None if spec == "gpio" => Ok(spawn(board::open_controls()?)),
+        _ => Err(format!("unknown controls {:?}, use gpio or mock:SCRIPT", spec).into()),
+    }
+}
+
+/// apply a patch's controls forms to its params from a thread of
+/// their own, for rasynth play --controls
+pub fn open_control(
+    graph: &FlowGraph,
+    engine: &Engine,
+    spec: &str,
+) -> Result<Control, Box<dyn Error>> {
+    let mut bindings = match graph.ast.lock().unwrap().as_ref() {
+        Some(top) => Bindings::new(top, engine)?,
+        None => Bindings::default(),
+    };
+    if bindings.is_empty() {
+        warn!("The patch has no controls form, the controls do nothing");
+    }
+    let store = ParamStore::new(engine);
+    let rx = open(spec)?;
+    let setter = store.clone();
+    thread::Builder::new()
+        .name("bindings".to_string())
+        .spawn(move || {
+            for event in rx {
+                if let Some((port, value)) = bindings.apply(&event) {
+                    match setter.index(&port) {
+                        Some(i) => setter.set(i, value),
+                        None => warn!("{} is not a param, {} does nothing", port, event.name()),
+                    }
+                }
+            }
+        })?;
+    Ok(store.control())
+}
+
+/// rasynth controls - print the events of the board's controls
+pub fn command(args: Vec<String>) {
+    let mut mock: Option<String> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Print encoder, button and knob events");
+        ap.refer(&mut mock).add_option(
+            &["--mock"],
+            StoreOption,
+            "Read this script instead of the board: e0+2 turns encoder 0, \
+             b1 presses button 1, k0=0.5 sets knob 0, . waits",
+        );
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    let spec = match &mock {
+        Some(script) => format!("mock:{}", script),
+        None => "gpio".to_string(),
+    };
+    let rx = match open(&spec) {
+        Ok(rx) => rx,
+        Err(e) => {
+            error!("Cannot open controls: {}", e);
+            std::process::exit(1);
+        }
+    };
+    for event in rx {
+        match event {
+            ControlEvent::Encoder(_, n) => println!("{} {:+}", event.name(), n),
+            ControlEvent::Button(_, pressed) => {
+                let state = if pressed { "down" } else { "up" };
+                println!("{} {}", event.name(), state)
+            }
+            ControlEvent::Knob(_, v) => println!("{} {:.3}", event.name(), v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::i2c::{ErrorKind, ErrorType, Operation};
+
+    /// every event a script makes, polled the way the control thread does
+    fn events(script: &str) -> Vec<ControlEvent> {
+        let mut controls = Controls::new(MockControls::new(script).unwrap());
+        let mut events = Vec::new();
+        while controls.poll(&mut events).unwrap() {}
+        events
+    }
+
+    #[test]
+    fn encoders_count_detents_both_ways() {
+        assert_eq!(
+            events("e0+2"),
+            [ControlEvent::Encoder(0, 1), ControlEvent::Encoder(0, 1)]
+        );
+        assert_eq!(
+            events("e1-1 e1+1"),
+            [ControlEvent::Encoder(1, -1), ControlEvent::Encoder(1, 1)]
+        );
+    }
+
+    #[test]
+    fn half_a_detent_and_a_missed_reading_count_nothing() {
+        let mut q = Quadrature::default();
+        assert_eq!(q.update(true, false), 0);
+        assert_eq!(q.update(true, true), 0);
+        assert_eq!(q.update(true, false), 0);
+        assert_eq!(q.update(false, false), 0);
+        // A and B both changing at once is a reading missed
+        assert_eq!(q.update(true, true), 0);
+        assert_eq!(q.update(false, false), 0);
+    }
+
+    #[test]
+    fn buttons_ignore_the_bounce() {
+        assert_eq!(
+            events("b2"),
+            [
+                ControlEvent::Button(2, true),
+                ControlEvent::Button(2, false)
+            ]
+        );
+        let mut d = Debounce::default();
+        for _ in 1..DEBOUNCE_POLLS {
+            assert_eq!(d.update(true), None);
+        }
+        // a reading back at the old level starts the count over
+        assert_eq!(d.update(false), None);
+        for _ in 1..DEBOUNCE_POLLS {
+            assert_eq!(d.update(true), None);
+        }
+        assert_eq!(d.update(true), Some(true));
+    }
+
+    #[test]
+    fn knobs_move_past_the_deadband() {
+        assert_eq!(
+            events("k0=0.5 k0=0.501 k0=0.51 k1=2"),
+            [
+                ControlEvent::Knob(0, 0.5),
+                ControlEvent::Knob(0, 0.51),
+                ControlEvent::Knob(1, 1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn the_controls_command_script() {
+        // rasynth controls --mock "e0+2 b2 k0=0.5 k0=0.501"
+        assert_eq!(
+            events("e0+2 b2 k0=0.5 k0=0.501"),
+            [
+                ControlEvent::Encoder(0, 1),
+                ControlEvent::Encoder(0, 1),
+                ControlEvent::Button(2, true),
+                ControlEvent::Button(2, false),
+                ControlEvent::Knob(0, 0.5),
+            ]
+        );
+        assert!(MockControls::new("x1").is_err());
+        assert!(MockControls::new("e0").is_err());
+    }
+
+    fn binding(control: &str, range: (f32, f32), curve: &str, step: Option<f32>) -> Binding {
+        let (min, max) = (Numeric::Float(range.0), Numeric::Float(range.1));
+        let step = step.map(Numeric::Float);
+        let map = ControlMap::Map(
+            control.to_string(),
+            "x".to_string(),
+            min,
+            max,
+            Some(curve.to_string()),
+            step,
+        );
+        Binding::from_map(&map).unwrap()
+    }
+
+    #[test]
+    fn an_exponential_knob_multiplies_equal_travel() {
+        let mut b = binding("knob0", (55.0, 1760.0), "exp", None);
+        assert_eq!(b.port, "main/x");
+        assert_eq!(b.apply(&ControlEvent::Knob(0, 0.0)), 55.0);
+        let v = b.apply(&ControlEvent::Knob(0, 0.5));
+        assert!((v - 311.127).abs() < 0.01, "{}", v);
+        let v = b.apply(&ControlEvent::Knob(0, 1.0));
+        assert!((v - 1760.0).abs() < 0.01, "{}", v);
+        let mut b = binding("knob0", (0.0, 2.0), "lin", None);
+        assert_eq!(b.apply(&ControlEvent::Knob(0, 0.25)), 0.5);
+    }
+
+    #[test]
+    fn a_stepped_encoder_stays_on_its_steps_and_in_range() {
+        let mut b = binding("encoder1", (0.0, 1.0), "lin", Some(0.25));
+        b.set_value(0.3);
+        assert_eq!(b.value(), 0.25);
+        let mut seen = Vec::new();
+        for n in [1, 1, 3, -1, -10, 2] {
+            let v = b.apply(&ControlEvent::Encoder(1, n));
+            assert_eq!((v / 0.25).fract(), 0.0, "{}", v);
+            seen.push(v);
+        }
+        assert_eq!(seen, [0.5, 0.75, 1.0, 0.75, 0.0, 0.5]);
+        // clockwise goes towards max, down a range that falls
+        let mut b = binding("encoder1", (10.0, 0.0), "lin", Some(2.0));
+        assert_eq!(b.apply(&ControlEvent::Encoder(1, 1)), 8.0);
+        assert_eq!(b.apply(&ControlEvent::Encoder(1, -5)), 10.0);
+        // without a step a detent is ENCODER_TRAVEL of the range
+        let mut b = binding("encoder1", (0.0, 64.0), "lin", None);
+        assert_eq!(b.apply(&ControlEvent::Encoder(1, 3)), 3.0);
+        assert_eq!(b.apply(&ControlEvent::Encoder(1, -9)), 0.0);
+    }
+
+    #[test]
+    fn a_button_holds_max_while_pressed() {
+        let mut b = binding("button2", (-1.0, 5.0), "lin", None);
+        assert_eq!(b.apply(&ControlEvent::Button(2, true)), 5.0);
+        assert_eq!(b.apply(&ControlEvent::Button(2, false)), -1.0);
+        assert_eq!(b.apply(&ControlEvent::Button(2, true)), 5.0);
+    }
+
+    #[test]
+    fn set_value_puts_the_control_where_value_reads_it() {
+        for (range, curve) in [
+            ((-2.0, 6.0), "lin"),
+            ((55.0, 1760.0), "exp"),
+            ((-1.0, -100.0), "exp"),
+        ] {
+            let mut b = binding("knob0", range, curve, None);
+            for t in [0.0, 0.1, 0.5, 0.9, 1.0] {
+                let v = range.0 + t * (range.1 - range.0);
+                b.set_value(v);
+                assert!(
+                    (b.value() - v).abs() < 1e-3 * v.abs().max(1.0),
+                    "{} {}",
+                    v,
+                    b.value()
+                );
+            }
+            // past the range is held at its ends
+            b.set_value(range.1 * 2.0);
+            assert!((b.value() - range.1).abs() < 1e-3 * range.1.abs());
+        }
+        let bad = |min, max, curve: &str| {
+            let map = ControlMap::Map(
+                "knob0".to_string(),
+                "x".to_string(),
+                Numeric::Float(min),
+                Numeric::Float(max),
+                Some(curve.to_string()),
+                None,
+            );
+            Binding::from_map(&map).is_err()
+        };
+        assert!(bad(1.0, 1.0, "lin"));
+        assert!(bad(-1.0, 1.0, "exp"));
+        assert!(bad(0.0, 1.0, "log"));
+    }
+
+    /// an ADS1115 whose conversion is ready after some reads of its
+    /// config register
+    struct FakeAdc {
+        reg: u8,
+        busy: u32,
+        raw: i16,
+    }
+
+    impl ErrorType for FakeAdc {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for FakeAdc {
+        fn transaction(&mut self, _: u8, ops: &mut [Operation<'_>]) -> Result<(), ErrorKind> {
+            for op in ops.iter_mut() {
+                match op {
+                    Operation::Write(bytes) => self.reg = bytes[0],
+                    Operation::Read(buf) if self.reg == 0x01 => {
+                        let ready = match self.busy {
+                            0 => 0x80,
+                            _ => 0,
+                        };
+                        self.busy = self.busy.saturating_sub(1);
+                        buf[0] = ready;
+                    }
+                    Operation::Read(buf) => buf.copy_from_slice(&self.raw.to_be_bytes()),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn adc_reads_a_conversion_and_gives_up_on_a_stuck_one() {
+        let half = (POT_VOLTS / 2.0 / 4.096 * i16::MAX as f32) as i16;
+        let fake = FakeAdc {
+            reg: 0,
+            busy: 1,
+            raw: half,
+        };
+        let v = Ads1115::new(fake, ADS1115_ADDRESS).read(0).unwrap();
+        assert!((v - 0.5).abs() < 1e-3, "{}", v);
+        let stuck = FakeAdc {
+            reg: 0,
+            busy: u32::MAX,
+            raw: half,
+        };
+        assert!(matches!(
+            Ads1115::new(stuck, ADS1115_ADDRESS).read(0),
+            Err(AdcError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn knobs_are_read_off_the_poll_and_a_stuck_adc_is_dropped() {
+        let half = (POT_VOLTS / 2.0 / 4.096 * i16::MAX as f32) as i16;
+        let adc = |busy| {
+            let fake = FakeAdc {
+                reg: 0,
+                busy,
+                raw: half,
+            };
+            Ads1115::new(fake, ADS1115_ADDRESS)
+        };
+        let reader = KnobReader::spawn(vec![(adc(0), 2), (adc(u32::MAX), 1)]);
+        let mut knobs = Vec::new();
+        reader.read(&mut knobs);
+        // reading takes no time, the conversions happen on the thread
+        let started = std::time::Instant::now();
+        while knobs.is_empty() && started.elapsed() < Duration::from_secs(1) {
+            thread::sleep(POLL);
+            reader.read(&mut knobs);
+        }
+        assert_eq!(knobs.len(), 2, "{:?}", knobs);
+        assert!(knobs.iter().all(|v| (v - 0.5).abs() < 1e-3), "{:?}", knobs);
+    }
+}
@@ -0,0 +1,249 @@
+use embedded_graphics::{
+    mono_font::{ascii::FONT_10X20, MonoTextStyle},
+    pixelcolor::{Rgb565, Rgb888},
+    prelude::*,
+    text::Text,
+};
+use log::*;
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// the ST7789 panel on the board
+pub const WIDTH: u32 = 240;
+pub const HEIGHT: u32 = 240;
+
+/// something the UI draws on, the LCD on the board or a framebuffer,
+/// screens are written against this so they run off the Pi too
+pub trait Display: DrawTarget<Color = Rgb565, Error: Debug> {
+    /// show what was drawn since the last flush
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn set_backlight(&mut self, _on: bool) {}
+}
+
+/// an in-memory display, it can write what it holds to PNG or PPM and,
+/// given a directory, write a numbered PNG on every flush
+pub struct Framebuffer {
+    size: Size,
+    pixels: Vec<Rgb565>,
+    snapshots: Option<PathBuf>,
+    frame: usize,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Framebuffer {
+            size: Size::new(width, height),
+            pixels: vec![Rgb565::BLACK; (width * height) as usize],
+            snapshots: None,
+            frame: 0,
+        }
+    }
+
+    /// one the size of the board's panel, as rotated
+    pub fn panel() -> Self {
+        let (width, height) = crate::config::board().size();
+        Self::new(width, height)
+    }
+
+    /// write frame_NNNN.png into dir on every flush
+    pub fn with_snapshots(mut self, dir: &Path) -> Result<Self, Box<dyn Error>> {
+        std::fs::create_dir_all(dir)?;
+        self.snapshots = Some(dir.to_path_buf());
+        Ok(self)
+    }
+
+    pub fn pixel(&self, p: Point) -> Option<Rgb565> {
+        self.index(p).map(|i| self.pixels[i])
+    }
+
+    pub fn pixels(&self) -> &Vec<Rgb565> {
+        &self.pixels
+    }
+
+    fn index(&self, p: Point) -> Option<usize> {
+        let (w, h) = (self.size.width as i32, self.size.height as i32);
+        match p.x >= 0 && p.x < w && p.y >= 0 && p.y < h {
+            true => Some((p.y * w + p.x) as usize),
+            false => None,
+        }
+    }
+
+    /// 8 bits a channel, row by row
+    pub fn rgb888(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|c| {
+                let c = Rgb888::from(*c);
+                [c.r(), c.g(), c.b()]
+            })
+            .collect()
+    }
+
+    pub fn save_ppm(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "P6\n{} {}\n255\n", self.size.width, self.size.height)?;
+        out.write_all(&self.rgb888())?;
+        out.flush()?;
+        Ok(())
+    }
+
+    pub fn save_png(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        self.write_png(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn write_png<W: Write>(&self, out: W) -> Result<(), Box<dyn Error>> {
+        let mut encoder = png::Encoder::new(out, self.size.width, self.size.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.rgb888())?;
+        Ok(())
+    }
+
+    /// PPM for a .ppm path, PNG otherwise
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("ppm") => self.save_ppm(path),
+            _ => self.save_png(path),
+        }
+    }
+}
+
+impl OriginDimensions for Framebuffer {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl DrawTarget for Framebuffer {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(p, color) in pixels {
+            if let Some(i) = self.index(p) {
+                self.pixels[i] = color;
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.pixels.fill(color);
+        Ok(())
+    }
+}
+
+impl Display for Framebuffer {
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = &self.snapshots {
+            let path = dir.join(format!("frame_{:04}.png", self.frame));
+            self.save_png(&path)?;
+            trace!("Wrote {}", path.display());
+        }
+        self.frame += 1;
+        Ok(())
+    }
+}
+
+/// frame k of the panel test: a color changing every 8 frames with a
+/// line of text scrolling right to left
+pub fn test_pattern<D: Display>(display: &mut D, k: usize) -> Result<(), D::Error> {
+    let text = "Hello World ^_^;";
+    let char_w = 10;
+    let colors = [Rgb565::RED, Rgb565::GREEN, Rgb565::BLUE];
+    let area = display.bounding_box();
+    display.clear(colors[(k / 8) % colors.len()])?;
+    // wraps once the text has left the screen on the left
+    let width = area.size.width as i32;
+    let span = width + text.len() as i32 * char_w;
+    let x = width - (k as i32 * char_w) % span;
+    let style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+    let y = area.center().y;
+    Text::new(text, Point::new(x, y), style).draw(display)?;
+    Ok(())
+}
+
+/// draw frames of the panel test into a framebuffer and save the last
+/// one, the simulator's stand-in for rasynth --display
+pub fn test_snapshot(path: &Path, frames: usize) -> Result<(), Box<dyn Error>> {
+    let mut fb = Framebuffer::panel();
+    for k in 0..frames.max(1) {
+        test_pattern(&mut fb, k).unwrap();
+        fb.flush()?;
+    }
+    fb.save(path)?;
+    info!("Wrote {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_png(data: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut reader = png::Decoder::new(data).read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        buf.truncate(info.buffer_size());
+        (info.width, info.height, buf)
+    }
+
+    #[test]
+    fn test_pattern_draws_background_and_text() {
+        let mut fb = Framebuffer::new(WIDTH, HEIGHT);
+        test_pattern(&mut fb, 0).unwrap();
+        assert_eq!(fb.pixel(Point::new(0, 0)), Some(Rgb565::RED));
+        assert_eq!(fb.pixel(Point::new(239, 239)), Some(Rgb565::RED));
+        // frame 0 has the text just off the right edge
+        assert!(!fb.pixels().contains(&Rgb565::WHITE));
+        // 12 frames on it is on screen, across the middle, on green
+        test_pattern(&mut fb, 12).unwrap();
+        assert_eq!(fb.pixel(Point::new(0, 0)), Some(Rgb565::GREEN));
+        let white = (0..HEIGHT as i32)
+            .filter(|y| {
+                (0..WIDTH as i32).any(|x| fb.pixel(Point::new(x, *y)) == Some(Rgb565::WHITE))
+            })
+            .collect::<Vec<_>>();
+        assert!(!white.is_empty());
+        let center = HEIGHT as i32 / 2;
+        assert!(white.iter().all(|y| (y - center).abs() < 20), "{:?}", white);
+        assert_eq!(fb.pixel(Point::new(-1, 0)), None);
+        assert_eq!(fb.pixel(Point::new(0, HEIGHT as i32)), None);
+    }
+
+    #[test]
+    fn png_and_ppm_round_trip() {
+        let mut fb = Framebuffer::new(32, 16);
+        test_pattern(&mut fb, 20).unwrap();
+        fb.draw_iter([Pixel(Point::new(3, 4), Rgb565::WHITE)])
+            .unwrap();
+        let rgb = fb.rgb888();
+        assert_eq!(rgb.len(), 32 * 16 * 3);
+
+        let mut png = Vec::new();
+        fb.write_png(&mut png).unwrap();
+        assert_eq!(decode_png(&png), (32, 16, rgb.clone()));
+
+        let path = std::env::temp_dir().join(format!("rasynth-{}.ppm", std::process::id()));
+        fb.save(&path).unwrap();
+        let ppm = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let header = b"P6\n32 16\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(&ppm[header.len()..], &rgb[..]);
+        // white stays white through the 565 to 888 conversion
+        let i = (4 * 32 + 3) * 3;
+        assert_eq!(&rgb[i..i + 3], &[255, 255, 255]);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::audio::{self, PcmFormat};
+use crate::board;
+use crate::config::{self, BoardError};
+use argparse::{ArgumentParser, StoreTrue};
+use std::io::{stderr, stdout};
+
+/// what probing one part of the board found
+pub struct Probe {
+    pub part: String,
+    pub result: Result<String, BoardError>,
+}
+
+impl Probe {
+    fn new(part: impl Into<String>, result: Result<String, BoardError>) -> Self {
+        Probe {
+            part: part.into(),
+            result,
+        }
+    }
+
+    /// the board's config has it and it does not work
+    pub fn failed(&self) -> bool {
+        matches!(&self.result, Err(e) if !e.is_absent())
+    }
+}
+
+/// try every part the board's config names, the display only when
+/// asked as opening it blanks the panel
+pub fn probe(display: bool) -> Vec<Probe> {
+    let board = config::board();
+    let mut probes = vec![
+        Probe::new("gpio", board::probe_gpio()),
+        Probe::new("spi", board::probe_spi()),
+    ];
+    if display {
+        let d = &board.display;
+        let lcd = board::open_lcd().map(|_| {
+            format!(
+                "{:?} {}x{}, rotated {}",
+                d.controller, d.width, d.height, d.rotation
+            )
+        });
+        probes.push(Probe::new("display", lcd));
+    }
+    for (k, [a, b]) in board.controls.encoders.iter().enumerate() {
+        let pins = board::probe_pin(*a)
+            .and_then(|a| board::probe_pin(*b).map(|b| format!("{}, {}", a, b)));
+        probes.push(Probe::new(format!("encoder{}", k), pins));
+    }
+    for (k, pin) in board.controls.buttons.iter().enumerate() {
+        probes.push(Probe::new(format!("button{}", k), board::probe_pin(*pin)));
+    }
+    for (k, dev) in board.i2c.iter().enumerate() {
+        probes.push(Probe::new(format!("i2c{}", k), board::probe_adc(dev)));
+    }
+    probes.push(Probe::new("audio", probe_audio()));
+    probes
+}
+
+/// the board's ALSA device opens for playback
+fn probe_audio() -> Result<String, BoardError> {
+    let board = config::board();
+    let a = &board.audio;
+    if board.is_virtual {
+        return Err(BoardError::Absent("audio device"));
+    }
+    if !cfg!(feature = "alsa") {
+        return Ok("null sink only, rasynth was built without alsa".to_string());
+    }
+    audio::open_sink("alsa", &a.device, a.rate, 2, a.block, PcmFormat::S16Le)
+        .map(|_| format!("ALSA {} at {} Hz, {} frames", a.device, a.rate, a.block))
+        .map_err(|e| BoardError::Audio(a.device.clone(), e.to_string()))
+}
+
+/// rasynth doctor - report which of the board's parts are there
+pub fn command(args: Vec<String>) {
+    let mut no_display = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Probe the board's GPIO, SPI, LCD, controls, I2C and audio");
+        ap.refer(&mut no_display).add_option(
+            &["--no-display"],
+            StoreTrue,
+            "Leave the LCD alone, opening it blanks whatever is running on it",
+        );
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    println!("board     {}", config::board().name);
+    let probes = probe(!no_display);
+    for p in probes.iter() {
+        match &p.result {
+            Ok(found) => println!("{:<9} ok      {}", p.part, found),
+            Err(e) if e.is_absent() => println!("{:<9} absent  {}", p.part, e),
+            Err(e) => println!("{:<9} FAILED  {}", p.part, e),
+        }
+    }
+    if probes.iter().any(Probe::failed) {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::ast::Type;
+use crate::fmt::format_type;
+use crate::graph::{Constant, FlowGraph, Node};
+use crate::layout::{self, NodeKind};
+use crate::ops;
+use crate::symbol_table;
+use crate::voices;
+use argparse::{ArgumentParser, Store, StoreOption};
+use log::*;
+use std::collections::HashMap;
+use std::io::{stderr, stdout};
+use std::path::Path;
+
+fn const_type(c: &Constant) -> Type {
+    match c {
+        Constant::Int32(_) | Constant::Int64(_) => Type::Int32,
+        Constant::Float32(_) | Constant::Float64(_) => Type::Float,
+        Constant::Float32Array(_) => Type::Waveform,
+    }
+}
+
+/// the type of each node's value, by slot, None where it cannot be
+/// told: ports and lets have the type their box's symbols have, an op
+/// what its builtin makes of its args, a port nothing declares takes
+/// what flows in
+pub fn node_types(graph: &FlowGraph) -> Vec<Option<Type>> {
+    let tables = match graph.ast.lock().unwrap().as_ref() {
+        Some(top) => symbol_table::analyze(top).tables,
+        None => HashMap::new(),
+    };
+    let slot_of_id = graph
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(slot, n)| (n.id, slot))
+        .collect::<HashMap<_, _>>();
+    let mut args: Vec<Vec<(u64, usize)>> = vec![Vec::new(); graph.nodes.len()];
+    for e in graph.edges.iter() {
+        if let (Some(from), Some(to)) = (slot_of_id.get(&e.from.id), slot_of_id.get(&e.to.id)) {
+            args[*to].push((e.arg_no, *from));
+        }
+    }
+    for a in args.iter_mut() {
+        a.sort_by_key(|(arg_no, _)| *arg_no);
+    }
+    let declared = |node: &Node| {
+        let base = voices::base_name(&node.name);
+        let (b, local) = base.split_once('/')?;
+        tables.get(b)?.table.get(local)?.ty.clone()
+    };
+    let mut types = graph
+        .nodes
+        .iter()
+        .map(|n| match (&n.const_data, NodeKind::of(n)) {
+            (Some(c), _) => Some(const_type(c)),
+            (None, NodeKind::Port) => declared(n),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    // follow the edges until nothing more can be told
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (slot, node) in graph.nodes.iter().enumerate() {
+            if types[slot].is_some() {
+                continue;
+            }
+            let inputs = args[slot]
+                .iter()
+                .map(|(_, from)| types[*from].clone())
+                .collect::<Option<Vec<_>>>();
+            let ty = match (NodeKind::of(node), inputs) {
+                (NodeKind::Op, Some(inputs)) => {
+                    let local = node.name.split_once('/').map(|(_, l)| l).unwrap_or("");
+                    let op = local.split('@').next().unwrap_or("");
+                    ops::lookup(op).and_then(|b| b.result_type(&inputs).ok())
+                }
+                (NodeKind::Port, Some(inputs)) if inputs.len() == 1 => inputs.first().cloned(),
+                _ => None,
+            };
+            if ty.is_some() {
+                types[slot] = ty;
+                changed = true;
+            }
+        }
+    }
+    types
+}
+
+/// a DOT string literal
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// the graph in Graphviz's DOT, nodes keyed by id, one cluster per box
+/// and voice, edges labelled with the arg they feed and their type
+pub fn to_dot(graph: &FlowGraph) -> String {
+    let types = node_types(graph);
+    let mut out = String::new();
+    out += "digraph flow {\n";
+    out += "    rankdir=LR;\n";
+    out += "    node [fontname=\"Roboto\", fontsize=10];\n";
+    out += "    edge [fontname=\"Roboto\", fontsize=9];\n";
+    let mut groups: Vec<(String, Vec<&Node>)> = Vec::new();
+    for node in graph.nodes.iter() {
+        let group = layout::group(node);
+        match groups.iter_mut().find(|(g, _)| *g == group) {
+            Some((_, nodes)) => nodes.push(node),
+            None => groups.push((group, vec![node])),
+        }
+    }
+    for (k, (group, nodes)) in groups.iter().enumerate() {
+        out += &format!("    subgraph cluster_{} {{\n", k);
+        out += &format!("        label={};\n", quote(group));
+        out += "        style=rounded;\n";
+        out += "        color=gray60;\n";
+        for node in nodes.iter() {
+            let style = match NodeKind::of(node) {
+                NodeKind::Port => "shape=box, style=\"rounded,filled\", fillcolor=\"#cfe3ff\"",
+                NodeKind::Op => "shape=ellipse, style=filled, fillcolor=\"#ffe6b3\"",
+                NodeKind::Const => "shape=plaintext, fontcolor=gray30",
+            };
+            out += &format!(
+                "        n{} [label={}, tooltip={}, {}];\n",
+                node.id,
+                quote(&layout::label(node)),
+                quote(&node.name),
+                style
+            );
+        }
+        out += "    }\n";
+    }
+    let slot_of_id = graph
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(slot, n)| (n.id, slot))
+        .collect::<HashMap<_, _>>();
+    for e in graph.edges.iter() {
+        let ty = slot_of_id.get(&e.from.id).and_then(|s| types[*s].as_ref());
+        let label = match ty {
+            Some(ty) => format!("{}: {}", e.arg_no, format_type(ty)),
+            None => e.arg_no.to_string(),
+        };
+        out += &format!(
+            "    n{} -> n{} [label={}];\n",
+            e.from.id,
+            e.to.id,
+            quote(&label)
+        );
+    }
+    out += "}\n";
+    out
+}
+
+/// rasynth dot FILE [-o OUT] - write a patch's graph for Graphviz
+pub fn command(args: Vec<String>) {
+    let mut file = String::new();
+    let mut output: Option<String> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Write a patch's graph in Graphviz's DOT");
+        ap.refer(&mut file)
+            .required()
+            .add_argument("file", Store, "Raslisp file or saved graph");
+        ap.refer(&mut output).add_option(
+            &["-o", "--output"],
+            StoreOption,
+            "Where to write, - for stdout, FILE with .dot by default",
+        );
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    let graph = match FlowGraph::from_file(Path::new(&file)) {
+        Ok(graph) => graph,
+        Err(e) => {
+            error!("{}: {}", file, e);
+            std::process::exit(2);
+        }
+    };
+    let output = output.unwrap_or_else(|| {
+        Path::new(&file)
+            .with_extension("dot")
+            .to_string_lossy()
+            .to_string()
+    });
+    if output == "-" {
+        print!("{}", to_dot(&graph));
+    } else if let Err(e) = graph.dump_dot(Path::new(&output)) {
+        error!("Cannot write {}: {}", output, e);
+        std::process::exit(1);
+    } else {
+        info!("Wrote {}", output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAIR: &str = "\
+(box pair (
+    in  x:     float
+    out out1:  float
+    out out10: float
+)
+    (let out1 (+ x 1.0))
+    (let out10 (* x 10.0))
+)
+
+(box main (
+    out L: float
+    out R: float
+)
+    [pair 0.5 a b]
+    (let L a)
+    (let R b)
+)
+";
+
+    /// the value of key="..." in a DOT line
+    fn attr<'a>(line: &'a str, key: &str) -> &'a str {
+        let at = line.find(&format!("{}=\"", key)).unwrap() + key.len() + 2;
+        let len = line[at..].find('"').unwrap();
+        &line[at..at + len]
+    }
+
+    #[test]
+    fn edges_join_the_nodes_they_name_in_a_cluster_per_box() {
+        let graph = FlowGraph::from_source(PAIR).unwrap();
+        let dot = to_dot(&graph);
+        // node id to its name, and the box of the cluster it is in
+        let mut names = HashMap::new();
+        let mut clusters = Vec::new();
+        let mut edges = Vec::new();
+        for line in dot.lines().map(str::trim) {
+            if line.starts_with("subgraph cluster_") {
+                clusters.push(String::new());
+            } else if line.starts_with("label=") {
+                *clusters.last_mut().unwrap() = attr(line, "label").to_string();
+            } else if let Some((from, to)) = line.split_once(" -> ") {
+                let to = to.split_once(' ').unwrap().0;
+                edges.push((from.to_string(), to.to_string()));
+            } else if line.starts_with('n') && line.contains("tooltip=") {
+                let id = line.split_once(' ').unwrap().0.to_string();
+                let name = attr(line, "tooltip").to_string();
+                names.insert(id, (name, clusters.last().unwrap().clone()));
+            }
+        }
+        assert_eq!(clusters, ["pair", "main"]);
+        assert_eq!(names.len(), graph.nodes.len());
+        for (name, cluster) in names.values() {
+            assert_eq!(name.split_once('/').unwrap().0, cluster);
+        }
+        assert_eq!(edges.len(), graph.edges.len());
+        for e in graph.edges.iter() {
+            let edge = (format!("n{}", e.from.id), format!("n{}", e.to.id));
+            assert!(edges.contains(&edge), "{:?} missing", edge);
+            assert_eq!(names[&edge.0].0, e.from.name);
+            assert_eq!(names[&edge.1].0, e.to.name);
+        }
+        let named = |from: &str, to: &str| {
+            edges
+                .iter()
+                .any(|(a, b)| names[a].0 == from && names[b].0 == to)
+        };
+        assert!(named("pair/out1", "main/a"));
+        assert!(named("pair/out10", "main/b"));
+        assert!(!named("pair/out1", "main/b"));
+        assert!(!named("pair/out10", "main/a"));
+    }
+}
@@ -0,0 +1,790 @@
+use crate::ast;
+use crate::graph::{Constant, FlowGraph};
+use crate::ops::{self, Builtin};
+use crate::params::{Smoother, Smoothing, DEFAULT_SMOOTHING};
+use crate::voices;
+use log::*;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+pub const DEFAULT_SAMPLE_RATE: f32 = 48000.0;
+
+/// longest delay line, in samples
+const MAX_DELAY: usize = 1 << 20;
+/// largest table sinwave will allocate
+const MAX_TABLE: usize = 1 << 16;
+
+/// what a node does each sample
+#[derive(Debug, Clone)]
+pub enum Kind {
+    Const(f32),
+    Op(&'static Builtin),
+    Param, // in port nothing is wired into, set from outside
+    Pass,  // ports, lets and wires: the sum of the inputs
+}
+
+/// per-node state of the stateful builtins, buf is allocated by
+/// Engine::new and never changes length, a delay line writes at pos, a
+/// sinwave holds its table in the first pos samples
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    pub phase: f32,
+    pub z: f32,
+    pub rng: u32,
+    pub buf: Vec<f32>,
+    pub pos: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct EngineNode {
+    pub name: String,
+    pub kind: Kind,
+    pub args: Vec<usize>,    // slots of the inputs, in arg_no order
+    pub wave: Option<usize>, // slot of the sinwave node whose table this node carries
+    pub state: State,
+    pub smoother: Option<Smoother>, // params only
+    pub init: f32,                  // value before the first tick
+}
+
+/// Engine runs a FlowGraph one sample at a time, nodes are kept in
+/// topological order so each one sees the current value of its
+/// inputs, the inputs that close a cycle are read one sample late
+#[derive(Clone)]
+pub struct Engine {
+    pub sample_rate: f32,
+    pub nodes: Vec<EngineNode>,
+    pub values: Vec<f32>,
+    pub index: HashMap<String, usize>,
+    order: Vec<usize>,
+    outputs: Vec<String>,
+    output_slots: Vec<usize>,
+    inputs: Vec<String>,
+    input_slots: Vec<usize>,
+    ramping: Vec<usize>, // params whose smoother is moving them
+}
+
+impl Engine {
+    pub fn new(graph: &FlowGraph, sample_rate: f32) -> Self {
+        // defaults of the in ports, keyed by node name
+        let mut defaults: HashMap<String, f32> = HashMap::new();
+        let mut in_ports = Vec::new();
+        let mut outputs = Vec::new();
+        if let Some(top) = graph.ast.lock().unwrap().as_ref() {
+            for b in top.boxes() {
+                for port in b.ports() {
+                    let name = b.name().clone() + "/" + port.name();
+                    if let ast::Port::In(_, _, _, _) = port {
+                        in_ports.push(name.clone());
+                    }
+                    match &port {
+                        ast::Port::In(_, _, Some(ast::Numeric::Int32(v)), _) => {
+                            defaults.insert(name, *v as f32);
+                        }
+                        ast::Port::In(_, _, Some(ast::Numeric::Float(v)), _) => {
+                            defaults.insert(name, *v);
+                        }
+                        ast::Port::Out(_, _, _) if b.name() == "main" => outputs.push(name),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let mut index = HashMap::new();
+        let mut slot_of_id = HashMap::new();
+        for (slot, node) in graph.nodes.iter().enumerate() {
+            index.insert(node.name.clone(), slot);
+            slot_of_id.insert(node.id, slot);
+        }
+        let mut inputs: Vec<Vec<(u64, usize)>> = vec![Vec::new(); graph.nodes.len()];
+        for edge in graph.edges.iter() {
+            match (slot_of_id.get(&edge.from.id), slot_of_id.get(&edge.to.id)) {
+                (Some(from), Some(to)) => inputs[*to].push((edge.arg_no, *from)),
+                _ => warn!("Dangling edge: {:?}", edge),
+            }
+        }
+        let mut values = vec![0.0; graph.nodes.len()];
+        let mut nodes = Vec::new();
+        for (slot, node) in graph.nodes.iter().enumerate() {
+            inputs[slot].sort_by_key(|(arg_no, _)| *arg_no);
+            let args = inputs[slot]
+                .iter()
+                .map(|(_, from)| *from)
+                .collect::<Vec<_>>();
+            // node names are box/ident or box/op@n, op may be /, the
+            // copies of a box with voices are box#k/...
+            let base = voices::base_name(&node.name);
+            let local = node.name.split_once('/').map(|(_, l)| l).unwrap_or("");
+            let op = local.split('@').next().unwrap_or("");
+            let kind = match &node.const_data {
+                Some(c) => Kind::Const(const_value(c)),
+                None if local.contains('@') => match ops::lookup(op) {
+                    Some(b) => Kind::Op(b),
+                    None => {
+                        warn!("Unknown operator {} in {}, it will output 0", op, node.name);
+                        Kind::Const(0.0)
+                    }
+                },
+                None if args.is_empty() && in_ports.contains(&base) => Kind::Param,
+                None => Kind::Pass,
+            };
+            if let Kind::Const(v) = kind {
+                values[slot] = v;
+            }
+            if let Kind::Param = kind {
+                values[slot] = defaults.get(&base).cloned().unwrap_or(0.0);
+            }
+            let smoother = matches!(kind, Kind::Param).then(|| Smoother::new(DEFAULT_SMOOTHING));
+            nodes.push(EngineNode {
+                name: node.name.clone(),
+                kind,
+                args,
+                wave: None,
+                state: State {
+                    rng: 0x9e3779b9 ^ slot as u32,
+                    ..Default::default()
+                },
+                smoother,
+                init: values[slot],
+            });
+        }
+        // buffers get their length here so eval never allocates, a
+        // constant length is all a node needs, any other the most it can use
+        for slot in 0..nodes.len() {
+            let const_arg = |i: usize| match nodes[slot].args.get(i).map(|a| &nodes[*a].kind) {
+                Some(Kind::Const(v)) => Some(*v),
+                _ => None,
+            };
+            let len = match &nodes[slot].kind {
+                Kind::Op(b) if b.name == "delay" => match const_arg(1) {
+                    Some(d) => ((d.max(0.0) as usize).min(MAX_DELAY - 1) + 1).next_power_of_two(),
+                    None => MAX_DELAY,
+                },
+                Kind::Op(b) if b.name == "sinwave" => match const_arg(0) {
+                    Some(n) => (n.max(1.0) as usize).min(MAX_TABLE),
+                    None => MAX_TABLE,
+                },
+                _ => continue,
+            };
+            nodes[slot].state.buf = vec![0.0; len];
+        }
+        let order = topo_order(&nodes);
+        // waveforms flow through lets and ports by reference to their sinwave
+        for slot in order.iter() {
+            let wave = match &nodes[*slot].kind {
+                Kind::Op(b) if b.name == "sinwave" => Some(*slot),
+                Kind::Pass => nodes[*slot].args.first().and_then(|a| nodes[*a].wave),
+                _ => None,
+            };
+            nodes[*slot].wave = wave;
+        }
+        let output_slots = outputs
+            .iter()
+            .filter_map(|o| index.get(o).cloned())
+            .collect();
+        Engine {
+            sample_rate,
+            nodes,
+            values,
+            index,
+            order,
+            outputs,
+            output_slots,
+            inputs: Vec::new(),
+            input_slots: Vec::new(),
+            // a slot is in it at most once, so it never grows on the audio thread
+            ramping: Vec::with_capacity(graph.nodes.len()),
+        }
+    }
+
+    /// out ports of the main box
+    pub fn outputs(&self) -> &Vec<String> {
+        &self.outputs
+    }
+
+    /// params fed by process_input's channels, in channel order
+    pub fn inputs(&self) -> &Vec<String> {
+        &self.inputs
+    }
+
+    /// feed input channel c to the c-th of ports, names without a box
+    /// are main's, no param is an input until it is given here
+    pub fn map_inputs(&mut self, ports: &[String]) -> Result<(), String> {
+        let mut inputs = Vec::new();
+        let mut input_slots = Vec::new();
+        for port in ports {
+            let name = match port.contains('/') {
+                true => port.clone(),
+                false => format!("main/{}", port),
+            };
+            let slot = self.param_slot(&name)?;
+            if input_slots.contains(&slot) {
+                return Err(format!("{} is fed by two input channels", name));
+            }
+            inputs.push(name);
+            input_slots.push(slot);
+        }
+        self.inputs = inputs;
+        self.input_slots = input_slots;
+        Ok(())
+    }
+
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.index.get(name).cloned()
+    }
+
+    pub fn value(&self, name: &str) -> Option<f32> {
+        self.slot(name).map(|s| self.values[s])
+    }
+
+    /// set an in port that nothing is wired into
+    pub fn set_param(&mut self, name: &str, value: f32) -> Result<(), String> {
+        let slot = self.param_slot(name)?;
+        self.set_slot(slot, value);
+        Ok(())
+    }
+
+    /// slot of a param, for setting it later without the name lookup
+    pub fn param_slot(&self, name: &str) -> Result<usize, String> {
+        match self.slot(name) {
+            Some(slot) if matches!(self.nodes[slot].kind, Kind::Param) => Ok(slot),
+            Some(_) => Err(format!("{} is not a free in port", name)),
+            None => Err(format!("no node named {}", name)),
+        }
+    }
+
+    /// slot of a param, None where param_slot would say why, for the
+    /// audio thread that must not allocate the message
+    pub fn param(&self, name: &str) -> Option<usize> {
+        self.slot(name)
+            .filter(|slot| matches!(self.nodes[*slot].kind, Kind::Param))
+    }
+
+    /// set a param by the slot param_slot gave, at once
+    pub fn set_slot(&mut self, slot: usize, value: f32) {
+        if let Some(s) = self.nodes[slot].smoother.as_mut() {
+            s.stop();
+        }
+        self.values[slot] = value;
+    }
+
+    /// move a param to value with its smoothing, so a control that
+    /// jumps does not click
+    pub fn glide_slot(&mut self, slot: usize, value: f32) {
+        let (current, sample_rate) = (self.values[slot], self.sample_rate);
+        let gliding = match self.nodes[slot].smoother.as_mut() {
+            Some(s) => s.start(current, value, sample_rate),
+            None => false,
+        };
+        match gliding {
+            true if !self.ramping.contains(&slot) => self.ramping.push(slot),
+            true => {}
+            false => self.values[slot] = value,
+        }
+    }
+
+    pub fn glide_param(&mut self, name: &str, value: f32) -> Result<(), String> {
+        let slot = self.param_slot(name)?;
+        self.glide_slot(slot, value);
+        Ok(())
+    }
+
+    pub fn set_smoothing(&mut self, name: &str, smoothing: Smoothing) -> Result<(), String> {
+        let slot = self.param_slot(name)?;
+        self.nodes[slot].smoother = Some(Smoother::new(smoothing));
+        self.ramping.retain(|s| *s != slot);
+        Ok(())
+    }
+
+    /// names of the nodes set_param accepts
+    pub fn params(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .filter(|n| matches!(n.kind, Kind::Param))
+            .map(|n| n.name.clone())
+            .collect()
+    }
+
+    /// take over the state and values of the nodes of old whose name
+    /// and kind did not change, so a reloaded patch keeps its phases,
+    /// delay lines and param settings, a param whose starting value the
+    /// patch changed starts over, returns how many nodes were kept
+    pub fn adopt(&mut self, old: &Engine) -> usize {
+        let mut kept = 0;
+        for (slot, node) in self.nodes.iter_mut().enumerate() {
+            let prev = match old.slot(&node.name) {
+                Some(o) => &old.nodes[o],
+                None => continue,
+            };
+            let same = match (&node.kind, &prev.kind) {
+                // a delay line or table of another length starts over
+                (Kind::Op(a), Kind::Op(b)) => {
+                    a.name == b.name && node.state.buf.len() == prev.state.buf.len()
+                }
+                (Kind::Const(_), Kind::Const(_)) => true,
+                (Kind::Param, Kind::Param) => prev.init == node.init,
+                (Kind::Pass, Kind::Pass) => true,
+                _ => false,
+            };
+            if !same {
+                continue;
+            }
+            node.state.clone_from(&prev.state);
+            if let Kind::Param = node.kind {
+                node.smoother.clone_from(&prev.smoother);
+                if prev.smoother.as_ref().map(|s| s.active()).unwrap_or(false) {
+                    self.ramping.push(slot);
+                }
+            }
+            if !matches!(node.kind, Kind::Const(_)) {
+                self.values[slot] = old.values[old.index[&node.name]];
+            }
+            kept += 1;
+        }
+        kept
+    }
+
+    /// copy the state of every node into snap without allocating, for
+    /// the audio thread, a snap made for another engine is left
+    /// incomplete
+    pub fn snapshot_into(&self, snap: &mut Snapshot) {
+        snap.complete = snap.states.len() == self.nodes.len();
+        if !snap.complete {
+            return;
+        }
+        for (slot, node) in self.nodes.iter().enumerate() {
+            let to = &mut snap.states[slot];
+            if to.buf.capacity() < node.state.buf.len() {
+                snap.complete = false;
+                return;
+            }
+            to.phase = node.state.phase;
+            to.z = node.state.z;
+            to.rng = node.state.rng;
+            to.pos = node.state.pos;
+            to.buf.clear();
+            to.buf.extend_from_slice(&node.state.buf);
+            snap.smoothers[slot].clone_from(&node.smoother);
+        }
+        snap.values.copy_from_slice(&self.values);
+    }
+
+    /// put back the state a snapshot of this engine took
+    pub fn restore(&mut self, snap: &Snapshot) {
+        for (node, (state, smoother)) in self
+            .nodes
+            .iter_mut()
+            .zip(snap.states.iter().zip(snap.smoothers.iter()))
+        {
+            node.state.clone_from(state);
+            node.smoother.clone_from(smoother);
+        }
+        self.values.copy_from_slice(&snap.values);
+        let nodes = &self.nodes;
+        self.ramping.clear();
+        self.ramping.extend(
+            (0..nodes.len()).filter(|s| matches!(&nodes[*s].smoother, Some(sm) if sm.active())),
+        );
+    }
+
+    /// compute one sample of every node
+    pub fn tick(&mut self) {
+        if !self.ramping.is_empty() {
+            let (nodes, values) = (&mut self.nodes, &mut self.values);
+            self.ramping
+                .retain(|slot| match nodes[*slot].smoother.as_mut() {
+                    Some(s) if s.active() => {
+                        values[*slot] = s.next(values[*slot]);
+                        s.active()
+                    }
+                    _ => false,
+                });
+        }
+        for i in 0..self.order.len() {
+            let slot = self.order[i];
+            let v = self.eval(slot);
+            self.values[slot] = v;
+        }
+    }
+
+    /// render interleaved frames of the main box's out ports, channels
+    /// past the last out port repeat it
+    pub fn process_block(&mut self, interleaved: &mut [f32], channels: usize) {
+        self.process_input(&[], 0, interleaved, channels);
+    }
+
+    /// like process_block, input channel c sets the c-th param of
+    /// map_inputs before each frame, the other params keep their value,
+    /// channels past the mapped params are dropped and missing input
+    /// frames are silence
+    pub fn process_input(
+        &mut self,
+        input: &[f32],
+        in_channels: usize,
+        interleaved: &mut [f32],
+        channels: usize,
+    ) {
+        let mut frames = input.chunks(in_channels.max(1));
+        for frame in interleaved.chunks_mut(channels) {
+            let in_frame = frames.next().unwrap_or(&[]);
+            for (c, slot) in self.input_slots.iter().enumerate().take(in_channels) {
+                self.values[*slot] = in_frame.get(c).cloned().unwrap_or(0.0);
+            }
+            self.tick();
+            for (c, x) in frame.iter_mut().enumerate() {
+                *x = match self.output_slots.get(c).or(self.output_slots.last()) {
+                    Some(slot) => self.values[*slot],
+                    None => 0.0,
+                };
+            }
+        }
+    }
+
+    fn eval(&mut self, slot: usize) -> f32 {
+        let sr = self.sample_rate;
+        let node = &self.nodes[slot];
+        let a = |i: usize| node.args.get(i).map(|s| self.values[*s]).unwrap_or(0.0);
+        let b = match &node.kind {
+            Kind::Const(v) => return *v,
+            Kind::Param => return self.values[slot],
+            Kind::Pass => return node.args.iter().map(|s| self.values[*s]).sum(),
+            Kind::Op(b) => *b,
+        };
+        let n = node.args.len();
+        let all = node.args.iter().map(|s| self.values[*s]);
+        match b.name {
+            "+" => all.sum(),
+            "-" if n == 1 => -a(0),
+            "-" => a(0) - all.skip(1).sum::<f32>(),
+            "*" => all.product(),
+            "/" => {
+                let d = all.skip(1).product::<f32>();
+                if d == 0.0 {
+                    0.0
+                } else {
+                    a(0) / d
+                }
+            }
+            ">" => (a(0) > a(1)) as i32 as f32,
+            "<" => (a(0) < a(1)) as i32 as f32,
+            ">=" => (a(0) >= a(1)) as i32 as f32,
+            "<=" => (a(0) <= a(1)) as i32 as f32,
+            "==" => (a(0) == a(1)) as i32 as f32,
+            "min" => a(0).min(a(1)),
+            "max" => a(0).max(a(1)),
+            "abs" => a(0).abs(),
+            "clip" => a(0).max(a(1)).min(a(2)),
+            "sinwave" => {
+                let size = a(0).max(1.0) as usize;
+                let st = &mut self.nodes[slot].state;
+                let size = size.min(st.buf.len());
+                if st.pos != size {
+                    for (i, x) in st.buf[..size].iter_mut().enumerate() {
+                        *x = (2.0 * PI * i as f32 / size as f32).sin();
+                    }
+                    st.pos = size;
+                }
+                size as f32
+            }
+            "idx" => {
+                let i = a(1);
+                let wave = node.args.first().and_then(|s| self.nodes[*s].wave);
+                let table = match wave {
+                    Some(w) => &self.nodes[w].state.buf[..self.nodes[w].state.pos],
+                    None => return 0.0,
+                };
+                if table.is_empty() {
+                    return 0.0;
+                }
+                // linear interpolation between the two nearest samples
+                let len = table.len() as f32;
+                let pos = i.rem_euclid(len);
+                let i0 = pos.floor() as usize % table.len();
+                let i1 = (i0 + 1) % table.len();
+                let frac = pos - pos.floor();
+                table[i0] + (table[i1] - table[i0]) * frac
+            }
+            "phasor" | "sin" | "saw" | "square" | "tri" => {
+                let freq = a(0);
+                let st = &mut self.nodes[slot].state;
+                let p = st.phase;
+                st.phase = (p + freq / sr).rem_euclid(1.0);
+                match b.name {
+                    "phasor" => p,
+                    "sin" => (2.0 * PI * p).sin(),
+                    "saw" => 2.0 * p - 1.0,
+                    "square" => {
+                        if p < 0.5 {
+                            1.0
+                        } else {
+                            -1.0
+                        }
+                    }
+                    _ => 1.0 - 4.0 * (p - 0.5).abs(),
+                }
+            }
+            "noise" => {
+                let amp = a(0);
+                let st = &mut self.nodes[slot].state;
+                // xorshift32
+                st.rng ^= st.rng << 13;
+                st.rng ^= st.rng >> 17;
+                st.rng ^= st.rng << 5;
+                (st.rng as f32 / u32::MAX as f32 * 2.0 - 1.0) * amp
+            }
+            "lowpass" => {
+                let (x, cutoff) = (a(0), a(1));
+                let k = 1.0 - (-2.0 * PI * cutoff.max(0.0) / sr).exp();
+                let st = &mut self.nodes[slot].state;
+                st.z += k * (x - st.z);
+                st.z
+            }
+            "delay" => {
+                let (x, d) = (a(0), a(1));
+                let st = &mut self.nodes[slot].state;
+                let len = st.buf.len();
+                if len == 0 {
+                    return 0.0;
+                }
+                let d = (d.max(0.0) as usize).min(len - 1);
+                st.buf[st.pos] = x;
+                let y = st.buf[(st.pos + len - d) % len];
+                st.pos = (st.pos + 1) % len;
+                y
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+fn const_value(c: &Constant) -> f32 {
+    match c {
+        Constant::Int32(v) => *v as f32,
+        Constant::Int64(v) => *v as f32,
+        Constant::Float32(v) => *v,
+        Constant::Float64(v) => *v as f32,
+        Constant::Float32Array(_) => 0.0,
+    }
+}
+
+/// the per-node state of a playing engine, allocated off the audio
+/// thread with room for the engine's buffers, which never grow
+pub struct Snapshot {
+    pub states: Vec<State>,
+    pub smoothers: Vec<Option<Smoother>>,
+    pub values: Vec<f32>,
+    pub complete: bool,
+}
+
+impl Snapshot {
+    /// room for a snapshot of engine or a copy of it
+    pub fn new(engine: &Engine) -> Self {
+        let states = engine
+            .nodes
+            .iter()
+            .map(|n| State {
+                buf: Vec::with_capacity(n.state.buf.len()),
+                ..Default::default()
+            })
+            .collect();
+        Snapshot {
+            states,
+            smoothers: vec![None; engine.nodes.len()],
+            values: vec![0.0; engine.nodes.len()],
+            complete: false,
+        }
+    }
+}
+
+/// Kahn's algorithm over the node inputs, nodes left in cycles are
+/// appended in slot order
+fn topo_order(nodes: &[EngineNode]) -> Vec<usize> {
+    let mut indegree = nodes.iter().map(|n| n.args.len()).collect::<Vec<_>>();
+    let mut users: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+    for (slot, node) in nodes.iter().enumerate() {
+        for arg in node.args.iter() {
+            users[*arg].push(slot);
+        }
+    }
+    let mut ready = (0..nodes.len())
+        .filter(|s| indegree[*s] == 0)
+        .rev()
+        .collect::<Vec<_>>();
+    let mut order = Vec::new();
+    let mut done = vec![false; nodes.len()];
+    while let Some(slot) = ready.pop() {
+        order.push(slot);
+        done[slot] = true;
+        for user in users[slot].iter() {
+            indegree[*user] -= 1;
+            if indegree[*user] == 0 {
+                ready.push(*user);
+            }
+        }
+    }
+    for slot in 0..nodes.len() {
+        if !done[slot] {
+            debug!("Node {} is in a cycle", nodes[slot].name);
+            order.push(slot);
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: &str = "\
+(box main (
+    in  freq: float = 220.0
+    out l:    float
+)
+    (let l (delay (saw freq) 100))
+)
+";
+
+    const MODULATED: &str = "\
+(box main (
+    in  time: float = 100.0
+    out l:    float
+)
+    (let l (delay (saw 220.0) time))
+)
+";
+
+    #[test]
+    fn only_mapped_params_take_input() {
+        let src = "\
+(box main (
+    in  sig:  float
+    in  gain: float = 1.0
+    out l:    float
+)
+    (let l (* sig gain))
+)
+";
+        let graph = FlowGraph::from_source(src).unwrap();
+        let mut engine = Engine::new(&graph, 48000.0);
+        assert!(engine.inputs().is_empty());
+        assert!(engine.map_inputs(&["nope".to_string()]).is_err());
+        assert!(engine.map_inputs(&["l".to_string()]).is_err());
+        let twice = ["sig".to_string(), "main/sig".to_string()];
+        assert!(engine.map_inputs(&twice).is_err());
+        engine.map_inputs(&["sig".to_string()]).unwrap();
+        assert_eq!(engine.inputs(), &vec!["main/sig".to_string()]);
+        engine.set_param("main/gain", 0.5).unwrap();
+        // a stereo input, the second channel has no port and is dropped
+        let input = [0.5, 9.0, -1.0, 9.0];
+        let mut out = [0.0; 2];
+        engine.process_input(&input, 2, &mut out, 1);
+        assert_eq!(out, [0.25, -0.5]);
+        assert_eq!(engine.value("main/gain"), Some(0.5));
+    }
+
+    #[test]
+    fn snapshots_restore() {
+        let graph = FlowGraph::from_source(DELAY).unwrap();
+        let mut engine = Engine::new(&graph, 48000.0);
+        let mut copy = engine.clone();
+        for _ in 0..64 {
+            engine.tick();
+        }
+        let mut snap = Snapshot::new(&copy);
+        engine.snapshot_into(&mut snap);
+        assert!(snap.complete);
+        copy.restore(&snap);
+        assert_eq!(copy.values, engine.values);
+        for _ in 0..64 {
+            engine.tick();
+            copy.tick();
+        }
+        assert_eq!(copy.values, engine.values);
+        // one made for another engine does not fit, the modulated
+        // delay line is longer
+        let mut snap = Snapshot::new(&copy);
+        let other = Engine::new(&FlowGraph::from_source(MODULATED).unwrap(), 48000.0);
+        other.snapshot_into(&mut snap);
+        assert!(!snap.complete);
+    }
+
+    #[test]
+    fn a_reload_keeps_the_state_of_the_nodes_it_did_not_change() {
+        let src = DELAY.replace("(delay (saw freq) 100)", "(* 0.5 (delay (saw freq) 100))");
+        let mut old = Engine::new(&FlowGraph::from_source(&src).unwrap(), 48000.0);
+        old.set_param("main/freq", 330.0).unwrap();
+        for _ in 0..150 {
+            old.tick();
+        }
+        let src = src.replace("(* 0.5", "(+ 0.5");
+        let mut engine = Engine::new(&FlowGraph::from_source(&src).unwrap(), 48000.0);
+        assert_eq!(engine.adopt(&old), engine.nodes.len() - 1);
+        let slot = |e: &Engine, name: &str| e.nodes.iter().position(|n| n.name.contains(name));
+        let node = |e: &Engine, name: &str| e.nodes[slot(e, name).unwrap()].state.clone();
+        let (saw, line) = (node(&old, "saw@"), node(&old, "delay@"));
+        assert!(saw.phase > 0.0);
+        assert!(line.buf.iter().any(|v| *v != 0.0));
+        assert_eq!(node(&engine, "saw@").phase, saw.phase);
+        assert_eq!(node(&engine, "delay@").buf, line.buf);
+        assert_eq!(node(&engine, "delay@").pos, line.pos);
+        assert_eq!(engine.value("main/freq"), Some(330.0));
+
+        // and carries on where the old one was, the new node aside
+        let (was, is) = (
+            slot(&old, "delay@").unwrap(),
+            slot(&engine, "delay@").unwrap(),
+        );
+        for _ in 0..64 {
+            old.tick();
+            engine.tick();
+            assert_eq!(engine.values[is], old.values[was]);
+        }
+    }
+
+    #[test]
+    fn delay_lines_are_allocated_up_front() {
+        let graph = FlowGraph::from_source(DELAY).unwrap();
+        let mut engine = Engine::new(&graph, 48000.0);
+        let line = engine
+            .nodes
+            .iter()
+            .position(|n| n.name.contains("delay@"))
+            .unwrap();
+        assert_eq!(engine.nodes[line].state.buf.len(), 128);
+        let saw = engine
+            .nodes
+            .iter()
+            .position(|n| n.name.contains("saw@"))
+            .unwrap();
+        let mut history = Vec::new();
+        for _ in 0..300 {
+            engine.tick();
+            history.push(engine.values[saw]);
+            let out = engine.values[line];
+            match history.len() > 100 {
+                true => assert_eq!(out, history[history.len() - 101]),
+                false => assert_eq!(out, 0.0),
+            }
+        }
+
+        // a modulated delay time gets the longest line at once, the
+        // audio thread never resizes it
+        let graph = FlowGraph::from_source(MODULATED).unwrap();
+        let mut engine = Engine::new(&graph, 48000.0);
+        let line = engine
+            .nodes
+            .iter()
+            .position(|n| n.name.contains("delay@"))
+            .unwrap();
+        let buf = engine.nodes[line].state.buf.as_ptr();
+        assert_eq!(engine.nodes[line].state.buf.len(), MAX_DELAY);
+        for d in [10.0, 5000.0, 2.0e6] {
+            engine.set_param("main/time", d).unwrap();
+            for _ in 0..64 {
+                engine.tick();
+            }
+        }
+        assert_eq!(engine.nodes[line].state.buf.as_ptr(), buf);
+        assert_eq!(engine.nodes[line].state.buf.len(), MAX_DELAY);
+    }
+}
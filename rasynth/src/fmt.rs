@@ -0,0 +1,676 @@
+use crate::ast::*;
+use crate::raslisp;
+use argparse::{ArgumentParser, List, StoreTrue};
+use log::*;
+use std::fs;
+use std::io::{stderr, stdout};
+
+const INDENT: &str = "    ";
+
+/// rasynth fmt [--check] FILE...
+/// re-emits each file from its ast in canonical form, with --check
+/// nothing is written and the exit code tells if any file would change
+pub fn command(args: Vec<String>) {
+    let mut check = false;
+    let mut files: Vec<String> = Vec::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Format raslisp files");
+        ap.refer(&mut check).add_option(
+            &["--check"],
+            StoreTrue,
+            "Don't write the files, exit with 1 if any is not formatted",
+        );
+        ap.refer(&mut files)
+            .required()
+            .add_argument("files", List, "Raslisp files to format");
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    let mut unformatted = 0;
+    for file in files.iter() {
+        let src = match fs::read_to_string(file) {
+            Ok(src) => src,
+            Err(e) => {
+                error!("Cannot read {}: {}", file, e);
+                std::process::exit(1);
+            }
+        };
+        let formatted = match format_source(&src) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                error!("{}: {}", file, e);
+                std::process::exit(2);
+            }
+        };
+        if formatted == src {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", file);
+            unformatted += 1;
+        } else {
+            if let Err(e) = fs::write(file, formatted) {
+                error!("Cannot write {}: {}", file, e);
+                std::process::exit(1);
+            }
+            info!("Formatted {}", file);
+        }
+    }
+    if unformatted > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// canonical source of a file, with its comments put back
+pub fn format_source(src: &str) -> Result<String, String> {
+    let top = raslisp::TopParser::new()
+        .parse(src)
+        .map_err(|e| e.to_string())?;
+    Ok(top_text(&top, &FileNotes::scan(src)))
+}
+
+/// canonical source of a whole file, without comments
+pub fn format_top(top: &TopDef) -> String {
+    top_text(top, &FileNotes::default())
+}
+
+fn top_text(top: &TopDef, notes: &FileNotes) -> String {
+    let TopDef::Items(items) = top;
+    let mut out = String::new();
+    let mut prev: Option<&TopItem> = None;
+    for (k, item) in items.iter().enumerate() {
+        // midi maps and voices are kept together
+        let blank_line = !matches!(
+            (prev, item),
+            (None, _)
+                | (
+                    Some(TopItem::MidiMap(_) | TopItem::VoicesDef(_)),
+                    TopItem::MidiMap(_) | TopItem::VoicesDef(_),
+                )
+        );
+        if blank_line {
+            out += "\n";
+        }
+        let attached = notes.items.elem(k);
+        push_before(&mut out, &attached.before, "", k == 0 || blank_line);
+        let inner = notes.inner.get(k).cloned().unwrap_or_default();
+        match item {
+            TopItem::BoxDef(b) => out += &box_text(b, &inner),
+            TopItem::MacroDef(m) => out += &format_macro(m),
+            TopItem::MidiMap(m) => out += &format_midi_map(m),
+            TopItem::VoicesDef(v) => out += &format_voices(v),
+            TopItem::ControlsDef(c) => out += &controls_text(c, &inner.body),
+        }
+        push_after(&mut out, &attached.after);
+        prev = Some(item);
+    }
+    // a comment after the last item is set apart from it
+    if !notes.items.tail.is_empty() && !items.is_empty() {
+        out += "\n";
+    }
+    push_before(&mut out, &notes.items.tail, "", true);
+    out
+}
+
+/// a `; text` comment, the grammar skips them so they are found by
+/// scanning the source, the language has no strings so a ; always
+/// starts one
+#[derive(Debug, Clone)]
+struct Comment {
+    pos: usize,
+    text: String,
+    own_line: bool,   // nothing but blanks before it on its line
+    blank_line: bool, // a blank line before it, at most one is kept
+}
+
+/// the comments that go with one element of a list
+#[derive(Debug, Clone, Default)]
+struct Attached {
+    before: Vec<Comment>, // on lines of their own above it
+    after: Vec<String>,   // at the end of its line
+}
+
+/// the comments of a list: the items of a file, the ports or the
+/// statements of a box, the maps of a controls form
+#[derive(Debug, Clone, Default)]
+struct Notes {
+    head: Vec<String>, // at the end of the line that opens the list
+    elems: Vec<Attached>,
+    tail: Vec<Comment>, // after the last element
+}
+
+/// the comments of the lists inside an item, body holds a box's
+/// statements or a controls form's maps
+#[derive(Debug, Clone, Default)]
+struct ItemNotes {
+    ports: Notes,
+    body: Notes,
+}
+
+#[derive(Debug, Clone, Default)]
+struct FileNotes {
+    items: Notes,
+    inner: Vec<ItemNotes>,
+}
+
+/// what the scan sees: a (...) or [...] group, a word, or a comment,
+/// by its index
+#[derive(Debug)]
+enum Part {
+    Group(Group),
+    Word(usize, usize),
+    Comment(usize),
+}
+
+#[derive(Debug)]
+struct Group {
+    start: usize,
+    end: usize,
+    parts: Vec<Part>,
+}
+
+impl Group {
+    fn groups(&self) -> impl Iterator<Item = &Group> {
+        self.parts.iter().filter_map(|p| match p {
+            Part::Group(g) => Some(g),
+            _ => None,
+        })
+    }
+
+    /// the comments anywhere inside, in source order
+    fn comments(&self, out: &mut Vec<usize>) {
+        for part in self.parts.iter() {
+            match part {
+                Part::Group(g) => g.comments(out),
+                Part::Comment(c) => out.push(*c),
+                Part::Word(..) => {}
+            }
+        }
+    }
+}
+
+/// split src into groups, words and comments
+fn scan(src: &str) -> (Group, Vec<Comment>) {
+    let bytes = src.as_bytes();
+    let mut comments = Vec::new();
+    let mut stack = vec![Group {
+        start: 0,
+        end: src.len(),
+        parts: Vec::new(),
+    }];
+    let mut prev_end = 0; // of the last thing that was not a blank
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        match c {
+            b';' => {
+                while i < bytes.len() && bytes[i] != b'\n' && bytes[i] != b'\r' {
+                    i += 1;
+                }
+                let line_start = src[..start].rfind('\n').map(|n| n + 1).unwrap_or(0);
+                comments.push(Comment {
+                    pos: start,
+                    text: src[start..i].trim_end().to_string(),
+                    own_line: src[line_start..start].trim().is_empty(),
+                    blank_line: src[prev_end..start].matches('\n').count() > 1,
+                });
+                let part = Part::Comment(comments.len() - 1);
+                stack.last_mut().unwrap().parts.push(part);
+            }
+            b'(' | b'[' => {
+                i += 1;
+                stack.push(Group {
+                    start,
+                    end: start,
+                    parts: Vec::new(),
+                });
+            }
+            b')' | b']' => {
+                i += 1;
+                if stack.len() > 1 {
+                    let mut group = stack.pop().unwrap();
+                    group.end = i;
+                    stack.last_mut().unwrap().parts.push(Part::Group(group));
+                }
+            }
+            _ => {
+                while i < bytes.len() && !b"()[]; \t\r\n".contains(&bytes[i]) {
+                    i += 1;
+                }
+                stack.last_mut().unwrap().parts.push(Part::Word(start, i));
+            }
+        }
+        prev_end = i;
+    }
+    // only a source the parser took is scanned, the groups are closed
+    let root = stack.swap_remove(0);
+    (root, comments)
+}
+
+impl Notes {
+    fn elem(&self, k: usize) -> Attached {
+        self.elems.get(k).cloned().unwrap_or_default()
+    }
+
+    /// sort the comments among elements spanning (start, end): one
+    /// inside an element goes above it when on a line of its own and
+    /// at the end of its line when not, one between elements goes
+    /// above the next or at the end of the line of the one before,
+    /// with head the first line of the list takes it
+    fn attach(elems: &[(usize, usize)], ids: &[usize], comments: &[Comment], head: bool) -> Self {
+        let mut notes = Notes {
+            elems: vec![Attached::default(); elems.len()],
+            ..Default::default()
+        };
+        for c in ids.iter().map(|id| &comments[*id]) {
+            let inside = elems.iter().position(|(s, e)| *s <= c.pos && c.pos < *e);
+            let before = elems.iter().filter(|(_, e)| *e <= c.pos).count();
+            match (inside, c.own_line) {
+                (Some(k), true) => notes.elems[k].before.push(c.clone()),
+                (Some(k), false) => notes.elems[k].after.push(c.text.clone()),
+                (None, false) if before > 0 => notes.elems[before - 1].after.push(c.text.clone()),
+                (None, false) if head => notes.head.push(c.text.clone()),
+                _ => match notes.elems.get_mut(before) {
+                    Some(next) => next.before.push(c.clone()),
+                    None => notes.tail.push(c.clone()),
+                },
+            }
+        }
+        notes
+    }
+}
+
+impl FileNotes {
+    /// where each comment of src goes, the lists are matched with the
+    /// ast's by position, there are no comments in it to throw them off
+    fn scan(src: &str) -> Self {
+        let (root, comments) = scan(src);
+        let word = |part: &Part| match part {
+            Part::Word(s, e) => Some(&src[*s..*e]),
+            _ => None,
+        };
+        let items = root.groups().collect::<Vec<_>>();
+        let ids = root
+            .parts
+            .iter()
+            .filter_map(|p| match p {
+                Part::Comment(c) => Some(*c),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let spans = items.iter().map(|g| (g.start, g.end)).collect::<Vec<_>>();
+        let mut notes = FileNotes {
+            items: Notes::attach(&spans, &ids, &comments, false),
+            inner: Vec::new(),
+        };
+        for (k, item) in items.iter().enumerate() {
+            let mut inner = ItemNotes::default();
+            let mut ids = Vec::new();
+            item.comments(&mut ids);
+            let head = item.parts.first().and_then(word);
+            let port_list = item.groups().next();
+            match (head, port_list) {
+                (Some("box"), Some(port_list)) => {
+                    // ports run from in or out to the last word before the next
+                    let mut ports: Vec<(usize, usize)> = Vec::new();
+                    for part in port_list.parts.iter() {
+                        if let Part::Word(s, e) = part {
+                            match &src[*s..*e] {
+                                "in" | "out" => ports.push((*s, *e)),
+                                _ => {
+                                    if let Some(port) = ports.last_mut() {
+                                        port.1 = *e;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    let stmts = item
+                        .groups()
+                        .skip(1)
+                        .map(|g| (g.start, g.end))
+                        .collect::<Vec<_>>();
+                    let (in_ports, rest): (Vec<usize>, Vec<usize>) = ids
+                        .into_iter()
+                        .partition(|id| comments[*id].pos < port_list.end);
+                    let (header, in_ports): (Vec<usize>, Vec<usize>) = in_ports
+                        .into_iter()
+                        .partition(|id| comments[*id].pos < port_list.start);
+                    inner.ports = Notes::attach(&ports, &in_ports, &comments, true);
+                    inner.body = Notes::attach(&stmts, &rest, &comments, true);
+                    // between (box and the port list, above the box
+                    for id in header {
+                        notes.items.elems[k].before.push(comments[id].clone());
+                    }
+                }
+                (Some("controls"), _) => {
+                    let maps = item.groups().map(|g| (g.start, g.end)).collect::<Vec<_>>();
+                    inner.body = Notes::attach(&maps, &ids, &comments, true);
+                }
+                _ => {
+                    let spans = [(item.start, item.end)];
+                    let one = Notes::attach(&spans, &ids, &comments, false);
+                    let attached = one.elems.into_iter().next().unwrap_or_default();
+                    notes.items.elems[k].before.extend(attached.before);
+                    notes.items.elems[k].after.extend(attached.after);
+                }
+            }
+            notes.inner.push(inner);
+        }
+        notes
+    }
+}
+
+/// comments on lines of their own, the first of a list is not set
+/// apart by a blank line
+fn push_before(out: &mut String, comments: &[Comment], indent: &str, first_in_list: bool) {
+    for (i, c) in comments.iter().enumerate() {
+        if c.blank_line && !(first_in_list && i == 0) {
+            *out += "\n";
+        }
+        *out += indent;
+        *out += &c.text;
+        *out += "\n";
+    }
+}
+
+/// comments at the end of the last line of out, a line ends in one
+/// so the ones before the last go on lines of their own above it
+fn push_after(out: &mut String, comments: &[String]) {
+    let Some((last, above)) = comments.split_last() else {
+        return;
+    };
+    out.pop();
+    let line = out.split_off(out.rfind('\n').map(|n| n + 1).unwrap_or(0));
+    let indent = &line[..line.len() - line.trim_start().len()];
+    for c in above.iter() {
+        *out += indent;
+        *out += c;
+        *out += "\n";
+    }
+    *out += &line;
+    *out += " ";
+    *out += last;
+    *out += "\n";
+}
+
+pub fn format_midi_map(m: &MidiMap) -> String {
+    let MidiMap::Map(port, source, n) = m;
+    match n {
+        Some(n) => format!("(midi {} {} {})\n", port, source, format_num(n)),
+        None => format!("(midi {} {})\n", port, source),
+    }
+}
+
+pub fn format_voices(v: &VoicesDef) -> String {
+    let VoicesDef::Voices(n, name, steal) = v;
+    match steal {
+        Some(steal) => format!("(voices {} {} {})\n", format_num(n), name, steal),
+        None => format!("(voices {} {})\n", format_num(n), name),
+    }
+}
+
+/// one control map a line, closed like a box
+pub fn format_controls(c: &ControlsDef) -> String {
+    controls_text(c, &Notes::default())
+}
+
+fn controls_text(c: &ControlsDef, notes: &Notes) -> String {
+    let ControlsDef::Controls(maps) = c;
+    let mut out = "(controls\n".to_string();
+    push_after(&mut out, &notes.head);
+    for (k, m) in maps.iter().enumerate() {
+        let ControlMap::Map(control, port, min, max, curve, step) = m;
+        let attached = notes.elem(k);
+        push_before(&mut out, &attached.before, INDENT, k == 0);
+        out += &format!(
+            "{}({} {} {} {}",
+            INDENT,
+            control,
+            port,
+            format_num(min),
+            format_num(max)
+        );
+        if let Some(curve) = curve {
+            out += &format!(" {}", curve);
+        }
+        if let Some(step) = step {
+            out += &format!(" {}", format_num(step));
+        }
+        out += ")\n";
+        push_after(&mut out, &attached.after);
+    }
+    push_before(&mut out, &notes.tail, INDENT, maps.is_empty());
+    out += ")\n";
+    out
+}
+
+pub fn format_macro(m: &MacroDef) -> String {
+    let MacroDef::Macro(name, params, body, _, _) = m;
+    format!(
+        "(defmacro {} ({})\n{}{}\n)\n",
+        name,
+        params.join(" "),
+        INDENT,
+        format_expr(body)
+    )
+}
+
+pub fn format_box(b: &BoxDef) -> String {
+    box_text(b, &ItemNotes::default())
+}
+
+fn box_text(b: &BoxDef, notes: &ItemNotes) -> String {
+    let BoxDef::ModuleBox(name, ports, stmts, _) = b;
+    let mut out = format!("(box {} (\n", name);
+    push_after(&mut out, &notes.ports.head);
+    // align the types of all ports in the box
+    let name_width = ports.iter().map(|p| p.name().len()).max().unwrap_or(0);
+    for (k, port) in ports.iter().enumerate() {
+        let attached = notes.ports.elem(k);
+        push_before(&mut out, &attached.before, INDENT, k == 0);
+        out += &format!("{}{}\n", INDENT, format_port(port, name_width));
+        push_after(&mut out, &attached.after);
+    }
+    push_before(&mut out, &notes.ports.tail, INDENT, ports.is_empty());
+    out += ")\n";
+    push_after(&mut out, &notes.body.head);
+    for (k, stmt) in stmts.iter().enumerate() {
+        let attached = notes.body.elem(k);
+        push_before(&mut out, &attached.before, INDENT, k == 0);
+        out += &format!("{}{}\n", INDENT, format_stmt(stmt));
+        push_after(&mut out, &attached.after);
+    }
+    push_before(&mut out, &notes.body.tail, INDENT, stmts.is_empty());
+    out += ")\n";
+    out
+}
+
+/// `in  name: type = default`, with name padded to name_width
+pub fn format_port(port: &Port, name_width: usize) -> String {
+    let (dir, name, ty, default) = match port {
+        Port::In(name, ty, default, _) => ("in ", name, ty, default.as_ref()),
+        Port::Out(name, ty, _) => ("out", name, ty, None),
+    };
+    let mut s = format!(
+        "{} {:width$} {}",
+        dir,
+        name.clone() + ":",
+        format_type(ty),
+        width = name_width + 1
+    );
+    if let Some(default) = default {
+        s += &format!(" = {}", format_num(default));
+    }
+    s
+}
+
+pub fn format_stmt(stmt: &Stmt) -> String {
+    match stmt {
+        Stmt::LetDef(LetDef::Let(name, expr, _)) => format!("(let {} {})", name, format_expr(expr)),
+        Stmt::BoxWire(BoxWire::Boxw(name, args, _)) => {
+            let mut s = format!("[{}", name);
+            for arg in args {
+                match arg {
+                    WireArg::Positional(e) => s += &format!(" {}", format_expr(e)),
+                    WireArg::Keyword(k, e, _) => s += &format!(" :{} {}", k, format_expr(e)),
+                }
+            }
+            s + "]"
+        }
+    }
+}
+
+pub fn format_expr(expr: &Expr) -> String {
+    match expr {
+        Expr::NodeIdent(name, _) => name.clone(),
+        Expr::Num(n) => format_num(n),
+        Expr::Operator(op, args, _) => {
+            let args = args.iter().map(format_expr).collect::<Vec<_>>();
+            format!("({} {})", op, args.join(" "))
+        }
+    }
+}
+
+pub fn format_num(n: &Numeric) -> String {
+    match n {
+        Numeric::Int32(i) => i.to_string(),
+        Numeric::Float(f) => {
+            // Display never uses an exponent, the grammar needs the dot
+            let s = f.to_string();
+            if s.contains('.') {
+                s
+            } else {
+                s + ".0"
+            }
+        }
+    }
+}
+
+pub fn format_type(ty: &Type) -> &'static str {
+    match ty {
+        Type::Int32 => "i32",
+        Type::Float => "float",
+        Type::Waveform => "waveform",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMENTED: &str = "\
+(box osc ( ; ports
+    in freq: float = 220.0 ; hz
+    out sig: float
+)
+    (let sig (saw ; a saw
+        freq))
+)
+(box main (
+    out L: float
+    out R: float
+)
+    (let L (+ 0.1 ; one
+        0.2))
+    [osc ; wire
+        :freq 110.0 ; low
+        R]
+)
+";
+
+    const PLAIN: &str = "\
+(box osc (
+    in freq: float = 220.0
+    out sig: float
+)
+    (let sig (saw freq))
+)
+(box main (
+    out L: float
+    out R: float
+)
+    (let L (+ 0.1 0.2))
+    [osc :freq 110.0 R]
+)
+";
+
+    fn parse(src: &str) -> TopDef {
+        raslisp::TopParser::new().parse(src).unwrap()
+    }
+
+    #[test]
+    fn comments_inside_expressions_and_wires_parse() {
+        assert_eq!(format_top(&parse(COMMENTED)), format_top(&parse(PLAIN)));
+    }
+
+    #[test]
+    fn comments_are_kept_and_stable() {
+        let formatted = format_source(COMMENTED).unwrap();
+        assert_eq!(
+            formatted,
+            "\
+(box osc ( ; ports
+    in  freq: float = 220.0 ; hz
+    out sig:  float
+)
+    (let sig (saw freq)) ; a saw
+)
+
+(box main (
+    out L: float
+    out R: float
+)
+    (let L (+ 0.1 0.2)) ; one
+    ; wire
+    [osc :freq 110.0 R] ; low
+)
+"
+        );
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn comments_on_their_own_lines_stay_above() {
+        let src = "; about osc\n(box osc (\n    ; the output\n    out sig: float\n)\n\n    ; a saw\n    (let sig (saw 220.0))\n)\n\n; the end\n";
+        assert_eq!(
+            format_source(src).unwrap(),
+            src.replace("\n\n    ;", "\n    ;")
+        );
+    }
+    #[test]
+    fn trailing_comments_stay_apart() {
+        let src = "\
+(box main (
+    out L: float ; left
+    out R: float
+)
+    (let L (* 0.5 ; wire
+        (saw 220.0))) ; low
+    (let R L)
+)
+";
+        let formatted = format_source(src).unwrap();
+        assert_eq!(
+            formatted,
+            "\
+(box main (
+    out L: float ; left
+    out R: float
+)
+    ; wire
+    (let L (* 0.5 (saw 220.0))) ; low
+    (let R L)
+)
+"
+        );
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+}
@@ -1,11 +1,15 @@
 use crate::ast;
+use crate::dot;
+use crate::graph_json;
+use crate::macros;
+use crate::raslisp;
+use crate::symbol_table::{self, Severity};
+use crate::voices;
 use core::fmt;
 use log::*;
-use petgraph::dot::{Config, Dot};
-use petgraph::graph::Graph;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+use std::path::Path;
 use std::{borrow::BorrowMut, sync::Mutex};
 
 pub static FLOW_GRAPH: Mutex<Option<FlowGraph>> = Mutex::new(None);
@@ -16,12 +20,19 @@ pub struct Context {
     pub current_box_op_suffix_cnt: HashMap<String, u64>,
 }
 
-#[derive(Debug, Clone)]
+/// saved as {"type": "f32", "value": 0.5} in a graph's JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
 pub enum Constant {
+    #[serde(rename = "i32")]
     Int32(i32),
+    #[serde(rename = "i64")]
     Int64(i64),
+    #[serde(rename = "f32")]
     Float32(f32),
+    #[serde(rename = "f64")]
     Float64(f64),
+    #[serde(rename = "f32[]")]
     Float32Array(Vec<f32>),
     // Waveform(Waveform),
 }
@@ -106,33 +117,49 @@ impl FlowGraph {
             popped_nodes_hash: HashMap::new(),
         }
     }
-    pub fn dump_dot(&self) {
-        let mut dot_graph = Graph::<String, u64>::new();
-        for node in self.nodes.iter() {
-            let mut dot_node_name = node.name.clone();
-            // if is contant, append real value
-            if node.const_data.is_some() {
-                dot_node_name += format!("\n{:?}", node.const_data.as_ref().unwrap()).as_str();
+    /// generate the graph of a macro-expanded ast in one go
+    pub fn build(top: ast::TopDef) -> Result<Self, String> {
+        let mut graph = FlowGraph::new(Some(top.clone()));
+        let boxes = graph.generate();
+        graph.node_create(&boxes)?;
+        voices::expand(&mut graph, &top).map_err(|e| format!("expanding voices: {}", e))?;
+        Ok(graph)
+    }
+    /// parse, expand, check and build a raslisp source
+    pub fn from_source(src: &str) -> Result<Self, String> {
+        let top = raslisp::TopParser::new()
+            .parse(src)
+            .map_err(|e| e.to_string())?;
+        let top = macros::expand(top)?;
+        let analysis = symbol_table::analyze(&top);
+        let mut errors = Vec::new();
+        for e in analysis.errors.iter() {
+            match e.severity {
+                Severity::Error => errors.push(e.message.clone()),
+                Severity::Warning => warn!("{:?}: {}", e.box_name, e.message),
             }
-            dot_graph.add_node(dot_node_name);
         }
-        for edge in self.edges.iter() {
-            // prefix match
-            let a = dot_graph
-                .node_indices()
-                .find(|ni| dot_graph[*ni].starts_with(&edge.from.name));
-            let b = dot_graph
-                .node_indices()
-                .find(|ni| dot_graph[*ni].starts_with(&edge.to.name));
-            let w = edge.arg_no;
-            if a.is_some() && b.is_some() {
-                dot_graph.add_edge(a.unwrap(), b.unwrap(), w);
-            }
+        if !errors.is_empty() {
+            return Err(errors.join(", "));
+        }
+        FlowGraph::build(top)
+    }
+    /// a patch file, raslisp source or a graph saved by rasynth compile
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_text(path, &text)
+    }
+    /// the text of a patch file, saved JSON when path ends in .json
+    pub fn from_text(path: &Path, text: &str) -> Result<Self, String> {
+        match path.extension().map(|e| e == "json").unwrap_or(false) {
+            true => graph_json::from_json(text),
+            false => Self::from_source(text),
         }
-        // println!("{:?}", Dot::with_config(&dot_graph, &[Config::EdgeNoLabel]));
-        let mut f = File::create("flow.dot").unwrap();
-        let output = format!("{}", Dot::new(&dot_graph));
-        f.write_all(&output.as_bytes()).expect("write failed");
+    }
+    /// write the graph in DOT to path, see dot.rs
+    pub fn dump_dot(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, dot::to_dot(self))
     }
     pub fn add_edge(&mut self, from: &mut Box<Node>, to: &mut Box<Node>, arg_no: u64) {
         let edge = Box::new(Edge {
@@ -159,7 +186,7 @@ impl FlowGraph {
         self.nodes.push(node.clone());
         self.node_id_counter += 1;
     }
-    pub fn pop_node_by_name(&mut self, name: &String) -> Box<Node> {
+    pub fn pop_node_by_name(&mut self, name: &String) -> Result<Box<Node>, String> {
         debug!("pop_node_by_name: {}", name);
         let mut ret: Option<Box<Node>> = None;
         // name should be box_name/ident without @ suffix
@@ -187,21 +214,17 @@ impl FlowGraph {
                 }
             }
         }
-        if ret.is_none() {
-            error!("Node not found: {}", name);
-            panic!("Node not found: {}", name);
-        }
         debug!("pop_node_by_name->ret: {:?}", ret);
-        ret.unwrap()
+        ret.ok_or(format!("node not found: {}", name))
     }
-    pub fn node_create(&mut self, boxes: &Vec<ast::BoxDef>) {
+    pub fn node_create(&mut self, boxes: &[ast::BoxDef]) -> Result<(), String> {
         // first iteration, create all the nodes
         info!(">>> ITERATION 1: Creating Nodes...");
         for box_def in boxes {
             // create a new module box
             let module_box = ModuleBox {
                 name: match box_def {
-                    ast::BoxDef::ModuleBox(name, _, _) => name.clone(),
+                    ast::BoxDef::ModuleBox(name, _, _, _) => name.clone(),
                 },
             };
 
@@ -211,16 +234,16 @@ impl FlowGraph {
             self.ctx.current_box_op_suffix_cnt = HashMap::new();
 
             match box_def {
-                ast::BoxDef::ModuleBox(name, ports, stmts) => {
+                ast::BoxDef::ModuleBox(name, ports, stmts, _) => {
                     debug!("Box: {}", name);
                     // for every in/out ports, create a node
                     for port in ports {
                         match port {
-                            ast::Port::In(name, _) => {
+                            ast::Port::In(name, _, _, _) => {
                                 debug!("InPort: {}", name);
                                 self.new_node(name.clone(), self.ctx.current_box.clone().unwrap());
                             }
-                            ast::Port::Out(name, _) => {
+                            ast::Port::Out(name, _, _) => {
                                 debug!("OutPort: {}", name);
                                 self.new_node(name.clone(), self.ctx.current_box.clone().unwrap());
                             }
@@ -229,7 +252,7 @@ impl FlowGraph {
                     for stmt in stmts {
                         match stmt {
                             ast::Stmt::LetDef(let_def) => match let_def {
-                                ast::LetDef::Let(name, expr) => {
+                                ast::LetDef::Let(name, expr, _) => {
                                     debug!("Let: {}", name);
                                     self.new_node(
                                         name.clone(),
@@ -242,12 +265,17 @@ impl FlowGraph {
                                 }
                             },
                             ast::Stmt::BoxWire(box_wire) => match box_wire {
-                                ast::BoxWire::Boxw(name, _exprs) => {
+                                ast::BoxWire::Boxw(name, args, _) => {
                                     debug!("BoxWire: {}", name);
                                     self.new_node(
                                         name.clone(),
                                         self.ctx.current_box.clone().unwrap(),
                                     );
+                                    // defaults are filled in here, so each of them
+                                    // gets a const node in the calling box
+                                    for (_, expr) in Self::resolve_box_wire(boxes, name, args)? {
+                                        self.dfs_expr(&expr);
+                                    }
                                 }
                             },
                         }
@@ -274,9 +302,11 @@ impl FlowGraph {
                 node_map.insert(name.clone(), node.clone());
             }
         }
-        // update the nodes and sort by name
-        self.nodes = node_map.values().map(|v| v.clone()).collect();
-        self.nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        // update the nodes and sort by id, pop_node_by_name relies on
+        // operator nodes being in creation order, sorting by name would
+        // put op@10 before op@2
+        self.nodes = node_map.values().cloned().collect();
+        self.nodes.sort_by_key(|n| n.id);
         info!("Nodes: {:?}", self.nodes);
 
         info!(">>> ITERATION 3: Creating Edges...");
@@ -285,39 +315,70 @@ impl FlowGraph {
             // update ctx
             self.ctx.current_box = Some(Box::new(ModuleBox {
                 name: match box_def {
-                    ast::BoxDef::ModuleBox(name, _, _) => name.clone(),
+                    ast::BoxDef::ModuleBox(name, _, _, _) => name.clone(),
                 },
             }));
 
             match box_def {
-                ast::BoxDef::ModuleBox(_, _, stmts) => {
+                ast::BoxDef::ModuleBox(_, _, stmts, _) => {
                     for stmt in stmts {
                         match stmt {
                             ast::Stmt::LetDef(let_def) => match let_def {
-                                ast::LetDef::Let(name, expr) => {
+                                ast::LetDef::Let(name, expr, _) => {
                                     let cat_name =
                                         self.ctx.current_box.clone().unwrap().name.clone()
                                             + "/"
                                             + name;
-                                    let mut node = self.pop_node_by_name(&cat_name);
+                                    let mut node = self.pop_node_by_name(&cat_name)?;
                                     debug!("Let: popped node: {:?} for {}", node, cat_name);
-                                    let mut nd = self.dfs_edge(expr, &node);
+                                    let mut nd = self.dfs_edge(expr, &node)?;
                                     self.add_edge(&mut nd, &mut node, 0);
                                 }
                             },
-                            _ => {
-                                warn!("not implemented for stmt: {:?}", stmt);
-                            }
+                            ast::Stmt::BoxWire(box_wire) => match box_wire {
+                                ast::BoxWire::Boxw(name, args, _) => {
+                                    // [box arg...] wires the args into the in ports of
+                                    // the box, and its out ports into the bound wires
+                                    for (port, expr) in Self::resolve_box_wire(boxes, name, args)? {
+                                        let mut port_node = self.pop_node_by_name(
+                                            &(name.clone() + "/" + port.name()),
+                                        )?;
+                                        let mut nd = self.dfs_edge(&expr, &port_node)?;
+                                        match port {
+                                            ast::Port::In(_, _, _, _) => {
+                                                self.add_edge(&mut nd, &mut port_node, 0)
+                                            }
+                                            ast::Port::Out(_, _, _) => {
+                                                self.add_edge(&mut port_node, &mut nd, 0)
+                                            }
+                                        }
+                                    }
+                                }
+                            },
                         }
                     }
                 }
             }
         }
+        Ok(())
+    }
+    fn resolve_box_wire(
+        boxes: &[ast::BoxDef],
+        name: &str,
+        args: &[ast::WireArg],
+    ) -> Result<Vec<(ast::Port, ast::Expr)>, String> {
+        let box_def = boxes
+            .iter()
+            .find(|b| b.name() == name)
+            .ok_or(format!("box not found: {}", name))?;
+        box_def
+            .resolve_wire(args)
+            .map_err(|e| format!("box wire {}: {}", name, e))
     }
     pub fn get_nodes(&self) -> Vec<Box<Node>> {
         self.nodes.clone()
     }
-    pub fn dfs_edge(&mut self, expr: &ast::Expr, parent: &Box<Node>) -> Box<Node> {
+    pub fn dfs_edge(&mut self, expr: &ast::Expr, parent: &Box<Node>) -> Result<Box<Node>, String> {
         let mut this_node: Option<Box<Node>> = None;
         match expr {
             ast::Expr::Num(x) => {
@@ -348,39 +409,32 @@ impl FlowGraph {
                     }
                 }
             }
-            ast::Expr::NodeIdent(name) => {
+            ast::Expr::NodeIdent(name, _) => {
                 let cat_name = self.ctx.current_box.clone().unwrap().name.clone() + "/" + name;
-                this_node = Some(self.pop_node_by_name(&cat_name));
+                this_node = Some(self.pop_node_by_name(&cat_name)?);
             }
-            ast::Expr::Operator(op, args) => {
+            ast::Expr::Operator(op, args, _) => {
                 let cat_name = self.ctx.current_box.clone().unwrap().name.clone() + "/" + op;
-                this_node = Some(self.pop_node_by_name(&cat_name));
+                this_node = Some(self.pop_node_by_name(&cat_name)?);
                 let mut this_node = this_node.clone().unwrap();
                 let mut arg_no = 0;
                 for arg in args {
-                    let mut nd = self.dfs_edge(arg, &this_node);
+                    let mut nd = self.dfs_edge(arg, &this_node)?;
                     self.add_edge(&mut nd, &mut this_node, arg_no);
                     arg_no += 1;
                 }
             }
         }
-        if this_node.is_none() {
-            error!("Node not found for expr: {:?}", expr);
-            panic!("Node not found for expr: {:?}", expr);
-        }
-        let this_node = this_node.unwrap();
-        return this_node;
+        this_node.ok_or(format!("node not found for expr: {:?}", expr))
     }
     pub fn generate(&mut self) -> Vec<ast::BoxDef> {
         info!("Generating Graph...");
         // get ast from mutable reference self
         let ast = self.borrow_mut().ast.lock().unwrap();
         debug!("AST: {:?}", ast.as_ref().unwrap());
-        // Top will consists of a vec of ModuleBox
-        let boxes = match ast.as_ref().unwrap() {
-            ast::TopDef::Boxes(boxes) => boxes,
-        };
-        boxes.clone()
+        // Top will consists of a vec of ModuleBox, macros should
+        // have been expanded by macros::expand at this point
+        ast.as_ref().unwrap().boxes()
     }
     fn dfs_expr(&mut self, expr: &ast::Expr) {
         match expr {
@@ -407,7 +461,7 @@ impl FlowGraph {
                     }
                 }
             }
-            ast::Expr::Operator(op, args) => {
+            ast::Expr::Operator(op, args, _) => {
                 // create an operator node
                 let cnt = self.ctx.current_box_op_suffix_cnt.get(op);
                 if cnt.is_none() {
@@ -422,7 +476,7 @@ impl FlowGraph {
                     self.dfs_expr(arg);
                 }
             }
-            ast::Expr::NodeIdent(name) => {
+            ast::Expr::NodeIdent(name, _) => {
                 // create a node ident node
                 self.new_node(name.clone(), self.ctx.current_box.clone().unwrap());
             }
@@ -465,3 +519,81 @@ impl fmt::Debug for Node {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+
+    const AMP: &str = "\
+(box amp (
+    in  x:    float
+    in  gain: float = 1.0
+    out y:    float
+)
+    (let y (* x gain))
+)
+";
+
+    fn render(src: &str) -> Vec<f32> {
+        let graph = FlowGraph::from_source(src).unwrap();
+        let mut engine = Engine::new(&graph, 48000.0);
+        let mut frame = [0.0; 2];
+        engine.process_block(&mut frame, 2);
+        frame.to_vec()
+    }
+
+    #[test]
+    fn keyword_args_and_defaults_feed_the_ports() {
+        let main = "\
+(box main (
+    out L: float
+    out R: float
+)
+    [amp 0.25 :gain 2.0 y]
+    (let L y)
+    (let R y)
+)
+";
+        assert_eq!(render(&(AMP.to_string() + main)), vec![0.5, 0.5]);
+        let main = main.replace(" :gain 2.0", "");
+        assert_eq!(render(&(AMP.to_string() + &main)), vec![0.25, 0.25]);
+    }
+
+    #[test]
+    fn defaults_may_be_negative_and_numbers_must_fit() {
+        let src = "\
+(box main (
+    in  x: float = -1.5
+    in  n: i32 = -2
+    out L: float
+)
+    (let L (* x n))
+)
+";
+        assert_eq!(render(src), vec![3.0, 3.0]);
+        let e = FlowGraph::from_source(&src.replace("-2", "4294967296"))
+            .err()
+            .unwrap();
+        assert!(e.contains("4294967296 does not fit in an i32"), "{}", e);
+    }
+
+    #[test]
+    fn a_box_is_wired_once() {
+        let main = "\
+(box main (
+    out L: float
+    out R: float
+)
+    [amp 0.25 a]
+    [amp :gain 2.0 0.5 b]
+    (let L a)
+    (let R b)
+)
+";
+        let e = FlowGraph::from_source(&(AMP.to_string() + main))
+            .err()
+            .unwrap();
+        assert!(e.contains("box amp is wired by main already"), "{}", e);
+    }
+}
@@ -0,0 +1,413 @@
+use crate::display::Framebuffer;
+use crate::graph::FlowGraph;
+use crate::layout::{self, Layout, Metrics, NodeKind};
+use argparse::{ArgumentParser, Store, StoreOption};
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_4X6, FONT_6X10},
+        MonoTextStyle,
+    },
+    pixelcolor::{Rgb565, Rgb888},
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle},
+    text::{Baseline, Text, TextStyleBuilder},
+};
+use log::*;
+use std::error::Error;
+use std::io::{stderr, stdout, Write};
+use std::path::Path;
+
+/// nodes are labelled in FONT_6X10, on the LCD and in files
+pub const METRICS: Metrics = Metrics {
+    char_w: 6,
+    char_h: 10,
+    pad: 2,
+    layer_gap: 18,
+    node_gap: 4,
+};
+
+/// the colours a layout is drawn in
+#[derive(Debug, Clone, Copy)]
+pub struct Palette {
+    pub background: Rgb565,
+    pub frame: Rgb565, // box outlines, their names and arg numbers
+    pub wire: Rgb565,
+    pub back_wire: Rgb565,
+    pub selected: Rgb565,
+    pub selected_text: Rgb565,
+    pub port_fill: Rgb565,
+    pub port_text: Rgb565,
+    pub op_fill: Rgb565,
+    pub op_text: Rgb565,
+    pub const_fill: Rgb565,
+    pub const_text: Rgb565,
+}
+
+/// dark on white, for documentation
+pub const PAPER: Palette = Palette {
+    background: Rgb565::WHITE,
+    frame: Rgb565::new(19, 38, 19),
+    wire: Rgb565::new(16, 32, 16),
+    back_wire: Rgb565::new(25, 30, 0),
+    selected: Rgb565::new(28, 48, 0),
+    selected_text: Rgb565::BLACK,
+    port_fill: Rgb565::new(25, 56, 31),
+    port_text: Rgb565::BLACK,
+    op_fill: Rgb565::new(31, 57, 22),
+    op_text: Rgb565::BLACK,
+    const_fill: Rgb565::WHITE,
+    const_text: Rgb565::new(9, 19, 9),
+};
+
+/// what an image of a graph is written as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Svg,
+    Png,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "svg" => Ok(Format::Svg),
+            "png" => Ok(Format::Png),
+            _ => Err(format!("unknown format {}, svg or png", s)),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Svg => "svg",
+            Format::Png => "png",
+        }
+    }
+}
+
+/// how many args the node a wire goes into takes, and the point on its
+/// left side this one comes in at, spread down it in arg order
+fn input_point(graph: &Layout, to: usize, arg_no: u64) -> (i32, Point) {
+    let b = &graph.nodes[to];
+    let args = graph
+        .wires
+        .iter()
+        .filter(|w| w.to == to && !w.back)
+        .map(|w| w.arg_no)
+        .max()
+        .unwrap_or(0) as i32
+        + 1;
+    let end = Point::new(b.x - 1, b.y + (arg_no as i32 + 1) * b.h / (args + 1));
+    (args, end)
+}
+
+fn node_colors(palette: &Palette, kind: NodeKind, selected: bool) -> (Rgb565, Rgb565) {
+    match (selected, kind) {
+        (true, _) => (palette.selected, palette.selected_text),
+        (false, NodeKind::Port) => (palette.port_fill, palette.port_text),
+        (false, NodeKind::Op) => (palette.op_fill, palette.op_text),
+        (false, NodeKind::Const) => (palette.const_fill, palette.const_text),
+    }
+}
+
+/// boxes, then wires, then nodes over them, the selected node and its
+/// wires highlighted, arg numbers at the inputs they go into
+pub fn draw<T: DrawTarget<Color = Rgb565>>(
+    target: &mut T,
+    graph: &Layout,
+    selected: Option<usize>,
+    palette: &Palette,
+) -> Result<(), T::Error> {
+    let top = TextStyleBuilder::new().baseline(Baseline::Top).build();
+    let small = MonoTextStyle::new(&FONT_4X6, palette.frame);
+    let label = MonoTextStyle::new(&FONT_6X10, palette.frame);
+    for group in graph.groups.iter() {
+        Rectangle::new(
+            Point::new(group.x, group.y),
+            Size::new(group.w as u32, group.h as u32),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(palette.frame, 1))
+        .draw(target)?;
+        let at = Point::new(group.x + 2, group.y + 1);
+        Text::with_text_style(&group.name, at, label, top).draw(target)?;
+    }
+    for wire in graph.wires.iter() {
+        let (a, b) = (&graph.nodes[wire.from], &graph.nodes[wire.to]);
+        let lit = selected.is_some_and(|s| wire.from == s || wire.to == s);
+        let color = match (lit, wire.back) {
+            (true, _) => palette.selected,
+            (false, true) => palette.back_wire,
+            (false, false) => palette.wire,
+        };
+        let stroke = PrimitiveStyle::with_stroke(color, 1);
+        if wire.back {
+            // under the nodes, from the later one back to the earlier
+            Line::new(
+                Point::new(a.x + a.w / 2, a.y + a.h),
+                Point::new(b.x + b.w / 2, b.y + b.h),
+            )
+            .into_styled(stroke)
+            .draw(target)?;
+            continue;
+        }
+        let (args, end) = input_point(graph, wire.to, wire.arg_no);
+        Line::new(Point::new(a.x + a.w, a.center_y()), end)
+            .into_styled(stroke)
+            .draw(target)?;
+        if args > 1 {
+            let arg = wire.arg_no.to_string();
+            let at = end - Point::new(4 * arg.len() as i32 + 1, 6);
+            Text::with_text_style(&arg, at, small, top).draw(target)?;
+        }
+    }
+    for node in graph.nodes.iter() {
+        let (fill, color) = node_colors(palette, node.kind, selected == Some(node.node));
+        let area = Rectangle::new(
+            Point::new(node.x, node.y),
+            Size::new(node.w as u32, node.h as u32),
+        );
+        area.into_styled(
+            PrimitiveStyleBuilder::new()
+                .fill_color(fill)
+                .stroke_color(color)
+                .stroke_width(1)
+                .build(),
+        )
+        .draw(target)?;
+        let style = MonoTextStyle::new(&FONT_6X10, color);
+        let at = Point::new(node.x + METRICS.pad, node.y + METRICS.pad);
+        Text::with_text_style(&node.label, at, style, top).draw(target)?;
+    }
+    Ok(())
+}
+
+/// a graph drawn the size of its layout
+pub fn to_framebuffer(graph: &FlowGraph, palette: &Palette) -> Framebuffer {
+    let placed = layout::layout(graph, METRICS);
+    let mut fb = Framebuffer::new(placed.width.max(1) as u32, placed.height.max(1) as u32);
+    // drawing into memory cannot fail
+    let _ = fb.clear(palette.background);
+    let _ = draw(&mut fb, &placed, None, palette);
+    fb
+}
+
+fn hex(c: Rgb565) -> String {
+    let c = Rgb888::from(c);
+    format!("#{:02x}{:02x}{:02x}", c.r(), c.g(), c.b())
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// the same drawing as SVG, each node titled with its full name, back
+/// wires dashed
+pub fn to_svg(graph: &FlowGraph, palette: &Palette) -> String {
+    let placed = layout::layout(graph, METRICS);
+    let mut out = String::new();
+    out += &format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+         viewBox=\"0 0 {w} {h}\" font-family=\"monospace\" font-size=\"{f}\">\n",
+        w = placed.width,
+        h = placed.height,
+        f = METRICS.char_h,
+    );
+    out += &format!(
+        "  <rect width=\"100%\" height=\"100%\" fill=\"{}\"/>\n",
+        hex(palette.background)
+    );
+    // text sits on its baseline, two pixels above the bottom of a cell
+    let baseline = METRICS.char_h - 2;
+    for group in placed.groups.iter() {
+        out += &format!(
+            "  <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"3\" \
+             fill=\"none\" stroke=\"{}\"/>\n",
+            group.x,
+            group.y,
+            group.w,
+            group.h,
+            hex(palette.frame)
+        );
+        out += &format!(
+            "  <text x=\"{}\" y=\"{}\" fill=\"{}\">{}</text>\n",
+            group.x + 2,
+            group.y + 1 + baseline,
+            hex(palette.frame),
+            escape(&group.name)
+        );
+    }
+    for wire in placed.wires.iter() {
+        let (a, b) = (&placed.nodes[wire.from], &placed.nodes[wire.to]);
+        if wire.back {
+            out += &format!(
+                "  <line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\" \
+                 stroke-dasharray=\"3 2\"/>\n",
+                a.x + a.w / 2,
+                a.y + a.h,
+                b.x + b.w / 2,
+                b.y + b.h,
+                hex(palette.back_wire)
+            );
+            continue;
+        }
+        let (args, end) = input_point(&placed, wire.to, wire.arg_no);
+        out += &format!(
+            "  <line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\"/>\n",
+            a.x + a.w,
+            a.center_y(),
+            end.x,
+            end.y,
+            hex(palette.wire)
+        );
+        if args > 1 {
+            out += &format!(
+                "  <text x=\"{}\" y=\"{}\" font-size=\"6\" text-anchor=\"end\" \
+                 fill=\"{}\">{}</text>\n",
+                end.x - 1,
+                end.y - 1,
+                hex(palette.frame),
+                wire.arg_no
+            );
+        }
+    }
+    for node in placed.nodes.iter() {
+        let (fill, color) = node_colors(palette, node.kind, false);
+        out += &format!(
+            "  <g><title>{}</title>\
+             <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\" stroke=\"{}\"/>\
+             <text x=\"{}\" y=\"{}\" fill=\"{}\">{}</text></g>\n",
+            escape(&graph.nodes[node.node].name),
+            node.x,
+            node.y,
+            node.w,
+            node.h,
+            hex(fill),
+            hex(color),
+            node.x + METRICS.pad,
+            node.y + METRICS.pad + baseline,
+            hex(color),
+            escape(&node.label)
+        );
+    }
+    out += "</svg>\n";
+    out
+}
+
+/// the image in the format, into a file or - for stdout
+pub fn write(
+    graph: &FlowGraph,
+    format: Format,
+    palette: &Palette,
+    output: &str,
+) -> Result<(), Box<dyn Error>> {
+    let bytes = match format {
+        Format::Svg => to_svg(graph, palette).into_bytes(),
+        Format::Png => {
+            let mut png = Vec::new();
+            to_framebuffer(graph, palette).write_png(&mut png)?;
+            png
+        }
+    };
+    match output {
+        "-" => stdout().write_all(&bytes)?,
+        _ => std::fs::write(output, bytes)?,
+    }
+    Ok(())
+}
+
+/// rasynth graph FILE [--format svg|png] [-o OUT] - draw a patch's graph
+pub fn command(args: Vec<String>) {
+    let mut file = String::new();
+    let mut format: Option<String> = None;
+    let mut output: Option<String> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Lay out a patch's graph in layers, signal flowing left to right, \
+             and draw it as SVG or PNG",
+        );
+        ap.refer(&mut file)
+            .required()
+            .add_argument("file", Store, "Raslisp file or saved graph");
+        ap.refer(&mut format).add_option(
+            &["-f", "--format"],
+            StoreOption,
+            "svg or png, from the output's extension, svg by default",
+        );
+        ap.refer(&mut output).add_option(
+            &["-o", "--output"],
+            StoreOption,
+            "Where to write, - for stdout, FILE with the format's extension by default",
+        );
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    let from_output = output
+        .as_deref()
+        .and_then(|o| Path::new(o).extension())
+        .and_then(|e| e.to_str())
+        .and_then(|e| Format::parse(e).ok());
+    let format = match format.as_deref().map(Format::parse) {
+        Some(Ok(format)) => format,
+        Some(Err(e)) => {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+        None => from_output.unwrap_or(Format::Svg),
+    };
+    let graph = match FlowGraph::from_file(Path::new(&file)) {
+        Ok(graph) => graph,
+        Err(e) => {
+            error!("{}: {}", file, e);
+            std::process::exit(2);
+        }
+    };
+    let output = output.unwrap_or_else(|| {
+        Path::new(&file)
+            .with_extension(format.extension())
+            .to_string_lossy()
+            .to_string()
+    });
+    if let Err(e) = write(&graph, format, &PAPER, &output) {
+        error!("Cannot write {}: {}", output, e);
+        std::process::exit(1);
+    }
+    if output != "-" {
+        info!(
+            "Wrote {}, {} nodes and {} edges",
+            output,
+            graph.nodes.len(),
+            graph.edges.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn svg_titles_each_node_and_dashes_the_back_wire() {
+        let src = "\
+(box main (
+    out L: float
+    out R: float
+)
+    (let y (+ (saw 110.0) (* 0.5 (delay y 100))))
+    (let L y)
+    (let R y)
+)
+";
+        let graph = FlowGraph::from_source(src).unwrap();
+        let svg = to_svg(&graph, &PAPER);
+        assert!(svg.starts_with("<svg ") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<title>").count(), graph.nodes.len());
+        for node in graph.nodes.iter() {
+            let title = format!("<title>{}</title>", escape(&node.name));
+            assert_eq!(svg.matches(&title).count(), 1, "{}", title);
+        }
+        assert_eq!(svg.matches("stroke-dasharray").count(), 1);
+    }
+}
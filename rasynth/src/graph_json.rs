@@ -0,0 +1,309 @@
+use crate::fmt;
+use crate::graph::{Constant, Edge, FlowGraph, ModuleBox, Node};
+use crate::layout::NodeKind;
+use crate::macros;
+use crate::raslisp;
+use argparse::{ArgumentParser, Store, StoreOption};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{stderr, stdout};
+use std::path::Path;
+
+/// what the format field of a saved graph says
+pub const FORMAT: &str = "rasynth-flowgraph";
+/// bumped when a saved graph stops meaning what it did
+pub const VERSION: u32 = 1;
+
+/// a FlowGraph as saved, nodes in engine slot order, the macro
+/// expanded patch rides along for the ports' types and defaults and
+/// the midi, voices and controls forms
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GraphJson {
+    pub format: String,
+    pub version: u32,
+    pub source: String,
+    pub boxes: Vec<String>,
+    pub nodes: Vec<NodeJson>,
+    pub edges: Vec<EdgeJson>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeJson {
+    pub id: u64,
+    pub name: String,
+    #[serde(rename = "box")]
+    pub box_name: String, // with its voice, osc#0
+    pub kind: String, // port, op or const, from the name and the constant
+    #[serde(rename = "const", default, skip_serializing_if = "Option::is_none")]
+    pub constant: Option<Constant>,
+}
+
+/// from and to are node ids, arg_no orders the inputs of to
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EdgeJson {
+    pub id: u64,
+    pub from: u64,
+    pub to: u64,
+    pub arg_no: u64,
+}
+
+fn kind_name(kind: NodeKind) -> &'static str {
+    match kind {
+        NodeKind::Port => "port",
+        NodeKind::Op => "op",
+        NodeKind::Const => "const",
+    }
+}
+
+impl GraphJson {
+    pub fn from_graph(graph: &FlowGraph) -> Result<Self, String> {
+        let source = match graph.ast.lock().unwrap().as_ref() {
+            Some(top) => fmt::format_top(top),
+            None => return Err("the graph has no patch to save".to_string()),
+        };
+        Ok(GraphJson {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            source,
+            boxes: graph.boxes.iter().map(|b| b.name.clone()).collect(),
+            nodes: graph
+                .nodes
+                .iter()
+                .map(|n| NodeJson {
+                    id: n.id,
+                    name: n.name.clone(),
+                    box_name: n.parent_box.name.clone(),
+                    kind: kind_name(NodeKind::of(n)).to_string(),
+                    constant: n.const_data.clone(),
+                })
+                .collect(),
+            edges: graph
+                .edges
+                .iter()
+                .map(|e| EdgeJson {
+                    id: e.id,
+                    from: e.from.id,
+                    to: e.to.id,
+                    arg_no: e.arg_no,
+                })
+                .collect(),
+        })
+    }
+
+    /// the graph as built from the patch, checked for ids that do not
+    /// add up, voices are not expanded again
+    pub fn to_graph(&self) -> Result<FlowGraph, String> {
+        if self.format != FORMAT {
+            return Err(format!("not a saved graph, format is {:?}", self.format));
+        }
+        if self.version != VERSION {
+            return Err(format!(
+                "saved graph version {}, this rasynth reads {}",
+                self.version, VERSION
+            ));
+        }
+        let top = raslisp::TopParser::new()
+            .parse(&self.source)
+            .map_err(|e| format!("the saved patch does not parse: {}", e))?;
+        let top = macros::expand(top)?;
+        let mut graph = FlowGraph::new(Some(top));
+        graph.boxes = self
+            .boxes
+            .iter()
+            .map(|name| Box::new(ModuleBox { name: name.clone() }))
+            .collect();
+        let mut slot_of_id = HashMap::new();
+        for (slot, n) in self.nodes.iter().enumerate() {
+            if slot_of_id.insert(n.id, slot).is_some() {
+                return Err(format!("node id {} is used twice", n.id));
+            }
+            let node = Box::new(Node {
+                id: n.id,
+                name: n.name.clone(),
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+                parent_box: Box::new(ModuleBox {
+                    name: n.box_name.clone(),
+                }),
+                const_data: n.constant.clone(),
+            });
+            let kind = kind_name(NodeKind::of(&node));
+            if n.kind != kind {
+                return Err(format!(
+                    "node {} says it is {}, it is {}",
+                    n.name, n.kind, kind
+                ));
+            }
+            graph.nodes.push(node);
+        }
+        let mut edge_ids = Vec::new();
+        for e in self.edges.iter() {
+            if edge_ids.contains(&e.id) {
+                return Err(format!("edge id {} is used twice", e.id));
+            }
+            edge_ids.push(e.id);
+            let slot = |id: u64| {
+                slot_of_id
+                    .get(&id)
+                    .cloned()
+                    .ok_or(format!("edge {} names no node {}", e.id, id))
+            };
+            let (from, to) = (slot(e.from)?, slot(e.to)?);
+            let edge = Box::new(Edge {
+                id: e.id,
+                arg_no: e.arg_no,
+                from: graph.nodes[from].clone(),
+                to: graph.nodes[to].clone(),
+            });
+            graph.nodes[from].outputs.push(edge.clone());
+            graph.nodes[to].inputs.push(edge.clone());
+            graph.edges.push(edge);
+        }
+        graph.node_id_counter = self.nodes.iter().map(|n| n.id + 1).max().unwrap_or(0);
+        graph.edge_id_counter = self.edges.iter().map(|e| e.id + 1).max().unwrap_or(0);
+        Ok(graph)
+    }
+}
+
+/// a graph as pretty JSON, the same graph always reads the same
+pub fn to_json(graph: &FlowGraph) -> Result<String, String> {
+    let saved = GraphJson::from_graph(graph)?;
+    serde_json::to_string_pretty(&saved)
+        .map(|s| s + "\n")
+        .map_err(|e| e.to_string())
+}
+
+pub fn from_json(text: &str) -> Result<FlowGraph, String> {
+    let saved: GraphJson = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    saved.to_graph()
+}
+
+/// whether a and b name one file, b need not exist
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// rasynth compile FILE [-o OUT] - save a patch's graph as JSON
+pub fn command(args: Vec<String>) {
+    let mut file = String::new();
+    let mut output: Option<String> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Build a patch and save its graph as JSON, which play, render and ui \
+             load like the patch",
+        );
+        ap.refer(&mut file).required().add_argument(
+            "file",
+            Store,
+            "Raslisp file, or a saved graph",
+        );
+        ap.refer(&mut output).add_option(
+            &["-o", "--output"],
+            StoreOption,
+            "Where to write, - for stdout, FILE with .json by default",
+        );
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    let graph = match FlowGraph::from_file(Path::new(&file)) {
+        Ok(graph) => graph,
+        Err(e) => {
+            error!("{}: {}", file, e);
+            std::process::exit(2);
+        }
+    };
+    let json = match to_json(&graph) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("{}: {}", file, e);
+            std::process::exit(1);
+        }
+    };
+    let output = output.unwrap_or_else(|| {
+        Path::new(&file)
+            .with_extension("json")
+            .to_string_lossy()
+            .to_string()
+    });
+    if output != "-" && same_file(Path::new(&file), Path::new(&output)) {
+        error!("{} would overwrite itself, give another file with -o", file);
+        std::process::exit(2);
+    }
+    if output == "-" {
+        print!("{}", json);
+    } else if let Err(e) = std::fs::write(&output, json) {
+        error!("Cannot write {}: {}", output, e);
+        std::process::exit(1);
+    } else {
+        info!(
+            "Wrote {}, {} nodes and {} edges",
+            output,
+            graph.nodes.len(),
+            graph.edges.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dot;
+    use crate::engine::Engine;
+
+    const PATCHES: [(&str, &str); 7] = [
+        ("controls", include_str!("../../test/controls.raslisp")),
+        ("defaults", include_str!("../../test/defaults.raslisp")),
+        ("macro", include_str!("../../test/macro.raslisp")),
+        ("mono", include_str!("../../test/mono.raslisp")),
+        ("osc1", include_str!("../../test/osc1.raslisp")),
+        ("poly", include_str!("../../test/poly.raslisp")),
+        ("test", include_str!("../../test/test.raslisp")),
+    ];
+
+    fn samples(graph: &FlowGraph) -> Vec<f32> {
+        let mut engine = Engine::new(graph, 48000.0);
+        let mut out = vec![0.0; 4096 * 2];
+        engine.process_block(&mut out, 2);
+        out
+    }
+
+    #[test]
+    fn saved_graphs_render_like_their_source() {
+        let mut sounding = 0;
+        for (name, src) in PATCHES {
+            let built = FlowGraph::from_source(src).unwrap();
+            let json = to_json(&built).unwrap();
+            let loaded = from_json(&json).unwrap();
+            assert_eq!(to_json(&loaded).unwrap(), json, "{}", name);
+            assert_eq!(dot::to_dot(&loaded), dot::to_dot(&built), "{}", name);
+            let (a, b) = (samples(&built), samples(&loaded));
+            assert!(
+                a.iter()
+                    .zip(b.iter())
+                    .all(|(a, b)| a.to_bits() == b.to_bits()),
+                "{}",
+                name
+            );
+            sounding += a.iter().any(|x| *x != 0.0) as usize;
+        }
+        // no notes are played, the patches without voices still sound
+        assert!(sounding > 0);
+    }
+
+    #[test]
+    fn a_file_is_the_same_as_itself() {
+        let file = Path::new("../test/osc1.raslisp");
+        assert!(same_file(file, Path::new("../test/../test/osc1.raslisp")));
+        assert!(!same_file(file, &file.with_extension("json")));
+        assert!(same_file(Path::new("gone.json"), Path::new("gone.json")));
+    }
+}
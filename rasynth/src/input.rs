@@ -0,0 +1,146 @@
+use crate::controls::ControlEvent;
+use log::*;
+use std::io::BufRead;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/// what the UI reacts to, whatever the controls on the board are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    Up,
+    Down,
+    Left,
+    Right,
+    Select,
+    Back,
+    Turn(i32),             // encoder detents, clockwise positive
+    Control(ControlEvent), // straight from the board, see ControlEvent::to_input
+}
+
+impl InputEvent {
+    /// one key of a script or the terminal: w s a d move, e selects,
+    /// q goes back, + and - turn
+    pub fn from_key(key: char) -> Option<Self> {
+        match key {
+            'w' => Some(InputEvent::Up),
+            's' => Some(InputEvent::Down),
+            'a' => Some(InputEvent::Left),
+            'd' => Some(InputEvent::Right),
+            'e' => Some(InputEvent::Select),
+            'q' => Some(InputEvent::Back),
+            '+' => Some(InputEvent::Turn(1)),
+            '-' => Some(InputEvent::Turn(-1)),
+            _ => None,
+        }
+    }
+}
+
+/// a source of input events, polled by the UI loop
+pub trait Input {
+    /// the next event if one is waiting, never blocks
+    fn poll(&mut self) -> Option<InputEvent>;
+
+    /// false once no more events can come
+    fn open(&self) -> bool {
+        true
+    }
+}
+
+/// keys typed on the terminal, a line at a time
+pub struct TermInput {
+    rx: Receiver<InputEvent>,
+    open: bool,
+}
+
+impl TermInput {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("keys".to_string())
+            .spawn(move || {
+                for line in std::io::stdin().lock().lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    // an empty line is enter, which selects
+                    let keys = match line.trim().is_empty() {
+                        true => "e".to_string(),
+                        false => line,
+                    };
+                    for event in keys.chars().filter_map(InputEvent::from_key) {
+                        if tx.send(event).is_err() {
+                            return;
+                        }
+                    }
+                }
+                debug!("Terminal input closed");
+            })
+            .expect("failed to spawn keys thread");
+        TermInput { rx, open: true }
+    }
+}
+
+impl Default for TermInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Input for TermInput {
+    fn poll(&mut self) -> Option<InputEvent> {
+        match self.rx.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.open = false;
+                None
+            }
+        }
+    }
+
+    fn open(&self) -> bool {
+        self.open
+    }
+}
+
+/// events from a string of keys, one a frame, for driving the UI in
+/// the simulator, a poll after each event gives None so the UI draws
+/// what it did
+pub struct ScriptInput {
+    events: Vec<InputEvent>,
+    next: usize,
+    drawn: bool,
+}
+
+impl ScriptInput {
+    pub fn new(keys: &str) -> Result<Self, String> {
+        let events = keys
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| InputEvent::from_key(c).ok_or(format!("unknown key {:?}", c)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ScriptInput {
+            events,
+            next: 0,
+            drawn: true,
+        })
+    }
+}
+
+impl Input for ScriptInput {
+    fn poll(&mut self) -> Option<InputEvent> {
+        if !self.drawn {
+            self.drawn = true;
+            return None;
+        }
+        let event = self.events.get(self.next).cloned();
+        self.next += 1;
+        self.drawn = event.is_none();
+        event
+    }
+
+    fn open(&self) -> bool {
+        self.next < self.events.len()
+    }
+}
@@ -0,0 +1,396 @@
+use crate::graph::{Constant, FlowGraph, Node};
+use std::collections::HashMap;
+
+/// sizes a layout is measured in, in the units of whatever draws it
+#[derive(Debug, Clone, Copy)]
+pub struct Metrics {
+    pub char_w: i32,
+    pub char_h: i32,
+    pub pad: i32,       // around a label inside its node
+    pub layer_gap: i32, // between columns
+    pub node_gap: i32,  // between nodes in a column, twice that between boxes
+}
+
+/// what a node stands for, drawn differently
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    Port,  // a port or let of a box
+    Op,    // a builtin
+    Const, // a literal
+}
+
+impl NodeKind {
+    pub fn of(node: &Node) -> Self {
+        match (&node.const_data, node.name.contains('@')) {
+            (Some(_), _) => NodeKind::Const,
+            (None, true) => NodeKind::Op,
+            (None, false) => NodeKind::Port,
+        }
+    }
+}
+
+/// a node of the graph and where it goes, node is its index in the
+/// graph's nodes, which is also its engine slot
+#[derive(Debug, Clone)]
+pub struct Placed {
+    pub node: usize,
+    pub label: String,
+    pub group: String,
+    pub kind: NodeKind,
+    pub layer: usize,
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+impl Placed {
+    pub fn center_y(&self) -> i32 {
+        self.y + self.h / 2
+    }
+}
+
+/// an edge between two placed nodes, back edges close a cycle and run
+/// right to left
+#[derive(Debug, Clone)]
+pub struct Wire {
+    pub from: usize,
+    pub to: usize,
+    pub arg_no: u64,
+    pub back: bool,
+}
+
+/// the area around the nodes of a box
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+/// a layered drawing of a FlowGraph, signal flowing left to right,
+/// nodes and wires are indexed like the graph's nodes
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    pub nodes: Vec<Placed>,
+    pub wires: Vec<Wire>,
+    pub groups: Vec<Group>,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// what a node is called in a drawing: its port or let name, its
+/// builtin without the @k, or the value of a constant
+pub fn label(node: &Node) -> String {
+    let local = node
+        .name
+        .split_once('/')
+        .map(|(_, l)| l)
+        .unwrap_or(&node.name);
+    match &node.const_data {
+        Some(Constant::Int32(v)) => v.to_string(),
+        Some(Constant::Int64(v)) => v.to_string(),
+        Some(Constant::Float32(v)) => v.to_string(),
+        Some(Constant::Float64(v)) => v.to_string(),
+        Some(Constant::Float32Array(v)) => format!("[{}]", v.len()),
+        None => local.split('@').next().unwrap_or(local).to_string(),
+    }
+}
+
+/// the box a node belongs to, with its voice number
+pub fn group(node: &Node) -> String {
+    node.name
+        .split_once('/')
+        .map(|(b, _)| b)
+        .unwrap_or("")
+        .to_string()
+}
+
+/// lay out a graph: edges closing cycles are turned around, each node
+/// goes one column right of its furthest input, the columns are sorted
+/// by the mean position of the inputs with the nodes of a box together
+pub fn layout(graph: &FlowGraph, m: Metrics) -> Layout {
+    let n = graph.nodes.len();
+    let index = graph
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.id, i))
+        .collect::<HashMap<_, _>>();
+    let mut wires = graph
+        .edges
+        .iter()
+        .filter_map(|e| match (index.get(&e.from.id), index.get(&e.to.id)) {
+            (Some(from), Some(to)) => Some(Wire {
+                from: *from,
+                to: *to,
+                arg_no: e.arg_no,
+                back: false,
+            }),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    mark_back_edges(n, &mut wires);
+
+    // longest path from the sources over the forward wires
+    let mut layer = vec![0usize; n];
+    let mut indegree = vec![0usize; n];
+    let mut outs: Vec<Vec<usize>> = vec![Vec::new(); n];
+    let mut ins: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for w in wires.iter().filter(|w| !w.back) {
+        indegree[w.to] += 1;
+        outs[w.from].push(w.to);
+        ins[w.to].push(w.from);
+    }
+    let mut todo = (0..n).filter(|i| indegree[*i] == 0).collect::<Vec<_>>();
+    while let Some(i) = todo.pop() {
+        for &j in outs[i].iter() {
+            layer[j] = layer[j].max(layer[i] + 1);
+            indegree[j] -= 1;
+            if indegree[j] == 0 {
+                todo.push(j);
+            }
+        }
+    }
+
+    let groups = {
+        let mut names: Vec<String> = Vec::new();
+        for node in graph.nodes.iter() {
+            let g = group(node);
+            if !names.contains(&g) {
+                names.push(g);
+            }
+        }
+        names
+    };
+    let group_of = graph
+        .nodes
+        .iter()
+        .map(|node| groups.iter().position(|g| *g == group(node)).unwrap())
+        .collect::<Vec<_>>();
+    let layers = layer.iter().max().map(|l| l + 1).unwrap_or(0);
+    let mut columns: Vec<Vec<usize>> = vec![Vec::new(); layers];
+    for i in 0..n {
+        columns[layer[i]].push(i);
+    }
+    // a few sweeps each way, sorting by the mean row of the neighbours
+    let mut row = vec![0.0f32; n];
+    let place_rows = |columns: &[Vec<usize>], row: &mut [f32]| {
+        for column in columns.iter() {
+            for (k, i) in column.iter().enumerate() {
+                row[*i] = k as f32;
+            }
+        }
+    };
+    place_rows(&columns, &mut row);
+    for sweep in 0..4 {
+        let order: Vec<usize> = match sweep % 2 {
+            0 => (1..layers).collect(),
+            _ => (0..layers.saturating_sub(1)).rev().collect(),
+        };
+        for l in order {
+            let neighbours = |i: usize| match sweep % 2 {
+                0 => &ins[i],
+                _ => &outs[i],
+            };
+            let mut keyed = columns[l]
+                .iter()
+                .map(|i| {
+                    let ns = neighbours(*i);
+                    let bary = match ns.is_empty() {
+                        true => row[*i],
+                        false => ns.iter().map(|j| row[*j]).sum::<f32>() / ns.len() as f32,
+                    };
+                    (group_of[*i], bary, *i)
+                })
+                .collect::<Vec<_>>();
+            keyed.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)).then(a.2.cmp(&b.2)));
+            columns[l] = keyed.into_iter().map(|(_, _, i)| i).collect();
+            place_rows(&columns, &mut row);
+        }
+    }
+
+    let mut nodes = graph
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| {
+            let label = label(node);
+            Placed {
+                node: i,
+                w: label.len() as i32 * m.char_w + 2 * m.pad,
+                h: m.char_h + 2 * m.pad,
+                label,
+                group: group(node),
+                kind: NodeKind::of(node),
+                layer: layer[i],
+                x: 0,
+                y: 0,
+            }
+        })
+        .collect::<Vec<_>>();
+    // each box gets a band as tall as its tallest column, under its name
+    let node_h = m.char_h + 2 * m.pad + m.node_gap;
+    let mut band = vec![0; groups.len()];
+    let mut y = 2 * m.node_gap + m.char_h;
+    for (g, top) in band.iter_mut().enumerate() {
+        *top = y;
+        let rows = columns
+            .iter()
+            .map(|c| c.iter().filter(|i| group_of[**i] == g).count())
+            .max()
+            .unwrap_or(0) as i32;
+        y += rows * node_h + 3 * m.node_gap + m.char_h;
+    }
+    let height = y - m.node_gap - m.char_h;
+    let mut x = m.layer_gap;
+    for column in columns.iter() {
+        let mut next = band.clone();
+        for i in column.iter() {
+            nodes[*i].x = x;
+            nodes[*i].y = next[group_of[*i]];
+            next[group_of[*i]] += node_h;
+        }
+        x += column.iter().map(|i| nodes[*i].w).max().unwrap_or(0) + m.layer_gap;
+    }
+
+    let groups = groups
+        .iter()
+        .filter_map(|g| {
+            let members = nodes.iter().filter(|p| p.group == *g);
+            let x0 = members.clone().map(|p| p.x).min()?;
+            let y0 = members.clone().map(|p| p.y).min()?;
+            let x1 = members.clone().map(|p| p.x + p.w).max()?;
+            let y1 = members.map(|p| p.y + p.h).max()?;
+            let margin = m.node_gap;
+            Some(Group {
+                name: g.clone(),
+                x: x0 - margin,
+                y: y0 - margin - m.char_h,
+                w: x1 - x0 + 2 * margin,
+                h: y1 - y0 + 2 * margin + m.char_h,
+            })
+        })
+        .collect();
+    Layout {
+        nodes,
+        wires,
+        groups,
+        width: x,
+        height,
+    }
+}
+
+/// depth first from every unvisited node, an edge to a node still on
+/// the stack closes a cycle
+fn mark_back_edges(n: usize, wires: &mut [Wire]) {
+    let mut outs: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for (k, w) in wires.iter().enumerate() {
+        outs[w.from].push(k);
+    }
+    // 0 unvisited, 1 on the stack, 2 done
+    let mut state = vec![0u8; n];
+    for start in 0..n {
+        if state[start] != 0 {
+            continue;
+        }
+        let mut stack = vec![(start, 0usize)];
+        state[start] = 1;
+        while let Some((i, next)) = stack.pop() {
+            match outs[i].get(next) {
+                Some(&k) => {
+                    stack.push((i, next + 1));
+                    let j = wires[k].to;
+                    match state[j] {
+                        0 => {
+                            state[j] = 1;
+                            stack.push((j, 0));
+                        }
+                        1 => wires[k].back = true,
+                        _ => {}
+                    }
+                }
+                None => state[i] = 2,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph_image::METRICS;
+
+    const FEEDBACK: &str = "\
+(box main (
+    out L: float
+    out R: float
+)
+    (let y (+ (saw 110.0) (* 0.5 (delay y 100))))
+    (let L y)
+    (let R y)
+)
+";
+
+    fn wire(from: usize, to: usize) -> Wire {
+        let (arg_no, back) = (0, false);
+        Wire {
+            from,
+            to,
+            arg_no,
+            back,
+        }
+    }
+
+    #[test]
+    fn one_wire_of_a_cycle_runs_back() {
+        // a diamond has no cycle, closing it from the bottom makes one
+        let mut wires = vec![wire(0, 1), wire(0, 2), wire(1, 3), wire(2, 3)];
+        mark_back_edges(4, &mut wires);
+        assert!(wires.iter().all(|w| !w.back));
+        wires.push(wire(3, 0));
+        mark_back_edges(4, &mut wires);
+        let back = wires.iter().filter(|w| w.back).collect::<Vec<_>>();
+        assert_eq!(back.len(), 1);
+        assert_eq!((back[0].from, back[0].to), (3, 0));
+
+        let graph = FlowGraph::from_source(FEEDBACK).unwrap();
+        let placed = layout(&graph, METRICS);
+        let back = placed.wires.iter().filter(|w| w.back).count();
+        assert_eq!(back, 1);
+        for w in placed.wires.iter().filter(|w| !w.back) {
+            let (from, to) = (&placed.nodes[w.from], &placed.nodes[w.to]);
+            assert!(from.layer < to.layer, "{} -> {}", from.label, to.label);
+            assert!(from.x < to.x, "{} -> {}", from.label, to.label);
+        }
+    }
+
+    #[test]
+    fn nodes_of_a_column_do_not_overlap() {
+        let graph = FlowGraph::from_source(include_str!("../../test/test.raslisp")).unwrap();
+        let placed = layout(&graph, METRICS);
+        assert_eq!(placed.nodes.len(), graph.nodes.len());
+        for (i, a) in placed.nodes.iter().enumerate() {
+            assert_eq!(a.node, i);
+            assert!(a.x + a.w <= placed.width && a.y + a.h <= placed.height);
+            for b in placed.nodes.iter().skip(i + 1) {
+                if a.layer != b.layer {
+                    continue;
+                }
+                assert_eq!(a.x, b.x);
+                let apart = a.y + a.h <= b.y || b.y + b.h <= a.y;
+                assert!(apart, "{} and {} overlap", a.label, b.label);
+            }
+        }
+        // each box frames its own nodes
+        assert_eq!(placed.groups.len(), 2);
+        for g in placed.groups.iter() {
+            for p in placed.nodes.iter().filter(|p| p.group == g.name) {
+                assert!(g.x < p.x && p.x + p.w < g.x + g.w);
+                assert!(g.y < p.y && p.y + p.h < g.y + g.h);
+            }
+        }
+    }
+}
@@ -0,0 +1,629 @@
+use crate::ast::*;
+use crate::fmt;
+use crate::macros;
+use crate::ops;
+use crate::raslisp;
+use crate::symbol_table::{self, Analysis, Severity};
+use log::*;
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+    PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as _};
+use lsp_types::*;
+use std::collections::HashMap;
+
+/// rasynth lsp - language server for raslisp on stdin/stdout
+pub fn command(_args: Vec<String>) {
+    info!("Starting raslisp language server on stdio");
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec!["(".to_string(), "[".to_string()]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let capabilities = serde_json::to_value(capabilities).unwrap();
+    if let Err(e) = connection.initialize(capabilities) {
+        error!("LSP initialize failed: {}", e);
+        return;
+    }
+    let mut server = Server {
+        connection,
+        docs: HashMap::new(),
+    };
+    if let Err(e) = server.run() {
+        error!("LSP server failed: {}", e);
+    }
+    drop(server);
+    io_threads.join().unwrap();
+    info!("Language server stopped");
+}
+
+struct Server {
+    connection: Connection,
+    docs: HashMap<Url, Document>,
+}
+
+/// an open .raslisp file and what we know about it
+struct Document {
+    src: String,
+    top: Option<TopDef>,
+    analysis: Analysis,
+    occurrences: Vec<Occurrence>,
+}
+
+impl Server {
+    fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        while let Ok(msg) = self.connection.receiver.recv() {
+            match msg {
+                Message::Request(req) => {
+                    if self.connection.handle_shutdown(&req)? {
+                        return Ok(());
+                    }
+                    self.on_request(req)?;
+                }
+                Message::Notification(not) => self.on_notification(not)?,
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn on_request(&mut self, req: Request) -> Result<(), Box<dyn std::error::Error>> {
+        let id = req.id.clone();
+        match req.method.as_str() {
+            GotoDefinition::METHOD => {
+                let params: GotoDefinitionParams = serde_json::from_value(req.params)?;
+                let pos = params.text_document_position_params;
+                let result = self
+                    .docs
+                    .get(&pos.text_document.uri)
+                    .and_then(|doc| doc.definition(pos.position))
+                    .map(|range| {
+                        GotoDefinitionResponse::Scalar(Location {
+                            uri: pos.text_document.uri.clone(),
+                            range,
+                        })
+                    });
+                self.respond(id, result)
+            }
+            HoverRequest::METHOD => {
+                let params: HoverParams = serde_json::from_value(req.params)?;
+                let pos = params.text_document_position_params;
+                let result = self
+                    .docs
+                    .get(&pos.text_document.uri)
+                    .and_then(|doc| doc.hover(pos.position));
+                self.respond(id, result)
+            }
+            Completion::METHOD => {
+                let params: CompletionParams = serde_json::from_value(req.params)?;
+                let pos = params.text_document_position;
+                let result = self
+                    .docs
+                    .get(&pos.text_document.uri)
+                    .map(|doc| CompletionResponse::Array(doc.completion(pos.position)));
+                self.respond(id, result)
+            }
+            _ => {
+                debug!("Unhandled request: {}", req.method);
+                self.connection
+                    .sender
+                    .send(Message::Response(Response::new_err(
+                        id,
+                        lsp_server::ErrorCode::MethodNotFound as i32,
+                        format!("unhandled method {}", req.method),
+                    )))?;
+                Ok(())
+            }
+        }
+    }
+
+    fn respond<T: serde::Serialize>(
+        &self,
+        id: RequestId,
+        result: T,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let resp = Response::new_ok(id, result);
+        self.connection.sender.send(Message::Response(resp))?;
+        Ok(())
+    }
+
+    fn on_notification(&mut self, not: Notification) -> Result<(), Box<dyn std::error::Error>> {
+        match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams = serde_json::from_value(not.params)?;
+                let doc = params.text_document;
+                self.update(doc.uri, doc.text, Some(doc.version))
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams = serde_json::from_value(not.params)?;
+                // full sync, the last change holds the whole text
+                match params.content_changes.into_iter().last() {
+                    Some(change) => self.update(
+                        params.text_document.uri,
+                        change.text,
+                        Some(params.text_document.version),
+                    ),
+                    None => Ok(()),
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams = serde_json::from_value(not.params)?;
+                self.docs.remove(&params.text_document.uri);
+                self.publish(params.text_document.uri, Vec::new(), None)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn update(
+        &mut self,
+        uri: Url,
+        src: String,
+        version: Option<i32>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (doc, diagnostics) = Document::new(src);
+        self.docs.insert(uri.clone(), doc);
+        self.publish(uri, diagnostics, version)
+    }
+
+    fn publish(
+        &self,
+        uri: Url,
+        diagnostics: Vec<Diagnostic>,
+        version: Option<i32>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let params = PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version,
+        };
+        let not = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+        self.connection.sender.send(Message::Notification(not))?;
+        Ok(())
+    }
+}
+
+impl Document {
+    /// parse, expand and analyze src, returns the diagnostics with it
+    fn new(src: String) -> (Document, Vec<Diagnostic>) {
+        let mut diagnostics = Vec::new();
+        let mut doc = Document {
+            src,
+            top: None,
+            analysis: Analysis::default(),
+            occurrences: Vec::new(),
+        };
+        let top = match raslisp::TopParser::new().parse(&doc.src) {
+            Ok(top) => top,
+            Err(e) => {
+                let (start, end, message) = match &e {
+                    lalrpop_util::ParseError::InvalidToken { location } => {
+                        (*location, *location, e.to_string())
+                    }
+                    lalrpop_util::ParseError::UnrecognizedEof { location, .. } => {
+                        (*location, *location, e.to_string())
+                    }
+                    lalrpop_util::ParseError::UnrecognizedToken { token, .. }
+                    | lalrpop_util::ParseError::ExtraToken { token } => {
+                        (token.0, token.2, e.to_string())
+                    }
+                    lalrpop_util::ParseError::User { error } => {
+                        (error.span.start, error.span.end, error.message.clone())
+                    }
+                };
+                diagnostics.push(doc.diagnostic(start, end, Severity::Error, message));
+                return (doc, diagnostics);
+            }
+        };
+        doc.occurrences = occurrences(&top);
+        let top = match macros::expand(top) {
+            Ok(top) => top,
+            Err(e) => {
+                let (start, end) = (e.span.start, e.span.end);
+                diagnostics.push(doc.diagnostic(start, end, Severity::Error, e.message));
+                return (doc, diagnostics);
+            }
+        };
+        doc.analysis = symbol_table::analyze(&top);
+        doc.top = Some(top);
+        for e in doc.analysis.errors.iter() {
+            let (start, end) =
+                doc.occurrences
+                    .iter()
+                    .find(|o| o.scope == e.box_name && o.name == e.name)
+                    .or(doc.occurrences.iter().find(|o| {
+                        o.kind == OccKind::BoxDef && Some(&o.name) == e.box_name.as_ref()
+                    }))
+                    .map(|o| (o.start, o.end))
+                    .unwrap_or((0, 0));
+            diagnostics.push(doc.diagnostic(start, end, e.severity.clone(), e.message.clone()));
+        }
+        (doc, diagnostics)
+    }
+
+    fn diagnostic(&self, start: usize, end: usize, severity: Severity, msg: String) -> Diagnostic {
+        Diagnostic {
+            range: Range::new(self.position(start), self.position(end)),
+            severity: Some(match severity {
+                Severity::Error => DiagnosticSeverity::ERROR,
+                Severity::Warning => DiagnosticSeverity::WARNING,
+            }),
+            source: Some("raslisp".to_string()),
+            message: msg,
+            ..Default::default()
+        }
+    }
+
+    /// byte offset to lsp position, columns count utf-16 code units
+    fn position(&self, offset: usize) -> Position {
+        let before = &self.src[..offset.min(self.src.len())];
+        let line = before.matches('\n').count();
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let col = before[line_start..].encode_utf16().count();
+        Position::new(line as u32, col as u32)
+    }
+
+    fn offset(&self, pos: Position) -> usize {
+        let mut offset = 0;
+        for (i, line) in self.src.split_inclusive('\n').enumerate() {
+            if i == pos.line as usize {
+                let mut units = 0;
+                for (j, c) in line.char_indices() {
+                    if units >= pos.character as usize {
+                        return offset + j;
+                    }
+                    units += c.len_utf16();
+                }
+                return offset + line.trim_end_matches('\n').len();
+            }
+            offset += line.len();
+        }
+        self.src.len()
+    }
+
+    fn range(&self, o: &Occurrence) -> Range {
+        Range::new(self.position(o.start), self.position(o.end))
+    }
+
+    fn occurrence_at(&self, pos: Position) -> Option<&Occurrence> {
+        let offset = self.offset(pos);
+        self.occurrences
+            .iter()
+            .find(|o| o.start <= offset && offset <= o.end)
+    }
+
+    fn find(&self, kind: OccKind, name: &str, scope: Option<&String>) -> Option<&Occurrence> {
+        self.occurrences
+            .iter()
+            .find(|o| o.kind == kind && o.name == name && o.scope.as_ref() == scope)
+    }
+
+    fn definition(&self, pos: Position) -> Option<Range> {
+        let o = self.occurrence_at(pos)?;
+        let def = match &o.kind {
+            OccKind::BoxRef | OccKind::BoxDef => self.find(OccKind::BoxDef, &o.name, None),
+            OccKind::OpRef => self.find(OccKind::MacroDef, &o.name, None),
+            OccKind::KeywordRef(callee) => self.find(OccKind::PortDef, &o.name, Some(callee)),
+            _ => {
+                let scope = o.scope.as_ref();
+                self.find(OccKind::PortDef, &o.name, scope)
+                    .or(self.find(OccKind::LetDef, &o.name, scope))
+                    .or(self.find(OccKind::ParamDef, &o.name, scope))
+                    // a wire bound by a box wire has no declaration, use its binding
+                    .or(self.find(OccKind::WireArg, &o.name, scope))
+            }
+        }?;
+        Some(self.range(def))
+    }
+
+    fn hover(&self, pos: Position) -> Option<Hover> {
+        let o = self.occurrence_at(pos)?;
+        let text = match &o.kind {
+            OccKind::BoxRef | OccKind::BoxDef => self.box_signature(&o.name)?,
+            OccKind::OpRef | OccKind::MacroDef => match ops::lookup(&o.name) {
+                Some(b) => format!("{}\n\n{}", b.signature, b.doc),
+                None => self.macro_signature(&o.name)?,
+            },
+            OccKind::KeywordRef(callee) => self.port_signature(callee, &o.name)?,
+            OccKind::ParamDef => format!("macro param {}", o.name),
+            _ => {
+                let scope = o.scope.as_ref()?;
+                let sym = self.analysis.tables.get(scope)?.table.get(&o.name)?;
+                let ty = match &sym.ty {
+                    Some(ty) => fmt::format_type(ty),
+                    None => "unknown",
+                };
+                format!("{}: {} ({:?} of box {})", o.name, ty, sym.kind, scope)
+            }
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```raslisp\n{}\n```", text),
+            }),
+            range: Some(self.range(o)),
+        })
+    }
+
+    fn box_def(&self, name: &str) -> Option<BoxDef> {
+        self.top
+            .as_ref()?
+            .boxes()
+            .into_iter()
+            .find(|b| b.name() == name)
+    }
+
+    fn box_signature(&self, name: &str) -> Option<String> {
+        let b = self.box_def(name)?;
+        let ports = b.ports();
+        let width = ports.iter().map(|p| p.name().len()).max().unwrap_or(0);
+        let ports = ports
+            .iter()
+            .map(|p| format!("    {}", fmt::format_port(p, width)))
+            .collect::<Vec<_>>();
+        Some(format!("(box {} (\n{}\n))", name, ports.join("\n")))
+    }
+
+    fn port_signature(&self, box_name: &str, port: &str) -> Option<String> {
+        let b = self.box_def(box_name)?;
+        let p = b.ports().into_iter().find(|p| p.name() == port)?;
+        Some(format!(
+            "{} ; port of box {}",
+            fmt::format_port(&p, 0),
+            box_name
+        ))
+    }
+
+    fn macro_signature(&self, name: &str) -> Option<String> {
+        let TopDef::Items(items) = self.top.as_ref()?;
+        items.iter().find_map(|item| match item {
+            TopItem::MacroDef(MacroDef::Macro(m, params, _, _, _)) if m == name => {
+                Some(format!("(defmacro {} ({}) ...)", m, params.join(" ")))
+            }
+            _ => None,
+        })
+    }
+
+    fn completion(&self, pos: Position) -> Vec<CompletionItem> {
+        let offset = self.offset(pos);
+        let before = self.src[..offset].trim_end_matches(|c: char| c.is_alphanumeric() || c == '_');
+        let mut items = Vec::new();
+        if before.ends_with('[') {
+            // [box args...]
+            for o in self
+                .occurrences
+                .iter()
+                .filter(|o| o.kind == OccKind::BoxDef)
+            {
+                items.push(CompletionItem {
+                    label: o.name.clone(),
+                    kind: Some(CompletionItemKind::MODULE),
+                    detail: self.box_signature(&o.name),
+                    ..Default::default()
+                });
+            }
+            return items;
+        }
+        for b in ops::BUILTINS.iter() {
+            items.push(CompletionItem {
+                label: b.name.to_string(),
+                kind: Some(CompletionItemKind::FUNCTION),
+                detail: Some(b.signature.to_string()),
+                documentation: Some(Documentation::String(b.doc.to_string())),
+                ..Default::default()
+            });
+        }
+        for o in self
+            .occurrences
+            .iter()
+            .filter(|o| o.kind == OccKind::MacroDef)
+        {
+            items.push(CompletionItem {
+                label: o.name.clone(),
+                kind: Some(CompletionItemKind::SNIPPET),
+                detail: self.macro_signature(&o.name),
+                ..Default::default()
+            });
+        }
+        items
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum OccKind {
+    BoxDef,
+    MacroDef,
+    PortDef,
+    LetDef,
+    ParamDef,
+    BoxRef,
+    OpRef,
+    KeywordRef(String), // :port in a wire to the named box
+    WireArg,            // a name passed to a box wire
+    NameRef,
+}
+
+/// an identifier in the source, scope is the box or macro it is in
+#[derive(Debug, Clone)]
+struct Occurrence {
+    name: String,
+    kind: OccKind,
+    scope: Option<String>,
+    start: usize,
+    end: usize,
+}
+
+/// every name in the parsed source and what it is, from the spans the
+/// parser puts in the ast, macros are not expanded so their calls are
+/// still there to jump from
+fn occurrences(top: &TopDef) -> Vec<Occurrence> {
+    let TopDef::Items(items) = top;
+    let mut occs = Vec::new();
+    let mut push = |name: &String, kind: OccKind, scope: Option<&String>, span: &Span| {
+        occs.push(Occurrence {
+            name: name.clone(),
+            kind,
+            scope: scope.cloned(),
+            start: span.start,
+            end: span.end,
+        })
+    };
+    for item in items {
+        match item {
+            TopItem::BoxDef(BoxDef::ModuleBox(name, ports, stmts, span)) => {
+                push(name, OccKind::BoxDef, None, span);
+                let scope = Some(name);
+                for port in ports {
+                    match port {
+                        Port::In(port, _, _, span) | Port::Out(port, _, span) => {
+                            push(port, OccKind::PortDef, scope, span)
+                        }
+                    }
+                }
+                for stmt in stmts {
+                    match stmt {
+                        Stmt::LetDef(LetDef::Let(wire, expr, span)) => {
+                            push(wire, OccKind::LetDef, scope, span);
+                            expr_occurrences(expr, scope, &mut push);
+                        }
+                        Stmt::BoxWire(BoxWire::Boxw(callee, args, span)) => {
+                            push(callee, OccKind::BoxRef, scope, span);
+                            for arg in args {
+                                match arg {
+                                    WireArg::Positional(Expr::NodeIdent(wire, span)) => {
+                                        push(wire, OccKind::WireArg, scope, span)
+                                    }
+                                    WireArg::Positional(expr) => {
+                                        expr_occurrences(expr, scope, &mut push)
+                                    }
+                                    WireArg::Keyword(port, expr, span) => {
+                                        push(
+                                            port,
+                                            OccKind::KeywordRef(callee.clone()),
+                                            scope,
+                                            span,
+                                        );
+                                        expr_occurrences(expr, scope, &mut push);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            TopItem::MacroDef(MacroDef::Macro(name, params, body, span, param_spans)) => {
+                push(name, OccKind::MacroDef, None, span);
+                let scope = Some(name);
+                for (param, span) in params.iter().zip(param_spans) {
+                    push(param, OccKind::ParamDef, scope, span);
+                }
+                expr_occurrences(body, scope, &mut push);
+            }
+            _ => {}
+        }
+    }
+    occs
+}
+
+fn expr_occurrences<F>(expr: &Expr, scope: Option<&String>, push: &mut F)
+where
+    F: FnMut(&String, OccKind, Option<&String>, &Span),
+{
+    match expr {
+        Expr::Operator(op, args, span) => {
+            push(op, OccKind::OpRef, scope, span);
+            for arg in args {
+                expr_occurrences(arg, scope, push);
+            }
+        }
+        Expr::NodeIdent(name, span) => push(name, OccKind::NameRef, scope, span),
+        Expr::Num(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACRO: &str = include_str!("../../test/macro.raslisp");
+
+    fn text(doc: &Document, range: Range) -> &str {
+        &doc.src[doc.offset(range.start)..doc.offset(range.end)]
+    }
+
+    fn at(doc: &Document, needle: &str, nth: usize) -> Position {
+        let offset = doc.src.match_indices(needle).nth(nth).unwrap().0;
+        doc.position(offset)
+    }
+
+    #[test]
+    fn fixture_has_no_diagnostics() {
+        let (_, diagnostics) = Document::new(MACRO.to_string());
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+
+    #[test]
+    fn macro_call_goes_to_defmacro() {
+        let (doc, _) = Document::new(MACRO.to_string());
+        // the first unison is in the comment, the second names the macro
+        let def = doc.definition(at(&doc, "unison", 2)).unwrap();
+        assert_eq!(text(&doc, def), "unison");
+        assert_eq!(def.start, at(&doc, "unison", 1));
+    }
+
+    #[test]
+    fn wire_goes_to_its_port() {
+        let (doc, _) = Document::new(MACRO.to_string());
+        let def = doc.definition(at(&doc, "freq 0.1", 0)).unwrap();
+        assert_eq!(def.start, at(&doc, "freq: float", 0));
+        // in the macro body freq is the param
+        let def = doc.definition(at(&doc, "freq (+", 0)).unwrap();
+        // the comment says "freq detune)" too
+        assert_eq!(def.start, at(&doc, "freq detune)", 1));
+    }
+
+    #[test]
+    fn macro_errors_point_at_the_call() {
+        let src = MACRO.replace("(unison 3 saw freq 0.05)", "(unison 3 saw freq)");
+        let (doc, diagnostics) = Document::new(src);
+        assert_eq!(diagnostics.len(), 1);
+        let d = &diagnostics[0];
+        assert_eq!(d.message, "macro unison takes 4 args, 3 given");
+        assert_eq!(d.range.start, at(&doc, "unison 3", 0));
+        assert_eq!(text(&doc, d.range), "unison");
+    }
+
+    #[test]
+    fn half_typed_tokens_are_diagnostics() {
+        let src = MACRO.replace("freq: float", "freq: flo");
+        let (doc, diagnostics) = Document::new(src);
+        assert_eq!(diagnostics.len(), 1);
+        let d = &diagnostics[0];
+        assert_eq!(d.message, "unknown type flo, use i32, float or waveform");
+        assert_eq!(text(&doc, d.range), "flo");
+        let src = MACRO.replace("unison 7", "unison 77777777777");
+        let (doc, diagnostics) = Document::new(src);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(text(&doc, diagnostics[0].range), "77777777777");
+    }
+
+    #[test]
+    fn unknown_wires_point_at_the_name() {
+        let src = MACRO.replace("(unison 3 saw freq 0.05)", "(unison 3 saw frq 0.05)");
+        let (doc, diagnostics) = Document::new(src);
+        assert!(!diagnostics.is_empty());
+        assert!(
+            diagnostics.iter().all(|d| text(&doc, d.range) == "frq"),
+            "{:?}",
+            diagnostics
+        );
+    }
+}
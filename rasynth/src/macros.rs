@@ -0,0 +1,255 @@
+use crate::ast::*;
+use log::*;
+use std::collections::HashMap;
+use std::fmt;
+
+/// nesting limit of macro calls, to catch recursive macros
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// special form only valid inside a macro body:
+/// (repeat n i expr) is spliced into the enclosing operator's args
+/// as n copies of expr, with i bound to 0, 1, ..., n-1
+const REPEAT: &str = "repeat";
+
+/// why a patch's macros did not expand, span is of the name or the
+/// operator it is about
+#[derive(Debug, Clone)]
+pub struct MacroError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl MacroError {
+    fn new(span: Span, message: String) -> Self {
+        MacroError { message, span }
+    }
+}
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for MacroError {}
+
+impl From<MacroError> for String {
+    fn from(e: MacroError) -> String {
+        e.message
+    }
+}
+
+/// expand every macro call in the boxes of top, this should be
+/// done before handing the ast to FlowGraph::generate
+///
+/// macros are hygienic: the only names a macro body may refer to
+/// are its params and the index vars of its repeat forms, so a macro
+/// can never capture a wire of the calling box or the other way round
+///
+/// args are expanded before they are substituted, the result is then
+/// expanded again for the macros the body calls, by then the args
+/// hold no macro calls so scanning them again changes nothing
+pub fn expand(top: TopDef) -> Result<TopDef, MacroError> {
+    let TopDef::Items(items) = top;
+    let mut macros: HashMap<String, MacroDef> = HashMap::new();
+    for item in items.iter() {
+        if let TopItem::MacroDef(m) = item {
+            let MacroDef::Macro(name, params, _, span, param_spans) = m;
+            if REPEAT == name {
+                return Err(MacroError::new(
+                    *span,
+                    format!("{} is a reserved name", REPEAT),
+                ));
+            }
+            for (i, param) in params.iter().enumerate() {
+                if params[..i].contains(param) {
+                    return Err(MacroError::new(
+                        param_spans.get(i).cloned().unwrap_or(*span),
+                        format!("duplicate param {} in macro {}", param, name),
+                    ));
+                }
+            }
+            if macros.insert(name.clone(), m.clone()).is_some() {
+                return Err(MacroError::new(
+                    *span,
+                    format!("macro {} defined twice", name),
+                ));
+            }
+        }
+    }
+    let mut expanded = Vec::new();
+    for item in items {
+        expanded.push(match item {
+            TopItem::BoxDef(BoxDef::ModuleBox(name, ports, stmts, span)) => {
+                debug!("Expanding macros in box: {}", name);
+                let mut new_stmts = Vec::new();
+                for stmt in stmts {
+                    new_stmts.push(match stmt {
+                        Stmt::LetDef(LetDef::Let(name, expr, span)) => {
+                            Stmt::LetDef(LetDef::Let(name, expand_expr(&expr, &macros, 0)?, span))
+                        }
+                        Stmt::BoxWire(BoxWire::Boxw(name, args, span)) => {
+                            let mut new_args = Vec::new();
+                            for arg in args {
+                                new_args.push(match arg {
+                                    WireArg::Positional(e) => {
+                                        WireArg::Positional(expand_expr(&e, &macros, 0)?)
+                                    }
+                                    WireArg::Keyword(k, e, span) => {
+                                        WireArg::Keyword(k, expand_expr(&e, &macros, 0)?, span)
+                                    }
+                                });
+                            }
+                            Stmt::BoxWire(BoxWire::Boxw(name, new_args, span))
+                        }
+                    });
+                }
+                TopItem::BoxDef(BoxDef::ModuleBox(name, ports, new_stmts, span))
+            }
+            m => m,
+        });
+    }
+    Ok(TopDef::Items(expanded))
+}
+
+fn expand_expr(
+    expr: &Expr,
+    macros: &HashMap<String, MacroDef>,
+    depth: usize,
+) -> Result<Expr, MacroError> {
+    match expr {
+        Expr::Operator(op, args, span) => {
+            let span = *span;
+            if op == REPEAT {
+                return Err(MacroError::new(
+                    span,
+                    format!("{} can only be used inside a macro body", REPEAT),
+                ));
+            }
+            let mut new_args = Vec::new();
+            for arg in args {
+                new_args.push(expand_expr(arg, macros, depth)?);
+            }
+            let m = match macros.get(op) {
+                Some(m) => m,
+                None => return Ok(Expr::Operator(op.clone(), new_args, span)),
+            };
+            if depth >= MAX_EXPANSION_DEPTH {
+                return Err(MacroError::new(
+                    span,
+                    format!("macro {} nested too deep, is it recursive?", op),
+                ));
+            }
+            let MacroDef::Macro(name, params, body, _, _) = m;
+            if params.len() != new_args.len() {
+                return Err(MacroError::new(
+                    span,
+                    format!(
+                        "macro {} takes {} args, {} given",
+                        name,
+                        params.len(),
+                        new_args.len()
+                    ),
+                ));
+            }
+            let env = params
+                .iter()
+                .cloned()
+                .zip(new_args)
+                .collect::<HashMap<String, Expr>>();
+            let mut result = substitute(body, &env, name)?;
+            if result.len() != 1 {
+                return Err(MacroError::new(
+                    span,
+                    format!("macro {} must expand to a single expression", name),
+                ));
+            }
+            let result = result.pop().unwrap();
+            trace!("Macro {} expanded to: {:?}", name, result);
+            expand_expr(&result, macros, depth + 1)
+        }
+        _ => Ok(expr.clone()),
+    }
+}
+
+/// instantiate a macro body template, returns a vec because a
+/// repeat form expands to any number of expressions
+fn substitute(
+    expr: &Expr,
+    env: &HashMap<String, Expr>,
+    macro_name: &String,
+) -> Result<Vec<Expr>, MacroError> {
+    match expr {
+        Expr::Num(_) => Ok(vec![expr.clone()]),
+        Expr::NodeIdent(name, span) => match env.get(name) {
+            Some(e) => Ok(vec![e.clone()]),
+            None => Err(MacroError::new(
+                *span,
+                format!(
+                    "unbound name {} in macro {}, only params can be used",
+                    name, macro_name
+                ),
+            )),
+        },
+        Expr::Operator(op, args, span) if op == REPEAT => {
+            let (count, var, body) = match &args[..] {
+                [count, Expr::NodeIdent(var, _), body] => (count, var, body),
+                _ => {
+                    return Err(MacroError::new(
+                        *span,
+                        format!("({} n i expr) expected in macro {}", REPEAT, macro_name),
+                    ))
+                }
+            };
+            let n = match &substitute(count, env, macro_name)?[..] {
+                [Expr::Num(Numeric::Int32(n))] if *n >= 0 => *n,
+                _ => {
+                    return Err(MacroError::new(
+                        *span,
+                        format!(
+                            "{} count must be a non-negative integer in macro {}",
+                            REPEAT, macro_name
+                        ),
+                    ))
+                }
+            };
+            let mut ret = Vec::new();
+            for i in 0..n {
+                let mut inner = env.clone();
+                inner.insert(var.clone(), Expr::Num(Numeric::Int32(i)));
+                ret.append(&mut substitute(body, &inner, macro_name)?);
+            }
+            Ok(ret)
+        }
+        Expr::Operator(op, args, span) => {
+            // a param in operator position is replaced by the name it is bound to
+            let op = match env.get(op) {
+                Some(Expr::NodeIdent(bound, _)) => bound.clone(),
+                Some(e) => {
+                    return Err(MacroError::new(
+                        *span,
+                        format!(
+                            "operator param {} in macro {} bound to {:?}, a name is required",
+                            op, macro_name, e
+                        ),
+                    ))
+                }
+                None => op.clone(),
+            };
+            let mut new_args = Vec::new();
+            for arg in args {
+                new_args.append(&mut substitute(arg, env, macro_name)?);
+            }
+            if new_args.is_empty() {
+                return Err(MacroError::new(
+                    *span,
+                    format!(
+                        "operator {} has no args after expanding macro {}",
+                        op, macro_name
+                    ),
+                ));
+            }
+            Ok(vec![Expr::Operator(op, new_args, *span)])
+        }
+    }
+}
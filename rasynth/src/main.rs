@@ -1,13 +1,40 @@
-use argparse::{ArgumentParser, Store, StoreTrue};
+use argparse::{ArgumentParser, List, Store, StoreOption, StoreTrue};
 use env_logger::Env;
 use lalrpop_util::lalrpop_mod;
 use log::*;
 use std::fs;
+use std::path::Path;
 
 pub mod ast;
+pub mod audio;
 pub mod board;
+pub mod config;
+pub mod controls;
+pub mod display;
+pub mod doctor;
+pub mod dot;
+pub mod engine;
+pub mod fmt;
 pub mod graph;
+pub mod graph_image;
+pub mod graph_json;
+pub mod input;
+pub mod layout;
+pub mod lsp;
+pub mod macros;
+pub mod midi;
+pub mod ops;
+pub mod osc;
+pub mod params;
+pub mod preset;
+pub mod reload;
+pub mod repl;
+pub mod scope;
+#[cfg(feature = "alsa")]
+pub mod seq;
 pub mod symbol_table;
+pub mod ui;
+pub mod voices;
 
 lalrpop_mod!(pub raslisp); // synthesized by LALRPOP
 
@@ -26,6 +53,10 @@ fn main() {
     let mut verbose = false;
     let mut parse_box = false;
     let mut test_display = false;
+    let mut snapshot: Option<String> = None;
+    let mut board: Option<String> = None;
+    let mut command = String::new();
+    let mut args: Vec<String> = Vec::new();
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("RASYNTH/RASLISP Interpreter");
@@ -37,12 +68,88 @@ fn main() {
             .add_option(&["-b", "--box"], StoreTrue, "Parse the input file");
         ap.refer(&mut test_display)
             .add_option(&["-d", "--display"], StoreTrue, "Test GPIO stuff");
+        ap.refer(&mut snapshot).add_option(
+            &["--snapshot"],
+            StoreOption,
+            "With --display, draw into a PNG or PPM file instead of the LCD",
+        );
+        ap.refer(&mut board).add_option(
+            &["--board"],
+            StoreOption,
+            "Board config TOML, or virtual for no hardware",
+        );
+        // rasynth fmt [--check] FILE... - format raslisp files
+        // rasynth lsp - language server on stdio
+        // rasynth repl [FILE] - interactive session
+        // rasynth play FILE - play a patch on an audio sink
+        // rasynth render FILE --midi SONG - render a MIDI file to WAV
+        // rasynth midi list|monitor|send - ALSA sequencer tools
+        // rasynth ui [DIR] - patch browser and params on the LCD
+        // rasynth controls - print encoder, button and knob events
+        // rasynth doctor - probe the board's peripherals
+        // rasynth preset PATCH [ACTION] - save, load, rename and morph presets
+        // rasynth compile FILE [-o OUT] - save the built graph as JSON
+        // rasynth dot FILE [-o OUT] - write the graph for Graphviz
+        // rasynth graph FILE [--format svg|png] - draw the graph without Graphviz
+        ap.refer(&mut command).add_argument(
+            "command",
+            Store,
+            "Command to run (fmt, lsp, repl, play, render, midi, ui, controls, doctor, preset, compile, dot, graph)",
+        );
+        ap.refer(&mut args)
+            .add_argument("arguments", List, "Arguments for command");
+        ap.stop_on_first_argument(true);
         ap.parse_args_or_exit();
     }
+    args.insert(0, format!("rasynth {}", command));
     if verbose {
         info!("Verbose mode enabled");
     }
-    if parse_box {
+    // only the commands on the hardware read the board, the rest keep
+    // working with a broken board.toml
+    let on_board = ["play", "ui", "controls", "doctor"].contains(&command.as_str());
+    if on_board || test_display {
+        if let Err(e) = config::init(board.as_deref()) {
+            error!("Cannot load the board: {}", e);
+            std::process::exit(2);
+        }
+    }
+    if command == "fmt" {
+        fmt::command(args);
+    } else if command == "lsp" {
+        lsp::command(args);
+    } else if command == "repl" {
+        repl::command(args);
+    } else if command == "play" {
+        audio::command(args);
+    } else if command == "render" {
+        midi::command(args);
+    } else if command == "midi" {
+        #[cfg(feature = "alsa")]
+        seq::command(args);
+        #[cfg(not(feature = "alsa"))]
+        {
+            error!("rasynth was built without the alsa feature");
+            std::process::exit(2);
+        }
+    } else if command == "ui" {
+        ui::command(args);
+    } else if command == "controls" {
+        controls::command(args);
+    } else if command == "doctor" {
+        doctor::command(args);
+    } else if command == "preset" {
+        preset::command(args);
+    } else if command == "compile" {
+        graph_json::command(args);
+    } else if command == "dot" {
+        dot::command(args);
+    } else if command == "graph" {
+        graph_image::command(args);
+    } else if !command.is_empty() {
+        error!("Unknown command: {}", command);
+        std::process::exit(2);
+    } else if parse_box {
         let input_file = "../test/osc1.raslisp";
         info!("Input Top File Path: {}", input_file);
         let test1 = fs::read_to_string(input_file).expect("Unable to read file");
@@ -50,6 +157,25 @@ fn main() {
         let top = raslisp::TopParser::new().parse(&test1).unwrap();
         info!("AST Parsed Successfully!");
 
+        let top = match macros::expand(top) {
+            Ok(top) => top,
+            Err(e) => {
+                error!("Macro expansion failed: {}", e);
+                panic!("Macro expansion failed: {}", e);
+            }
+        };
+
+        let analysis = symbol_table::analyze(&top);
+        for e in analysis.errors.iter() {
+            match e.severity {
+                symbol_table::Severity::Error => error!("{:?}: {}", e.box_name, e.message),
+                symbol_table::Severity::Warning => warn!("{:?}: {}", e.box_name, e.message),
+            }
+        }
+        if analysis.has_errors() {
+            panic!("Semantic analysis failed");
+        }
+
         graph::FLOW_GRAPH
             .lock()
             .unwrap()
@@ -62,23 +188,43 @@ fn main() {
             .unwrap()
             .generate();
 
-        graph::FLOW_GRAPH
+        let built = graph::FLOW_GRAPH
             .lock()
             .unwrap()
             .as_mut()
             .unwrap()
             .node_create(&boxes);
+        if let Err(e) = built {
+            error!("Graph build failed: {}", e);
+            panic!("Graph build failed: {}", e);
+        }
 
         info!("Graph: {:?}", graph::FLOW_GRAPH.lock().unwrap());
 
-        graph::FLOW_GRAPH
+        let dot = graph::FLOW_GRAPH
             .lock()
             .unwrap()
-            .as_mut()
+            .as_ref()
             .unwrap()
-            .dump_dot();
+            .dump_dot(Path::new("flow.dot"));
+        if let Err(e) = dot {
+            error!("Cannot write flow.dot: {}", e);
+        }
     } else if test_display {
-        board::test_display();
+        match snapshot {
+            Some(path) => {
+                if let Err(e) = display::test_snapshot(Path::new(&path), 64) {
+                    error!("Snapshot failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            None => {
+                if let Err(e) = board::test_display() {
+                    error!("Display test failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
     }
     info!("Goodbye!");
 }
@@ -0,0 +1,536 @@
+use crate::ast::*;
+use crate::audio::{self, AudioSink, WavSink};
+use crate::engine::{self, Engine};
+use crate::graph::FlowGraph;
+use crate::voices::{self, Steal, VoiceAllocator};
+use argparse::{ArgumentParser, Store, StoreOption};
+use log::*;
+use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use std::error::Error;
+use std::io::{stderr, stdout};
+use std::path::Path;
+
+/// tempo of a file without tempo events, 120 bpm
+const DEFAULT_TEMPO: u32 = 500_000;
+/// pitch bend range, in semitones either way
+const BEND_RANGE: f32 = 2.0;
+
+/// rasynth render FILE --midi SONG - render a patch played by a MIDI file
+pub fn command(args: Vec<String>) {
+    let mut file = String::new();
+    let mut midi_file: Option<String> = None;
+    let mut output = "out.wav".to_string();
+    let mut rate = engine::DEFAULT_SAMPLE_RATE as u32;
+    let mut channels: usize = 2;
+    let mut tail: f64 = 1.0;
+    let mut seconds: Option<f64> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Render a raslisp patch to a WAV file");
+        ap.refer(&mut file)
+            .required()
+            .add_argument("file", Store, "Raslisp file or saved graph to render");
+        ap.refer(&mut midi_file).add_option(
+            &["-m", "--midi"],
+            StoreOption,
+            "Standard MIDI file driving the (midi ...) maps",
+        );
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store, "WAV file to write");
+        ap.refer(&mut rate)
+            .add_option(&["-r", "--rate"], Store, "Sample rate in Hz");
+        ap.refer(&mut channels)
+            .add_option(&["-c", "--channels"], Store, "Number of channels");
+        ap.refer(&mut tail).add_option(
+            &["--tail"],
+            Store,
+            "Seconds to keep rendering after the last MIDI event",
+        );
+        ap.refer(&mut seconds).add_option(
+            &["-t", "--seconds"],
+            StoreOption,
+            "Length of the render, instead of the MIDI file's",
+        );
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    let graph = match FlowGraph::from_file(Path::new(&file)) {
+        Ok(graph) => graph,
+        Err(e) => {
+            error!("{}: {}", file, e);
+            std::process::exit(2);
+        }
+    };
+    let events = match &midi_file {
+        Some(path) => match load_smf(path, rate) {
+            Ok(events) => events,
+            Err(e) => {
+                error!("{}: {}", path, e);
+                std::process::exit(2);
+            }
+        },
+        None => Vec::new(),
+    };
+    let length = match (seconds, events.last()) {
+        (Some(s), _) => (s * rate as f64) as u64,
+        (None, Some((at, _))) => at + (tail * rate as f64) as u64,
+        (None, None) => {
+            error!("Nothing to render, give --midi or --seconds");
+            std::process::exit(2);
+        }
+    };
+    let mut engine = Engine::new(&graph, rate as f32);
+    let mut mapper = match MidiMapper::new(&graph, &engine) {
+        Ok(mapper) => mapper,
+        Err(e) => {
+            error!("{}: {}", file, e);
+            std::process::exit(2);
+        }
+    };
+    let mut sink = match WavSink::create(&output, rate, channels) {
+        Ok(sink) => sink,
+        Err(e) => {
+            error!("Cannot create {}: {}", output, e);
+            std::process::exit(2);
+        }
+    };
+    info!(
+        "Rendering {} frames of {} with {} MIDI events to {}",
+        length,
+        file,
+        events.len(),
+        output
+    );
+    if let Err(e) = render(&mut engine, &mut mapper, &events, length, &mut sink) {
+        error!("Render failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// run engine for length frames, applying each event before the frame
+/// it falls on
+pub fn render(
+    engine: &mut Engine,
+    mapper: &mut MidiMapper,
+    events: &[(u64, MidiMessage)],
+    length: u64,
+    sink: &mut dyn AudioSink,
+) -> Result<(), Box<dyn Error>> {
+    let channels = sink.channels();
+    let mut block = vec![0.0; audio::DEFAULT_BLOCK_SIZE * channels];
+    let mut events = events.iter().peekable();
+    let mut done: u64 = 0;
+    while done < length {
+        while let Some((_, msg)) = events.next_if(|(at, _)| *at <= done) {
+            mapper.handle(msg, engine);
+        }
+        // stop the block at the next event
+        let until = events.peek().map(|(at, _)| *at).unwrap_or(length);
+        let n = (until.min(length) - done).min(audio::DEFAULT_BLOCK_SIZE as u64) as usize;
+        let block = &mut block[..n * channels];
+        engine.process_block(block, channels);
+        sink.write(block)?;
+        done += n as u64;
+    }
+    sink.close()
+}
+
+/// the channel messages of an SMF type 0 or 1 file, all channels
+/// merged, keyed by the frame they fall on at rate
+pub fn load_smf(path: &str, rate: u32) -> Result<Vec<(u64, MidiMessage)>, Box<dyn Error>> {
+    let bytes = std::fs::read(path)?;
+    let events = smf_events(&bytes, rate)?;
+    debug!("{}: {} MIDI events", path, events.len());
+    Ok(events)
+}
+
+/// load_smf of the bytes of a file
+fn smf_events(bytes: &[u8], rate: u32) -> Result<Vec<(u64, MidiMessage)>, Box<dyn Error>> {
+    let smf = Smf::parse(bytes)?;
+    if smf.header.format == Format::Sequential {
+        return Err("SMF type 2 is not supported".into());
+    }
+    // (tick, track, order in track) keeps simultaneous events in file order
+    let mut merged = Vec::new();
+    for (t, track) in smf.tracks.iter().enumerate() {
+        let mut tick: u64 = 0;
+        for (i, ev) in track.iter().enumerate() {
+            tick += ev.delta.as_int() as u64;
+            merged.push((tick, t, i, ev.kind));
+        }
+    }
+    merged.sort_by_key(|(tick, t, i, _)| (*tick, *t, *i));
+
+    let mut events = Vec::new();
+    let mut tempo = DEFAULT_TEMPO;
+    let (mut last_tick, mut seconds) = (0, 0.0);
+    for (tick, _, _, kind) in merged {
+        seconds += match smf.header.timing {
+            Timing::Metrical(tpb) => {
+                (tick - last_tick) as f64 * tempo as f64 / 1e6 / tpb.as_int() as f64
+            }
+            Timing::Timecode(fps, sub) => {
+                (tick - last_tick) as f64 / fps.as_f32() as f64 / sub as f64
+            }
+        };
+        last_tick = tick;
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => tempo = t.as_int(),
+            TrackEventKind::Midi { message, .. } => {
+                events.push(((seconds * rate as f64).round() as u64, message))
+            }
+            _ => {}
+        }
+    }
+    debug!("{:.2}s of MIDI", seconds);
+    Ok(events)
+}
+
+/// what a (midi ...) map reads from the MIDI stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Note,     // number of the sounding note
+    Freq,     // its frequency in Hz, bent
+    Gate,     // 1 while a note is held
+    Velocity, // of the last note on, 0 to 1
+    Bend,     // -1 to 1
+    Pressure, // channel aftertouch, 0 to 1
+    Cc(u8),   // 0 to 1
+}
+
+impl Source {
+    /// sources that follow the note a voice plays
+    pub fn per_voice(&self) -> bool {
+        matches!(
+            self,
+            Source::Note | Source::Freq | Source::Gate | Source::Velocity
+        )
+    }
+
+    /// sources a controller sweeps, glided to so they do not zipper,
+    /// notes and gates jump
+    pub fn continuous(&self) -> bool {
+        matches!(self, Source::Bend | Source::Pressure | Source::Cc(_))
+    }
+
+    pub fn from_map(m: &MidiMap) -> Result<Self, String> {
+        let MidiMap::Map(port, source, n) = m;
+        let source = match (source.as_str(), n) {
+            ("note", None) => Source::Note,
+            ("freq", None) => Source::Freq,
+            ("gate", None) => Source::Gate,
+            ("velocity", None) => Source::Velocity,
+            ("bend", None) => Source::Bend,
+            ("pressure", None) => Source::Pressure,
+            ("cc", Some(Numeric::Int32(n))) if (0..128).contains(n) => Source::Cc(*n as u8),
+            ("cc", _) => return Err(format!("{}: cc takes a controller number 0-127", port)),
+            (_, None) => return Err(format!("{}: unknown MIDI source {}", port, source)),
+            (_, Some(_)) => return Err(format!("{}: only cc takes a number", port)),
+        };
+        Ok(source)
+    }
+}
+
+/// keeps the MIDI state and sets the mapped in ports from it, main's
+/// ports follow the last note held, with (voices ...) the note
+/// sources go to the voice the allocator picked
+pub struct MidiMapper {
+    maps: Vec<(String, Source)>,
+    voice_maps: Vec<Vec<(String, Source)>>, // per voice
+    alloc: Option<VoiceAllocator>,
+    held: Vec<u8>,
+    velocity: f32,
+    bend: f32,
+    pressure: f32,
+    cc: [f32; 128],
+}
+
+impl MidiMapper {
+    pub fn new(graph: &FlowGraph, engine: &Engine) -> Result<Self, String> {
+        let mut maps = Vec::new();
+        let mut voice_maps = Vec::new();
+        let mut alloc = None;
+        let params = engine.params();
+        let check = |name: &String| match params.contains(name) {
+            true => Ok(()),
+            false => Err(format!("{} is not a free in port", name)),
+        };
+        if let Some(top) = graph.ast.lock().unwrap().as_ref() {
+            let voices = top.voices();
+            if let Some(VoicesDef::Voices(Numeric::Int32(n), voice_box, steal)) = voices.first() {
+                let steal = match steal {
+                    Some(steal) => steal.parse::<Steal>()?,
+                    None => Steal::default(),
+                };
+                for k in 0..*n as usize {
+                    let mut maps = Vec::new();
+                    for (port, source) in voices::voice_ports(top) {
+                        let name = format!("{}#{}/{}", voice_box, k, port);
+                        check(&name)?;
+                        maps.push((name, source));
+                    }
+                    voice_maps.push(maps);
+                }
+                alloc = Some(VoiceAllocator::new(*n as usize, steal));
+            }
+            for m in top.midi_maps() {
+                let MidiMap::Map(port, _, _) = &m;
+                let source = Source::from_map(&m)?;
+                if alloc.is_some() && source.per_voice() {
+                    continue;
+                }
+                let name = format!("main/{}", port);
+                check(&name)?;
+                maps.push((name, source));
+            }
+        }
+        if maps.is_empty() && voice_maps.is_empty() {
+            warn!("No (midi ...) maps, MIDI input will be ignored");
+        }
+        Ok(MidiMapper {
+            maps,
+            voice_maps,
+            alloc,
+            held: Vec::new(),
+            velocity: 0.0,
+            bend: 0.0,
+            pressure: 0.0,
+            cc: [0.0; 128],
+        })
+    }
+
+    pub fn handle(&mut self, msg: &MidiMessage, engine: &mut Engine) {
+        // voices whose ports change
+        let mut changed = None;
+        match *msg {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                self.held.retain(|k| *k != key.as_int());
+                self.held.push(key.as_int());
+                self.velocity = vel.as_int() as f32 / 127.0;
+                if let Some(alloc) = self.alloc.as_mut() {
+                    changed = Some(alloc.note_on(key.as_int(), self.velocity));
+                }
+            }
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                self.held.retain(|k| *k != key.as_int());
+                if let Some(alloc) = self.alloc.as_mut() {
+                    changed = alloc.note_off(key.as_int());
+                }
+            }
+            MidiMessage::Controller { controller, value } => {
+                self.cc[controller.as_int() as usize] = value.as_int() as f32 / 127.0;
+            }
+            MidiMessage::PitchBend { bend } => {
+                self.bend = bend.as_f32();
+                // bends every voice's freq
+                for k in 0..self.voice_maps.len() {
+                    self.set_voice(k, engine);
+                }
+            }
+            MidiMessage::ChannelAftertouch { vel } => self.pressure = vel.as_int() as f32 / 127.0,
+            _ => return,
+        }
+        if let Some(k) = changed {
+            self.set_voice(k, engine);
+        }
+        // ports a reload removed are skipped
+        for (name, source) in self.maps.iter() {
+            match (engine.param(name), self.value(*source)) {
+                (Some(slot), Some(v)) if source.continuous() => engine.glide_slot(slot, v),
+                (Some(slot), Some(v)) => engine.set_slot(slot, v),
+                _ => (),
+            }
+        }
+    }
+
+    fn set_voice(&self, k: usize, engine: &mut Engine) {
+        let voice = match self.alloc.as_ref() {
+            Some(alloc) => &alloc.voices[k],
+            None => return,
+        };
+        let note = voice.note as f32;
+        for (name, source) in self.voice_maps[k].iter() {
+            let v = match source {
+                Source::Note => note,
+                Source::Freq => note_freq(note + self.bend * BEND_RANGE),
+                Source::Gate => voice.gate as i32 as f32,
+                _ => voice.velocity,
+            };
+            if let Some(slot) = engine.param(name) {
+                engine.set_slot(slot, v);
+            }
+        }
+    }
+
+    /// None keeps the port's value, note and freq hold after release
+    fn value(&self, source: Source) -> Option<f32> {
+        let note = self.held.last().map(|k| *k as f32);
+        match source {
+            Source::Note => note,
+            Source::Freq => note.map(|n| note_freq(n + self.bend * BEND_RANGE)),
+            Source::Gate => Some(note.is_some() as i32 as f32),
+            Source::Velocity => Some(self.velocity),
+            Source::Bend => Some(self.bend),
+            Source::Pressure => Some(self.pressure),
+            Source::Cc(n) => Some(self.cc[n as usize]),
+        }
+    }
+}
+
+/// equal tempered, A4 = note 69 = 440 Hz
+pub fn note_freq(note: f32) -> f32 {
+    440.0 * 2f32.powf((note - 69.0) / 12.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::{u15, u24, u28, u4, u7};
+    use midly::{Header, TrackEvent};
+
+    const RATE: u32 = 1000;
+
+    fn note(key: u8, vel: u8) -> MidiMessage {
+        let (key, vel) = (u7::new(key), u7::new(vel));
+        MidiMessage::NoteOn { key, vel }
+    }
+
+    fn cc(controller: u8, value: u8) -> MidiMessage {
+        let (controller, value) = (u7::new(controller), u7::new(value));
+        MidiMessage::Controller { controller, value }
+    }
+
+    fn midi(delta: u32, channel: u8, message: MidiMessage) -> TrackEvent<'static> {
+        let channel = u4::new(channel);
+        let (delta, kind) = (u28::new(delta), TrackEventKind::Midi { channel, message });
+        TrackEvent { delta, kind }
+    }
+
+    fn meta(delta: u32, message: MetaMessage<'static>) -> TrackEvent<'static> {
+        let (delta, kind) = (u28::new(delta), TrackEventKind::Meta(message));
+        TrackEvent { delta, kind }
+    }
+
+    /// an SMF of the tracks at 96 ticks a beat
+    fn smf(format: Format, tracks: Vec<Vec<TrackEvent<'static>>>) -> Vec<u8> {
+        let mut smf = Smf::new(Header::new(format, Timing::Metrical(u15::new(96))));
+        for mut track in tracks {
+            track.push(meta(0, MetaMessage::EndOfTrack));
+            smf.tracks.push(track);
+        }
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// the mono patch at RATE
+    fn mono() -> (Engine, MidiMapper) {
+        let graph = FlowGraph::from_source(include_str!("../../test/mono.raslisp")).unwrap();
+        let engine = Engine::new(&graph, RATE as f32);
+        let mapper = MidiMapper::new(&graph, &engine).unwrap();
+        (engine, mapper)
+    }
+
+    /// a sink keeping what it is given
+    struct Frames(Vec<f32>);
+
+    impl AudioSink for Frames {
+        fn sample_rate(&self) -> u32 {
+            RATE
+        }
+        fn channels(&self) -> usize {
+            2
+        }
+        fn write(&mut self, interleaved: &[f32]) -> Result<(), Box<dyn Error>> {
+            self.0.extend_from_slice(interleaved);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ticks_become_frames_at_the_tempo_of_the_time() {
+        // a beat is a second, then half a second from tick 96 on
+        let tempo = vec![
+            meta(0, MetaMessage::Tempo(u24::new(1_000_000))),
+            meta(96, MetaMessage::Tempo(u24::new(500_000))),
+        ];
+        let notes = vec![
+            midi(48, 0, note(60, 100)),
+            midi(48, 0, note(60, 0)),
+            midi(0, 3, cc(74, 10)),
+            midi(96, 0, note(62, 100)),
+        ];
+        let bytes = smf(Format::Parallel, vec![tempo.clone(), notes.clone()]);
+        let events = smf_events(&bytes, RATE).unwrap();
+        assert_eq!(
+            events,
+            [
+                (500, note(60, 100)),
+                (1000, note(60, 0)),
+                (1000, cc(74, 10)),
+                (1500, note(62, 100))
+            ]
+        );
+        // one track of both is the same
+        let single = vec![
+            tempo[0],
+            midi(48, 0, note(60, 100)),
+            midi(48, 0, note(60, 0)),
+            meta(0, MetaMessage::Tempo(u24::new(500_000))),
+            midi(0, 3, cc(74, 10)),
+            midi(96, 0, note(62, 100)),
+        ];
+        let bytes = smf(Format::SingleTrack, vec![single]);
+        assert_eq!(smf_events(&bytes, RATE).unwrap(), events);
+
+        let bytes = smf(Format::Sequential, vec![notes]);
+        assert!(smf_events(&bytes, RATE).is_err());
+        assert!(smf_events(b"MThd", RATE).is_err());
+    }
+
+    #[test]
+    fn notes_and_ccs_set_the_mapped_ports() {
+        let (mut engine, mut mapper) = mono();
+        let port = |engine: &Engine, name: &str| engine.value(&format!("main/{}", name)).unwrap();
+        mapper.handle(&note(69, 127), &mut engine);
+        assert_eq!(port(&engine, "freq"), 440.0);
+        assert_eq!(port(&engine, "gate"), 1.0);
+        assert_eq!(port(&engine, "vel"), 1.0);
+        mapper.handle(&note(81, 64), &mut engine);
+        assert_eq!(port(&engine, "freq"), 880.0);
+        assert_eq!(port(&engine, "vel"), 64.0 / 127.0);
+        // velocity 0 releases 81, 69 is still held
+        mapper.handle(&note(81, 0), &mut engine);
+        assert_eq!(port(&engine, "freq"), 440.0);
+        assert_eq!(port(&engine, "gate"), 1.0);
+        let (key, vel) = (u7::new(69), u7::new(64));
+        let off = MidiMessage::NoteOff { key, vel };
+        mapper.handle(&off, &mut engine);
+        assert_eq!(port(&engine, "gate"), 0.0);
+        assert_eq!(port(&engine, "freq"), 440.0);
+
+        // a cc glides, and only its own
+        mapper.handle(&cc(1, 127), &mut engine);
+        mapper.handle(&cc(74, 127), &mut engine);
+        assert_eq!(port(&engine, "cutoff"), 0.0);
+        for _ in 0..100 {
+            engine.tick();
+        }
+        assert_eq!(port(&engine, "cutoff"), 1.0);
+    }
+
+    #[test]
+    fn render_applies_each_event_on_its_frame() {
+        let (mut engine, mut mapper) = mono();
+        let events = [(500, note(69, 127)), (1000, note(69, 0))];
+        let mut sink = Frames(Vec::new());
+        render(&mut engine, &mut mapper, &events, 1500, &mut sink).unwrap();
+        let left = sink.0.iter().step_by(2).cloned().collect::<Vec<_>>();
+        assert_eq!(left.len(), 1500);
+        assert!(left[..500].iter().all(|x| *x == 0.0));
+        assert!(left[500] != 0.0);
+        assert!(left[500..1000].iter().any(|x| *x > 0.5));
+        assert!(left[1000..].iter().all(|x| *x == 0.0));
+    }
+}
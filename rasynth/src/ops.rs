@@ -0,0 +1,199 @@
+use crate::ast::Type;
+
+/// number of args an operator takes
+#[derive(Debug, Clone, Copy)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+}
+
+/// a built-in operator of raslisp, (name args...)
+#[derive(Debug)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub arity: Arity,
+    pub signature: &'static str,
+    pub doc: &'static str,
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "+",
+        arity: Arity::AtLeast(1),
+        signature: "(+ a b ...)",
+        doc: "sum of the args",
+    },
+    Builtin {
+        name: "-",
+        arity: Arity::AtLeast(1),
+        signature: "(- a b ...)",
+        doc: "a minus the rest of the args, or -a with a single arg",
+    },
+    Builtin {
+        name: "*",
+        arity: Arity::AtLeast(1),
+        signature: "(* a b ...)",
+        doc: "product of the args",
+    },
+    Builtin {
+        name: "/",
+        arity: Arity::AtLeast(2),
+        signature: "(/ a b ...)",
+        doc: "a divided by the rest of the args, division by zero gives 0",
+    },
+    Builtin {
+        name: ">",
+        arity: Arity::Exact(2),
+        signature: "(> a b)",
+        doc: "1 if a > b, else 0",
+    },
+    Builtin {
+        name: "<",
+        arity: Arity::Exact(2),
+        signature: "(< a b)",
+        doc: "1 if a < b, else 0",
+    },
+    Builtin {
+        name: ">=",
+        arity: Arity::Exact(2),
+        signature: "(>= a b)",
+        doc: "1 if a >= b, else 0",
+    },
+    Builtin {
+        name: "<=",
+        arity: Arity::Exact(2),
+        signature: "(<= a b)",
+        doc: "1 if a <= b, else 0",
+    },
+    Builtin {
+        name: "==",
+        arity: Arity::Exact(2),
+        signature: "(== a b)",
+        doc: "1 if a == b, else 0",
+    },
+    Builtin {
+        name: "min",
+        arity: Arity::Exact(2),
+        signature: "(min a b)",
+        doc: "the smaller of a and b",
+    },
+    Builtin {
+        name: "max",
+        arity: Arity::Exact(2),
+        signature: "(max a b)",
+        doc: "the larger of a and b",
+    },
+    Builtin {
+        name: "abs",
+        arity: Arity::Exact(1),
+        signature: "(abs a)",
+        doc: "absolute value of a",
+    },
+    Builtin {
+        name: "clip",
+        arity: Arity::Exact(3),
+        signature: "(clip x lo hi)",
+        doc: "x limited to the range [lo, hi]",
+    },
+    Builtin {
+        name: "sinwave",
+        arity: Arity::Exact(1),
+        signature: "(sinwave size)",
+        doc: "waveform holding one period of a sine in size samples",
+    },
+    Builtin {
+        name: "idx",
+        arity: Arity::Exact(2),
+        signature: "(idx wave i)",
+        doc: "sample i of wave, i wraps around the waveform size",
+    },
+    Builtin {
+        name: "phasor",
+        arity: Arity::Exact(1),
+        signature: "(phasor freq)",
+        doc: "ramp from 0 to 1 at freq Hz",
+    },
+    Builtin {
+        name: "sin",
+        arity: Arity::Exact(1),
+        signature: "(sin freq)",
+        doc: "sine oscillator at freq Hz, in [-1, 1]",
+    },
+    Builtin {
+        name: "saw",
+        arity: Arity::Exact(1),
+        signature: "(saw freq)",
+        doc: "sawtooth oscillator at freq Hz, in [-1, 1]",
+    },
+    Builtin {
+        name: "square",
+        arity: Arity::Exact(1),
+        signature: "(square freq)",
+        doc: "square oscillator at freq Hz, in [-1, 1]",
+    },
+    Builtin {
+        name: "tri",
+        arity: Arity::Exact(1),
+        signature: "(tri freq)",
+        doc: "triangle oscillator at freq Hz, in [-1, 1]",
+    },
+    Builtin {
+        name: "noise",
+        arity: Arity::Exact(1),
+        signature: "(noise amp)",
+        doc: "white noise in [-amp, amp]",
+    },
+    Builtin {
+        name: "lowpass",
+        arity: Arity::Exact(2),
+        signature: "(lowpass sig cutoff)",
+        doc: "one-pole lowpass filter of sig at cutoff Hz",
+    },
+    Builtin {
+        name: "delay",
+        arity: Arity::Exact(2),
+        signature: "(delay sig n)",
+        doc: "sig delayed by n samples",
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|b| b.name == name)
+}
+
+impl Builtin {
+    pub fn accepts(&self, n_args: usize) -> bool {
+        match self.arity {
+            Arity::Exact(n) => n_args == n,
+            Arity::AtLeast(n) => n_args >= n,
+        }
+    }
+
+    /// type of (self args...) given the types of the args
+    pub fn result_type(&self, args: &[Type]) -> Result<Type, String> {
+        match self.name {
+            "sinwave" => match args {
+                [Type::Int32] => Ok(Type::Waveform),
+                _ => Err("sinwave size must be an i32".to_string()),
+            },
+            "idx" => match args {
+                [Type::Waveform, Type::Waveform] => Err("idx index must be a number".to_string()),
+                [Type::Waveform, _] => Ok(Type::Float),
+                _ => Err("idx needs a waveform as first arg".to_string()),
+            },
+            _ if args.contains(&Type::Waveform) => Err(format!(
+                "{} does not take a waveform, use idx to read its samples",
+                self.name
+            )),
+            ">" | "<" | ">=" | "<=" | "==" => Ok(Type::Int32),
+            "+" | "-" | "*" | "min" | "max" | "abs" | "clip" => {
+                if args.iter().all(|t| *t == Type::Int32) {
+                    Ok(Type::Int32)
+                } else {
+                    Ok(Type::Float)
+                }
+            }
+            _ => Ok(Type::Float),
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use crate::audio::Control;
+use crate::engine::Engine;
+use crate::params::ParamStore;
+use log::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// replies with the addresses of the params
+pub const QUERY: &str = "/rasynth/query";
+pub const REPLY: &str = "/rasynth/params";
+/// longest packet read, larger ones are truncated and fail to decode
+const MAX_PACKET: usize = 65536;
+/// seconds from the NTP epoch, 1900, to the unix one
+const NTP_OFFSET: u64 = 2_208_988_800;
+/// the timetag of a bundle to run at once
+const IMMEDIATELY: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Str(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    /// the arg as a param value, strings have none
+    pub fn value(&self) -> Option<f32> {
+        match self {
+            OscArg::Int(v) => Some(*v as f32),
+            OscArg::Long(v) => Some(*v as f32),
+            OscArg::Float(v) => Some(*v),
+            OscArg::Double(v) => Some(*v as f32),
+            OscArg::Bool(v) => Some(*v as i32 as f32),
+            OscArg::Str(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub addr: String,
+    pub args: Vec<OscArg>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscPacket {
+    Message(OscMessage),
+    Bundle(u64, Vec<OscPacket>), // NTP timetag, 32.32 fixed point
+}
+
+/// a big endian reader over a packet, OSC pads everything to 4 bytes
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.buf.len() {
+            return Err("truncated OSC packet".to_string());
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let rest = &self.buf[self.pos..];
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or("unterminated OSC string")?;
+        let s = String::from_utf8_lossy(&rest[..len]).to_string();
+        self.take((len + 4) & !3)?;
+        Ok(s)
+    }
+}
+
+pub fn decode(buf: &[u8]) -> Result<OscPacket, String> {
+    let mut r = Reader { buf, pos: 0 };
+    if buf.starts_with(b"#bundle\0") {
+        r.take(8)?;
+        let timetag = r.u64()?;
+        let mut packets = Vec::new();
+        while r.pos < buf.len() {
+            let size = r.u32()? as usize;
+            packets.push(decode(r.take(size)?)?);
+        }
+        return Ok(OscPacket::Bundle(timetag, packets));
+    }
+    let addr = r.string()?;
+    if !addr.starts_with('/') {
+        return Err(format!("bad OSC address {}", addr));
+    }
+    // old senders may leave out the type tags, then there are no args
+    let tags = match r.pos < buf.len() {
+        true => r.string()?,
+        false => ",".to_string(),
+    };
+    let mut args = Vec::new();
+    for tag in tags.chars().skip(1) {
+        args.push(match tag {
+            'i' => OscArg::Int(r.u32()? as i32),
+            'h' => OscArg::Long(r.u64()? as i64),
+            'f' => OscArg::Float(f32::from_bits(r.u32()?)),
+            'd' => OscArg::Double(f64::from_bits(r.u64()?)),
+            's' | 'S' => OscArg::Str(r.string()?),
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            'N' | 'I' => continue,
+            'b' => {
+                let size = r.u32()? as usize;
+                r.take((size + 3) & !3)?;
+                continue;
+            }
+            _ => return Err(format!("unsupported OSC type tag {}", tag)),
+        });
+    }
+    Ok(OscPacket::Message(OscMessage { addr, args }))
+}
+
+pub fn encode_message(msg: &OscMessage) -> Vec<u8> {
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(s.as_bytes());
+        out.extend(std::iter::repeat_n(0, 4 - s.len() % 4));
+    }
+    let mut out = Vec::new();
+    string(&mut out, &msg.addr);
+    let mut tags = ",".to_string();
+    let mut data = Vec::new();
+    for arg in msg.args.iter() {
+        match arg {
+            OscArg::Int(v) => {
+                tags.push('i');
+                data.extend_from_slice(&v.to_be_bytes());
+            }
+            OscArg::Long(v) => {
+                tags.push('h');
+                data.extend_from_slice(&v.to_be_bytes());
+            }
+            OscArg::Float(v) => {
+                tags.push('f');
+                data.extend_from_slice(&v.to_be_bytes());
+            }
+            OscArg::Double(v) => {
+                tags.push('d');
+                data.extend_from_slice(&v.to_be_bytes());
+            }
+            OscArg::Str(s) => {
+                tags.push('s');
+                string(&mut data, s);
+            }
+            OscArg::Bool(v) => tags.push(if *v { 'T' } else { 'F' }),
+        }
+    }
+    string(&mut out, &tags);
+    out.extend(data);
+    out
+}
+
+/// OSC address of a param: main/x is /main/x, box/x is /main/box/x and
+/// voice k of box, box#k/x, is /main/box/k/x
+pub fn address(name: &str) -> String {
+    let path = name.replacen('#', "/", 1);
+    match path.starts_with("main/") {
+        true => format!("/{}", path),
+        false => format!("/main/{}", path),
+    }
+}
+
+fn timetag_time(timetag: u64) -> SystemTime {
+    let secs = (timetag >> 32).saturating_sub(NTP_OFFSET);
+    let nanos = ((timetag & 0xffff_ffff) * 1_000_000_000) >> 32;
+    UNIX_EPOCH + Duration::new(secs, nanos as u32)
+}
+
+/// a UDP socket setting params by address, bundles with a timetag in
+/// the future are held until it passes
+pub struct OscServer {
+    socket: UdpSocket,
+    store: Arc<ParamStore>,
+    params: HashMap<String, usize>, // address to store index
+    pending: Vec<(SystemTime, OscMessage)>,
+}
+
+impl OscServer {
+    pub fn open(bind: &str, store: Arc<ParamStore>) -> Result<Self, Box<dyn Error>> {
+        let socket = UdpSocket::bind(bind)?;
+        let params = store
+            .names()
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (address(name), i))
+            .collect::<HashMap<_, _>>();
+        info!(
+            "OSC on udp {}, {} params",
+            socket.local_addr()?,
+            params.len()
+        );
+        Ok(OscServer {
+            socket,
+            store,
+            params,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Box<dyn Error>> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// receive on a thread of its own and set the store, nothing here
+    /// blocks the audio thread
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        thread::Builder::new()
+            .name("osc".to_string())
+            .spawn(move || {
+                let mut buf = vec![0u8; MAX_PACKET];
+                loop {
+                    self.run_due();
+                    let wait = self
+                        .pending
+                        .iter()
+                        .map(|(t, _)| t.duration_since(SystemTime::now()).unwrap_or_default())
+                        .min()
+                        .map(|d| d.max(Duration::from_millis(1)));
+                    if let Err(e) = self.socket.set_read_timeout(wait) {
+                        warn!("OSC: {}", e);
+                    }
+                    let (n, from) = match self.socket.recv_from(&mut buf) {
+                        Ok(x) => x,
+                        Err(e)
+                            if matches!(
+                                e.kind(),
+                                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                            ) =>
+                        {
+                            continue
+                        }
+                        Err(e) => {
+                            warn!("OSC: {}", e);
+                            continue;
+                        }
+                    };
+                    match decode(&buf[..n]) {
+                        Ok(packet) => self.packet(packet, IMMEDIATELY, from),
+                        Err(e) => warn!("OSC from {}: {}", from, e),
+                    }
+                }
+            })
+            .expect("failed to spawn osc thread")
+    }
+
+    /// messages in a bundle wait for the bundle's timetag, a nested
+    /// bundle for the later of its own and its parent's
+    fn packet(&mut self, packet: OscPacket, timetag: u64, from: SocketAddr) {
+        match packet {
+            OscPacket::Bundle(t, packets) => {
+                for p in packets {
+                    self.packet(p, t.max(timetag), from);
+                }
+            }
+            OscPacket::Message(msg) if msg.addr == QUERY => self.query(from),
+            OscPacket::Message(msg) => match timetag {
+                IMMEDIATELY => self.message(&msg),
+                t if timetag_time(t) <= SystemTime::now() => self.message(&msg),
+                t => self.pending.push((timetag_time(t), msg)),
+            },
+        }
+    }
+
+    fn run_due(&mut self) {
+        let now = SystemTime::now();
+        let (due, later) = self.pending.drain(..).partition(|(t, _)| *t <= now);
+        self.pending = later;
+        for (_, msg) in due {
+            self.message(&msg);
+        }
+    }
+
+    /// the first numeric arg sets the param, /main may be left out
+    fn message(&self, msg: &OscMessage) {
+        let index = match self.params.get(&msg.addr) {
+            Some(index) => *index,
+            None => match self.params.get(&format!("/main{}", msg.addr)) {
+                Some(index) => *index,
+                None => {
+                    warn!("OSC: no param at {}", msg.addr);
+                    return;
+                }
+            },
+        };
+        let value = match msg.args.iter().find_map(|a| a.value()) {
+            Some(v) => v,
+            None => {
+                warn!("OSC: {} needs a number, got {:?}", msg.addr, msg.args);
+                return;
+            }
+        };
+        trace!("OSC {} {}", msg.addr, value);
+        self.store.set(index, value);
+    }
+
+    fn query(&self, to: SocketAddr) {
+        let mut addrs = self.params.keys().cloned().collect::<Vec<_>>();
+        addrs.sort();
+        let reply = OscMessage {
+            addr: REPLY.to_string(),
+            args: addrs.into_iter().map(OscArg::Str).collect(),
+        };
+        if let Err(e) = self.socket.send_to(&encode_message(&reply), to) {
+            warn!("OSC reply to {}: {}", to, e);
+        }
+    }
+}
+
+/// open the OSC server and return the control that hands its changes to
+/// the engine before each block
+pub fn open_control(engine: &Engine, bind: &str) -> Result<Control, Box<dyn Error>> {
+    let store = ParamStore::new(engine);
+    OscServer::open(bind, store.clone())?.spawn();
+    Ok(store.control())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(addr: &str, args: Vec<OscArg>) -> OscMessage {
+        let addr = addr.to_string();
+        OscMessage { addr, args }
+    }
+
+    /// a bundle of encoded packets, each after its size
+    fn bundle(timetag: u64, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = b"#bundle\0".to_vec();
+        out.extend_from_slice(&timetag.to_be_bytes());
+        for p in packets.iter() {
+            out.extend_from_slice(&(p.len() as u32).to_be_bytes());
+            out.extend_from_slice(p);
+        }
+        out
+    }
+
+    #[test]
+    fn messages_decode_as_encoded() {
+        let msg = message(
+            "/main/freq",
+            vec![
+                OscArg::Int(-3),
+                OscArg::Long(1 << 40),
+                OscArg::Float(440.0),
+                OscArg::Double(0.25),
+                OscArg::Str("abc".to_string()),
+                OscArg::Str("four".to_string()),
+                OscArg::Bool(true),
+                OscArg::Bool(false),
+            ],
+        );
+        let buf = encode_message(&msg);
+        assert_eq!(buf.len() % 4, 0);
+        assert_eq!(decode(&buf), Ok(OscPacket::Message(msg)));
+        let bare = message("/rasynth/query", Vec::new());
+        let buf = encode_message(&bare);
+        assert_eq!(decode(&buf), Ok(OscPacket::Message(bare.clone())));
+        // an old sender's message without type tags has no args
+        assert_eq!(decode(b"/rasynth/query\0\0"), Ok(OscPacket::Message(bare)));
+    }
+
+    #[test]
+    fn bundles_nest() {
+        let a = message("/main/a", vec![OscArg::Float(1.0)]);
+        let b = message("/main/b", vec![OscArg::Int(2)]);
+        let inner = bundle(IMMEDIATELY, &[encode_message(&b)]);
+        let outer = bundle(7 << 32, &[encode_message(&a), inner]);
+        assert_eq!(
+            decode(&outer),
+            Ok(OscPacket::Bundle(
+                7 << 32,
+                vec![
+                    OscPacket::Message(a),
+                    OscPacket::Bundle(IMMEDIATELY, vec![OscPacket::Message(b)])
+                ]
+            ))
+        );
+        assert_eq!(
+            decode(&bundle(IMMEDIATELY, &[])),
+            Ok(OscPacket::Bundle(1, vec![]))
+        );
+    }
+
+    #[test]
+    fn short_and_unterminated_packets_are_errors() {
+        let msg = message(
+            "/main/x",
+            vec![OscArg::Str("name".to_string()), OscArg::Double(1.0)],
+        );
+        let buf = encode_message(&msg);
+        // cut anywhere past the address, which alone is a message
+        for len in 9..buf.len() {
+            assert!(decode(&buf[..len]).is_err(), "{} bytes", len);
+        }
+        assert!(decode(b"/main/x").is_err());
+        assert!(decode(b"main/x\0\0").is_err());
+        assert!(decode(b"/main/x\0,z\0\0").is_err());
+        let whole = bundle(IMMEDIATELY, &[buf]);
+        assert!(decode(&whole[..whole.len() - 4]).is_err());
+        assert!(decode(&whole[..18]).is_err());
+        assert!(decode(&whole[..12]).is_err());
+    }
+
+    #[test]
+    fn param_addresses() {
+        assert_eq!(address("main/x"), "/main/x");
+        assert_eq!(address("box/x"), "/main/box/x");
+        assert_eq!(address("box#2/x"), "/main/box/2/x");
+    }
+}
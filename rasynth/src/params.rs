@@ -0,0 +1,279 @@
+use crate::audio::Control;
+use crate::engine::Engine;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// what params set from outside glide with unless told otherwise
+pub const DEFAULT_SMOOTHING: Smoothing = Smoothing::OnePole(0.005);
+/// how close a one-pole glide has to get before it snaps to its target
+const SETTLED: f32 = 1e-5;
+
+/// how a param moves to a value set from outside, times are in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Smoothing {
+    Step,
+    OnePole(f32), // time constant
+    Ramp(f32),    // length of a straight line to the target
+}
+
+impl std::str::FromStr for Smoothing {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (kind, time) = match s.split_once(':') {
+            Some((kind, time)) => match time.parse::<f32>() {
+                Ok(t) if t >= 0.0 => (kind, t),
+                _ => return Err(format!("bad smoothing time {}", time)),
+            },
+            None => (s, 0.0),
+        };
+        match kind {
+            "step" => Ok(Smoothing::Step),
+            "pole" => Ok(Smoothing::OnePole(time)),
+            "ramp" => Ok(Smoothing::Ramp(time)),
+            _ => Err(format!(
+                "unknown smoothing {}, use step, pole:SECONDS or ramp:SECONDS",
+                s
+            )),
+        }
+    }
+}
+
+/// a param on its way to a target, advanced by the engine every sample
+#[derive(Debug, Clone)]
+pub struct Smoother {
+    pub smoothing: Smoothing,
+    target: f32,
+    step: f32, // per sample for a ramp, the coefficient for a pole
+    left: u32, // samples to go, 0 when settled
+}
+
+impl Smoother {
+    pub fn new(smoothing: Smoothing) -> Self {
+        Smoother {
+            smoothing,
+            target: 0.0,
+            step: 0.0,
+            left: 0,
+        }
+    }
+
+    /// head from current to target, false if there is nothing to glide
+    pub fn start(&mut self, current: f32, target: f32, sample_rate: f32) -> bool {
+        self.target = target;
+        let samples = |t: f32| (t * sample_rate).round() as u32;
+        match self.smoothing {
+            Smoothing::Ramp(t) if samples(t) > 0 && current != target => {
+                self.left = samples(t);
+                self.step = (target - current) / self.left as f32;
+            }
+            Smoothing::OnePole(t) if samples(t) > 0 && current != target => {
+                self.left = u32::MAX;
+                self.step = 1.0 - (-1.0 / (t * sample_rate)).exp();
+            }
+            _ => self.left = 0,
+        }
+        self.active()
+    }
+
+    pub fn stop(&mut self) {
+        self.left = 0;
+    }
+
+    pub fn active(&self) -> bool {
+        self.left > 0
+    }
+
+    /// the value one sample on from current
+    pub fn next(&mut self, current: f32) -> f32 {
+        let x = match self.smoothing {
+            Smoothing::Ramp(_) if self.left > 1 => current + self.step,
+            Smoothing::OnePole(_) => current + (self.target - current) * self.step,
+            _ => self.target,
+        };
+        self.left = self.left.saturating_sub(1);
+        if (self.target - x).abs() <= SETTLED * (1.0 + self.target.abs()) {
+            self.left = 0;
+            return self.target;
+        }
+        x
+    }
+}
+
+/// the engine's params shared with control threads without a lock, a
+/// control thread stores a value and flags it, the audio thread takes
+/// the flagged ones before each block and glides to them
+pub struct ParamStore {
+    names: Vec<String>,
+    values: Vec<AtomicU32>, // f32 bits
+    changed: Vec<AtomicBool>,
+    any: AtomicBool,
+}
+
+impl ParamStore {
+    pub fn new(engine: &Engine) -> Arc<Self> {
+        let names = engine.params();
+        let values = names
+            .iter()
+            .map(|name| AtomicU32::new(engine.value(name).unwrap_or(0.0).to_bits()))
+            .collect();
+        let changed = names.iter().map(|_| AtomicBool::new(false)).collect();
+        Arc::new(ParamStore {
+            names,
+            values,
+            changed,
+            any: AtomicBool::new(false),
+        })
+    }
+
+    /// param names, a param's position is its index
+    pub fn names(&self) -> &Vec<String> {
+        &self.names
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+
+    /// the value last set, not where the engine's glide is
+    pub fn get(&self, index: usize) -> f32 {
+        f32::from_bits(self.values[index].load(Ordering::Relaxed))
+    }
+
+    /// from any thread, a value set twice before the audio thread looks
+    /// only glides to the second
+    pub fn set(&self, index: usize, value: f32) {
+        self.values[index].store(value.to_bits(), Ordering::Relaxed);
+        self.changed[index].store(true, Ordering::Release);
+        self.any.store(true, Ordering::Release);
+    }
+
+    /// on the audio thread, start the glides to what changed, by name
+    /// since a reload moves the slots, params it removed are skipped
+    pub fn apply(&self, engine: &mut Engine) {
+        if !self.any.swap(false, Ordering::Acquire) {
+            return;
+        }
+        for (i, name) in self.names.iter().enumerate() {
+            if !self.changed[i].swap(false, Ordering::Acquire) {
+                continue;
+            }
+            if let Some(slot) = engine.param(name) {
+                engine.glide_slot(slot, self.get(i));
+            }
+        }
+    }
+
+    /// the control that applies the store before each block
+    pub fn control(self: &Arc<Self>) -> Control {
+        let store = self.clone();
+        Box::new(move |engine: &mut Engine| store.apply(engine))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::FlowGraph;
+
+    const RATE: f32 = 1000.0;
+
+    /// the values a smoother goes through from 0 towards 1
+    fn glide(smoothing: Smoothing, samples: usize) -> (Smoother, Vec<f32>) {
+        let mut s = Smoother::new(smoothing);
+        s.start(0.0, 1.0, RATE);
+        let mut x = 0.0;
+        let values = (0..samples)
+            .map(|_| {
+                x = s.next(x);
+                x
+            })
+            .collect();
+        (s, values)
+    }
+
+    #[test]
+    fn smoothers_reach_the_target_in_their_time() {
+        let mut s = Smoother::new(Smoothing::Step);
+        assert!(!s.start(0.0, 1.0, RATE));
+        assert_eq!(s.next(0.0), 1.0);
+        // nothing to glide to, or no time to do it in
+        assert!(!Smoother::new(Smoothing::Ramp(0.01)).start(1.0, 1.0, RATE));
+        assert!(!Smoother::new(Smoothing::OnePole(0.0)).start(0.0, 1.0, RATE));
+
+        // 10 ms at 1 kHz is 10 samples in a straight line
+        let (s, values) = glide(Smoothing::Ramp(0.01), 10);
+        for (k, v) in values.iter().enumerate() {
+            assert!((v - (k + 1) as f32 / 10.0).abs() < 1e-6, "{} {}", k, v);
+        }
+        assert_eq!(values[9], 1.0);
+        assert!(!s.active());
+
+        // a pole is 1 - 1/e of the way after its time constant and
+        // snaps to the target once close
+        let (s, values) = glide(Smoothing::OnePole(0.01), 200);
+        assert!(
+            (values[9] - (1.0 - (-1.0f32).exp())).abs() < 1e-3,
+            "{}",
+            values[9]
+        );
+        assert!(values.windows(2).all(|w| w[0] <= w[1]));
+        let settled = values.iter().position(|v| *v == 1.0).unwrap();
+        assert!((100..130).contains(&settled), "{}", settled);
+        assert!(!s.active());
+    }
+
+    #[test]
+    fn apply_glides_only_what_changed() {
+        let src = "\
+(box main (
+    in  a: float = 1.0
+    in  b: float = 5.0
+    out l: float
+)
+    (let l (+ a b))
+)
+";
+        let graph = FlowGraph::from_source(src).unwrap();
+        let mut engine = Engine::new(&graph, RATE);
+        engine
+            .set_smoothing("main/a", Smoothing::Ramp(0.01))
+            .unwrap();
+        engine
+            .set_smoothing("main/b", Smoothing::Ramp(0.01))
+            .unwrap();
+        let store = ParamStore::new(&engine);
+        assert_eq!(store.names(), &["main/a", "main/b"]);
+        let (a, b) = (
+            store.index("main/a").unwrap(),
+            store.index("main/b").unwrap(),
+        );
+        assert_eq!(store.get(b), 5.0);
+
+        // b is set behind the engine's back, a from the store
+        engine.set_param("main/b", 7.0).unwrap();
+        store.set(a, 2.0);
+        store.set(a, 3.0);
+        store.apply(&mut engine);
+        let mut a_values = Vec::new();
+        for _ in 0..10 {
+            engine.tick();
+            a_values.push(engine.value("main/a").unwrap());
+            assert_eq!(engine.value("main/b"), Some(7.0));
+        }
+        assert!((a_values[4] - 2.0).abs() < 1e-6, "{:?}", a_values);
+        assert_eq!(a_values[9], 3.0);
+        // nothing is set again until something changes
+        engine.set_param("main/a", 0.0).unwrap();
+        store.apply(&mut engine);
+        engine.tick();
+        assert_eq!(engine.value("main/a"), Some(0.0));
+
+        // a param a reload removed is skipped
+        let src = src.replace("    in  b: float = 5.0\n", "");
+        let graph = FlowGraph::from_source(&src.replace("(+ a b)", "a")).unwrap();
+        let mut reloaded = Engine::new(&graph, RATE);
+        store.set(b, 1.0);
+        store.apply(&mut reloaded);
+        assert_eq!(reloaded.value("main/b"), None);
+    }
+}
@@ -0,0 +1,502 @@
+use crate::ast::{Port as AstPort, Type};
+use crate::engine::{self, Engine};
+use crate::fmt::format_type;
+use crate::graph::FlowGraph;
+use argparse::{ArgumentParser, List, Store, StoreOption};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{stderr, stdout};
+use std::path::{Path, PathBuf};
+
+/// a settable in port of main and its value, what a preset keeps
+#[derive(Debug, Clone)]
+pub struct Port {
+    pub name: String, // node name, main/x
+    pub ty: Type,
+    pub value: f32,
+}
+
+/// the settable in ports of a patch's main box with their values
+pub fn ports(graph: &FlowGraph, engine: &Engine) -> Vec<Port> {
+    let settable = engine.params();
+    let ast = graph.ast.lock().unwrap();
+    let Some(top) = ast.as_ref() else {
+        return Vec::new();
+    };
+    top.boxes()
+        .iter()
+        .filter(|b| b.name() == "main")
+        .flat_map(|b| b.ports())
+        .filter_map(|port| match port {
+            AstPort::In(name, ty, ..) => Some((format!("main/{}", name), ty)),
+            _ => None,
+        })
+        .filter(|(name, _)| settable.contains(name))
+        .map(|(name, ty)| Port {
+            value: engine.value(&name).unwrap_or(0.0),
+            name,
+            ty,
+        })
+        .collect()
+}
+
+/// one port's value with the type it had when saved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Saved {
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub value: f32,
+}
+
+/// the values of main's settable in ports, kept in PATCH.presets/NAME.json
+/// next to the patch, ports by their name in main
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    #[serde(skip)]
+    pub name: String,
+    pub ports: BTreeMap<String, Saved>,
+}
+
+/// how a preset and the patch it is applied to disagree
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    Gone(String),                  // saved, not a settable port any more
+    Added(String),                 // a port the preset has no value for
+    Retyped(String, String, Type), // saved as one type, is another now
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Gone(port) => write!(f, "{} is gone from the patch", port),
+            Mismatch::Added(port) => write!(f, "{} is new, it keeps its value", port),
+            Mismatch::Retyped(port, was, is) => {
+                write!(f, "{} was {}, is {} now", port, was, format_type(is))
+            }
+        }
+    }
+}
+
+fn short(name: &str) -> &str {
+    name.trim_start_matches("main/")
+}
+
+/// the directory a patch's presets are in, osc1.raslisp keeps them in
+/// osc1.presets
+pub fn dir(patch: &Path) -> PathBuf {
+    patch.with_extension("presets")
+}
+
+/// the file of a preset, the name is checked so it cannot point out
+/// of the presets directory
+fn path(patch: &Path, name: &str) -> Result<PathBuf, String> {
+    check_name(name)?;
+    Ok(dir(patch).join(format!("{}.json", name)))
+}
+
+/// names are file names: letters, digits, - and _
+pub fn check_name(name: &str) -> Result<(), String> {
+    let ok = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    match !name.is_empty() && name.chars().all(ok) {
+        true => Ok(()),
+        false => Err(format!(
+            "bad preset name {:?}, use letters, digits, - and _",
+            name
+        )),
+    }
+}
+
+/// the names of a patch's presets, sorted, none when it has no
+/// presets directory
+pub fn list(patch: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let dir = dir(patch);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut names = std::fs::read_dir(&dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().map(|e| e == "json").unwrap_or(false))
+        .filter_map(|p| p.file_stem().map(|s| s.to_string_lossy().to_string()))
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+/// every preset of a patch that reads, the others are logged
+pub fn load_all(patch: &Path) -> Vec<Preset> {
+    let names = list(patch).unwrap_or_else(|e| {
+        warn!("Cannot list {}: {}", dir(patch).display(), e);
+        Vec::new()
+    });
+    names
+        .iter()
+        .filter_map(|name| match Preset::load(patch, name) {
+            Ok(preset) => Some(preset),
+            Err(e) => {
+                warn!("Skipping preset {}: {}", name, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// the first of preset1, preset2, ... not in names
+pub fn next_name(names: &[String]) -> String {
+    (1..)
+        .map(|k| format!("preset{}", k))
+        .find(|n| !names.contains(n))
+        .unwrap()
+}
+
+pub fn rename(patch: &Path, from: &str, to: &str) -> Result<(), Box<dyn Error>> {
+    let (old, new) = (path(patch, from)?, path(patch, to)?);
+    if !old.exists() {
+        return Err(format!("no preset named {}", from).into());
+    }
+    if new.exists() {
+        return Err(format!("there is a preset named {} already", to).into());
+    }
+    std::fs::rename(old, new)?;
+    Ok(())
+}
+
+pub fn delete(patch: &Path, name: &str) -> Result<(), Box<dyn Error>> {
+    std::fs::remove_file(path(patch, name)?).map_err(|e| format!("preset {}: {}", name, e))?;
+    Ok(())
+}
+
+impl Preset {
+    /// a preset of the ports as they are
+    pub fn capture(name: &str, ports: &[Port]) -> Self {
+        let ports = ports
+            .iter()
+            .map(|p| {
+                let saved = Saved {
+                    ty: format_type(&p.ty).to_string(),
+                    value: p.value,
+                };
+                (short(&p.name).to_string(), saved)
+            })
+            .collect();
+        Preset {
+            name: name.to_string(),
+            ports,
+        }
+    }
+
+    pub fn load(patch: &Path, name: &str) -> Result<Self, Box<dyn Error>> {
+        let file = path(patch, name)?;
+        let text =
+            std::fs::read_to_string(&file).map_err(|e| format!("{}: {}", file.display(), e))?;
+        let mut preset: Preset =
+            serde_json::from_str(&text).map_err(|e| format!("{}: {}", file.display(), e))?;
+        preset.name = name.to_string();
+        Ok(preset)
+    }
+
+    /// write the preset, over one of the same name
+    pub fn save(&self, patch: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        let file = path(patch, &self.name)?;
+        std::fs::create_dir_all(dir(patch))?;
+        std::fs::write(&file, serde_json::to_string_pretty(self)? + "\n")?;
+        Ok(file)
+    }
+
+    /// the saved value of a port, if the type still matches
+    fn value(&self, port: &Port) -> Option<f32> {
+        let saved = self.ports.get(short(&port.name))?;
+        match saved.ty == format_type(&port.ty) {
+            true => Some(saved.value),
+            false => None,
+        }
+    }
+
+    /// what changed in the patch since the preset was saved
+    pub fn check(&self, ports: &[Port]) -> Vec<Mismatch> {
+        let mut found = Vec::new();
+        for (name, saved) in self.ports.iter() {
+            match ports.iter().find(|p| short(&p.name) == name) {
+                None => found.push(Mismatch::Gone(name.clone())),
+                Some(p) if saved.ty != format_type(&p.ty) => found.push(Mismatch::Retyped(
+                    name.clone(),
+                    saved.ty.clone(),
+                    p.ty.clone(),
+                )),
+                Some(_) => {}
+            }
+        }
+        for p in ports.iter() {
+            if !self.ports.contains_key(short(&p.name)) {
+                found.push(Mismatch::Added(short(&p.name).to_string()));
+            }
+        }
+        found
+    }
+
+    /// the values to set, ports the preset does not fit are left out
+    pub fn apply(&self, ports: &[Port]) -> Vec<(String, f32)> {
+        ports
+            .iter()
+            .filter_map(|p| self.value(p).map(|v| (p.name.clone(), v)))
+            .collect()
+    }
+}
+
+/// the values t of the way from a to b, 0 is a and 1 is b, floats move
+/// in a line, i32s round and waveforms switch half way, a port one of
+/// them does not fit stays where it is
+pub fn morph(a: &Preset, b: &Preset, t: f32, ports: &[Port]) -> Vec<(String, f32)> {
+    let t = t.clamp(0.0, 1.0);
+    ports
+        .iter()
+        .map(|p| {
+            let from = a.value(p).unwrap_or(p.value);
+            let to = b.value(p).unwrap_or(p.value);
+            let value = match p.ty {
+                Type::Float => from + (to - from) * t,
+                Type::Int32 => (from + (to - from) * t).round(),
+                Type::Waveform if t < 0.5 => from,
+                Type::Waveform => to,
+            };
+            (p.name.clone(), value)
+        })
+        .collect()
+}
+
+/// build a patch for its ports and their starting values
+fn open(patch: &str) -> Result<Vec<Port>, Box<dyn Error>> {
+    let graph = FlowGraph::from_file(Path::new(patch))?;
+    let engine = Engine::new(&graph, engine::DEFAULT_SAMPLE_RATE);
+    Ok(ports(&graph, &engine))
+}
+
+fn print_values(values: &[(String, f32)]) {
+    for (name, value) in values.iter() {
+        println!("{} = {}", short(name), value);
+    }
+}
+
+/// print the morph of two presets, and save it when asked
+fn morph_command(
+    path: &Path,
+    ports: &[Port],
+    from: &str,
+    to: &str,
+    t: &str,
+    save: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let t = t
+        .parse::<f32>()
+        .map_err(|e| format!("bad position {}: {}", t, e))?;
+    let (a, b) = (Preset::load(path, from)?, Preset::load(path, to)?);
+    for m in a.check(ports).iter().chain(b.check(ports).iter()) {
+        warn!("{}", m);
+    }
+    let values = morph(&a, &b, t, ports);
+    print_values(&values);
+    if let Some(name) = save {
+        let mut ports = ports.to_vec();
+        for (port, (_, value)) in ports.iter_mut().zip(values) {
+            port.value = value;
+        }
+        let file = Preset::capture(name, &ports).save(path)?;
+        println!("wrote {}", file.display());
+    }
+    Ok(())
+}
+
+/// rasynth preset PATCH [ACTION ...] - keep and recall param settings
+pub fn command(args: Vec<String>) {
+    let mut patch = String::new();
+    let mut action = "list".to_string();
+    let mut rest: Vec<String> = Vec::new();
+    let mut save: Option<String> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "Keep and recall the values of the main box's in ports: list, \
+             save NAME [PORT=VALUE...], show NAME, rename FROM TO, delete NAME, \
+             morph FROM TO T",
+        );
+        ap.refer(&mut patch).required().add_argument(
+            "patch",
+            Store,
+            "Raslisp file the presets belong to",
+        );
+        ap.refer(&mut action)
+            .add_argument("action", Store, "What to do, list by default");
+        ap.refer(&mut rest)
+            .add_argument("arguments", List, "Arguments for the action");
+        ap.refer(&mut save).add_option(
+            &["--save"],
+            StoreOption,
+            "With morph, save the values as this preset",
+        );
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    let path = Path::new(&patch);
+    let ports = match open(&patch) {
+        Ok(ports) => ports,
+        Err(e) => {
+            error!("{}: {}", patch, e);
+            std::process::exit(2);
+        }
+    };
+    let words = rest.iter().map(String::as_str).collect::<Vec<_>>();
+    let result: Result<(), Box<dyn Error>> = match (action.as_str(), &words[..]) {
+        ("list", []) => list(path).map(|names| {
+            for name in names {
+                println!("{}", name);
+            }
+        }),
+        // the patch's starting values with the ones given changed
+        ("save", [name, values @ ..]) => {
+            let mut ports = ports.clone();
+            let mut result = Ok(());
+            for v in values.iter() {
+                let set = v.split_once('=').and_then(|(port, value)| {
+                    let port = ports.iter_mut().find(|p| short(&p.name) == port)?;
+                    port.value = value.parse::<f32>().ok()?;
+                    Some(())
+                });
+                if set.is_none() {
+                    result = Err(format!("{} is not PORT=VALUE for a settable port", v).into());
+                }
+            }
+            result
+                .and_then(|_| Preset::capture(name, &ports).save(path))
+                .map(|file| println!("wrote {}", file.display()))
+        }
+        ("show", [name]) => Preset::load(path, name).map(|preset| {
+            print_values(&preset.apply(&ports));
+            for m in preset.check(&ports) {
+                println!("warning: {}", m);
+            }
+        }),
+        ("rename", [from, to]) => rename(path, from, to),
+        ("delete", [name]) => delete(path, name),
+        ("morph", [from, to, t]) => morph_command(path, &ports, from, to, t, save.as_deref()),
+        _ => Err(format!(
+            "bad preset action {} {}, see --help",
+            action,
+            rest.join(" ")
+        )
+        .into()),
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_cannot_leave_the_presets_directory() {
+        let patch = Path::new("/tmp/osc1.raslisp");
+        assert_eq!(
+            path(patch, "bright-2").unwrap(),
+            Path::new("/tmp/osc1.presets/bright-2.json")
+        );
+        for name in ["../osc1", "a/b", "/etc/passwd", ""] {
+            assert!(path(patch, name).is_err(), "{:?}", name);
+            assert!(Preset::load(patch, name).is_err());
+            assert!(delete(patch, name).is_err());
+            assert!(rename(patch, name, "ok").is_err());
+        }
+    }
+    fn port(name: &str, ty: Type, value: f32) -> Port {
+        let name = format!("main/{}", name);
+        Port { name, ty, value }
+    }
+
+    #[test]
+    fn check_finds_what_changed_and_apply_skips_it() {
+        let saved = [
+            port("freq", Type::Float, 440.0),
+            port("voices", Type::Int32, 4.0),
+            port("wave", Type::Float, 0.5),
+            port("old", Type::Float, 1.0),
+        ];
+        let preset = Preset::capture("a", &saved);
+        assert_eq!(preset.ports["freq"].ty, "float");
+        let now = [
+            port("freq", Type::Float, 220.0),
+            port("voices", Type::Int32, 8.0),
+            port("wave", Type::Waveform, 0.0),
+            port("new", Type::Float, 3.0),
+        ];
+        assert_eq!(
+            preset.check(&now),
+            [
+                Mismatch::Gone("old".to_string()),
+                Mismatch::Retyped("wave".to_string(), "float".to_string(), Type::Waveform),
+                Mismatch::Added("new".to_string()),
+            ]
+        );
+        assert_eq!(
+            preset.apply(&now),
+            [
+                ("main/freq".to_string(), 440.0),
+                ("main/voices".to_string(), 4.0)
+            ]
+        );
+        assert!(preset.check(&saved).is_empty());
+        assert_eq!(preset.apply(&saved).len(), saved.len());
+    }
+
+    #[test]
+    fn morph_moves_floats_rounds_ints_and_switches_waveforms_half_way() {
+        let a = Preset::capture(
+            "a",
+            &[
+                port("freq", Type::Float, 100.0),
+                port("voices", Type::Int32, 1.0),
+                port("table", Type::Waveform, 0.0),
+            ],
+        );
+        let b = Preset::capture(
+            "b",
+            &[
+                port("freq", Type::Float, 300.0),
+                port("voices", Type::Int32, 4.0),
+                port("table", Type::Waveform, 2.0),
+            ],
+        );
+        let ports = [
+            port("freq", Type::Float, 0.0),
+            port("voices", Type::Int32, 0.0),
+            port("table", Type::Waveform, 0.0),
+            port("gain", Type::Float, 0.7),
+        ];
+        let at = |t: f32| {
+            morph(&a, &b, t, &ports)
+                .into_iter()
+                .map(|(_, v)| v)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(at(0.0), [100.0, 1.0, 0.0, 0.7]);
+        assert_eq!(at(0.25), [150.0, 2.0, 0.0, 0.7]);
+        assert_eq!(at(0.4), [180.0, 2.0, 0.0, 0.7]);
+        assert_eq!(at(0.5), [200.0, 3.0, 2.0, 0.7]);
+        assert_eq!(at(1.0), [300.0, 4.0, 2.0, 0.7]);
+        // past the ends is held at them
+        assert_eq!(at(-1.0), at(0.0));
+        assert_eq!(at(2.0), at(1.0));
+        // a port one side saved as another type stays where it is
+        let ports = [port("voices", Type::Float, 9.0)];
+        assert_eq!(
+            morph(&a, &b, 0.5, &ports),
+            [("main/voices".to_string(), 9.0)]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use crate::engine::{Engine, Snapshot};
+use crate::graph::FlowGraph;
+use log::*;
+use notify::{EventKind, RecursiveMode, Watcher};
+use rtrb::{Consumer, Producer, PushError, RingBuffer};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// how long the old and the new patch play together after a reload
+pub const DEFAULT_CROSSFADE: f32 = 0.05;
+/// editors write in bursts, wait for the file to stay quiet this long
+const DEBOUNCE: Duration = Duration::from_millis(100);
+/// reloads built before the audio thread took the last one are dropped
+const QUEUE_SIZE: usize = 2;
+/// engines the audio thread is done with and the reload thread has not
+/// dropped yet, it drops them at least this often
+const RETIRED_SIZE: usize = 4;
+const RETIRE_POLL: Duration = Duration::from_millis(500);
+/// how long the audio thread gets to answer a snapshot request
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(1);
+const SNAPSHOT_POLL: Duration = Duration::from_millis(1);
+
+/// the audio thread's end of a watch: rebuilt patches to swap in at a
+/// block boundary, requests for a snapshot of the playing engine, and
+/// a way to hand back engines so they are not dropped on the audio
+/// thread
+pub struct Reloads {
+    pub engines: Consumer<Engine>,
+    pub crossfade: f32, // seconds
+    requests: Consumer<Snapshot>,
+    snapshots: Producer<Snapshot>,
+    retired: Producer<Engine>,
+}
+
+impl Reloads {
+    /// fill the snapshot the reload thread asked for, if it did, the
+    /// copy allocates nothing
+    pub fn serve(&mut self, engine: &Engine) {
+        if let Ok(mut snap) = self.requests.pop() {
+            engine.snapshot_into(&mut snap);
+            // one request is out at a time, so there is room for it
+            let _ = self.snapshots.push(snap);
+        }
+    }
+
+    /// give an engine to the reload thread to drop
+    pub fn retire(&mut self, engine: Engine) {
+        if let Err(PushError::Full(engine)) = self.retired.push(engine) {
+            warn!("Reload thread is behind, dropping an engine on the audio thread");
+            drop(engine);
+        }
+    }
+}
+
+/// watch a patch file and rebuild it on a thread of its own whenever it
+/// changes, a file that fails to build is logged and the playing patch
+/// stays, playing is a copy of the engine the audio thread starts with
+pub fn watch(path: &str, playing: Engine, crossfade: f32) -> Result<Reloads, Box<dyn Error>> {
+    let path = PathBuf::from(path);
+    let (events_tx, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(events_tx)?;
+    // watch the directory, editors often replace the file rather than
+    // write it
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    let (mut tx, engines) = RingBuffer::<Engine>::new(QUEUE_SIZE);
+    let (mut ask, requests) = RingBuffer::<Snapshot>::new(1);
+    let (snapshots, mut answers) = RingBuffer::<Snapshot>::new(1);
+    let (retired, mut to_drop) = RingBuffer::<Engine>::new(RETIRED_SIZE);
+    let mut last = std::fs::read_to_string(&path)?;
+    info!("Watching {} for changes", path.display());
+    thread::Builder::new()
+        .name("reload".to_string())
+        .spawn(move || {
+            let _watcher = watcher; // stops watching when dropped
+            let mut playing = playing;
+            loop {
+                while to_drop.pop().is_ok() {}
+                let event = match events.recv_timeout(RETIRE_POLL) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                match event {
+                    Ok(event)
+                        if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                            && event.paths.iter().any(|p| same_file(p, &path)) => {}
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!("Watching {}: {}", path.display(), e);
+                        continue;
+                    }
+                }
+                while events.recv_timeout(DEBOUNCE).is_ok() {}
+                let src = match std::fs::read_to_string(&path) {
+                    Ok(src) if src == last => continue,
+                    Ok(src) => src,
+                    Err(e) => {
+                        warn!("Cannot read {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                last = src.clone();
+                let graph = match FlowGraph::from_text(&path, &src) {
+                    Ok(graph) => graph,
+                    Err(e) => {
+                        error!("{}: {}, keeping the playing patch", path.display(), e);
+                        continue;
+                    }
+                };
+                let mut engine = Engine::new(&graph, playing.sample_rate);
+                if let Err(e) = engine.map_inputs(playing.inputs()) {
+                    error!("{}: {}, keeping the playing patch", path.display(), e);
+                    continue;
+                }
+                // the audio thread takes reloads before it answers, so
+                // the snapshot is of the engine sent last
+                let snap = match snapshot(&playing, &mut ask, &mut answers) {
+                    Some(snap) => snap,
+                    None => {
+                        error!(
+                            "{}: audio thread did not answer, keeping the playing patch",
+                            path.display()
+                        );
+                        continue;
+                    }
+                };
+                playing.restore(&snap);
+                let kept = engine.adopt(&playing);
+                info!(
+                    "Reloaded {}, {} nodes, kept {}",
+                    path.display(),
+                    engine.nodes.len(),
+                    kept
+                );
+                let copy = engine.clone();
+                match tx.push(engine) {
+                    Ok(()) => playing = copy,
+                    Err(_) => warn!("Audio thread has not taken the last reload, dropped this one"),
+                }
+            }
+        })?;
+    Ok(Reloads {
+        engines,
+        crossfade,
+        requests,
+        snapshots,
+        retired,
+    })
+}
+
+/// the state of the playing engine at a block boundary, copied by the
+/// audio thread into room made here, none if it does not answer
+fn snapshot(
+    playing: &Engine,
+    ask: &mut Producer<Snapshot>,
+    answers: &mut Consumer<Snapshot>,
+) -> Option<Snapshot> {
+    // answers to requests that timed out are stale
+    while answers.pop().is_ok() {}
+    // a request that timed out may still be out, its answer will do
+    let _ = ask.push(Snapshot::new(playing));
+    let started = Instant::now();
+    let snap = loop {
+        match answers.pop() {
+            Ok(snap) => break snap,
+            Err(_) if started.elapsed() > SNAPSHOT_TIMEOUT => return None,
+            Err(_) => thread::sleep(SNAPSHOT_POLL),
+        }
+    };
+    // one made for the engine before a reload the audio thread has
+    // since taken does not fit
+    snap.complete.then_some(snap)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a.file_name() == b.file_name(),
+    }
+}
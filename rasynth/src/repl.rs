@@ -0,0 +1,418 @@
+use crate::ast::*;
+use crate::engine::{self, Engine};
+use crate::fmt;
+use crate::graph::FlowGraph;
+use crate::macros;
+use crate::raslisp;
+use crate::symbol_table::{self, Severity};
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue};
+use std::io::{stderr, stdin, stdout, BufRead, Write};
+
+const HELP: &str = "\
+(box ...) (defmacro ...)  define or redefine a box or macro
+(midi PORT SOURCE)        map MIDI onto an in port of main
+(voices N BOX [STEAL])    play BOX as N voices
+(let x expr) [box args]   add a statement to the current box, which
+                          must be defined, a let with an existing
+                          name replaces it
+expr                      add (let _n expr) to the current box and
+                          show its first samples
+:box NAME                 switch the current box
+:boxes                    list the boxes
+:show [BOX]               print a box
+:nodes                    list the graph nodes
+:edges                    list the graph edges
+:query NODE [N]           run the next N samples, print NODE's values
+:params                   list the free in ports
+:set PARAM VALUE          set a free in port
+:reset                    restart the engine from silence
+:load FILE                load the boxes and macros of a file
+:clear                    forget everything
+:dot [FILE]               write the graph in DOT, to flow.dot
+:help                     this text
+:quit                     leave";
+
+/// rasynth repl [FILE] - build and audition graphs interactively
+pub fn command(args: Vec<String>) {
+    let mut verbose = false;
+    let mut sample_rate = engine::DEFAULT_SAMPLE_RATE;
+    let mut file: Option<String> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Interactive raslisp session");
+        ap.refer(&mut verbose).add_option(
+            &["-v", "--verbose"],
+            StoreTrue,
+            "Keep the graph builder's logs",
+        );
+        ap.refer(&mut sample_rate)
+            .add_option(&["-r", "--sample-rate"], Store, "Sample rate in Hz");
+        ap.refer(&mut file)
+            .add_argument("file", StoreOption, "Raslisp file to start from");
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    if !verbose {
+        // the graph builder traces every node it creates
+        log::set_max_level(log::LevelFilter::Warn);
+    }
+    let mut session = Session::new(sample_rate);
+    if let Some(file) = file {
+        session.run_command(&format!(":load {}", file));
+    }
+    println!("raslisp repl, :help for help");
+    let mut input = String::new();
+    let mut lines = stdin().lock().lines();
+    loop {
+        print!(
+            "{}",
+            if input.is_empty() {
+                "raslisp> "
+            } else {
+                "      .. "
+            }
+        );
+        stdout().flush().unwrap();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        input += &line;
+        input += "\n";
+        if depth(&input) > 0 {
+            continue;
+        }
+        let text = std::mem::take(&mut input);
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        if text == ":quit" || text == ":q" {
+            break;
+        }
+        session.run_command(text);
+    }
+}
+
+/// open parens minus closed ones, ignoring comments
+fn depth(s: &str) -> i32 {
+    let mut d = 0;
+    for line in s.lines() {
+        for c in line.split(';').next().unwrap_or("").chars() {
+            match c {
+                '(' | '[' => d += 1,
+                ')' | ']' => d -= 1,
+                _ => {}
+            }
+        }
+    }
+    d
+}
+
+/// the boxes and macros typed so far and the graph built from them
+struct Session {
+    items: Vec<TopItem>,
+    current_box: String,
+    graph: Option<FlowGraph>,
+    engine: Option<Engine>,
+    sample_rate: f32,
+    expr_counter: usize,
+}
+
+impl Session {
+    fn new(sample_rate: f32) -> Self {
+        Session {
+            items: Vec::new(),
+            current_box: "main".to_string(),
+            graph: None,
+            engine: None,
+            sample_rate,
+            expr_counter: 0,
+        }
+    }
+
+    fn run_command(&mut self, text: &str) {
+        let result = if text.starts_with(':') {
+            self.meta(text)
+        } else {
+            self.define(text)
+        };
+        match result {
+            Ok(out) if !out.is_empty() => println!("{}", out),
+            Ok(_) => {}
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    fn define(&mut self, text: &str) -> Result<String, String> {
+        let saved = self.items.clone();
+        // the first token after the paren, (boxed ...) is an expression
+        let head = text
+            .trim_start()
+            .trim_start_matches('(')
+            .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+            .find(|t| !t.is_empty())
+            .unwrap_or("");
+        let mut preview = None;
+        if matches!(head, "box" | "defmacro" | "midi" | "voices" | "controls")
+            || text.starts_with(';')
+        {
+            let TopDef::Items(items) = raslisp::TopParser::new()
+                .parse(text)
+                .map_err(|e| e.to_string())?;
+            for item in items {
+                self.put_item(item);
+            }
+        } else if head == "let" || text.starts_with('[') {
+            let stmt = raslisp::StmtParser::new()
+                .parse(text)
+                .map_err(|e| e.to_string())?;
+            self.put_stmt(stmt)?;
+        } else {
+            let expr = raslisp::ExprParser::new()
+                .parse(text)
+                .map_err(|e| e.to_string())?;
+            let name = format!("_{}", self.expr_counter);
+            self.put_stmt(Stmt::LetDef(LetDef::Let(
+                name.clone(),
+                expr,
+                Span::default(),
+            )))?;
+            self.expr_counter += 1;
+            preview = Some(name);
+        }
+        if let Err(e) = self.rebuild() {
+            self.items = saved;
+            return Err(e);
+        }
+        let graph = self.graph.as_ref().unwrap();
+        let mut out = format!(
+            "ok, {} nodes, {} edges",
+            graph.nodes.len(),
+            graph.edges.len()
+        );
+        if let Some(name) = preview {
+            // run a copy so the preview doesn't move the session's time
+            let mut engine = self.engine.clone().unwrap();
+            let node = format!("{}/{}", self.current_box, name);
+            out = format!("{} = {}", name, query(&mut engine, &node, 8)?);
+        }
+        Ok(out)
+    }
+
+    /// add a box or macro, replacing the one with the same name
+    fn put_item(&mut self, item: TopItem) {
+        let name = match &item {
+            TopItem::BoxDef(b) => b.name().clone(),
+            TopItem::MacroDef(MacroDef::Macro(name, _, _, _, _)) => name.clone(),
+            TopItem::MidiMap(MidiMap::Map(port, _, _)) => port.clone(),
+            TopItem::VoicesDef(VoicesDef::Voices(_, name, _)) => name.clone(),
+            // there is one set of controls, a new one replaces it
+            TopItem::ControlsDef(_) => "controls".to_string(),
+        };
+        let same = |other: &TopItem| match (other, &item) {
+            (TopItem::BoxDef(a), TopItem::BoxDef(_)) => *a.name() == name,
+            (TopItem::MacroDef(MacroDef::Macro(a, _, _, _, _)), TopItem::MacroDef(_)) => *a == name,
+            (TopItem::MidiMap(MidiMap::Map(a, _, _)), TopItem::MidiMap(_)) => *a == name,
+            (TopItem::VoicesDef(VoicesDef::Voices(_, a, _)), TopItem::VoicesDef(_)) => *a == name,
+            (TopItem::ControlsDef(_), TopItem::ControlsDef(_)) => true,
+            _ => false,
+        };
+        match self.items.iter().position(same) {
+            Some(i) => self.items[i] = item,
+            None => self.items.push(item),
+        }
+    }
+
+    /// add a statement to the current box, a box needs ports to be
+    /// written out so one is never made here
+    fn put_stmt(&mut self, stmt: Stmt) -> Result<(), String> {
+        let current = self.current_box.clone();
+        let pos = self.items.iter().position(|item| match item {
+            TopItem::BoxDef(b) => *b.name() == current,
+            _ => false,
+        });
+        let pos =
+            pos.ok_or_else(|| format!("no box named {}, define it with its ports first", current))?;
+        if let TopItem::BoxDef(BoxDef::ModuleBox(_, _, stmts, _)) = &mut self.items[pos] {
+            if let Stmt::LetDef(LetDef::Let(name, _, _)) = &stmt {
+                let existing = stmts.iter().position(|s| match s {
+                    Stmt::LetDef(LetDef::Let(other, _, _)) => other == name,
+                    _ => false,
+                });
+                if let Some(i) = existing {
+                    stmts[i] = stmt;
+                    return Ok(());
+                }
+            }
+            stmts.push(stmt);
+        }
+        Ok(())
+    }
+
+    /// the same pipeline as --box: expand, check, build the graph
+    fn rebuild(&mut self) -> Result<(), String> {
+        let top = macros::expand(TopDef::Items(self.items.clone()))?;
+        let analysis = symbol_table::analyze(&top);
+        let mut errors = Vec::new();
+        for e in analysis.errors.iter() {
+            match e.severity {
+                Severity::Error => errors.push(e.message.clone()),
+                Severity::Warning if e.name != "main" => println!("warning: {}", e.message),
+                Severity::Warning => {}
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors.join("\nerror: "));
+        }
+        let graph = FlowGraph::build(top)?;
+        self.engine = Some(Engine::new(&graph, self.sample_rate));
+        self.graph = Some(graph);
+        Ok(())
+    }
+
+    fn meta(&mut self, text: &str) -> Result<String, String> {
+        let words = text.split_whitespace().collect::<Vec<_>>();
+        match words[..] {
+            [":help"] => Ok(HELP.to_string()),
+            [":box", name] => {
+                self.current_box = name.to_string();
+                Ok(format!("current box is {}", name))
+            }
+            [":boxes"] => Ok(TopDef::Items(self.items.clone())
+                .boxes()
+                .iter()
+                .map(|b| {
+                    let mark = if *b.name() == self.current_box {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    format!("{} {} ({} ports)", mark, b.name(), b.ports().len())
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+            [":show"] | [":show", _] => {
+                let name = words.get(1).cloned().unwrap_or(&self.current_box);
+                TopDef::Items(self.items.clone())
+                    .boxes()
+                    .iter()
+                    .find(|b| b.name() == name)
+                    .map(|b| fmt::format_box(b).trim_end().to_string())
+                    .ok_or(format!("no box named {}", name))
+            }
+            [":nodes"] => Ok(self
+                .graph()?
+                .nodes
+                .iter()
+                .map(|n| format!("{:?}", n))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            [":edges"] => Ok(self
+                .graph()?
+                .edges
+                .iter()
+                .map(|e| format!("{} -> {} (arg {})", e.from.name, e.to.name, e.arg_no))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            [":query", node] | [":query", node, _] => {
+                let n = match words.get(2) {
+                    Some(n) => n.parse::<usize>().map_err(|e| e.to_string())?,
+                    None => 16,
+                };
+                let node = self.node_name(node);
+                let engine = self.engine.as_mut().ok_or("nothing defined yet")?;
+                query(engine, &node, n)
+            }
+            [":params"] => Ok(self
+                .engine
+                .as_ref()
+                .ok_or("nothing defined yet")?
+                .params()
+                .join("\n")),
+            [":set", param, value] => {
+                let value = value.parse::<f32>().map_err(|e| e.to_string())?;
+                let param = self.node_name(param);
+                let engine = self.engine.as_mut().ok_or("nothing defined yet")?;
+                engine.set_param(&param, value)?;
+                Ok(format!("{} = {}", param, value))
+            }
+            [":reset"] => {
+                self.engine = Some(Engine::new(self.graph()?, self.sample_rate));
+                Ok("engine reset".to_string())
+            }
+            [":load", file] => {
+                let src = std::fs::read_to_string(file).map_err(|e| e.to_string())?;
+                self.define(&src)
+                    .map(|out| format!("loaded {}, {}", file, out))
+            }
+            [":clear"] => {
+                *self = Session::new(self.sample_rate);
+                Ok("cleared".to_string())
+            }
+            [":dot"] | [":dot", _] => {
+                let path = words.get(1).cloned().unwrap_or("flow.dot");
+                self.graph()?
+                    .dump_dot(std::path::Path::new(path))
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                Ok(format!("wrote {}", path))
+            }
+            _ => Err(format!("unknown command {}, try :help", text)),
+        }
+    }
+
+    fn graph(&self) -> Result<&FlowGraph, String> {
+        self.graph.as_ref().ok_or("nothing defined yet".to_string())
+    }
+
+    /// names without a box are looked up in the current box
+    fn node_name(&self, name: &str) -> String {
+        if name.contains('/') {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.current_box, name)
+        }
+    }
+}
+
+/// run n samples and format the values of node
+fn query(engine: &mut Engine, node: &str, n: usize) -> Result<String, String> {
+    let slot = engine.slot(node).ok_or(format!("no node named {}", node))?;
+    let mut values = Vec::new();
+    for _ in 0..n {
+        engine.tick();
+        values.push(format!("{:.4}", engine.values[slot]));
+    }
+    Ok(values
+        .chunks(8)
+        .map(|c| c.join(" "))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements_go_into_a_defined_box_and_show_parses() {
+        let mut session = Session::new(engine::DEFAULT_SAMPLE_RATE);
+        assert!(session.define("(let x 1.0)").is_err());
+        assert!(session.define("(saw 220.0)").is_err());
+        assert!(session.items.is_empty());
+        session
+            .define("(box main (\n    out L: float\n)\n    (let L 0.5)\n)")
+            .unwrap();
+        session.define("(let L (* 0.5 (saw 220.0)))").unwrap();
+        assert!(session
+            .define("(+ 1.0 2.0)")
+            .unwrap()
+            .starts_with("_0 = 3.0000"));
+        let shown = session.meta(":show").unwrap();
+        assert!(raslisp::TopParser::new().parse(&shown).is_ok(), "{}", shown);
+        assert!(shown.contains("(let L (* 0.5 (saw 220.0)))"), "{}", shown);
+        session.meta(":box voice").unwrap();
+        assert!(session.define("(let y 1.0)").is_err());
+    }
+}
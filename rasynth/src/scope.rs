@@ -0,0 +1,215 @@
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// samples the scope keeps, a trace and a spectrum both fit
+pub const SCOPE_LEN: usize = 2048;
+pub const FFT_SIZE: usize = 1024;
+/// the floor of the spectrum
+pub const MIN_DB: f32 = -90.0;
+
+/// the latest samples of one output channel, written by the audio
+/// thread and read by the UI without a lock, a read racing a write may
+/// mix two blocks, which a display does not mind, and the value of one
+/// engine slot at the end of the last block
+pub struct Scope {
+    ring: Vec<AtomicU32>, // f32 bits
+    written: AtomicUsize,
+    channel: AtomicUsize,
+    probe: AtomicUsize,     // slot, usize::MAX for none
+    probed: AtomicU32,      // f32 bits
+    probed_at: AtomicUsize, // the slot probed was taken from
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Scope {
+            ring: (0..SCOPE_LEN).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+            channel: AtomicUsize::new(0),
+            probe: AtomicUsize::new(usize::MAX),
+            probed: AtomicU32::new(0),
+            probed_at: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    pub fn channel(&self) -> usize {
+        self.channel.load(Ordering::Relaxed)
+    }
+
+    /// which channel of the blocks record keeps
+    pub fn set_channel(&self, channel: usize) {
+        self.channel.store(channel, Ordering::Relaxed);
+    }
+
+    /// on the audio thread, keep the scope's channel of a block
+    pub fn record(&self, interleaved: &[f32], channels: usize) {
+        let c = self.channel();
+        if c >= channels {
+            return;
+        }
+        let mut w = self.written.load(Ordering::Relaxed);
+        for frame in interleaved.chunks(channels) {
+            self.ring[w % SCOPE_LEN].store(frame[c].to_bits(), Ordering::Relaxed);
+            w += 1;
+        }
+        self.written.store(w, Ordering::Release);
+    }
+
+    /// which engine slot record_probe keeps, None for none
+    pub fn set_probe(&self, slot: Option<usize>) {
+        self.probe
+            .store(slot.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    /// on the audio thread, keep the value of the probed slot
+    pub fn record_probe(&self, values: &[f32]) {
+        let slot = self.probe.load(Ordering::Relaxed);
+        if let Some(v) = values.get(slot) {
+            self.probed.store(v.to_bits(), Ordering::Relaxed);
+            self.probed_at.store(slot, Ordering::Release);
+        }
+    }
+
+    /// the probed slot's value as of the last block, None until a block
+    /// has been rendered since the probe moved
+    pub fn probed(&self) -> Option<f32> {
+        let slot = self.probed_at.load(Ordering::Acquire);
+        match slot == self.probe.load(Ordering::Relaxed) {
+            true => Some(f32::from_bits(self.probed.load(Ordering::Relaxed))),
+            false => None,
+        }
+    }
+
+    /// the last SCOPE_LEN samples, oldest first
+    pub fn snapshot(&self, out: &mut Vec<f32>) {
+        let w = self.written.load(Ordering::Acquire);
+        out.clear();
+        out.extend(
+            (w..w + SCOPE_LEN)
+                .map(|i| f32::from_bits(self.ring[i % SCOPE_LEN].load(Ordering::Relaxed))),
+        );
+    }
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// where a trace width samples long starts: the first rising zero
+/// crossing that leaves room for it, or the start when there is none,
+/// so a steady wave stands still on the screen
+pub fn trigger(samples: &[f32], width: usize) -> usize {
+    let last = samples.len().saturating_sub(width);
+    (1..last)
+        .find(|i| samples[i - 1] < 0.0 && samples[*i] >= 0.0)
+        .unwrap_or(0)
+}
+
+/// magnitudes of the last FFT_SIZE samples in dB, full scale sine at
+/// 0 dB, through a Hann window
+pub struct Spectrum {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buf: Vec<Complex<f32>>,
+}
+
+impl Spectrum {
+    pub fn new() -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(FFT_SIZE);
+        let window = (0..FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos())
+            .collect();
+        Spectrum {
+            fft,
+            window,
+            buf: vec![Complex::default(); FFT_SIZE],
+        }
+    }
+
+    /// dB of the bins from DC to just below Nyquist, FFT_SIZE / 2 of them
+    pub fn analyze(&mut self, samples: &[f32]) -> Vec<f32> {
+        let tail = &samples[samples.len().saturating_sub(FFT_SIZE)..];
+        for (i, c) in self.buf.iter_mut().enumerate() {
+            let x = tail.get(i).cloned().unwrap_or(0.0);
+            *c = Complex::new(x * self.window[i], 0.0);
+        }
+        self.fft.process(&mut self.buf);
+        let gain = 2.0 / self.window.iter().sum::<f32>();
+        self.buf[..FFT_SIZE / 2]
+            .iter()
+            .map(|c| (20.0 * (c.norm() * gain).log10()).max(MIN_DB))
+            .collect()
+    }
+}
+
+impl Default for Spectrum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a sine of amplitude 1 with cycles periods over FFT_SIZE samples
+    fn sine(len: usize, cycles: f32, phase: f32) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * cycles * i as f32 / FFT_SIZE as f32 + phase).sin())
+            .collect()
+    }
+
+    #[test]
+    fn trigger_finds_the_rising_zero_crossing() {
+        // 16 cycles over FFT_SIZE, 64 samples a period, starting past the crossing
+        let samples = sine(SCOPE_LEN, 16.0, 1.0);
+        let t = trigger(&samples, 240);
+        assert!(t > 0 && t <= 64, "{}", t);
+        assert!(samples[t - 1] < 0.0 && samples[t] >= 0.0);
+        assert!(samples[t + 1] > samples[t]);
+        // the crossing is where the phase wraps to 0
+        let expected = (64.0 * (1.0 - 1.0 / (2.0 * PI))).ceil() as usize;
+        assert_eq!(t, expected);
+        // no room after the crossing, no trigger
+        assert_eq!(trigger(&samples[..100], 240), 0);
+    }
+
+    #[test]
+    fn spectrum_peaks_in_the_sine_bin_at_0_db() {
+        let mut spectrum = Spectrum::new();
+        let db = spectrum.analyze(&sine(SCOPE_LEN, 64.0, 0.3));
+        assert_eq!(db.len(), FFT_SIZE / 2);
+        let peak = (0..db.len())
+            .max_by(|a, b| db[*a].total_cmp(&db[*b]))
+            .unwrap();
+        assert_eq!(peak, 64);
+        assert!(db[64].abs() < 0.1, "{}", db[64]);
+        // far from the sine only the floor is left
+        assert!(db[200] < -60.0, "{}", db[200]);
+    }
+
+    #[test]
+    fn ring_wraps_and_snapshots_oldest_first() {
+        let scope = Scope::new();
+        scope.set_channel(1);
+        let wave = sine(SCOPE_LEN * 5 / 2 + 7, 5.0, 0.0);
+        // stereo blocks of 100 frames, the sine in the right channel
+        for block in wave.chunks(100) {
+            let interleaved = block.iter().flat_map(|x| [0.0, *x]).collect::<Vec<_>>();
+            scope.record(&interleaved, 2);
+        }
+        let mut out = Vec::new();
+        scope.snapshot(&mut out);
+        assert_eq!(out, wave[wave.len() - SCOPE_LEN..]);
+        // a channel the blocks do not have is not recorded
+        scope.set_channel(2);
+        scope.record(&[1.0, 1.0], 2);
+        let mut again = Vec::new();
+        scope.snapshot(&mut again);
+        assert_eq!(again, out);
+    }
+}
@@ -0,0 +1,277 @@
+use crate::audio::Control;
+use crate::engine::Engine;
+use crate::graph::FlowGraph;
+use crate::midi::MidiMapper;
+use alsa::seq::{
+    Addr, ClientIter, EvNote, Event, EventType, MidiEvent, PortCap, PortInfo, PortIter,
+    PortSubscribe, PortType, Seq,
+};
+use alsa::Direction;
+use argparse::{ArgumentParser, Collect, Store};
+use log::*;
+use midly::live::LiveEvent;
+use midly::MidiMessage;
+use rtrb::{Producer, RingBuffer};
+use std::error::Error;
+use std::ffi::CString;
+use std::io::{stderr, stdout, ErrorKind};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub const CLIENT_NAME: &str = "rasynth";
+/// messages the audio thread may fall behind by before new ones are dropped
+pub const QUEUE_SIZE: usize = 1024;
+
+/// rasynth midi list|monitor|send - inspect the ALSA sequencer
+pub fn command(args: Vec<String>) {
+    let mut action = String::new();
+    let mut connect: Vec<String> = Vec::new();
+    let mut to = CLIENT_NAME.to_string();
+    let mut notes: Vec<u8> = Vec::new();
+    let mut length: u64 = 250;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(
+            "ALSA sequencer tools: list the ports, monitor what rasynth would \
+             receive, or send notes as a virtual keyboard",
+        );
+        ap.refer(&mut action)
+            .required()
+            .add_argument("action", Store, "list, monitor or send");
+        ap.refer(&mut connect).add_option(
+            &["-c", "--connect"],
+            Collect,
+            "Source port for monitor, client:port or a name",
+        );
+        ap.refer(&mut to)
+            .add_option(&["--to"], Store, "Destination port for send");
+        ap.refer(&mut notes)
+            .add_option(&["-n", "--note"], Collect, "Note number to send");
+        ap.refer(&mut length)
+            .add_option(&["-l", "--length"], Store, "Note length in ms");
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    let result = match action.as_str() {
+        "list" => list(),
+        "monitor" => monitor(&connect),
+        "send" => send(&to, &notes, Duration::from_millis(length)),
+        _ => Err(format!("unknown midi action {}", action).into()),
+    };
+    if let Err(e) = result {
+        error!("midi {}: {}", action, e);
+        std::process::exit(1);
+    }
+}
+
+/// a sequencer client with one writable port other clients connect to
+pub struct SeqInput {
+    seq: Seq,
+    port: i32,
+}
+
+impl SeqInput {
+    pub fn open(name: &str) -> Result<Self, Box<dyn Error>> {
+        let seq = Seq::open(None, Some(Direction::Capture), false)?;
+        seq.set_client_name(&CString::new(name)?)?;
+        let port = seq.create_simple_port(
+            &CString::new("midi in")?,
+            PortCap::WRITE | PortCap::SUBS_WRITE,
+            PortType::MIDI_GENERIC | PortType::APPLICATION,
+        )?;
+        info!("MIDI input on sequencer port {}:{}", seq.client_id()?, port);
+        Ok(SeqInput { seq, port })
+    }
+
+    pub fn addr(&self) -> Result<Addr, Box<dyn Error>> {
+        Ok(Addr {
+            client: self.seq.client_id()?,
+            port: self.port,
+        })
+    }
+
+    /// subscribe to a readable port, given as client:port or a name
+    pub fn connect(&self, source: &str) -> Result<Addr, Box<dyn Error>> {
+        let sender = find_port(&self.seq, source, PortCap::READ | PortCap::SUBS_READ)?;
+        let subs = PortSubscribe::empty()?;
+        subs.set_sender(sender);
+        subs.set_dest(self.addr()?);
+        self.seq.subscribe_port(&subs)?;
+        info!(
+            "Connected MIDI {} ({}:{})",
+            source, sender.client, sender.port
+        );
+        Ok(sender)
+    }
+
+    /// read events on a thread of its own and push the channel messages
+    /// into tx, nothing here blocks the audio thread
+    pub fn spawn(self, mut tx: Producer<MidiMessage>) -> JoinHandle<()> {
+        thread::Builder::new()
+            .name("midi".to_string())
+            .spawn(move || {
+                let decoder = MidiEvent::new(16).expect("failed to create MIDI decoder");
+                decoder.enable_running_status(false);
+                let mut input = self.seq.input();
+                let mut buf = [0u8; 16];
+                loop {
+                    let mut ev = match input.event_input() {
+                        Ok(ev) => ev,
+                        Err(e) => match std::io::Error::from_raw_os_error(e.errno()).kind() {
+                            // the queue overran, events were lost but the port is fine
+                            ErrorKind::StorageFull | ErrorKind::Interrupted => {
+                                warn!("MIDI input: {}", e);
+                                continue;
+                            }
+                            // anything else will fail again straight away
+                            _ => {
+                                error!("MIDI input: {}, no more MIDI is read", e);
+                                return;
+                            }
+                        },
+                    };
+                    let n = match decoder.decode(&mut buf, &mut ev) {
+                        Ok(n) => n,
+                        Err(_) => continue, // not a MIDI message, e.g. port announcements
+                    };
+                    if let Ok(LiveEvent::Midi { message, .. }) = LiveEvent::parse(&buf[..n]) {
+                        trace!("MIDI {:?}", message);
+                        if tx.push(message).is_err() {
+                            warn!("MIDI queue full, dropped {:?}", message);
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn midi thread")
+    }
+}
+
+/// open the sequencer input, connect it to sources and return the
+/// control that hands its messages to the mapper before each block
+pub fn open_control(
+    graph: &FlowGraph,
+    engine: &Engine,
+    sources: &[String],
+) -> Result<Control, Box<dyn Error>> {
+    let mut mapper = MidiMapper::new(graph, engine)?;
+    let input = SeqInput::open(CLIENT_NAME)?;
+    for source in sources {
+        input.connect(source)?;
+    }
+    let (tx, mut rx) = RingBuffer::<MidiMessage>::new(QUEUE_SIZE);
+    input.spawn(tx);
+    Ok(Box::new(move |engine: &mut Engine| {
+        while let Ok(msg) = rx.pop() {
+            mapper.handle(&msg, engine);
+        }
+    }))
+}
+
+/// address of a port with caps, by client:port or by a client or port
+/// name containing name
+pub fn find_port(seq: &Seq, name: &str, caps: PortCap) -> Result<Addr, Box<dyn Error>> {
+    if let Ok(addr) = name.parse::<Addr>() {
+        return Ok(addr);
+    }
+    for client in ClientIter::new(seq) {
+        let client_name = client.get_name()?.to_string();
+        for port in PortIter::new(seq, client.get_client()) {
+            if !port.get_capability().contains(caps) {
+                continue;
+            }
+            if client_name.contains(name) || port.get_name()?.contains(name) {
+                return Ok(port.addr());
+            }
+        }
+    }
+    Err(format!("no MIDI port matching {}", name).into())
+}
+
+fn list() -> Result<(), Box<dyn Error>> {
+    let seq = Seq::open(None, None, false)?;
+    for client in ClientIter::new(&seq) {
+        for port in PortIter::new(&seq, client.get_client()) {
+            let caps = port.get_capability();
+            let dir = match (
+                caps.contains(PortCap::READ | PortCap::SUBS_READ),
+                caps.contains(PortCap::WRITE | PortCap::SUBS_WRITE),
+            ) {
+                (true, true) => "in/out",
+                (true, false) => "out",
+                (false, true) => "in",
+                (false, false) => continue,
+            };
+            println!(
+                "{:>3}:{:<3} {:<6} {} / {}",
+                client.get_client(),
+                port.get_port(),
+                dir,
+                client.get_name()?,
+                port.get_name()?
+            );
+        }
+    }
+    Ok(())
+}
+
+/// print what arrives at the rasynth port, for checking a keyboard or
+/// the virtual one of send
+fn monitor(sources: &[String]) -> Result<(), Box<dyn Error>> {
+    let input = SeqInput::open(CLIENT_NAME)?;
+    let addr = input.addr()?;
+    for source in sources {
+        input.connect(source)?;
+    }
+    println!("listening on {}:{}", addr.client, addr.port);
+    let (tx, mut rx) = RingBuffer::new(QUEUE_SIZE);
+    input.spawn(tx);
+    loop {
+        while let Ok(msg) = rx.pop() {
+            println!("{:?}", msg);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// a virtual keyboard: plays notes one after the other into a port
+fn send(to: &str, notes: &[u8], length: Duration) -> Result<(), Box<dyn Error>> {
+    let seq = Seq::open(None, Some(Direction::Playback), false)?;
+    seq.set_client_name(&CString::new("rasynth keyboard")?)?;
+    let mut info = PortInfo::empty()?;
+    info.set_capability(PortCap::READ | PortCap::SUBS_READ);
+    info.set_type(PortType::MIDI_GENERIC | PortType::APPLICATION);
+    info.set_name(&CString::new("keys")?);
+    seq.create_port(&info)?;
+    let port = info.get_port();
+    let dest = find_port(&seq, to, PortCap::WRITE | PortCap::SUBS_WRITE)?;
+    let subs = PortSubscribe::empty()?;
+    subs.set_sender(Addr {
+        client: seq.client_id()?,
+        port,
+    });
+    subs.set_dest(dest);
+    seq.subscribe_port(&subs)?;
+    for note in notes {
+        for (t, velocity) in [(EventType::Noteon, 100), (EventType::Noteoff, 0)] {
+            let mut ev = Event::new(
+                t,
+                &EvNote {
+                    channel: 0,
+                    note: *note,
+                    velocity,
+                    off_velocity: 0,
+                    duration: 0,
+                },
+            );
+            ev.set_source(port);
+            ev.set_subs();
+            ev.set_direct();
+            seq.event_output(&mut ev)?;
+            seq.drain_output()?;
+            println!("{:?} {}", t, note);
+            thread::sleep(length);
+        }
+    }
+    Ok(())
+}
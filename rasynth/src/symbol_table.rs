@@ -1,6 +1,392 @@
-use crate::graph::*;
+use crate::ast::*;
+use crate::controls::Binding;
+use crate::midi;
+use crate::ops;
+use crate::voices::{self, Steal};
 use std::collections::*;
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolKind {
+    InPort,
+    OutPort,
+    Let,
+    Wire, // bound to an out port of a box wire
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub ty: Option<Type>, // None until inferred
+}
+
+/// the names visible in one box
+#[derive(Debug, Clone, Default)]
 pub struct SymbolTable {
-    pub table: HashMap<String, Node>,
+    pub table: HashMap<String, Symbol>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// a problem found by analyze, name is the identifier it is about
+/// and box_name the box it was found in
+#[derive(Debug, Clone)]
+pub struct SemaError {
+    pub severity: Severity,
+    pub box_name: Option<String>,
+    pub name: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub tables: HashMap<String, SymbolTable>,
+    pub errors: Vec<SemaError>,
+}
+
+impl Analysis {
+    pub fn has_errors(&self) -> bool {
+        self.errors.iter().any(|e| e.severity == Severity::Error)
+    }
+
+    fn error(&mut self, box_name: &str, name: &str, message: String) {
+        self.errors.push(SemaError {
+            severity: Severity::Error,
+            box_name: Some(box_name.to_string()),
+            name: name.to_string(),
+            message,
+        });
+    }
+
+    fn warning(&mut self, box_name: &str, name: &str, message: String) {
+        self.errors.push(SemaError {
+            severity: Severity::Warning,
+            box_name: Some(box_name.to_string()),
+            name: name.to_string(),
+            message,
+        });
+    }
+}
+
+/// check the boxes of a macro-expanded ast and infer the type of
+/// every let and wire
+pub fn analyze(top: &TopDef) -> Analysis {
+    let mut a = Analysis::default();
+    let boxes = top.boxes();
+    for (i, b) in boxes.iter().enumerate() {
+        if boxes[..i].iter().any(|o| o.name() == b.name()) {
+            a.error(
+                b.name(),
+                b.name(),
+                format!("box {} defined twice", b.name()),
+            );
+        }
+    }
+    // a box has one set of nodes, a second wire would sum its args into
+    // the ports the first one feeds
+    let mut wired: HashMap<&String, &String> = HashMap::new();
+    for b in boxes.iter() {
+        let BoxDef::ModuleBox(_, _, stmts, _) = b;
+        for stmt in stmts {
+            if let Stmt::BoxWire(BoxWire::Boxw(callee, _, _)) = stmt {
+                match wired.get(callee) {
+                    Some(first) => a.error(
+                        b.name(),
+                        callee,
+                        format!(
+                            "box {} is wired by {} already, a box can be wired once",
+                            callee, first
+                        ),
+                    ),
+                    None => {
+                        wired.insert(callee, b.name());
+                    }
+                }
+            }
+        }
+    }
+    if !boxes.iter().any(|b| b.name() == "main") {
+        a.errors.push(SemaError {
+            severity: Severity::Warning,
+            box_name: None,
+            name: "main".to_string(),
+            message: "no main box".to_string(),
+        });
+    }
+    for b in boxes.iter() {
+        let table = analyze_box(b, &boxes, &mut a);
+        a.tables.insert(b.name().clone(), table);
+    }
+    let voices = top.voices();
+    for (i, VoicesDef::Voices(n, name, steal)) in voices.iter().enumerate() {
+        if i > 0 {
+            a.error(name, name, "only one box can have voices".to_string());
+        }
+        if name == "main" {
+            a.error(name, name, "main cannot have voices".to_string());
+        } else if !boxes.iter().any(|b| b.name() == name) {
+            a.error(name, name, format!("unknown box {}", name));
+        }
+        match n {
+            Numeric::Int32(n) if (1..=voices::MAX_VOICES as i32).contains(n) => {}
+            _ => a.error(
+                name,
+                name,
+                format!("voice count must be 1-{}", voices::MAX_VOICES),
+            ),
+        }
+        if let Some(steal) = steal {
+            if let Err(e) = steal.parse::<Steal>() {
+                a.error(name, steal, e);
+            }
+        }
+    }
+    let mut mapped = HashSet::new();
+    for m in top.midi_maps() {
+        let MidiMap::Map(port, _, _) = &m;
+        // note sources go to each voice when there are voices
+        let target = match midi::Source::from_map(&m) {
+            Ok(source) if source.per_voice() => match voices.first() {
+                Some(VoicesDef::Voices(_, name, _)) => name.clone(),
+                None => "main".to_string(),
+            },
+            Ok(_) => "main".to_string(),
+            Err(e) => {
+                a.error("main", port, e);
+                continue;
+            }
+        };
+        match a.tables.get(&target).and_then(|t| t.table.get(port)) {
+            Some(s) if s.kind == SymbolKind::InPort => {}
+            _ => a.error(
+                &target,
+                port,
+                format!("{} has no in port {} to map MIDI to", target, port),
+            ),
+        }
+        if target != "main" {
+            let b = boxes.iter().find(|b| *b.name() == target);
+            let port_def = b.and_then(|b| b.ports().into_iter().find(|p| p.name() == port));
+            if let Some(Port::In(_, _, None, _)) = port_def {
+                a.error(
+                    &target,
+                    port,
+                    format!("{} is set per voice and needs a default value", port),
+                );
+            }
+        }
+        if !mapped.insert((target.clone(), port.clone())) {
+            a.error(&target, port, format!("MIDI mapped to {} twice", port));
+        }
+    }
+    let mut bound = HashSet::new();
+    for m in top.controls() {
+        let ControlMap::Map(control, port, ..) = &m;
+        if let Err(e) = Binding::from_map(&m) {
+            a.error("main", control, e);
+        }
+        match a.tables.get("main").and_then(|t| t.table.get(port)) {
+            Some(s) if s.kind == SymbolKind::InPort => {}
+            _ => a.error(
+                "main",
+                port,
+                format!("main has no in port {} to bind {} to", port, control),
+            ),
+        }
+        if !bound.insert(control.clone()) {
+            a.error("main", control, format!("{} bound twice", control));
+        }
+    }
+    a
+}
+
+fn analyze_box(b: &BoxDef, boxes: &[BoxDef], a: &mut Analysis) -> SymbolTable {
+    let BoxDef::ModuleBox(box_name, _, stmts, _) = b;
+    let mut st = SymbolTable::default();
+    let define = |st: &mut SymbolTable, a: &mut Analysis, sym: Symbol| {
+        match st.table.get(&sym.name) {
+            // a let is how an out port gets its value
+            Some(s) if s.kind == SymbolKind::OutPort && sym.kind == SymbolKind::Let => {}
+            Some(s) => a.error(
+                box_name,
+                &sym.name,
+                format!("{} already defined as {:?}", sym.name, s.kind),
+            ),
+            None => {
+                st.table.insert(sym.name.clone(), sym);
+            }
+        }
+    };
+    for port in b.ports() {
+        let (kind, ty) = match &port {
+            Port::In(_, ty, _, _) => (SymbolKind::InPort, ty),
+            Port::Out(_, ty, _) => (SymbolKind::OutPort, ty),
+        };
+        let sym = Symbol {
+            name: port.name().to_string(),
+            kind,
+            ty: Some(ty.clone()),
+        };
+        define(&mut st, a, sym);
+    }
+    let mut assigned = HashSet::new();
+    for stmt in stmts {
+        match stmt {
+            Stmt::LetDef(LetDef::Let(name, _, _)) => {
+                if !assigned.insert(name.clone()) {
+                    a.error(box_name, name, format!("{} assigned twice", name));
+                }
+                let sym = Symbol {
+                    name: name.clone(),
+                    kind: SymbolKind::Let,
+                    ty: None,
+                };
+                define(&mut st, a, sym);
+            }
+            Stmt::BoxWire(BoxWire::Boxw(callee, args, _)) => {
+                let callee_def = match boxes.iter().find(|o| o.name() == callee) {
+                    Some(c) => c,
+                    None => {
+                        a.error(box_name, callee, format!("unknown box {}", callee));
+                        continue;
+                    }
+                };
+                let resolved = match callee_def.resolve_wire(args) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        a.error(box_name, callee, e);
+                        continue;
+                    }
+                };
+                for (port, expr) in resolved {
+                    if let (Port::Out(_, ty, _), Expr::NodeIdent(wire, _)) = (port, expr) {
+                        if let Some(s) = st.table.get(&wire) {
+                            if s.kind == SymbolKind::OutPort && assigned.insert(wire.clone()) {
+                                continue;
+                            }
+                        }
+                        let sym = Symbol {
+                            name: wire.clone(),
+                            kind: SymbolKind::Wire,
+                            ty: Some(ty),
+                        };
+                        define(&mut st, a, sym);
+                    }
+                }
+            }
+        }
+    }
+    for (name, s) in st.table.iter() {
+        if s.kind == SymbolKind::OutPort && !assigned.contains(name) {
+            a.warning(
+                box_name,
+                name,
+                format!("out port {} is never assigned", name),
+            );
+        }
+        if s.kind == SymbolKind::InPort && assigned.contains(name) {
+            a.error(box_name, name, format!("cannot assign to in port {}", name));
+        }
+    }
+
+    // lets may refer to lets further down, so infer until nothing changes
+    let mut errors = Vec::new();
+    loop {
+        let mut changed = false;
+        errors.clear();
+        for stmt in stmts {
+            if let Stmt::LetDef(LetDef::Let(name, expr, _)) = stmt {
+                match infer(expr, &st) {
+                    Ok(Some(ty)) => {
+                        let sym = st.table.get_mut(name).unwrap();
+                        if sym.kind == SymbolKind::OutPort {
+                            let port_ty = sym.ty.clone().unwrap();
+                            if !assignable(&ty, &port_ty) {
+                                errors.push((name.clone(), mismatch(name, &port_ty, &ty)));
+                            }
+                        } else if sym.ty.is_none() {
+                            sym.ty = Some(ty);
+                            changed = true;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err((ident, e)) => errors.push((ident.unwrap_or(name.clone()), e)),
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    for (name, e) in errors {
+        a.error(box_name, &name, e);
+    }
+    for stmt in stmts {
+        if let Stmt::BoxWire(BoxWire::Boxw(callee, args, _)) = stmt {
+            let resolved = match boxes.iter().find(|o| o.name() == callee) {
+                Some(c) => c.resolve_wire(args).unwrap_or_default(),
+                None => continue,
+            };
+            for (port, expr) in resolved {
+                if let Port::In(port_name, port_ty, _, _) = port {
+                    match infer(&expr, &st) {
+                        Ok(Some(ty)) if !assignable(&ty, &port_ty) => {
+                            a.error(box_name, callee, mismatch(&port_name, &port_ty, &ty))
+                        }
+                        Err((ident, e)) => a.error(box_name, &ident.unwrap_or(callee.clone()), e),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    st
+}
+
+fn assignable(from: &Type, to: &Type) -> bool {
+    from == to || (*from == Type::Int32 && *to == Type::Float)
+}
+
+fn mismatch(name: &str, expected: &Type, found: &Type) -> String {
+    format!("{} expects {:?}, found {:?}", name, expected, found)
+}
+
+/// type of expr, None if it depends on a let not inferred yet,
+/// errors carry the identifier they are about when there is one
+pub fn infer(expr: &Expr, st: &SymbolTable) -> Result<Option<Type>, (Option<String>, String)> {
+    match expr {
+        Expr::Num(Numeric::Int32(_)) => Ok(Some(Type::Int32)),
+        Expr::Num(Numeric::Float(_)) => Ok(Some(Type::Float)),
+        Expr::NodeIdent(name, _) => match st.table.get(name) {
+            Some(s) => Ok(s.ty.clone()),
+            None => Err((Some(name.clone()), format!("unknown name {}", name))),
+        },
+        Expr::Operator(op, args, _) => {
+            let builtin = match ops::lookup(op) {
+                Some(b) => b,
+                None => return Err((Some(op.clone()), format!("unknown operator {}", op))),
+            };
+            if !builtin.accepts(args.len()) {
+                return Err((
+                    Some(op.clone()),
+                    format!("wrong number of args, expected {}", builtin.signature),
+                ));
+            }
+            let mut tys = Vec::new();
+            for arg in args {
+                match infer(arg, st)? {
+                    Some(ty) => tys.push(ty),
+                    None => return Ok(None),
+                }
+            }
+            builtin
+                .result_type(&tys)
+                .map(Some)
+                .map_err(|e| (Some(op.clone()), e))
+        }
+    }
 }
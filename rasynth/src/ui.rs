@@ -0,0 +1,1520 @@
+use crate::ast::{self, Type};
+use crate::audio::{self, AudioThread, Meters, PcmFormat};
+use crate::board;
+use crate::config::{self, BoardError};
+use crate::controls::{self, Bindings, ControlInput};
+use crate::display::{Display, Framebuffer};
+use crate::engine::{self, Engine};
+use crate::graph::FlowGraph;
+use crate::graph_image::{self, Palette};
+use crate::input::{Input, InputEvent, ScriptInput, TermInput};
+use crate::layout::{self, Layout};
+use crate::params::ParamStore;
+use crate::preset::{self, Preset};
+use crate::scope::{trigger, Scope, Spectrum, MIN_DB};
+use argparse::{ArgumentParser, Store, StoreOption};
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_7X13, FONT_7X13_BOLD},
+        MonoTextStyle,
+    },
+    pixelcolor::Rgb565,
+    prelude::*,
+    primitives::{Line, Polyline, PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use log::*;
+use std::error::Error;
+use std::io::{stderr, stdout};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// how often the UI loop looks at its inputs
+const FRAME: Duration = Duration::from_millis(50);
+/// how often the meters are redrawn without any input
+const METER_INTERVAL: Duration = Duration::from_millis(200);
+/// what a peak keeps of itself each frame it is not topped
+const PEAK_DECAY: f32 = 0.85;
+const HEADER_H: i32 = 20;
+const ROW_H: i32 = 16;
+
+const BACKGROUND: Rgb565 = Rgb565::BLACK;
+const FOREGROUND: Rgb565 = Rgb565::WHITE;
+const HEADER: Rgb565 = Rgb565::new(4, 12, 16);
+const CURSOR: Rgb565 = Rgb565::new(8, 16, 8);
+const EDIT: Rgb565 = Rgb565::new(28, 48, 0);
+const DIM: Rgb565 = Rgb565::new(14, 28, 14);
+const WIRE: Rgb565 = Rgb565::new(10, 20, 10);
+const BACK_WIRE: Rgb565 = Rgb565::new(24, 24, 0);
+
+/// the graph page in the UI's colours
+const GRAPH_PALETTE: Palette = Palette {
+    background: BACKGROUND,
+    frame: DIM,
+    wire: WIRE,
+    back_wire: BACK_WIRE,
+    selected: EDIT,
+    selected_text: BACKGROUND,
+    port_fill: CURSOR,
+    port_text: FOREGROUND,
+    op_fill: HEADER,
+    op_text: FOREGROUND,
+    const_fill: BACKGROUND,
+    const_text: DIM,
+};
+/// what can be done to the preset at the cursor
+const PRESET_MENU: [&str; 5] = ["load", "save", "rename", "morph", "delete"];
+/// what a letter of a preset's name turns through, a space drops it
+const NAME_CHARS: &str = " abcdefghijklmnopqrstuvwxyz0123456789-_";
+/// how far one detent morphs
+const MORPH_STEP: f32 = 0.05;
+
+/// rasynth ui [DIR] - the board's UI, on the LCD or the simulator
+pub fn command(args: Vec<String>) {
+    let mut dir = ".".to_string();
+    let mut sim: Option<String> = None;
+    let mut keys: Option<String> = None;
+    let mut controls: Option<String> = None;
+    let board = config::board();
+    let mut backend = if cfg!(feature = "alsa") && !board.is_virtual {
+        "alsa".to_string()
+    } else {
+        "null".to_string()
+    };
+    let mut target = board.audio.device.clone();
+    let mut rate = board.audio.rate;
+    let mut block_size = board.audio.block;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Browse, play and edit patches on the board's display");
+        ap.refer(&mut dir)
+            .add_argument("dir", Store, "Directory of raslisp patches");
+        ap.refer(&mut sim).add_option(
+            &["--sim"],
+            StoreOption,
+            "Draw into a framebuffer and write a PNG per frame into this directory",
+        );
+        ap.refer(&mut keys).add_option(
+            &["-k", "--keys"],
+            StoreOption,
+            "Play these keys instead of reading the terminal, then quit: \
+             w s a d move, e selects, q goes back, + - turn",
+        );
+        ap.refer(&mut controls).add_option(
+            &["-c", "--controls"],
+            StoreOption,
+            "Take input from the board's controls, gpio, or a script of \
+             them, mock:SCRIPT, encoder 0 turns and buttons 0-3 are \
+             select, back, left and right",
+        );
+        ap.refer(&mut backend).add_option(
+            &["-s", "--sink"],
+            Store,
+            "Audio backend: alsa, stdout, null or wav",
+        );
+        ap.refer(&mut target).add_option(
+            &["-o", "--output"],
+            Store,
+            "ALSA device or WAV file path",
+        );
+        ap.refer(&mut rate)
+            .add_option(&["-r", "--rate"], Store, "Sample rate in Hz");
+        ap.refer(&mut block_size)
+            .add_option(&["-b", "--block"], Store, "Frames per block");
+        if let Err(x) = ap.parse(args, &mut stdout(), &mut stderr()) {
+            std::process::exit(x);
+        }
+    }
+    let patches = match scan(Path::new(&dir)) {
+        Ok(patches) => patches,
+        Err(e) => {
+            error!("Cannot list {}: {}", dir, e);
+            std::process::exit(2);
+        }
+    };
+    let input: Box<dyn Input> = match (&keys, &controls) {
+        (Some(keys), _) => match ScriptInput::new(keys) {
+            Ok(input) => Box::new(input),
+            Err(e) => {
+                error!("--keys: {}", e);
+                std::process::exit(2);
+            }
+        },
+        (None, Some(spec)) => match controls::open(spec) {
+            Ok(rx) => Box::new(ControlInput::new(rx)),
+            // missing hardware leaves the terminal, a bad spec is an error
+            Err(e) if e.is::<BoardError>() => {
+                warn!("No controls, reading the terminal: {}", e);
+                Box::new(TermInput::new())
+            }
+            Err(e) => {
+                error!("--controls: {}", e);
+                std::process::exit(2);
+            }
+        },
+        (None, None) => Box::new(TermInput::new()),
+    };
+    let sound = Sound {
+        backend,
+        target,
+        rate,
+        block_size,
+    };
+    let result = match &sim {
+        Some(dir) => Framebuffer::panel()
+            .with_snapshots(Path::new(dir))
+            .and_then(|mut fb| run(&mut fb, input, Ui::new(patches), &sound)),
+        None => match board::open_lcd() {
+            Ok(mut lcd) => run(&mut lcd, input, Ui::new(patches), &sound),
+            // headless, the UI still runs for the terminal and the controls
+            Err(e) => {
+                match e.is_absent() {
+                    true => info!("Running headless: {}", e),
+                    false => warn!("Running headless: {}", e),
+                }
+                run(&mut Framebuffer::panel(), input, Ui::new(patches), &sound)
+            }
+        },
+    };
+    if let Err(e) = result {
+        error!("UI failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// the raslisp files and saved graphs in dir, by name
+pub fn scan(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut patches = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            matches!(
+                p.extension().and_then(|e| e.to_str()),
+                Some("raslisp" | "json")
+            )
+        })
+        .collect::<Vec<_>>();
+    patches.sort();
+    Ok(patches)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Page {
+    Patches,
+    Params,
+    Presets,
+    Meters,
+    Scope,
+    Graph,
+}
+
+const PAGES: [Page; 6] = [
+    Page::Patches,
+    Page::Params,
+    Page::Presets,
+    Page::Meters,
+    Page::Scope,
+    Page::Graph,
+];
+
+impl Page {
+    fn title(&self) -> &'static str {
+        match self {
+            Page::Patches => "PATCHES",
+            Page::Params => "PARAMS",
+            Page::Presets => "PRESETS",
+            Page::Meters => "METERS",
+            Page::Scope => "SCOPE",
+            Page::Graph => "GRAPH",
+        }
+    }
+}
+
+/// an in port of the main box
+#[derive(Debug, Clone)]
+pub struct ParamRow {
+    pub name: String, // node name, main/x
+    pub ty: Type,
+    pub value: f32,
+    pub settable: bool, // false for ports something is wired into
+}
+
+impl ParamRow {
+    /// how far one detent moves the value, a tenth of its magnitude
+    fn step(&self) -> f32 {
+        match self.ty {
+            Type::Int32 => 1.0,
+            _ if self.value == 0.0 => 0.01,
+            _ => 10f32
+                .powf(self.value.abs().log10().floor() - 1.0)
+                .max(0.001),
+        }
+    }
+}
+
+/// what the presets page is doing with the preset at the cursor
+#[derive(Debug, Clone, PartialEq)]
+pub enum PresetMode {
+    Browse,
+    Menu(usize),           // the entry of PRESET_MENU at the cursor
+    Naming(String, usize), // the new name and the letter being turned
+    Morph(usize, f32),     // the preset morphed towards and how far
+}
+
+/// what the UI asks of the rest of rasynth, presets are saved, renamed
+/// and deleted next to the playing patch
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Load(PathBuf),
+    Set(String, f32),
+    SetAll(Vec<(String, f32)>),
+    SavePreset(Preset),
+    RenamePreset(String, String),
+    DeletePreset(String),
+}
+
+/// the state of the screens, input events change it and draw shows it
+pub struct Ui {
+    pub page: Page,
+    pub patches: Vec<PathBuf>,
+    pub playing: Option<usize>,
+    pub params: Vec<ParamRow>,
+    pub outputs: Vec<String>,
+    pub load: f32,
+    pub peaks: Vec<f32>,
+    pub message: Option<String>,
+    pub sample_rate: f32,
+    pub channel: usize,     // the output the scope shows
+    pub samples: Vec<f32>,  // the scope's, oldest first
+    pub spectrum: Vec<f32>, // dB per bin
+    pub graph: Layout,
+    pub names: Vec<String>,   // of the graph's nodes
+    pub probed: Option<f32>,  // the value at the selected node
+    pub presets: Vec<Preset>, // of the playing patch
+    pub mode: PresetMode,
+    analyzer: Spectrum,
+    patch: usize,  // cursor on the patches page
+    param: usize,  // cursor on the params page
+    preset: usize, // cursor on the presets page, past the last is new
+    editing: bool,
+    bindings: Bindings,
+    order: Vec<usize>, // the graph's nodes column by column, top down
+    node: usize,       // cursor into order on the graph page
+}
+
+impl Ui {
+    pub fn new(patches: Vec<PathBuf>) -> Self {
+        Ui {
+            page: Page::Patches,
+            patches,
+            playing: None,
+            params: Vec::new(),
+            outputs: Vec::new(),
+            load: 0.0,
+            peaks: Vec::new(),
+            message: None,
+            sample_rate: engine::DEFAULT_SAMPLE_RATE,
+            channel: 0,
+            samples: Vec::new(),
+            spectrum: Vec::new(),
+            graph: Layout::default(),
+            names: Vec::new(),
+            probed: None,
+            presets: Vec::new(),
+            mode: PresetMode::Browse,
+            analyzer: Spectrum::new(),
+            patch: 0,
+            param: 0,
+            preset: 0,
+            editing: false,
+            bindings: Bindings::default(),
+            order: Vec::new(),
+            node: 0,
+        }
+    }
+
+    /// show the patch at the cursor as playing, with its main box's in
+    /// ports as params and its graph laid out
+    pub fn loaded(&mut self, graph: &FlowGraph, engine: &Engine) {
+        let settable = engine.params();
+        let (ports, bindings) = match graph.ast.lock().unwrap().as_ref() {
+            Some(top) => (
+                top.boxes()
+                    .iter()
+                    .filter(|b| b.name() == "main")
+                    .flat_map(|b| b.ports())
+                    .collect(),
+                Bindings::new(top, engine),
+            ),
+            None => (Vec::new(), Ok(Bindings::default())),
+        };
+        self.bindings = bindings.unwrap_or_else(|e| {
+            warn!("Ignoring the patch's controls: {}", e);
+            Bindings::default()
+        });
+        self.params = ports
+            .into_iter()
+            .filter_map(|port| match port {
+                ast::Port::In(name, ty, _, _) => {
+                    let name = format!("main/{}", name);
+                    Some(ParamRow {
+                        value: engine.value(&name).unwrap_or(0.0),
+                        settable: settable.contains(&name),
+                        name,
+                        ty,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        self.outputs = engine.outputs().clone();
+        self.peaks = vec![0.0; self.outputs.len()];
+        self.sample_rate = engine.sample_rate;
+        self.channel = self.channel.min(self.outputs.len().saturating_sub(1));
+        self.graph = layout::layout(graph, graph_image::METRICS);
+        self.names = graph.nodes.iter().map(|n| n.name.clone()).collect();
+        self.order = (0..self.graph.nodes.len()).collect();
+        self.order
+            .sort_by_key(|i| (self.graph.nodes[*i].x, self.graph.nodes[*i].y));
+        self.node = 0;
+        self.playing = Some(self.patch);
+        self.param = 0;
+        self.editing = false;
+        self.presets = Vec::new();
+        self.preset = 0;
+        self.mode = PresetMode::Browse;
+        self.message = None;
+    }
+
+    /// take the playing patch's presets, with the cursor on selected
+    pub fn set_presets(&mut self, presets: Vec<Preset>, selected: Option<&str>) {
+        self.presets = presets;
+        self.preset = match selected {
+            Some(name) => self.presets.iter().position(|p| p.name == name),
+            None => None,
+        }
+        .unwrap_or(self.preset.min(self.presets.len()));
+        self.mode = PresetMode::Browse;
+    }
+
+    /// the settable params as a preset sees them
+    fn ports(&self) -> Vec<preset::Port> {
+        self.params
+            .iter()
+            .filter(|r| r.settable)
+            .map(|r| preset::Port {
+                name: r.name.clone(),
+                ty: r.ty.clone(),
+                value: r.value,
+            })
+            .collect()
+    }
+
+    /// show values about to be set on their rows
+    fn set_rows(&mut self, values: &[(String, f32)]) {
+        for (name, value) in values.iter() {
+            if let Some(row) = self.params.iter_mut().find(|r| r.name == *name) {
+                row.value = *value;
+            }
+        }
+    }
+
+    /// take the audio thread's latest measurements
+    pub fn meter(&mut self, meters: &Meters) {
+        self.load = meters.load();
+        let peaks = meters.take_peaks();
+        self.peaks.resize(peaks.len(), 0.0);
+        for (held, peak) in self.peaks.iter_mut().zip(peaks) {
+            *held = peak.max(*held * PEAK_DECAY);
+        }
+    }
+
+    /// point the scope at the output shown and take its samples
+    pub fn watch(&mut self, scope: &Scope) {
+        scope.set_channel(self.channel);
+        scope.snapshot(&mut self.samples);
+        self.spectrum = self.analyzer.analyze(&self.samples);
+    }
+
+    /// the engine slot of the node selected on the graph page
+    pub fn selected(&self) -> Option<usize> {
+        self.order.get(self.node).map(|i| self.graph.nodes[*i].node)
+    }
+
+    /// probe the selected node and take the value it had
+    pub fn inspect(&mut self, scope: &Scope) {
+        scope.set_probe(self.selected());
+        self.probed = scope.probed();
+    }
+
+    pub fn handle(&mut self, event: InputEvent) -> Option<Action> {
+        // a control the patch binds sets its port instead of moving the UI
+        if let InputEvent::Control(control) = event {
+            if let Some((port, value)) = self.bindings.apply(&control) {
+                if let Some(row) = self.params.iter_mut().find(|r| r.name == port) {
+                    row.value = value;
+                }
+                return Some(Action::Set(port, value));
+            }
+            return self.handle(control.to_input()?);
+        }
+        // down the list, and up the value being edited
+        let (moved, raised) = match event {
+            InputEvent::Up => (-1, 1),
+            InputEvent::Down => (1, -1),
+            InputEvent::Turn(n) => (n, n),
+            _ => (0, 0),
+        };
+        match (self.page, event) {
+            (Page::Presets, _) if self.mode != PresetMode::Browse => {
+                self.handle_preset(event, moved, raised)
+            }
+            (_, InputEvent::Left | InputEvent::Right) if !self.editing => {
+                let k = PAGES.iter().position(|p| *p == self.page).unwrap_or(0);
+                let k = match event {
+                    InputEvent::Left => k + PAGES.len() - 1,
+                    _ => k + 1,
+                };
+                self.page = PAGES[k % PAGES.len()];
+                None
+            }
+            (Page::Patches, InputEvent::Select) => {
+                let path = self.patches.get(self.patch)?.clone();
+                Some(Action::Load(path))
+            }
+            (Page::Patches, _) if moved != 0 => {
+                self.patch = step_cursor(self.patch, moved, self.patches.len());
+                None
+            }
+            (Page::Params, InputEvent::Select) => {
+                let settable = self.params.get(self.param).map(|p| p.settable);
+                self.editing = !self.editing && settable.unwrap_or(false);
+                None
+            }
+            (Page::Params, InputEvent::Back) if self.editing => {
+                self.editing = false;
+                None
+            }
+            (Page::Params, _) if self.editing && moved != 0 => {
+                let row = self.params.get_mut(self.param)?;
+                row.value += raised as f32 * row.step();
+                if let Type::Int32 = row.ty {
+                    row.value = row.value.round();
+                }
+                Some(Action::Set(row.name.clone(), row.value))
+            }
+            (Page::Params, _) if moved != 0 => {
+                self.param = step_cursor(self.param, moved, self.params.len());
+                None
+            }
+            (Page::Presets, InputEvent::Select) if self.playing.is_some() => {
+                match self.presets.get(self.preset) {
+                    Some(_) => {
+                        self.mode = PresetMode::Menu(0);
+                        None
+                    }
+                    None => {
+                        let names = self
+                            .presets
+                            .iter()
+                            .map(|p| p.name.clone())
+                            .collect::<Vec<_>>();
+                        let name = preset::next_name(&names);
+                        Some(Action::SavePreset(Preset::capture(&name, &self.ports())))
+                    }
+                }
+            }
+            (Page::Presets, _) if moved != 0 => {
+                self.preset = step_cursor(self.preset, moved, self.presets.len() + 1);
+                None
+            }
+            (Page::Scope, _) if moved != 0 => {
+                self.channel = step_cursor(self.channel, moved, self.outputs.len());
+                None
+            }
+            (Page::Graph, InputEvent::Select) => {
+                // follow the signal to the first node the selection feeds
+                let from = self.selected()?;
+                let to = self
+                    .graph
+                    .wires
+                    .iter()
+                    .find(|w| w.from == from && !w.back)?
+                    .to;
+                self.node = self.order.iter().position(|i| *i == to)?;
+                None
+            }
+            (Page::Graph, _) if moved != 0 => {
+                self.node = step_cursor(self.node, moved, self.order.len());
+                None
+            }
+            (_, InputEvent::Back) => {
+                self.page = Page::Patches;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// the menu, the name and the morph of the preset at the cursor
+    fn handle_preset(&mut self, event: InputEvent, moved: i32, raised: i32) -> Option<Action> {
+        let Some(current) = self.presets.get(self.preset).cloned() else {
+            self.mode = PresetMode::Browse;
+            return None;
+        };
+        match (self.mode.clone(), event) {
+            (PresetMode::Menu(_), InputEvent::Back) => {
+                self.mode = PresetMode::Browse;
+                None
+            }
+            (PresetMode::Menu(k), InputEvent::Select) => {
+                self.mode = PresetMode::Browse;
+                let ports = self.ports();
+                match PRESET_MENU[k] {
+                    "load" => {
+                        let mismatches = current.check(&ports);
+                        for m in mismatches.iter() {
+                            warn!("Preset {}: {}", current.name, m);
+                        }
+                        if !mismatches.is_empty() {
+                            self.message = Some(format!(
+                                "{}: {} ports changed",
+                                current.name,
+                                mismatches.len()
+                            ));
+                        }
+                        let values = current.apply(&ports);
+                        self.set_rows(&values);
+                        Some(Action::SetAll(values))
+                    }
+                    "save" => Some(Action::SavePreset(Preset::capture(&current.name, &ports))),
+                    "rename" => {
+                        self.mode = PresetMode::Naming(current.name.clone(), 0);
+                        None
+                    }
+                    "morph" if self.presets.len() < 2 => {
+                        self.message = Some("morphing needs two presets".to_string());
+                        None
+                    }
+                    "morph" => {
+                        let to = (self.preset + 1) % self.presets.len();
+                        self.mode = PresetMode::Morph(to, 0.0);
+                        None
+                    }
+                    _ => Some(Action::DeletePreset(current.name)),
+                }
+            }
+            (PresetMode::Menu(k), _) => {
+                self.mode = PresetMode::Menu(step_cursor(k, moved, PRESET_MENU.len()));
+                None
+            }
+            (PresetMode::Naming(..), InputEvent::Back) => {
+                self.mode = PresetMode::Browse;
+                None
+            }
+            (PresetMode::Naming(name, _), InputEvent::Select) => {
+                self.mode = PresetMode::Browse;
+                let name = name.replace(' ', "");
+                match name != current.name {
+                    true => Some(Action::RenamePreset(current.name, name)),
+                    false => None,
+                }
+            }
+            // past the last letter is room for one more
+            (PresetMode::Naming(name, k), InputEvent::Left) => {
+                self.mode = PresetMode::Naming(name, k.saturating_sub(1));
+                None
+            }
+            (PresetMode::Naming(name, k), InputEvent::Right) => {
+                let k = (k + 1).min(name.len());
+                self.mode = PresetMode::Naming(name, k);
+                None
+            }
+            (PresetMode::Naming(mut name, k), _) if raised != 0 => {
+                let chars = NAME_CHARS.chars().collect::<Vec<_>>();
+                let at = name.chars().nth(k).unwrap_or(' ');
+                let i = chars.iter().position(|c| *c == at).unwrap_or(0) as i32;
+                let c = chars[(i + raised).rem_euclid(chars.len() as i32) as usize];
+                match k < name.len() {
+                    true => name.replace_range(k..k + 1, &c.to_string()),
+                    false => name.push(c),
+                }
+                self.mode = PresetMode::Naming(name, k);
+                None
+            }
+            (PresetMode::Morph(..), InputEvent::Select | InputEvent::Back) => {
+                self.mode = PresetMode::Browse;
+                None
+            }
+            (PresetMode::Morph(to, t), _) => {
+                // left and right pick the other preset, turning morphs
+                let n = self.presets.len();
+                let to = match event {
+                    InputEvent::Left => (to + n - 1) % n,
+                    InputEvent::Right => (to + 1) % n,
+                    _ => to,
+                };
+                let to = match to == self.preset {
+                    true if event == InputEvent::Left => (to + n - 1) % n,
+                    true => (to + 1) % n,
+                    false => to,
+                };
+                let t = (t + raised as f32 * MORPH_STEP).clamp(0.0, 1.0);
+                self.mode = PresetMode::Morph(to, t);
+                let values = preset::morph(&current, &self.presets[to], t, &self.ports());
+                self.set_rows(&values);
+                Some(Action::SetAll(values))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn draw<D: Display>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.clear(BACKGROUND)?;
+        let width = display.bounding_box().size.width as i32;
+        let height = display.bounding_box().size.height as i32;
+        let rows = ((height - HEADER_H) / ROW_H) as usize;
+
+        let header = MonoTextStyle::new(&FONT_7X13_BOLD, FOREGROUND);
+        Rectangle::new(Point::zero(), Size::new(width as u32, HEADER_H as u32))
+            .into_styled(PrimitiveStyle::with_fill(HEADER))
+            .draw(display)?;
+        let k = PAGES.iter().position(|p| *p == self.page).unwrap_or(0);
+        let title = format!("{} {}/{}", self.page.title(), k + 1, PAGES.len());
+        text(display, &title, Point::new(4, 3), header, Alignment::Left)?;
+        let cpu = format!("CPU {:.0}%", self.load * 100.0);
+        text(
+            display,
+            &cpu,
+            Point::new(width - 4, 3),
+            header,
+            Alignment::Right,
+        )?;
+
+        let y0 = HEADER_H + 2;
+        match self.page {
+            Page::Patches => {
+                if self.patches.is_empty() {
+                    let style = MonoTextStyle::new(&FONT_7X13, DIM);
+                    text(
+                        display,
+                        "no patches",
+                        Point::new(4, y0),
+                        style,
+                        Alignment::Left,
+                    )?;
+                }
+                let first = self.patch.saturating_sub(rows.saturating_sub(1));
+                for (i, path) in self.patches.iter().enumerate().skip(first).take(rows) {
+                    let y = y0 + (i - first) as i32 * ROW_H;
+                    if i == self.patch {
+                        row_fill(display, y, width, CURSOR)?;
+                    }
+                    let mark = if self.playing == Some(i) { '*' } else { ' ' };
+                    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                    let style = MonoTextStyle::new(&FONT_7X13, FOREGROUND);
+                    let line = format!("{} {}", mark, name);
+                    text(display, &line, Point::new(4, y + 1), style, Alignment::Left)?;
+                }
+            }
+            Page::Params => {
+                if self.params.is_empty() {
+                    let style = MonoTextStyle::new(&FONT_7X13, DIM);
+                    let line = match self.playing {
+                        Some(_) => "main has no in ports",
+                        None => "no patch loaded",
+                    };
+                    text(display, line, Point::new(4, y0), style, Alignment::Left)?;
+                }
+                let first = self.param.saturating_sub(rows.saturating_sub(1));
+                for (i, row) in self.params.iter().enumerate().skip(first).take(rows) {
+                    let y = y0 + (i - first) as i32 * ROW_H;
+                    if i == self.param {
+                        row_fill(display, y, width, CURSOR)?;
+                    }
+                    let color = if row.settable { FOREGROUND } else { DIM };
+                    let style = MonoTextStyle::new(&FONT_7X13, color);
+                    let name = row.name.trim_start_matches("main/");
+                    text(display, name, Point::new(4, y + 1), style, Alignment::Left)?;
+                    let ty = match row.ty {
+                        Type::Int32 => "i32",
+                        Type::Float => "float",
+                        Type::Waveform => "wave",
+                    };
+                    text(display, ty, Point::new(120, y + 1), style, Alignment::Left)?;
+                    let value = match row.ty {
+                        Type::Int32 => format!("{}", row.value as i32),
+                        _ => format!("{:.3}", row.value),
+                    };
+                    if self.editing && i == self.param {
+                        let w = value.len() as u32 * 7 + 4;
+                        Rectangle::new(
+                            Point::new(width - 6 - w as i32, y),
+                            Size::new(w, ROW_H as u32),
+                        )
+                        .into_styled(PrimitiveStyle::with_fill(EDIT))
+                        .draw(display)?;
+                    }
+                    text(
+                        display,
+                        &value,
+                        Point::new(width - 8, y + 1),
+                        style,
+                        Alignment::Right,
+                    )?;
+                }
+            }
+            Page::Presets => self.draw_presets(display, y0, width, rows)?,
+            Page::Meters => {
+                let mut y = y0 + 4;
+                let cpu = format!("{:.1}%", self.load * 100.0);
+                meter(display, "cpu", &cpu, self.load, y, width)?;
+                for (name, peak) in self.outputs.iter().zip(self.peaks.iter()) {
+                    y += 2 * ROW_H;
+                    let name = name.trim_start_matches("main/");
+                    let db = match *peak > 0.0 {
+                        true => format!("{:.1} dB", 20.0 * peak.log10()),
+                        false => "-inf dB".to_string(),
+                    };
+                    meter(display, name, &db, *peak, y, width)?;
+                }
+            }
+            Page::Scope => self.draw_scope(display, y0, width, height)?,
+            Page::Graph => self.draw_graph(display, y0, width, height)?,
+        }
+        if let Some(message) = &self.message {
+            let style = MonoTextStyle::new(&FONT_7X13, Rgb565::RED);
+            let y = height - ROW_H;
+            row_fill(display, y, width, BACKGROUND)?;
+            text(
+                display,
+                message,
+                Point::new(4, y + 1),
+                style,
+                Alignment::Left,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Ui {
+    /// the presets with a row to save a new one, or what is being done
+    /// to the one at the cursor
+    fn draw_presets<D: Display>(
+        &self,
+        display: &mut D,
+        y0: i32,
+        width: i32,
+        rows: usize,
+    ) -> Result<(), D::Error> {
+        let style = MonoTextStyle::new(&FONT_7X13, FOREGROUND);
+        let dim = MonoTextStyle::new(&FONT_7X13, DIM);
+        if self.playing.is_none() {
+            return text(
+                display,
+                "no patch loaded",
+                Point::new(4, y0),
+                dim,
+                Alignment::Left,
+            );
+        }
+        let name = self
+            .presets
+            .get(self.preset)
+            .map(|p| p.name.as_str())
+            .unwrap_or("");
+        match &self.mode {
+            PresetMode::Browse => {
+                let first = self.preset.saturating_sub(rows.saturating_sub(1));
+                let names = self.presets.iter().map(|p| p.name.as_str());
+                for (i, name) in names
+                    .chain(["+ save new"])
+                    .enumerate()
+                    .skip(first)
+                    .take(rows)
+                {
+                    let y = y0 + (i - first) as i32 * ROW_H;
+                    if i == self.preset {
+                        row_fill(display, y, width, CURSOR)?;
+                    }
+                    let style = if i == self.presets.len() { dim } else { style };
+                    text(display, name, Point::new(4, y + 1), style, Alignment::Left)?;
+                }
+            }
+            PresetMode::Menu(k) => {
+                text(display, name, Point::new(4, y0 + 1), dim, Alignment::Left)?;
+                for (i, entry) in PRESET_MENU.iter().enumerate() {
+                    let y = y0 + (i + 1) as i32 * ROW_H;
+                    if i == *k {
+                        row_fill(display, y, width, CURSOR)?;
+                    }
+                    text(
+                        display,
+                        entry,
+                        Point::new(12, y + 1),
+                        style,
+                        Alignment::Left,
+                    )?;
+                }
+            }
+            PresetMode::Naming(new, k) => {
+                let line = format!("rename {}", name);
+                text(display, &line, Point::new(4, y0 + 1), dim, Alignment::Left)?;
+                let y = y0 + 2 * ROW_H;
+                Rectangle::new(Point::new(4 + *k as i32 * 7, y), Size::new(7, ROW_H as u32))
+                    .into_styled(PrimitiveStyle::with_fill(EDIT))
+                    .draw(display)?;
+                text(display, new, Point::new(4, y + 1), style, Alignment::Left)?;
+                let help = "turn: letter, < >: move";
+                text(
+                    display,
+                    help,
+                    Point::new(4, y + 2 * ROW_H),
+                    dim,
+                    Alignment::Left,
+                )?;
+            }
+            PresetMode::Morph(to, t) => {
+                let to = self.presets.get(*to).map(|p| p.name.as_str()).unwrap_or("");
+                let y = y0 + 4;
+                let line = format!("{} > {}", name, to);
+                text(display, &line, Point::new(4, y), style, Alignment::Left)?;
+                let amount = format!("{:.0}%", t * 100.0);
+                text(
+                    display,
+                    &amount,
+                    Point::new(width - 4, y),
+                    style,
+                    Alignment::Right,
+                )?;
+                let bar = Rectangle::new(Point::new(4, y + ROW_H), Size::new(width as u32 - 8, 8));
+                bar.into_styled(PrimitiveStyle::with_stroke(DIM, 1))
+                    .draw(display)?;
+                let fill = (t * bar.size.width as f32) as u32;
+                Rectangle::new(bar.top_left, Size::new(fill, 8))
+                    .into_styled(PrimitiveStyle::with_fill(EDIT))
+                    .draw(display)?;
+                let help = "turn: morph, < >: other preset";
+                text(
+                    display,
+                    help,
+                    Point::new(4, y + 3 * ROW_H),
+                    dim,
+                    Alignment::Left,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// a triggered trace of the output over its spectrum, log spaced
+    /// from 20 Hz to Nyquist
+    fn draw_scope<D: Display>(
+        &self,
+        display: &mut D,
+        y0: i32,
+        width: i32,
+        height: i32,
+    ) -> Result<(), D::Error> {
+        let style = MonoTextStyle::new(&FONT_7X13, FOREGROUND);
+        let name = match self.outputs.get(self.channel) {
+            Some(name) => name.trim_start_matches("main/"),
+            None => "no patch loaded",
+        };
+        text(display, name, Point::new(4, y0), style, Alignment::Left)?;
+        let w = width - 8;
+        // two panels under the name with room for the frequency labels
+        let h = (height - y0 - 2 * ROW_H - 8) / 2;
+
+        let trace = Rectangle::new(Point::new(4, y0 + ROW_H), Size::new(w as u32, h as u32));
+        trace
+            .into_styled(PrimitiveStyle::with_stroke(DIM, 1))
+            .draw(display)?;
+        let mid = trace.center().y;
+        Line::new(Point::new(4, mid), Point::new(4 + w - 1, mid))
+            .into_styled(PrimitiveStyle::with_stroke(DIM, 1))
+            .draw(display)?;
+        let start = trigger(&self.samples, w as usize);
+        let points = self
+            .samples
+            .iter()
+            .skip(start)
+            .take(w as usize)
+            .enumerate()
+            .map(|(x, s)| {
+                let y = mid - (s.clamp(-1.0, 1.0) * (h / 2 - 1) as f32) as i32;
+                Point::new(4 + x as i32, y)
+            })
+            .collect::<Vec<_>>();
+        Polyline::new(&points)
+            .into_styled(PrimitiveStyle::with_stroke(Rgb565::GREEN, 1))
+            .draw(display)?;
+
+        let top = trace.bottom_right().map(|p| p.y).unwrap_or(mid) + 6;
+        let area = Rectangle::new(Point::new(4, top), Size::new(w as u32, h as u32));
+        area.into_styled(PrimitiveStyle::with_stroke(DIM, 1))
+            .draw(display)?;
+        let nyquist = self.sample_rate / 2.0;
+        let bins = self.spectrum.len();
+        for x in 0..w {
+            // the bins the column spans, at least one
+            let f = |x: i32| 20.0 * (nyquist / 20.0).powf(x as f32 / w as f32);
+            let b0 = (f(x) / nyquist * bins as f32) as usize;
+            let b1 = ((f(x + 1) / nyquist * bins as f32) as usize).max(b0 + 1);
+            let db = match self.spectrum.get(b0..b1.min(bins)) {
+                Some(column) if !column.is_empty() => column.iter().cloned().fold(MIN_DB, f32::max),
+                _ => continue,
+            };
+            let bar = ((db - MIN_DB) / -MIN_DB * (h - 2) as f32) as i32;
+            if bar > 0 {
+                let bottom = top + h - 2;
+                Line::new(
+                    Point::new(4 + x, bottom),
+                    Point::new(4 + x, bottom - bar + 1),
+                )
+                .into_styled(PrimitiveStyle::with_stroke(Rgb565::CYAN, 1))
+                .draw(display)?;
+            }
+        }
+        let small = MonoTextStyle::new(&FONT_7X13, DIM);
+        let y = top + h + 1;
+        text(display, "20", Point::new(4, y), small, Alignment::Left)?;
+        let high = format!("{:.0}k", nyquist / 1000.0);
+        text(
+            display,
+            &high,
+            Point::new(4 + w, y),
+            small,
+            Alignment::Right,
+        )?;
+        Ok(())
+    }
+}
+
+impl Ui {
+    /// the part of the graph around the selected node, with its name
+    /// and value under it
+    fn draw_graph<D: Display>(
+        &self,
+        display: &mut D,
+        y0: i32,
+        width: i32,
+        height: i32,
+    ) -> Result<(), D::Error> {
+        let style = MonoTextStyle::new(&FONT_7X13, FOREGROUND);
+        let Some(selected) = self.selected() else {
+            let style = MonoTextStyle::new(&FONT_7X13, DIM);
+            text(
+                display,
+                "no patch loaded",
+                Point::new(4, y0),
+                style,
+                Alignment::Left,
+            )?;
+            return Ok(());
+        };
+        let view = Rectangle::new(
+            Point::new(0, y0),
+            Size::new(width as u32, (height - ROW_H - y0) as u32),
+        );
+        // scroll so the selection is in the middle, as far as the graph goes
+        let at = &self.graph.nodes[selected];
+        let scroll =
+            |center: i32, view: i32, size: i32| (center - view / 2).clamp(0, (size - view).max(0));
+        let offset = Point::new(
+            scroll(at.x + at.w / 2, width, self.graph.width),
+            scroll(at.center_y(), view.size.height as i32, self.graph.height),
+        );
+        graph_image::draw(
+            &mut display.clipped(&view).translated(view.top_left - offset),
+            &self.graph,
+            Some(selected),
+            &GRAPH_PALETTE,
+        )?;
+
+        let y = height - ROW_H;
+        row_fill(display, y, width, HEADER)?;
+        let name = &self.names[selected];
+        text(display, name, Point::new(4, y + 1), style, Alignment::Left)?;
+        let value = match self.probed {
+            Some(v) => format!("{:.4}", v),
+            None => "...".to_string(),
+        };
+        text(
+            display,
+            &value,
+            Point::new(width - 4, y + 1),
+            style,
+            Alignment::Right,
+        )?;
+        Ok(())
+    }
+}
+
+fn step_cursor(cursor: usize, moved: i32, len: usize) -> usize {
+    (cursor as i32 + moved).clamp(0, len.max(1) as i32 - 1) as usize
+}
+
+fn text<D: Display>(
+    display: &mut D,
+    s: &str,
+    at: Point,
+    style: MonoTextStyle<Rgb565>,
+    alignment: Alignment,
+) -> Result<(), D::Error> {
+    let layout = TextStyleBuilder::new()
+        .alignment(alignment)
+        .baseline(Baseline::Top)
+        .build();
+    Text::with_text_style(s, at, style, layout).draw(display)?;
+    Ok(())
+}
+
+fn row_fill<D: Display>(
+    display: &mut D,
+    y: i32,
+    width: i32,
+    color: Rgb565,
+) -> Result<(), D::Error> {
+    Rectangle::new(Point::new(0, y), Size::new(width as u32, ROW_H as u32))
+        .into_styled(PrimitiveStyle::with_fill(color))
+        .draw(display)
+}
+
+/// a labelled bar of a level in [0, 1], green, then yellow past -6 dB
+/// and red at full scale
+fn meter<D: Display>(
+    display: &mut D,
+    label: &str,
+    reading: &str,
+    level: f32,
+    y: i32,
+    width: i32,
+) -> Result<(), D::Error> {
+    let style = MonoTextStyle::new(&FONT_7X13, FOREGROUND);
+    text(display, label, Point::new(4, y), style, Alignment::Left)?;
+    text(
+        display,
+        reading,
+        Point::new(width - 4, y),
+        style,
+        Alignment::Right,
+    )?;
+    let (x, w) = (4, width - 8);
+    let bar = Rectangle::new(Point::new(x, y + ROW_H), Size::new(w as u32, 8));
+    bar.into_styled(PrimitiveStyle::with_stroke(DIM, 1))
+        .draw(display)?;
+    let color = match level {
+        l if l >= 1.0 => Rgb565::RED,
+        l if l >= 0.5 => Rgb565::YELLOW,
+        _ => Rgb565::GREEN,
+    };
+    let fill = (level.clamp(0.0, 1.0) * w as f32) as u32;
+    Rectangle::new(bar.top_left, Size::new(fill, 8))
+        .into_styled(PrimitiveStyle::with_fill(color))
+        .draw(display)?;
+    Ok(())
+}
+
+/// where the UI plays patches
+struct Sound {
+    backend: String,
+    target: String,
+    rate: u32,
+    block_size: usize,
+}
+
+/// the playing patch, its params are set through the store
+struct Playing {
+    thread: AudioThread,
+    store: Arc<ParamStore>,
+}
+
+fn play(
+    path: &Path,
+    graph: &FlowGraph,
+    sound: &Sound,
+    ui: &mut Ui,
+) -> Result<Playing, Box<dyn Error>> {
+    let sink = audio::open_sink(
+        &sound.backend,
+        &sound.target,
+        sound.rate,
+        2,
+        sound.block_size,
+        PcmFormat::S16Le,
+    )?;
+    let engine = Engine::new(graph, sink.sample_rate() as f32);
+    ui.loaded(graph, &engine);
+    ui.set_presets(preset::load_all(path), None);
+    let store = ParamStore::new(&engine);
+    let controls = vec![store.control()];
+    let thread = AudioThread::spawn(engine, None, sink, controls, None, sound.block_size, None);
+    info!("Playing {}", path.display());
+    Ok(Playing { thread, store })
+}
+
+/// set params of the playing patch
+fn set(playing: &Option<Playing>, values: &[(String, f32)]) {
+    if let Some(p) = playing {
+        for (name, value) in values.iter() {
+            match p.store.index(name) {
+                Some(i) => p.store.set(i, *value),
+                None => warn!("{} is not a param", name),
+            }
+        }
+    }
+}
+
+/// the UI loop: one input event a frame, a redraw when something
+/// changed or the meters are due, until the input closes
+fn run<D: Display>(
+    display: &mut D,
+    mut input: Box<dyn Input>,
+    mut ui: Ui,
+    sound: &Sound,
+) -> Result<(), Box<dyn Error>> {
+    display.set_backlight(true);
+    let mut playing: Option<Playing> = None;
+    let mut last_meter = Instant::now();
+    let mut dirty = true;
+    loop {
+        // controls send events faster than frames, take all of them and
+        // draw once
+        while let Some(event) = input.poll() {
+            dirty = true;
+            match ui.handle(event) {
+                Some(Action::Load(path)) => {
+                    // a patch that does not build leaves the playing one alone
+                    let result = match FlowGraph::from_file(&path) {
+                        Ok(graph) => {
+                            if let Some(old) = playing.take() {
+                                old.thread.stop();
+                                if let Err(e) = old.thread.join() {
+                                    warn!("Audio thread: {}", e);
+                                }
+                            }
+                            // the sink is free for the new patch, nothing
+                            // plays until it does
+                            ui.playing = None;
+                            play(&path, &graph, sound, &mut ui)
+                        }
+                        Err(e) => Err(e.into()),
+                    };
+                    match result {
+                        Ok(p) => playing = Some(p),
+                        Err(e) => {
+                            error!("{}: {}", path.display(), e);
+                            let e = e.to_string();
+                            let first = e.lines().next().unwrap_or("");
+                            ui.message = Some(format!("cannot load: {}", first));
+                        }
+                    }
+                }
+                Some(Action::Set(name, value)) => set(&playing, &[(name, value)]),
+                Some(Action::SetAll(values)) => set(&playing, &values),
+                Some(action) => {
+                    let patch = ui.playing.and_then(|k| ui.patches.get(k)).cloned();
+                    if let Some(patch) = patch {
+                        let (result, selected) = match action {
+                            Action::SavePreset(p) => (p.save(&patch).map(|_| ()), Some(p.name)),
+                            Action::RenamePreset(from, to) => {
+                                (preset::rename(&patch, &from, &to), Some(to))
+                            }
+                            Action::DeletePreset(name) => (preset::delete(&patch, &name), None),
+                            _ => (Ok(()), None),
+                        };
+                        if let Err(e) = result {
+                            error!("Preset: {}", e);
+                            ui.message = Some(e.to_string());
+                        }
+                        ui.set_presets(preset::load_all(&patch), selected.as_deref());
+                    }
+                }
+                None => {}
+            }
+        }
+        if let Some(p) = &playing {
+            ui.meter(&p.thread.meters());
+            match ui.page {
+                Page::Scope => ui.watch(&p.thread.scope()),
+                Page::Graph => ui.inspect(&p.thread.scope()),
+                _ => {}
+            }
+            if last_meter.elapsed() >= METER_INTERVAL {
+                last_meter = Instant::now();
+                dirty = true;
+            }
+        }
+        if dirty {
+            ui.draw(display)
+                .map_err(|e| format!("drawing failed: {:?}", e))?;
+            display.flush()?;
+            dirty = false;
+        }
+        if !input.open() {
+            break;
+        }
+        thread::sleep(FRAME);
+    }
+    if let Some(p) = playing {
+        p.thread.stop();
+        p.thread.join()?;
+    }
+    display.set_backlight(false);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATCH: &str = "\
+(box main (
+    in  freq:  float = 220.0
+    in  steps: i32 = 3
+    out L:     float
+    out R:     float
+)
+    (let L (* 0.5 (saw freq)))
+    (let R L)
+)
+";
+
+    fn loaded() -> Ui {
+        let graph = FlowGraph::from_source(PATCH).unwrap();
+        let engine = Engine::new(&graph, 48000.0);
+        let mut ui = Ui::new(vec![PathBuf::from("a.raslisp"), PathBuf::from("b.raslisp")]);
+        ui.loaded(&graph, &engine);
+        ui
+    }
+
+    fn row(ty: Type, value: f32) -> ParamRow {
+        let name = "main/x".to_string();
+        let settable = true;
+        ParamRow {
+            name,
+            ty,
+            value,
+            settable,
+        }
+    }
+
+    fn port(name: &str, ty: Type, value: f32) -> preset::Port {
+        let name = format!("main/{}", name);
+        preset::Port { name, ty, value }
+    }
+
+    #[test]
+    fn pages_wrap_and_back_goes_to_the_patches() {
+        let mut ui = Ui::new(Vec::new());
+        assert_eq!(ui.handle(InputEvent::Left), None);
+        assert_eq!(ui.page, Page::Graph);
+        ui.handle(InputEvent::Right);
+        assert_eq!(ui.page, Page::Patches);
+        for page in PAGES.iter().skip(1).chain([&Page::Patches]) {
+            ui.handle(InputEvent::Right);
+            assert_eq!(ui.page, *page);
+        }
+        ui.handle(InputEvent::Right);
+        ui.handle(InputEvent::Right);
+        ui.handle(InputEvent::Back);
+        assert_eq!(ui.page, Page::Patches);
+        // with nothing to load select does nothing
+        assert_eq!(ui.handle(InputEvent::Select), None);
+    }
+
+    #[test]
+    fn patches_load_from_the_cursor() {
+        let mut ui = loaded();
+        ui.handle(InputEvent::Down);
+        ui.handle(InputEvent::Down);
+        let b = Action::Load(PathBuf::from("b.raslisp"));
+        assert_eq!(ui.handle(InputEvent::Select), Some(b));
+        ui.handle(InputEvent::Up);
+        let a = Action::Load(PathBuf::from("a.raslisp"));
+        assert_eq!(ui.handle(InputEvent::Select), Some(a));
+    }
+
+    #[test]
+    fn a_step_is_a_tenth_of_the_magnitude() {
+        assert_eq!(row(Type::Int32, 1000.0).step(), 1.0);
+        assert_eq!(row(Type::Float, 0.0).step(), 0.01);
+        assert_eq!(row(Type::Float, 220.0).step(), 10.0);
+        assert_eq!(row(Type::Float, -220.0).step(), 10.0);
+        assert!((row(Type::Float, 0.7).step() - 0.01).abs() < 1e-6);
+        assert_eq!(row(Type::Float, 1e-6).step(), 0.001);
+    }
+
+    #[test]
+    fn params_are_edited_between_select_and_back() {
+        let mut ui = loaded();
+        ui.handle(InputEvent::Right);
+        assert_eq!(ui.page, Page::Params);
+        let names = ui
+            .params
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["main/freq", "main/steps"]);
+        assert_eq!(ui.params[0].value, 220.0);
+
+        // not editing, turning moves the cursor
+        assert_eq!(ui.handle(InputEvent::Turn(1)), None);
+        assert_eq!(ui.param, 1);
+        ui.handle(InputEvent::Up);
+        ui.handle(InputEvent::Select);
+        assert!(ui.editing);
+        let set = ui.handle(InputEvent::Turn(1));
+        assert_eq!(set, Some(Action::Set("main/freq".to_string(), 230.0)));
+        let set = ui.handle(InputEvent::Down);
+        assert_eq!(set, Some(Action::Set("main/freq".to_string(), 220.0)));
+        // left and right stay on the page while editing
+        ui.handle(InputEvent::Right);
+        assert_eq!(ui.page, Page::Params);
+        ui.handle(InputEvent::Back);
+        assert!(!ui.editing);
+        assert_eq!(ui.page, Page::Params);
+
+        ui.handle(InputEvent::Down);
+        ui.handle(InputEvent::Select);
+        let set = ui.handle(InputEvent::Turn(-2));
+        assert_eq!(set, Some(Action::Set("main/steps".to_string(), 1.0)));
+        ui.handle(InputEvent::Select);
+        assert!(!ui.editing);
+
+        // a port something is wired into is not edited
+        ui.params[1].settable = false;
+        ui.handle(InputEvent::Select);
+        assert!(!ui.editing);
+        assert_eq!(ui.handle(InputEvent::Turn(1)), None);
+    }
+
+    #[test]
+    fn presets_are_renamed_and_morphed_from_the_menu() {
+        let mut ui = loaded();
+        let low = [
+            port("freq", Type::Float, 100.0),
+            port("steps", Type::Int32, 1.0),
+        ];
+        let high = [
+            port("freq", Type::Float, 300.0),
+            port("steps", Type::Int32, 4.0),
+        ];
+        let presets = vec![Preset::capture("low", &low), Preset::capture("high", &high)];
+        ui.set_presets(presets, Some("low"));
+        ui.page = Page::Presets;
+
+        // past the last preset is a new one, of the values playing
+        ui.handle(InputEvent::Down);
+        ui.handle(InputEvent::Down);
+        let Some(Action::SavePreset(saved)) = ui.handle(InputEvent::Select) else {
+            panic!("select past the presets saves one");
+        };
+        let playing = [
+            port("freq", Type::Float, 220.0),
+            port("steps", Type::Int32, 3.0),
+        ];
+        assert_eq!(
+            saved.apply(&playing),
+            Preset::capture("", &playing).apply(&playing)
+        );
+        assert_eq!(ui.mode, PresetMode::Browse);
+
+        ui.handle(InputEvent::Turn(-2));
+        ui.handle(InputEvent::Select);
+        assert_eq!(ui.mode, PresetMode::Menu(0));
+        ui.handle(InputEvent::Back);
+        assert_eq!(ui.mode, PresetMode::Browse);
+        assert_eq!(ui.page, Page::Presets);
+
+        // rename: turn the first letter from l to m, add a 2 at the end
+        ui.handle(InputEvent::Select);
+        ui.handle(InputEvent::Down);
+        ui.handle(InputEvent::Down);
+        assert_eq!(ui.mode, PresetMode::Menu(2));
+        assert_eq!(ui.handle(InputEvent::Select), None);
+        assert_eq!(ui.mode, PresetMode::Naming("low".to_string(), 0));
+        ui.handle(InputEvent::Turn(1));
+        for _ in 0..5 {
+            ui.handle(InputEvent::Right);
+        }
+        assert_eq!(ui.mode, PresetMode::Naming("mow".to_string(), 3));
+        ui.handle(InputEvent::Turn(-10));
+        let renamed = Action::RenamePreset("low".to_string(), "mow2".to_string());
+        assert_eq!(ui.handle(InputEvent::Select), Some(renamed));
+        assert_eq!(ui.mode, PresetMode::Browse);
+
+        // morph towards the other preset, a turn at a time
+        ui.handle(InputEvent::Select);
+        ui.handle(InputEvent::Turn(3));
+        ui.handle(InputEvent::Select);
+        assert_eq!(ui.mode, PresetMode::Morph(1, 0.0));
+        let Some(Action::SetAll(values)) = ui.handle(InputEvent::Turn(10)) else {
+            panic!("turning morphs");
+        };
+        let values = values
+            .into_iter()
+            .collect::<std::collections::HashMap<_, _>>();
+        assert_eq!(values["main/freq"], 200.0);
+        assert_eq!(values["main/steps"], 3.0);
+        assert_eq!(ui.params[0].value, 200.0);
+        assert_eq!(ui.mode, PresetMode::Morph(1, 0.5));
+        // the preset at the cursor is not a target
+        ui.handle(InputEvent::Right);
+        assert!(matches!(ui.mode, PresetMode::Morph(1, _)));
+        ui.handle(InputEvent::Back);
+        assert_eq!(ui.mode, PresetMode::Browse);
+
+        // with one preset there is nothing to morph to
+        ui.set_presets(vec![Preset::capture("low", &low)], Some("low"));
+        ui.handle(InputEvent::Select);
+        ui.handle(InputEvent::Turn(3));
+        assert_eq!(ui.handle(InputEvent::Select), None);
+        assert_eq!(ui.mode, PresetMode::Browse);
+        assert!(ui.message.is_some());
+    }
+
+    #[test]
+    fn select_follows_a_wire_on_the_graph_page() {
+        let mut ui = loaded();
+        ui.page = Page::Graph;
+        let name = |ui: &Ui| ui.names[ui.selected().unwrap()].clone();
+        while name(&ui) != "main/freq" {
+            assert!(ui.handle(InputEvent::Down).is_none());
+        }
+        ui.handle(InputEvent::Select);
+        assert!(name(&ui).contains("saw@"), "{}", name(&ui));
+        ui.handle(InputEvent::Select);
+        assert!(name(&ui).contains("*@"), "{}", name(&ui));
+
+        // an output feeds nothing, select stays on it
+        while !name(&ui).ends_with("R") {
+            ui.handle(InputEvent::Down);
+        }
+        let at = ui.selected();
+        ui.handle(InputEvent::Select);
+        assert_eq!(ui.selected(), at);
+    }
+
+    #[test]
+    fn the_params_page_draws_its_cursor_and_the_value_edited() {
+        let mut ui = loaded();
+        ui.page = Page::Params;
+        let mut fb = Framebuffer::new(240, 240);
+        ui.draw(&mut fb).unwrap();
+        let y0 = HEADER_H + 2;
+        assert_eq!(fb.pixel(Point::new(0, 0)), Some(HEADER));
+        assert_eq!(fb.pixel(Point::new(1, y0)), Some(CURSOR));
+        assert_eq!(fb.pixel(Point::new(1, y0 + ROW_H)), Some(BACKGROUND));
+        assert_eq!(fb.pixel(Point::new(233, y0)), Some(CURSOR));
+        assert!(fb.pixels().contains(&FOREGROUND));
+
+        ui.handle(InputEvent::Select);
+        ui.draw(&mut fb).unwrap();
+        assert_eq!(fb.pixel(Point::new(233, y0)), Some(EDIT));
+        assert_eq!(fb.pixel(Point::new(1, y0)), Some(CURSOR));
+    }
+}
@@ -0,0 +1,300 @@
+use crate::ast::*;
+use crate::graph::{Edge, FlowGraph, ModuleBox, Node};
+use crate::midi::Source;
+use log::*;
+use std::collections::HashSet;
+
+pub const MAX_VOICES: usize = 64;
+
+/// which sounding voice a note takes when all of them are busy
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Steal {
+    #[default]
+    Oldest,
+    Quietest,
+    Lowest,
+}
+
+impl std::str::FromStr for Steal {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "oldest" => Ok(Steal::Oldest),
+            "quietest" => Ok(Steal::Quietest),
+            "lowest" => Ok(Steal::Lowest),
+            _ => Err(format!(
+                "unknown voice stealing {}, use oldest, quietest or lowest",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Voice {
+    pub note: u8,
+    pub velocity: f32,
+    pub gate: bool,
+    pub age: u64, // when it was last started or released
+}
+
+/// hands notes to voices, a free voice is the one released longest
+/// ago, with none free one is stolen
+#[derive(Debug, Clone)]
+pub struct VoiceAllocator {
+    pub voices: Vec<Voice>,
+    steal: Steal,
+    clock: u64,
+}
+
+impl VoiceAllocator {
+    pub fn new(n: usize, steal: Steal) -> Self {
+        VoiceAllocator {
+            voices: vec![Voice::default(); n],
+            steal,
+            clock: 0,
+        }
+    }
+
+    /// the voice that plays key now
+    pub fn note_on(&mut self, key: u8, velocity: f32) -> usize {
+        self.clock += 1;
+        let voices = self.voices.iter().enumerate();
+        // a key that is still down retriggers its own voice
+        let k = match voices.clone().find(|(_, v)| v.gate && v.note == key) {
+            Some((k, _)) => k,
+            None => match voices.filter(|(_, v)| !v.gate).min_by_key(|(_, v)| v.age) {
+                Some((k, _)) => k,
+                None => self.victim(),
+            },
+        };
+        self.voices[k] = Voice {
+            note: key,
+            velocity,
+            gate: true,
+            age: self.clock,
+        };
+        k
+    }
+
+    /// the voice that was playing key, if one still is
+    pub fn note_off(&mut self, key: u8) -> Option<usize> {
+        self.clock += 1;
+        let k = self.voices.iter().position(|v| v.gate && v.note == key)?;
+        self.voices[k].gate = false;
+        self.voices[k].age = self.clock;
+        Some(k)
+    }
+
+    fn victim(&self) -> usize {
+        let voices = self.voices.iter().enumerate();
+        let k = match self.steal {
+            Steal::Oldest => voices.min_by_key(|(_, v)| v.age),
+            Steal::Quietest => voices.min_by(|(_, a), (_, b)| a.velocity.total_cmp(&b.velocity)),
+            Steal::Lowest => voices.min_by_key(|(_, v)| v.note),
+        };
+        k.map(|(k, _)| k).unwrap_or(0)
+    }
+}
+
+/// node name of node_name in voice k, box/x becomes box#k/x
+pub fn voice_name(node_name: &str, k: usize) -> String {
+    node_name.replacen('/', &format!("#{}/", k), 1)
+}
+
+/// node name without the voice number
+pub fn base_name(node_name: &str) -> String {
+    match node_name.split_once('/') {
+        Some((b, local)) if b.contains('#') => {
+            b.split('#').next().unwrap_or(b).to_string() + "/" + local
+        }
+        _ => node_name.to_string(),
+    }
+}
+
+/// in ports of the voice box the MIDI maps set per voice
+pub fn voice_ports(top: &TopDef) -> Vec<(String, Source)> {
+    top.midi_maps()
+        .iter()
+        .filter_map(|m| {
+            let MidiMap::Map(port, _, _) = m;
+            match Source::from_map(m) {
+                Ok(source) if source.per_voice() => Some((port.clone(), source)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// the boxes wired from a box, directly or not, and the box itself
+fn closure(boxes: &[BoxDef], name: &str) -> HashSet<String> {
+    let mut found = HashSet::new();
+    let mut todo = vec![name.to_string()];
+    while let Some(name) = todo.pop() {
+        if !found.insert(name.clone()) {
+            continue;
+        }
+        if let Some(BoxDef::ModuleBox(_, _, stmts, _)) = boxes.iter().find(|b| *b.name() == name) {
+            for stmt in stmts {
+                if let Stmt::BoxWire(BoxWire::Boxw(callee, _, _)) = stmt {
+                    todo.push(callee.clone());
+                }
+            }
+        }
+    }
+    found
+}
+
+/// replace the nodes of each (voices n box) and of the boxes it wires
+/// with n copies, the edges into the box are copied to every voice
+/// except those into its per-voice ports, which are left free for the
+/// allocator to set, and the edges out of it are copied from every
+/// voice so the wires they feed sum the voices
+pub fn expand(graph: &mut FlowGraph, top: &TopDef) -> Result<(), String> {
+    let boxes = top.boxes();
+    let per_voice = voice_ports(top);
+    for VoicesDef::Voices(n, voice_box, _) in top.voices() {
+        let n = match n {
+            Numeric::Int32(n) if n > 0 && n as usize <= MAX_VOICES => n as usize,
+            _ => return Err(format!("bad voice count for {}", voice_box)),
+        };
+        let inner = closure(&boxes, &voice_box);
+        for b in boxes.iter().filter(|b| !inner.contains(b.name())) {
+            let BoxDef::ModuleBox(_, _, stmts, _) = b;
+            for stmt in stmts {
+                match stmt {
+                    Stmt::BoxWire(BoxWire::Boxw(callee, _, _))
+                        if *callee != voice_box && inner.contains(callee) =>
+                    {
+                        return Err(format!(
+                            "box {} is wired by {} and by the voices of {}",
+                            callee,
+                            b.name(),
+                            voice_box
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let free_ports = per_voice
+            .iter()
+            .map(|(port, _)| format!("{}/{}", voice_box, port))
+            .collect::<HashSet<_>>();
+        debug!("Expanding {} voices of {}: {:?}", n, voice_box, inner);
+
+        let in_voice = |node: &Node| inner.contains(&node.parent_box.name);
+        let mut copies: Vec<Vec<Box<Node>>> = vec![Vec::new(); n];
+        for node in graph.nodes.iter().filter(|node| in_voice(node)) {
+            for (k, copy) in copies.iter_mut().enumerate() {
+                let mut c = node.clone();
+                c.id = graph.node_id_counter;
+                c.name = voice_name(&node.name, k);
+                c.parent_box = Box::new(ModuleBox {
+                    name: format!("{}#{}", node.parent_box.name, k),
+                });
+                graph.node_id_counter += 1;
+                copy.push(c);
+            }
+        }
+        let copy_of = |node: &Node, k: usize| -> Box<Node> {
+            let name = voice_name(&node.name, k);
+            copies[k].iter().find(|c| c.name == name).unwrap().clone()
+        };
+        let mut edges = Vec::new();
+        for e in graph.edges.iter() {
+            let (from_in, to_in) = (in_voice(&e.from), in_voice(&e.to));
+            if !from_in && !to_in {
+                edges.push(e.clone());
+                continue;
+            }
+            if !from_in && free_ports.contains(&e.to.name) {
+                continue;
+            }
+            for k in 0..n {
+                let from = if from_in {
+                    copy_of(&e.from, k)
+                } else {
+                    e.from.clone()
+                };
+                let to = if to_in {
+                    copy_of(&e.to, k)
+                } else {
+                    e.to.clone()
+                };
+                edges.push(Box::new(Edge {
+                    id: graph.edge_id_counter,
+                    arg_no: e.arg_no,
+                    from,
+                    to,
+                }));
+                graph.edge_id_counter += 1;
+            }
+        }
+        graph.nodes.retain(|node| !in_voice(node));
+        graph.nodes.extend(copies.into_iter().flatten());
+        graph.edges = edges;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// three voices holding 60, 64 and 67, 64 played the softest
+    fn chord(steal: Steal) -> VoiceAllocator {
+        let mut alloc = VoiceAllocator::new(3, steal);
+        for (key, velocity) in [(60, 0.8), (64, 0.2), (67, 0.5)] {
+            alloc.note_on(key, velocity);
+        }
+        alloc
+    }
+
+    #[test]
+    fn a_held_key_retriggers_its_voice() {
+        let mut alloc = VoiceAllocator::new(4, Steal::Oldest);
+        assert_eq!(alloc.note_on(60, 0.5), 0);
+        assert_eq!(alloc.note_on(62, 0.5), 1);
+        assert_eq!(alloc.note_on(60, 0.9), 0);
+        assert_eq!(alloc.voices[0].velocity, 0.9);
+        assert!(alloc.voices[0].gate);
+        assert_eq!(alloc.voices.iter().filter(|v| v.gate).count(), 2);
+        assert_eq!(alloc.note_off(60), Some(0));
+        assert_eq!(alloc.note_off(60), None);
+    }
+
+    #[test]
+    fn a_free_voice_is_the_one_released_longest_ago() {
+        let mut alloc = chord(Steal::Oldest);
+        assert_eq!(alloc.note_off(67), Some(2));
+        assert_eq!(alloc.note_off(60), Some(0));
+        // 67 was released first, its voice is taken before 60's
+        assert_eq!(alloc.note_on(72, 0.5), 2);
+        assert_eq!(alloc.note_on(74, 0.5), 0);
+        assert_eq!(alloc.voices[0].note, 74);
+        // a released voice goes before stealing one still held
+        alloc.note_off(64);
+        assert_eq!(alloc.note_on(76, 0.5), 1);
+    }
+
+    #[test]
+    fn with_none_free_a_voice_is_stolen() {
+        let mut alloc = chord(Steal::Oldest);
+        assert_eq!(alloc.note_on(72, 0.5), 0);
+        assert_eq!(alloc.note_on(74, 0.5), 1);
+        // a retrigger makes a voice new again
+        alloc.note_on(67, 0.5);
+        assert_eq!(alloc.note_on(76, 0.5), 0);
+
+        let mut alloc = chord(Steal::Quietest);
+        assert_eq!(alloc.note_on(72, 0.5), 1);
+        assert_eq!(alloc.voices[1].note, 72);
+
+        let mut alloc = chord(Steal::Lowest);
+        assert_eq!(alloc.note_on(48, 0.5), 0);
+        // the new low note is stolen next
+        assert_eq!(alloc.note_on(50, 0.5), 0);
+        assert_eq!(alloc.note_off(48), None);
+    }
+}
@@ -0,0 +1,44 @@
+#!/bin/sh
+# check live MIDI input without a keyboard: rasynth midi send plays the
+# virtual keyboard into rasynth midi monitor, then, if snd-virmidi is
+# loaded and amidi is around, the same notes go through a virtual raw
+# MIDI port as a USB keyboard's would
+RASYNTH=${RASYNTH:-rasynth/target/debug/rasynth}
+OUT=$(mktemp)
+trap 'rm -f "$OUT"' EXIT
+
+modprobe snd-seq 2>/dev/null
+modprobe snd-virmidi 2>/dev/null
+
+expect_notes() {
+    sleep 0.5
+    kill "$PID" 2>/dev/null
+    wait "$PID" 2>/dev/null
+    got=$(grep -c NoteOn "$OUT")
+    if [ "$got" -ne 3 ]; then
+        echo "FAIL $1: expected 3 notes, got $got"
+        cat "$OUT"
+        exit 1
+    fi
+    echo "ok $1"
+}
+
+"$RASYNTH" midi monitor > "$OUT" 2>/dev/null &
+PID=$!
+sleep 1
+"$RASYNTH" midi send --to rasynth -n 60 -n 64 -n 67 -l 50 > /dev/null 2>&1
+expect_notes "sequencer"
+
+VIRMIDI=$(amidi -l 2>/dev/null | awk '/Virtual Raw MIDI/ { print $2; exit }')
+if [ -z "$VIRMIDI" ]; then
+    echo "skip virmidi: snd-virmidi not loaded or amidi missing"
+    exit 0
+fi
+"$RASYNTH" midi monitor --connect "Virtual Raw MIDI" > "$OUT" 2>/dev/null &
+PID=$!
+sleep 1
+for note in 3C 40 43; do
+    amidi -p "$VIRMIDI" -S "90 $note 64"
+    amidi -p "$VIRMIDI" -S "80 $note 00"
+done
+expect_notes "virmidi"